@@ -0,0 +1,167 @@
+//! Taking files a tree already holds hard linked together under management,
+//! so that stats, verify and later runs account for them as for the groups
+//! mirage linked itself. The files are left as they are: the store only
+//! gets one more hard link to each group, in place of the copy of its
+//! original a run would have made.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use log::{debug, trace};
+use serde::Serialize;
+
+use crate::{
+    back_up, check_interrupted, record_session, report::DuplicateGroup, walk, warn_walk_error,
+    Action, ActionType, ApplyOptions, MirageError, MirageState, DEFAULT_BACKUPS,
+    DEFAULT_HASH_ALGORITHM,
+};
+
+/// What [`adopt_hardlinks`] took under management.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct AdoptReport {
+    /// The hard link groups adopted, or found on a dry run, in path order.
+    pub groups: Vec<DuplicateGroup>,
+    /// Files hard linked to an already managed one, left out.
+    pub managed: Vec<PathBuf>,
+    pub dry_run: bool,
+}
+
+/// Device and inode of a file having other names, which every name of it
+/// shares.
+#[cfg(unix)]
+fn linked_inode(meta: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    (meta.nlink() > 1).then_some((meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+fn linked_inode(_meta: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Records every group of files of the tree at `target_dir` hard linked
+/// together as a managed duplicate group, without modifying any of them.
+/// Files hard linked only to files outside the tree are left alone, as are
+/// those hard linked to a file already managed. On a `dry_run` the groups
+/// are only reported.
+pub fn adopt_hardlinks<T: AsRef<Path>>(
+    target_dir: T,
+    dry_run: bool,
+) -> Result<AdoptReport, MirageError> {
+    let target_dir = target_dir.as_ref();
+    let mut state = if dry_run {
+        MirageState::peek(target_dir)?
+    } else {
+        MirageState::get(target_dir)?
+    };
+    state.ensure_unfrozen()?;
+    state.lock_run()?;
+    if state.wal.checkpoint < state.wal.actions.len() {
+        return Err(MirageError::PendingActions(target_dir.to_path_buf()));
+    }
+
+    let root = fs::canonicalize(target_dir)?;
+    let mut inodes: BTreeMap<(u64, u64), Vec<PathBuf>> = BTreeMap::new();
+    for entry in walk(&root, &ApplyOptions::default()) {
+        check_interrupted()?;
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                warn_walk_error(&err);
+                continue;
+            }
+        };
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        if let Some(inode) = linked_inode(&entry.metadata()?) {
+            trace!("{:?} has other names", entry.path());
+            inodes
+                .entry(inode)
+                .or_default()
+                .push(entry.path().to_path_buf());
+        }
+    }
+
+    let mut report = AdoptReport {
+        dry_run: state.dry_run,
+        ..Default::default()
+    };
+    let mut groups = inodes
+        .into_values()
+        .filter(|files| files.len() > 1)
+        .map(|mut files| {
+            files.sort();
+            files
+        })
+        .collect::<Vec<_>>();
+    groups.sort();
+    if !groups.is_empty() {
+        back_up(&state, "adopt-hardlinks", DEFAULT_BACKUPS)?;
+    }
+
+    let start = state.wal.actions.len();
+    for files in groups {
+        check_interrupted()?;
+        if files
+            .iter()
+            .any(|file| state.wal.redirections.contains_key(file))
+        {
+            debug!("{:?} is linked to a managed file, leaving it", files[0]);
+            report.managed.extend(
+                files
+                    .into_iter()
+                    .filter(|file| !state.wal.redirections.contains_key(file)),
+            );
+            continue;
+        }
+        let first = &files[0];
+        let name = first
+            .file_name()
+            .ok_or(MirageError::DotMirageInInconsistentState)?;
+        let size = fs::metadata(first)?.len();
+        let content = DEFAULT_HASH_ALGORITHM.hash_file(first)?;
+        let original = state.new_original_path(name, &content);
+        report.groups.push(DuplicateGroup {
+            content: content.clone(),
+            size,
+            files: files.clone(),
+            saved: size * (files.len() as u64 - 1),
+        });
+        if state.dry_run {
+            continue;
+        }
+
+        debug!("Adopting {} hard links of {:?}", files.len(), first);
+        let _lock = state.lock_original(&original)?;
+        if let Some(dir) = original.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::hard_link(first, &original)?;
+        let mut copy = Action::new(ActionType::Copy, first.clone(), original.clone());
+        copy.digest = Some(content);
+        copy.algorithm = Some(DEFAULT_HASH_ALGORITHM);
+        state.wal.push(copy);
+        for file in files {
+            state.wal.push(Action::new(
+                ActionType::Hardlink,
+                file.clone(),
+                original.clone(),
+            ));
+            state.wal.redirections.insert(file, original.clone());
+        }
+        // the files already are what the actions make of them
+        state.wal.checkpoint = state.wal.actions.len();
+        state.commit()?;
+    }
+
+    if state.wal.actions.len() > start {
+        let store_size = state.store_size()?;
+        record_session(&state, &state.wal.actions[start..], store_size)?;
+    }
+    report.managed.sort();
+    Ok(report)
+}
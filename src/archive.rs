@@ -0,0 +1,423 @@
+//! Report-only detection of archive members that duplicate other content.
+//!
+//! Zip archives are matched on the size and CRC-32 recorded in their central
+//! directory, so members never have to be decompressed. Uncompressed tar
+//! archives are read directly. Loose files are checksummed the same way, and
+//! only when their size matches some archive member.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use log::{debug, trace, warn};
+use serde::Serialize;
+
+use crate::{walk, warn_walk_error, ApplyOptions, MirageError};
+
+/// Where a piece of content was found.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Location {
+    File(PathBuf),
+    Member { archive: PathBuf, name: String },
+}
+
+/// A member of an archive whose contents exist elsewhere in the tree.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct MemberDuplicate {
+    pub name: String,
+    pub size: u64,
+    pub matches: Vec<Location>,
+}
+
+/// Duplicated members of one archive.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct ArchiveReport {
+    pub archive: PathBuf,
+    /// Number of file members in the archive.
+    pub members: usize,
+    pub duplicated: Vec<MemberDuplicate>,
+    /// Every member of the archive exists elsewhere, the archive adds nothing.
+    pub redundant: bool,
+}
+
+struct Member {
+    name: String,
+    size: u64,
+    crc: u32,
+}
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                0xEDB8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static CRC_TABLE: [u32; 256] = crc_table();
+
+/// IEEE CRC-32, as stored by zip, of everything `reader` yields.
+fn crc32<R: Read>(mut reader: R) -> io::Result<u32> {
+    let mut crc = !0u32;
+    let mut buf = [0; 10000];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        for byte in &buf[..n] {
+            crc = CRC_TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+        }
+    }
+    Ok(!crc)
+}
+
+fn u16_at(buf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([buf[at], buf[at + 1]])
+}
+
+fn u32_at(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what.to_string())
+}
+
+/// Lists the members of a zip archive from its central directory.
+fn zip_members(path: &Path) -> io::Result<Vec<Member>> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    // the end of central directory record sits in the last 64k + 22 bytes
+    let tail_len = len.min(65536 + 22);
+    file.seek(SeekFrom::Start(len - tail_len))?;
+    let mut tail = vec![0; tail_len as usize];
+    file.read_exact(&mut tail)?;
+    let eocd = (0..tail.len().saturating_sub(21))
+        .rev()
+        .find(|&i| u32_at(&tail, i) == 0x0605_4b50)
+        .ok_or_else(|| invalid("no end of central directory"))?;
+    let count = u16_at(&tail, eocd + 10) as usize;
+    let size = u32_at(&tail, eocd + 12) as usize;
+    let offset = u32_at(&tail, eocd + 16) as u64;
+
+    file.seek(SeekFrom::Start(offset))?;
+    let mut directory = vec![0; size];
+    file.read_exact(&mut directory)?;
+
+    let mut members = Vec::with_capacity(count);
+    let mut at = 0;
+    for _ in 0..count {
+        if at + 46 > directory.len() || u32_at(&directory, at) != 0x0201_4b50 {
+            return Err(invalid("corrupt central directory"));
+        }
+        let crc = u32_at(&directory, at + 16);
+        let size = u32_at(&directory, at + 24);
+        let name_len = u16_at(&directory, at + 28) as usize;
+        let extra_len = u16_at(&directory, at + 30) as usize;
+        let comment_len = u16_at(&directory, at + 32) as usize;
+        let name = directory
+            .get(at + 46..at + 46 + name_len)
+            .ok_or_else(|| invalid("corrupt central directory"))?;
+        let name = String::from_utf8_lossy(name).into_owned();
+        at += 46 + name_len + extra_len + comment_len;
+
+        if name.ends_with('/') {
+            continue;
+        }
+        if size == u32::MAX {
+            debug!("Skipping zip64 member {} of {:?}", name, path);
+            continue;
+        }
+        members.push(Member {
+            name,
+            size: size as u64,
+            crc,
+        });
+    }
+    Ok(members)
+}
+
+fn tar_field(header: &[u8]) -> String {
+    let end = header.iter().position(|b| *b == 0).unwrap_or(header.len());
+    String::from_utf8_lossy(&header[..end]).into_owned()
+}
+
+fn tar_size(field: &[u8]) -> io::Result<u64> {
+    if field[0] & 0x80 != 0 {
+        // base-256, used for members of 8GiB and more
+        return Ok(field[1..]
+            .iter()
+            .fold(0u64, |size, byte| (size << 8) | *byte as u64));
+    }
+    let octal = tar_field(field);
+    let octal = octal.trim_matches(|c: char| c == ' ' || c == '\0');
+    if octal.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(octal, 8).map_err(|_| invalid("corrupt tar size"))
+}
+
+/// Lists the regular file members of an uncompressed tar archive.
+fn tar_members(path: &Path) -> io::Result<Vec<Member>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut members = Vec::new();
+    let mut long_name = None;
+    let mut header = [0u8; 512];
+    loop {
+        if reader.read_exact(&mut header).is_err() || header.iter().all(|b| *b == 0) {
+            break;
+        }
+        let size = tar_size(&header[124..136])?;
+        let padded = size.div_ceil(512) * 512;
+        let kind = header[156];
+        let mut name = tar_field(&header[0..100]);
+        if &header[257..262] == b"ustar" {
+            let prefix = tar_field(&header[345..500]);
+            if !prefix.is_empty() {
+                name = format!("{}/{}", prefix, name);
+            }
+        }
+
+        let mut data = (&mut reader).take(size);
+        match kind {
+            b'L' => {
+                // GNU long name for the following member
+                let mut buf = Vec::new();
+                data.read_to_end(&mut buf)?;
+                long_name = Some(tar_field(&buf));
+            }
+            b'0' | 0 => {
+                let crc = crc32(&mut data)?;
+                members.push(Member {
+                    name: long_name.take().unwrap_or(name),
+                    size,
+                    crc,
+                });
+            }
+            _ => {
+                trace!("Skipping tar entry {} of type {}", name, kind);
+                long_name = None;
+                io::copy(&mut data, &mut io::sink())?;
+            }
+        }
+        io::copy(&mut (&mut reader).take(padded - size), &mut io::sink())?;
+    }
+    Ok(members)
+}
+
+fn archive_members(path: &Path) -> Option<io::Result<Vec<Member>>> {
+    let extension = path.extension()?.to_string_lossy().to_lowercase();
+    match extension.as_str() {
+        "zip" => Some(zip_members(path)),
+        "tar" => Some(tar_members(path)),
+        _ => None,
+    }
+}
+
+/// Looks inside the zip and tar archives of `target_dir` and reports the
+/// members whose contents also exist as loose files or in other archives.
+/// Nothing is modified.
+pub fn archive_report<T: AsRef<Path>>(target_dir: T) -> Result<Vec<ArchiveReport>, MirageError> {
+    let mut files = Vec::new();
+    let mut archives = Vec::new();
+    for entry in walk(target_dir.as_ref(), &ApplyOptions::everything()) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(x) => {
+                warn_walk_error(&x);
+                continue;
+            }
+        };
+        if entry.file_type().is_dir() {
+            continue;
+        }
+        let path = entry.path().to_path_buf();
+        if let Some(members) = archive_members(&path) {
+            match members {
+                Ok(members) => archives.push((path.clone(), members)),
+                Err(err) => warn!("Can't read archive {:?} due to {:?}", path, err),
+            }
+        }
+        files.push(path);
+    }
+
+    // content key -> everywhere it was seen
+    let mut seen: HashMap<(u64, u32), Vec<Location>> = HashMap::new();
+    for (archive, members) in archives.iter() {
+        for member in members {
+            seen.entry((member.size, member.crc))
+                .or_default()
+                .push(Location::Member {
+                    archive: archive.clone(),
+                    name: member.name.clone(),
+                });
+        }
+    }
+    let sizes = seen.keys().map(|(size, _)| *size).collect::<HashSet<_>>();
+    for path in files {
+        let Ok(meta) = fs::metadata(&path) else {
+            continue;
+        };
+        if !meta.is_file() || !sizes.contains(&meta.len()) {
+            continue;
+        }
+        let crc = crc32(BufReader::new(File::open(&path)?))?;
+        if let Some(locations) = seen.get_mut(&(meta.len(), crc)) {
+            locations.push(Location::File(path));
+        }
+    }
+
+    let mut reports = Vec::new();
+    for (archive, members) in archives {
+        let duplicated = members
+            .iter()
+            .filter_map(|member| {
+                let mut matches = seen[&(member.size, member.crc)]
+                    .iter()
+                    .filter(|location| match location {
+                        Location::Member { archive: a, name } => {
+                            *a != archive || *name != member.name
+                        }
+                        Location::File(_) => true,
+                    })
+                    .cloned()
+                    .collect::<Vec<_>>();
+                matches.sort();
+                (!matches.is_empty()).then(|| MemberDuplicate {
+                    name: member.name.clone(),
+                    size: member.size,
+                    matches,
+                })
+            })
+            .collect::<Vec<_>>();
+        if duplicated.is_empty() {
+            continue;
+        }
+        reports.push(ArchiveReport {
+            redundant: duplicated.len() == members.len(),
+            archive,
+            members: members.len(),
+            duplicated,
+        });
+    }
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::tempdir;
+
+    use super::{archive_report, crc32, Location};
+
+    fn tar_entry(name: &str, contents: &[u8]) -> Vec<u8> {
+        let mut header = [0u8; 512];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        let size = format!("{:011o}\0", contents.len());
+        header[124..136].copy_from_slice(size.as_bytes());
+        header[156] = b'0';
+        let mut entry = header.to_vec();
+        entry.extend_from_slice(contents);
+        entry.resize(512 + contents.len().div_ceil(512) * 512, 0);
+        entry
+    }
+
+    fn stored_zip(name: &str, contents: &[u8]) -> Vec<u8> {
+        let crc = crc32(contents).unwrap();
+        let mut zip = Vec::new();
+        let mut common = Vec::new();
+        common.extend_from_slice(&0u16.to_le_bytes()); // flags
+        common.extend_from_slice(&0u16.to_le_bytes()); // stored
+        common.extend_from_slice(&0u32.to_le_bytes()); // time and date
+        common.extend_from_slice(&crc.to_le_bytes());
+        common.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+        common.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+        common.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes()); // extra
+
+        zip.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+        zip.extend_from_slice(&20u16.to_le_bytes());
+        zip.extend_from_slice(&common);
+        zip.extend_from_slice(name.as_bytes());
+        zip.extend_from_slice(contents);
+
+        let directory_offset = zip.len();
+        zip.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+        zip.extend_from_slice(&20u16.to_le_bytes());
+        zip.extend_from_slice(&20u16.to_le_bytes());
+        zip.extend_from_slice(&common);
+        zip.extend_from_slice(&[0; 10]); // comment, disk, attributes
+        zip.extend_from_slice(&0u32.to_le_bytes()); // local header offset
+        zip.extend_from_slice(name.as_bytes());
+        let directory_size = zip.len() - directory_offset;
+
+        zip.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+        zip.extend_from_slice(&[0; 4]);
+        zip.extend_from_slice(&1u16.to_le_bytes());
+        zip.extend_from_slice(&1u16.to_le_bytes());
+        zip.extend_from_slice(&(directory_size as u32).to_le_bytes());
+        zip.extend_from_slice(&(directory_offset as u32).to_le_bytes());
+        zip.extend_from_slice(&0u16.to_le_bytes());
+        zip
+    }
+
+    #[test]
+    fn archive_report_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        fs::write(dir_path.join("loose.txt"), "duplicate content").unwrap();
+
+        let mut tar = tar_entry("copy.txt", b"duplicate content");
+        tar.extend(tar_entry("unique.txt", b"unique content"));
+        tar.extend([0; 1024]);
+        fs::write(dir_path.join("bundle.tar"), tar).unwrap();
+
+        fs::write(
+            dir_path.join("bundle.zip"),
+            stored_zip("nested/copy.txt", b"duplicate content"),
+        )
+        .unwrap();
+
+        let reports = archive_report(dir_path).unwrap();
+        assert_eq!(reports.len(), 2);
+
+        let tar = &reports[0];
+        assert_eq!(tar.archive, dir_path.join("bundle.tar"));
+        assert_eq!(tar.members, 2);
+        assert!(!tar.redundant);
+        assert_eq!(tar.duplicated.len(), 1);
+        assert_eq!(tar.duplicated[0].name, "copy.txt");
+        assert_eq!(
+            tar.duplicated[0].matches,
+            vec![
+                Location::File(dir_path.join("loose.txt")),
+                Location::Member {
+                    archive: dir_path.join("bundle.zip"),
+                    name: "nested/copy.txt".to_string(),
+                },
+            ]
+        );
+
+        let zip = &reports[1];
+        assert_eq!(zip.archive, dir_path.join("bundle.zip"));
+        assert!(zip.redundant);
+    }
+}
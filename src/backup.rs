@@ -0,0 +1,188 @@
+//! Rotated backups of the WAL, taken before every apply and revert, so that
+//! a state that went wrong can be rolled back with `mirage restore-state`.
+//!
+//! A backup is a directory below `backups/` in the store holding a copy of
+//! `wal.json`, its signature and the segment and redirections files it
+//! lists, numbered from 1 for the most recent. Those files never change
+//! once written, so they are hard linked rather than copied where possible.
+
+use std::{
+    fs::{self, File},
+    io::BufReader,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{schema::read_wal, MirageError, MirageState, SIGNATURE_FILE, WAL};
+
+/// Directory in the store holding the backups.
+const BACKUPS_DIR: &str = "backups";
+
+/// File in a backup describing it.
+const BACKUP_FILE: &str = "backup.json";
+
+/// Number of backups kept when not told otherwise.
+pub const DEFAULT_BACKUPS: usize = 5;
+
+/// A backup of the state of a managed tree.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct StateBackup {
+    /// 1 for the most recent, as passed to [`restore_state`].
+    #[serde(skip)]
+    pub number: usize,
+    /// Seconds since the epoch when it was taken.
+    pub taken: u64,
+    /// The command it was taken before.
+    pub command: String,
+    /// Number of actions in the WAL.
+    pub actions: usize,
+    pub checkpoint: usize,
+}
+
+/// Copies `file` of `from` to `to`, linking it if `link` allows.
+fn copy(from: &Path, to: &Path, file: &str, link: bool) -> Result<(), MirageError> {
+    let (from, to) = (from.join(file), to.join(file));
+    if link && fs::hard_link(&from, &to).is_ok() {
+        return Ok(());
+    }
+    fs::copy(from, to)?;
+    Ok(())
+}
+
+/// The segment and redirections files `wal` lists.
+fn listed_files(wal: &WAL) -> impl Iterator<Item = &str> {
+    wal.segments
+        .iter()
+        .map(|segment| segment.file.as_str())
+        .chain(wal.redirection_files.iter().map(|file| file.file.as_str()))
+}
+
+/// Backs up the WAL of `state` as it is on disk before `command` runs,
+/// keeping the `keep` most recent backups. Nothing is kept of an empty WAL.
+pub(crate) fn back_up(state: &MirageState, command: &str, keep: usize) -> Result<(), MirageError> {
+    if keep == 0 || state.dry_run || state.wal.actions.is_empty() {
+        return Ok(());
+    }
+    let backups = state.source_path.join(BACKUPS_DIR);
+    fs::create_dir_all(&backups)?;
+    for backup in list(&state.source_path)?.into_iter().rev() {
+        let dir = backups.join(backup.number.to_string());
+        if backup.number >= keep {
+            fs::remove_dir_all(dir)?;
+        } else {
+            fs::rename(dir, backups.join((backup.number + 1).to_string()))?;
+        }
+    }
+
+    let dir = backups.join("1");
+    debug!(
+        "Backing up the state of {:?} to {:?}",
+        state.source_path, dir
+    );
+    fs::create_dir(&dir)?;
+    copy(&state.source_path, &dir, "wal.json", false)?;
+    if state.source_path.join(SIGNATURE_FILE).exists() {
+        copy(&state.source_path, &dir, SIGNATURE_FILE, false)?;
+    }
+    for file in listed_files(&state.wal) {
+        copy(&state.source_path, &dir, file, true)?;
+    }
+    let backup = StateBackup {
+        number: 1,
+        taken: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+        command: command.to_string(),
+        actions: state.wal.actions.len(),
+        checkpoint: state.wal.checkpoint,
+    };
+    serde_json::to_writer_pretty(File::create(dir.join(BACKUP_FILE))?, &backup)?;
+    Ok(())
+}
+
+/// The backups in the store at `mirage_path`, most recent first.
+fn list(mirage_path: &Path) -> Result<Vec<StateBackup>, MirageError> {
+    let backups = mirage_path.join(BACKUPS_DIR);
+    if !backups.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut found = Vec::new();
+    for entry in fs::read_dir(backups)? {
+        let entry = entry?;
+        let Some(number) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse().ok())
+        else {
+            continue;
+        };
+        let backup = match File::open(entry.path().join(BACKUP_FILE))
+            .map_err(MirageError::from)
+            .and_then(|file| Ok(serde_json::from_reader(BufReader::new(file))?))
+        {
+            Ok(backup) => backup,
+            Err(err) => {
+                warn!("Skipping unreadable backup {:?}: {:?}", entry.path(), err);
+                continue;
+            }
+        };
+        found.push(StateBackup { number, ..backup });
+    }
+    found.sort_by_key(|backup| backup.number);
+    Ok(found)
+}
+
+/// The backups of the state of the tree at `target_dir`, most recent first.
+pub fn state_backups<T: AsRef<Path>>(target_dir: T) -> Result<Vec<StateBackup>, MirageError> {
+    let state = MirageState::open(&target_dir)?;
+    list(&state.source_path)
+}
+
+/// Replaces the state of the tree at `target_dir` with backup `number`. The
+/// tree itself is left as it is, `mirage diff` tells how far it is from the
+/// restored state.
+pub fn restore_state<T: AsRef<Path>>(target_dir: T, number: usize) -> Result<(), MirageError> {
+    let mut state = MirageState::open(&target_dir)?;
+    state.ensure_unfrozen()?;
+    if state.dry_run {
+        warn!("Store is read-only, not restoring {:?}", state.source_path);
+        return Ok(());
+    }
+    state.lock_run()?;
+    let dir = state.source_path.join(BACKUPS_DIR).join(number.to_string());
+    if !dir.join(BACKUP_FILE).is_file() {
+        return Err(MirageError::MissingBackup(number));
+    }
+    debug!(
+        "Restoring the state of {:?} from {:?}",
+        state.source_path, dir
+    );
+
+    let restored = read_wal(&fs::read(dir.join("wal.json"))?)?;
+    for file in listed_files(&restored) {
+        if !state.source_path.join(file).exists() {
+            copy(&dir, &state.source_path, file, true)?;
+        }
+    }
+    // the new wal.json goes in whole or not at all
+    let tmp = state.source_path.join("wal.json.restore");
+    fs::copy(dir.join("wal.json"), &tmp)?;
+    if dir.join(SIGNATURE_FILE).exists() {
+        copy(&dir, &state.source_path, SIGNATURE_FILE, false)?;
+    } else if state.source_path.join(SIGNATURE_FILE).exists() {
+        fs::remove_file(state.source_path.join(SIGNATURE_FILE))?;
+    }
+    fs::rename(tmp, state.source_path.join("wal.json"))?;
+
+    let kept = listed_files(&restored).collect::<Vec<_>>();
+    for file in listed_files(&state.wal) {
+        if !kept.contains(&file) {
+            fs::remove_file(state.source_path.join(file))?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,363 @@
+//! Timing the ways mirage could compare and hash files over a sample of a
+//! tree, for picking settings on storage that behaves unlike a local disk,
+//! such as NFS mounts or SMR drives.
+//!
+//! Every strategy reads the whole sample. On Linux the sample is dropped
+//! from the page cache before each read, so every strategy reads from the
+//! storage rather than from memory left by the one before. Elsewhere only
+//! the first strategy reads cold and the timings flatter the others.
+
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use log::{debug, trace};
+use serde::Serialize;
+
+use crate::{
+    digest::Sha256, full_match, hash_file, walk, warn_walk_error, ApplyOptions, MirageError,
+};
+
+/// A way of telling whether files are the same.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+pub enum Strategy {
+    /// Reading both files through buffers, as apply does.
+    BufferedCompare,
+    /// Mapping both files into memory and comparing the mappings.
+    MmapCompare,
+    /// Hashing with MD5, the content id of files.
+    Md5,
+    /// Hashing with SHA-256.
+    Sha256,
+}
+
+impl Strategy {
+    /// Every strategy this platform supports.
+    fn all() -> Vec<Strategy> {
+        let mut all = vec![Strategy::BufferedCompare];
+        if cfg!(unix) {
+            all.push(Strategy::MmapCompare);
+        }
+        all.extend([Strategy::Md5, Strategy::Sha256]);
+        all
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Strategy::BufferedCompare => "buffered compare",
+            Strategy::MmapCompare => "mmap compare",
+            Strategy::Md5 => "md5",
+            Strategy::Sha256 => "sha256",
+        }
+    }
+
+    /// Whether the strategy hashes files rather than comparing them.
+    pub fn hashes(&self) -> bool {
+        matches!(self, Strategy::Md5 | Strategy::Sha256)
+    }
+
+    /// Reads `path` the way the strategy does.
+    fn run(&self, path: &Path) -> Result<(), MirageError> {
+        // each file is compared with itself, there is no telling which files
+        // of a sample are duplicates
+        match self {
+            Strategy::BufferedCompare => {
+                full_match(path, path)?;
+            }
+            Strategy::MmapCompare => {
+                mmap_match(path, path)?;
+            }
+            Strategy::Md5 => {
+                hash_file(path)?;
+            }
+            Strategy::Sha256 => {
+                let mut reader = BufReader::new(File::open(path)?);
+                let mut hasher = Sha256::default();
+                let mut buf = [0; 10000];
+                loop {
+                    let n = reader.read(&mut buf)?;
+                    if n == 0 {
+                        break;
+                    }
+                    hasher.update(&buf[..n]);
+                }
+                hasher.finish();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How much of the tree to benchmark on.
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    /// Most files in the sample, spread evenly over the tree.
+    pub sample_files: usize,
+    /// Most bytes in the sample, reached before `sample_files` on trees of
+    /// big files.
+    pub sample_bytes: u64,
+}
+
+impl Default for BenchOptions {
+    fn default() -> Self {
+        BenchOptions {
+            sample_files: 64,
+            sample_bytes: 256 << 20,
+        }
+    }
+}
+
+/// How fast one strategy went through the sample.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct StrategyTiming {
+    pub strategy: Strategy,
+    pub files: usize,
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl StrategyTiming {
+    /// Bytes read per second.
+    pub fn throughput(&self) -> f64 {
+        if self.elapsed.is_zero() {
+            return 0.0;
+        }
+        self.bytes as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+/// What [`bench`] measured and what it recommends.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct BenchReport {
+    /// One per strategy, in the order they ran.
+    pub timings: Vec<StrategyTiming>,
+    /// The fastest way of comparing, `None` for an empty sample.
+    pub compare: Option<Strategy>,
+    /// The fastest hash, `None` for an empty sample.
+    pub hash: Option<Strategy>,
+    /// Whether every strategy read the sample from the storage, see the
+    /// module docs.
+    pub cold: bool,
+}
+
+/// Drops the pages of `path` from the page cache.
+#[cfg(target_os = "linux")]
+fn evict(path: &Path) -> Result<(), MirageError> {
+    use std::os::unix::io::AsRawFd;
+
+    let file = File::open(path)?;
+    // SAFETY: the descriptor stays open for the call, advice never fails
+    // in a way that matters here
+    unsafe {
+        libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED);
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn evict(_path: &Path) -> Result<(), MirageError> {
+    Ok(())
+}
+
+/// A read-only mapping of a whole file.
+#[cfg(unix)]
+struct Mapping {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+#[cfg(unix)]
+impl Mapping {
+    fn new(file: &File, len: usize) -> Result<Mapping, MirageError> {
+        use std::os::unix::io::AsRawFd;
+
+        // SAFETY: a private read-only mapping of an open file, unmapped on drop
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(Mapping { ptr, len })
+    }
+
+    fn bytes(&self) -> &[u8] {
+        // SAFETY: the mapping is `len` bytes long and lives as long as self.
+        // A file truncated meanwhile faults, which a benchmark can live with
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: unmaps what `new` mapped, nothing borrows it any more
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+/// Compares two files by mapping them into memory.
+#[cfg(unix)]
+fn mmap_match(here: &Path, there: &Path) -> Result<bool, MirageError> {
+    let (here, there) = (File::open(here)?, File::open(there)?);
+    let len = here.metadata()?.len();
+    if len != there.metadata()?.len() {
+        return Ok(false);
+    }
+    if len == 0 {
+        return Ok(true);
+    }
+    let len = usize::try_from(len).map_err(|_| std::io::Error::other("file too big to map"))?;
+    Ok(Mapping::new(&here, len)?.bytes() == Mapping::new(&there, len)?.bytes())
+}
+
+#[cfg(not(unix))]
+fn mmap_match(here: &Path, there: &Path) -> Result<bool, MirageError> {
+    full_match(here, there)
+}
+
+/// Up to `options.sample_files` files of the tree, spread evenly over it,
+/// with their sizes.
+fn sample(target_dir: &Path, options: &BenchOptions) -> Vec<(PathBuf, u64)> {
+    let mut files = Vec::new();
+    for entry in walk(target_dir, &ApplyOptions::everything()) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                warn_walk_error(&err);
+                continue;
+            }
+        };
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        match entry.metadata() {
+            Ok(meta) if meta.len() > 0 => files.push((entry.into_path(), meta.len())),
+            _ => {}
+        }
+    }
+    let step = files.len().div_ceil(options.sample_files.max(1)).max(1);
+    let mut sample = Vec::new();
+    let mut bytes = 0;
+    for (path, len) in files.into_iter().step_by(step) {
+        if !sample.is_empty() && bytes + len > options.sample_bytes {
+            break;
+        }
+        bytes += len;
+        sample.push((path, len));
+    }
+    sample
+}
+
+/// Times every strategy over a sample of the tree at `target_dir`. Nothing
+/// is written, the tree doesn't need to be managed.
+pub fn bench<T: AsRef<Path>>(
+    target_dir: T,
+    options: &BenchOptions,
+) -> Result<BenchReport, MirageError> {
+    let sample = sample(target_dir.as_ref(), options);
+    debug!("Benchmarking over {} files", sample.len());
+    let mut timings = Vec::new();
+    for strategy in Strategy::all() {
+        let mut timing = StrategyTiming {
+            strategy,
+            files: 0,
+            bytes: 0,
+            elapsed: Duration::ZERO,
+        };
+        for (path, len) in &sample {
+            evict(path)?;
+            trace!("Reading {:?} with {}", path, strategy.name());
+            let started = Instant::now();
+            strategy.run(path)?;
+            timing.elapsed += started.elapsed();
+            timing.files += 1;
+            timing.bytes += len;
+        }
+        timings.push(timing);
+    }
+
+    let fastest = |hashes: bool| {
+        timings
+            .iter()
+            .filter(|timing| timing.strategy.hashes() == hashes && timing.files > 0)
+            .max_by(|a, b| a.throughput().total_cmp(&b.throughput()))
+            .map(|timing| timing.strategy)
+    };
+    Ok(BenchReport {
+        compare: fastest(false),
+        hash: fastest(true),
+        cold: cfg!(target_os = "linux"),
+        timings,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::tempdir;
+
+    use super::{bench, mmap_match, BenchOptions, Strategy};
+
+    #[test]
+    fn times_every_strategy_over_the_sample() {
+        let dir = tempdir().unwrap();
+        for i in 0..10 {
+            fs::write(dir.path().join(format!("file{}", i)), vec![i; 1000]).unwrap();
+        }
+        fs::write(dir.path().join("empty"), "").unwrap();
+
+        let options = BenchOptions {
+            sample_files: 4,
+            ..Default::default()
+        };
+        let report = bench(dir.path(), &options).unwrap();
+        assert_eq!(report.timings.len(), Strategy::all().len());
+        for timing in &report.timings {
+            // every third file, empty ones left out
+            assert_eq!((timing.files, timing.bytes), (4, 4000));
+        }
+        assert!(report.compare.is_some_and(|strategy| !strategy.hashes()));
+        assert!(report.hash.is_some_and(|strategy| strategy.hashes()));
+
+        // the sample stops short of the byte cap, one file at least
+        let options = BenchOptions {
+            sample_files: 10,
+            sample_bytes: 2500,
+        };
+        let report = bench(dir.path(), &options).unwrap();
+        assert_eq!(report.timings[0].files, 2);
+
+        let empty = tempdir().unwrap();
+        let report = bench(empty.path(), &BenchOptions::default()).unwrap();
+        assert_eq!((report.compare, report.hash), (None, None));
+    }
+
+    #[test]
+    fn mmap_compares_contents() {
+        let dir = tempdir().unwrap();
+        let (a, b, c) = (
+            dir.path().join("a"),
+            dir.path().join("b"),
+            dir.path().join("c"),
+        );
+        fs::write(&a, "same").unwrap();
+        fs::write(&b, "same").unwrap();
+        fs::write(&c, "diff").unwrap();
+        assert!(mmap_match(&a, &b).unwrap());
+        assert!(!mmap_match(&a, &c).unwrap());
+    }
+}
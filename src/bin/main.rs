@@ -1,13 +1,313 @@
-use clap::{Parser, Subcommand};
-use mirage::{apply, revert};
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::{self, BufRead, BufWriter, IsTerminal, Write},
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+    sync::OnceLock,
+    time::Duration,
+};
+
+use clap::{Parser, Subcommand, ValueEnum};
+use mirage::{
+    adopt_hardlinks, apply_plan, apply_with_options, archive_report, bench, break_stale_lock,
+    checkpoints, comparisons, create_checkpoint, delete_checkpoint, diff, diff_reports, disk_usage,
+    export_script, find_store_root, fsck_with_options, handle_interrupts, identical_subtrees,
+    inspect_groups, inspect_wal, journal, list_groups, lock, manifest, merge, migrate,
+    originals_dir, parse_size, read_file_list_at, reapply, rehash, remove, replay, replay_plan,
+    restore_state, revert_with_options, sandbox, set_read_only, shard, state_backups, stats,
+    stats_history, status, store_status, unlock, unshare, upgrade, verify, watch, why,
+    write_manifest_csv, ApplyOptions, ApplyReport, BenchOptions, Config, FsckOptions, GroupOrder,
+    HashAlgorithm, LinkMode, LinkPolicy, Location, MirageError, MirageState, Plan, PlannedAction,
+    Profile, Redaction, RevertOptions, RunReport, Shell, StoreLayout, Unmigrated, WalFilter,
+    DEFAULT_BACKUPS, DEFAULT_HASH_ALGORITHM, KEYFILE_ENV, SIGN_EXISTING_ENV, STATE_DIR_ENV,
+    STORE_ENV,
+};
+
+/// File named with --output, reports go to stdout without one.
+static OUTPUT: OnceLock<File> = OnceLock::new();
+
+/// Where the report of the command goes.
+fn output() -> Box<dyn Write> {
+    match OUTPUT.get() {
+        Some(file) => Box::new(file),
+        None => Box::new(io::stdout()),
+    }
+}
+
+/// How paths are redacted in what the command prints, set with --redact.
+static REDACTION: OnceLock<Redaction> = OnceLock::new();
+
+/// `path` for printing, redacted if asked to.
+fn shown<P: AsRef<Path>>(path: P) -> String {
+    match REDACTION.get() {
+        Some(redaction) => redaction.path(path.as_ref()).display().to_string(),
+        None => path.as_ref().display().to_string(),
+    }
+}
+
+/// `text` with the quoted paths in it redacted, if asked to.
+fn redacted(text: String) -> String {
+    match REDACTION.get() {
+        Some(redaction) => redaction.text(&text),
+        None => text,
+    }
+}
+
+/// Set with --json: commands print one JSON object per line instead of
+/// text, for other tools to read.
+static JSON: OnceLock<bool> = OnceLock::new();
+
+fn json_mode() -> bool {
+    JSON.get().copied().unwrap_or_default()
+}
+
+/// `message` as a JSON line of the `event` kind, for --json.
+fn message_line(event: &str, message: String) -> String {
+    serde_json::json!({ "event": event, "message": message }).to_string()
+}
+
+/// Prints a line of the report of the command, to the file named with
+/// --output if there is one. With --json it is a `message` event.
+macro_rules! outputln {
+    () => {
+        outputln!("")
+    };
+    ($($arg:tt)*) => {{
+        let line = redacted(format!($($arg)*));
+        let line = if json_mode() { message_line("message", line) } else { line };
+        if let Err(err) = writeln!(output(), "{}", line) {
+            errorln!("Error writing output: {}", err);
+            std::process::exit(1);
+        }
+    }};
+}
+
+/// Prints a line to stderr, with paths redacted if asked to. With --json it
+/// is an `error` event.
+macro_rules! errorln {
+    ($($arg:tt)*) => {{
+        let line = redacted(format!($($arg)*));
+        if json_mode() {
+            eprintln!("{}", message_line("error", line));
+        } else {
+            eprintln!("{}", line);
+        }
+    }};
+}
+
+/// Writes `value` to the output as pretty-printed JSON, or on a single line
+/// with --json, redacting the strings that hold paths if asked to.
+fn write_json<T: serde::Serialize>(value: &T) -> io::Result<()> {
+    let mut value = serde_json::to_value(value)?;
+    if let Some(redaction) = REDACTION.get() {
+        redaction.json(&mut value);
+    }
+    let mut out = BufWriter::new(output());
+    if json_mode() {
+        serde_json::to_writer(&mut out, &value)?;
+    } else {
+        serde_json::to_writer_pretty(&mut out, &value)?;
+    }
+    writeln!(out)?;
+    out.flush()
+}
+
+/// Prints `value` as one JSON line of the `event` kind, for --json. The
+/// fields of an object are the fields of the event, anything else is its
+/// `value`.
+fn emit<T: serde::Serialize>(event: &str, value: &T) {
+    let line = serde_json::to_value(value).map(|value| match value {
+        serde_json::Value::Object(mut fields) => {
+            fields.insert("event".to_string(), event.into());
+            serde_json::Value::Object(fields)
+        }
+        value => serde_json::json!({ "event": event, "value": value }),
+    });
+    if let Err(err) = line
+        .map_err(io::Error::from)
+        .and_then(|line| write_json(&line))
+    {
+        errorln!("Error writing output: {}", err);
+        std::process::exit(1);
+    }
+}
+
+/// Passes log records on to the logger it wraps with the paths in them
+/// redacted.
+struct RedactingLogger {
+    inner: Box<dyn log::Log>,
+    redaction: Redaction,
+}
+
+impl log::Log for RedactingLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.inner.enabled(record.metadata()) {
+            return;
+        }
+        let message = self.redaction.text(&record.args().to_string());
+        self.inner.log(
+            &log::Record::builder()
+                .args(format_args!("{}", message))
+                .metadata(record.metadata().clone())
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build(),
+        );
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+/// Number of files a revert restores from which it asks before going ahead.
+const CONFIRM_REVERT_FILES: usize = 1000;
+
+/// Whether someone is at a terminal to answer prompts: stdin and stderr are
+/// terminals, and `CI` isn't set as CI services set it.
+fn interactive() -> bool {
+    io::stdin().is_terminal() && io::stderr().is_terminal() && std::env::var_os("CI").is_none()
+}
+
+/// Whether logs are colored: only on a terminal, and neither `NO_COLOR` nor
+/// a dumb `TERM` is set.
+fn colored() -> bool {
+    io::stderr().is_terminal()
+        && std::env::var_os("NO_COLOR").is_none()
+        && std::env::var_os("TERM").is_none_or(|term| term != "dumb")
+}
+
+/// How a question a command would ask before going ahead is settled.
+#[derive(Debug, PartialEq, Eq)]
+enum Confirmation {
+    /// --yes was given.
+    GoAhead,
+    /// Someone is at the terminal to answer it.
+    Ask,
+    /// Nobody is there to answer it and --yes wasn't given.
+    Refuse,
+}
+
+impl Confirmation {
+    fn new(yes: bool, interactive: bool) -> Confirmation {
+        match (yes, interactive) {
+            (true, _) => Confirmation::GoAhead,
+            (false, true) => Confirmation::Ask,
+            (false, false) => Confirmation::Refuse,
+        }
+    }
+}
+
+/// Asks `question` on the terminal, true if it was answered yes. Runs given
+/// --yes go ahead without asking, unattended runs without it never do.
+fn confirm(question: &str, yes: bool) -> bool {
+    match Confirmation::new(yes, interactive()) {
+        Confirmation::GoAhead => return true,
+        Confirmation::Refuse => {
+            errorln!(
+                "{} Nobody is at a terminal to answer, rerun with --yes to go ahead",
+                question
+            );
+            return false;
+        }
+        Confirmation::Ask => {}
+    }
+    eprint!("{} [y/N] ", redacted(question.to_string()));
+    let mut answer = String::new();
+    if io::stdin().lock().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// Sets up logging as `RUST_LOG` says, redacting paths with `redaction`.
+fn init_logging(redaction: Option<Redaction>) {
+    let mut builder = pretty_env_logger::formatted_builder();
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    if !colored() {
+        builder.write_style(pretty_env_logger::env_logger::WriteStyle::Never);
+    }
+    let logger = builder.build();
+    let max_level = logger.filter();
+    let logger: Box<dyn log::Log> = match redaction {
+        Some(redaction) => Box::new(RedactingLogger {
+            inner: Box::new(logger),
+            redaction,
+        }),
+        None => Box::new(logger),
+    };
+    if log::set_boxed_logger(logger).is_ok() {
+        log::set_max_level(max_level);
+    }
+}
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
+    /// Keyfile used to sign the WAL and verify it before use, defaults to
+    /// $MIRAGE_KEYFILE
+    #[arg(long, global = true)]
+    keyfile: Option<PathBuf>,
+
+    /// Start signing a WAL written before there was a keyfile, which is
+    /// refused as tampered with otherwise
+    #[arg(long, global = true)]
+    sign_existing: bool,
+
+    /// Name of the directory holding the state of a managed tree, defaults
+    /// to $MIRAGE_STATE_DIR or .mirage
+    #[arg(long, global = true)]
+    state_dir: Option<OsString>,
+
+    /// Directory to keep the state of managed trees in, each in one of its
+    /// own, instead of inside them, defaults to $MIRAGE_STORE
+    #[arg(long, global = true)]
+    store: Option<PathBuf>,
+
+    /// Confine writes to the trees the command works on (Linux, Landlock)
+    #[arg(long, global = true)]
+    sandbox: bool,
+
+    /// Remove run locks left behind by runs that died, noting it in the
+    /// store's audit log
+    #[arg(long, global = true)]
+    break_stale_lock: bool,
+
+    /// Write the report of the command to this file instead of stdout,
+    /// leaving logs and errors on stderr
+    #[arg(long, global = true)]
+    output: Option<PathBuf>,
+
+    /// Redact the names in paths shown in logs, reports and notifications,
+    /// by hashing them or cutting them short (hash, truncate)
+    #[arg(long, global = true, value_parser = Redaction::from_str)]
+    redact: Option<Redaction>,
+
+    /// Go ahead without asking where a command would, which runs without a
+    /// terminal need to as they refuse otherwise
+    #[arg(long, short = 'y', global = true)]
+    yes: bool,
+
+    /// Print one JSON object per line instead of text: the result of the
+    /// command, and its messages and errors as events
+    #[arg(long, global = true)]
+    json: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+// parsed once, boxing the options of apply would buy nothing
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 enum Commands {
     /// Apply deduplication to target directory
@@ -15,33 +315,2294 @@ enum Commands {
         /// Target directory path
         #[arg(default_value = ".")]
         path: String,
+
+        /// Further directories to deduplicate along with the target, against
+        /// the same originals, all recorded in the store of the target
+        #[arg(conflicts_with_all = ["per_subdir", "upgrade", "plan"])]
+        others: Vec<String>,
+
+        /// Fold stores found in subdirectories into this one instead of failing
+        #[arg(long)]
+        adopt_nested: bool,
+
+        /// Keep an independent store in every top-level subdirectory
+        #[arg(long)]
+        per_subdir: bool,
+
+        /// Maximum size of the originals store, e.g. 500M or 20G
+        #[arg(long, value_parser = parse_size)]
+        max_store_size: Option<u64>,
+
+        /// Treat top-level directories as dated snapshots, newest preferred
+        #[arg(long)]
+        snapshots: bool,
+
+        /// Treat media differing only in embedded tags (EXIF, ID3) as duplicates
+        #[arg(long)]
+        ignore_metadata: bool,
+
+        /// Preset suited to a kind of tree (photos, code, backups, media),
+        /// defaults to the profile of the configuration
+        #[arg(long, value_parser = Profile::from_str)]
+        profile: Option<Profile>,
+
+        /// Only print the actions that would be taken
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Descend into symlinked directories, reporting loops
+        #[arg(long)]
+        follow_symlinks: bool,
+
+        /// Only descend this many levels, 1 for the files of the target
+        #[arg(long)]
+        max_depth: Option<usize>,
+
+        /// Never cross into other filesystems: mount points and symlinks
+        /// leading to other devices are left alone
+        #[arg(long)]
+        one_file_system: bool,
+
+        /// Most candidate files held in memory before spilling to .mirage/tmp
+        #[arg(long)]
+        memory_budget: Option<usize>,
+
+        /// Also deduplicate junk files like .DS_Store and Thumbs.db
+        #[arg(long)]
+        no_default_ignores: bool,
+
+        /// Also deduplicate inside .git, .hg and .svn directories
+        #[arg(long)]
+        include_vcs: bool,
+
+        /// Don't read .mirageignore files, deduplicating what they ignore
+        #[arg(long)]
+        no_ignore_files: bool,
+
+        /// Also skip what .gitignore files ignore, like build output
+        #[arg(long)]
+        respect_gitignore: bool,
+
+        /// Only deduplicate files matching this glob, e.g. '*.iso' or
+        /// 'media/**/*.mkv'; may be repeated
+        #[arg(long, value_name = "GLOB")]
+        include: Vec<String>,
+
+        /// Write metrics of the run to this node_exporter textfile collector
+        /// file, e.g. /var/lib/node_exporter/mirage.prom
+        #[arg(long)]
+        metrics_file: Option<PathBuf>,
+
+        /// Send a desktop notification when done
+        #[arg(long)]
+        notify: bool,
+
+        /// Journal every comparison and decision to .mirage/journal.jsonl.gz
+        #[arg(long)]
+        journal: bool,
+
+        /// Keep the originals below this directory, e.g. on another disk
+        #[arg(long)]
+        store_volume: Option<PathBuf>,
+
+        /// Number of state backups to keep, one taken before the run
+        #[arg(long, default_value_t = DEFAULT_BACKUPS)]
+        backups: usize,
+
+        /// Stop after working on this many files, the next run continues
+        #[arg(long)]
+        max_files: Option<usize>,
+
+        /// Stop after this long, e.g. 90s, 30m or 2h, the next run continues
+        #[arg(long, value_parser = parse_duration)]
+        max_duration: Option<Duration>,
+
+        /// Convert the symlinks of an applied tree to hardlinks or reflinks
+        /// in place instead of deduplicating
+        #[arg(long, value_parser = LinkMode::from_str)]
+        upgrade: Option<LinkMode>,
+
+        /// Deduplicate as this plan file says instead of walking the tree
+        #[arg(long, conflicts_with = "write_plan")]
+        plan: Option<PathBuf>,
+
+        /// Write the plan of a dry run to this file for review, implies
+        /// --dry-run
+        #[arg(long)]
+        write_plan: Option<PathBuf>,
+
+        /// Save a report of the duplicate groups of the run to this file, to
+        /// compare with a later one using `mirage report diff`
+        #[arg(long)]
+        save_report: Option<PathBuf>,
+
+        /// Clone files at least this large instead of symlinking them, where
+        /// the filesystem can, e.g. 1M
+        #[arg(long, value_parser = parse_size)]
+        reflink_min_size: Option<u64>,
+
+        /// Hard link files at most this large instead of symlinking them,
+        /// where the filesystem can, e.g. 64K
+        #[arg(long, value_parser = parse_size)]
+        hardlink_max_size: Option<u64>,
+
+        /// Only consider the files listed in this file, one per line, `-`
+        /// reading them from stdin
+        #[arg(long, conflicts_with = "plan")]
+        files_from: Option<PathBuf>,
+
+        /// The --files-from list is separated by NUL bytes, as find -print0
+        /// writes it
+        #[arg(short = '0', long, requires = "files_from")]
+        null: bool,
+
+        /// Number of files hashed at once, defaults to the number of CPUs
+        #[arg(long)]
+        jobs: Option<usize>,
+
+        /// Link duplicates to their original as symlinks, hardlinks or
+        /// reflinks, symlinking the ones on another device than the store
+        #[arg(long, value_parser = LinkMode::from_str, conflicts_with = "upgrade")]
+        link_mode: Option<LinkMode>,
+
+        /// Share the blocks of duplicates in place instead of linking them,
+        /// on filesystems that can deduplicate extents such as btrfs and XFS
+        #[arg(
+            long,
+            conflicts_with_all = ["link_mode", "upgrade", "plan", "reflink_min_size", "hardlink_max_size"]
+        )]
+        dedupe_extents: bool,
+
+        /// Leave files smaller than this alone, e.g. 4K
+        #[arg(long, value_parser = parse_size)]
+        min_size: Option<u64>,
     },
 
     Revert {
+        /// Target directory path, or a file or directory inside a managed
+        /// tree to restore only that part of it
+        #[arg(default_value = ".")]
+        path: String,
+
+        /// Revert the independent store of every top-level subdirectory
+        #[arg(long)]
+        per_subdir: bool,
+
+        /// Only print the actions that would be taken
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Send a desktop notification when done
+        #[arg(long)]
+        notify: bool,
+
+        /// Number of state backups to keep, one taken before the run
+        #[arg(long, default_value_t = DEFAULT_BACKUPS)]
+        backups: usize,
+
+        /// Number of duplicate groups restored at once, defaults to the
+        /// number of CPUs
+        #[arg(long)]
+        jobs: Option<usize>,
+
+        /// Hash the restored files against the contents recorded at dedup
+        /// time before deleting the store, keeping it if any differ
+        #[arg(long)]
+        verify: bool,
+
+        /// Restore what can be when originals are missing from the store,
+        /// leaving a .mirage-lost marker next to every file that can't be
+        #[arg(long)]
+        force: bool,
+
+        /// Archive the WAL and logs here before deleting the store: a .tar,
+        /// .tar.gz or .tgz file, or a directory to keep them in
+        #[arg(long)]
+        archive_state: Option<PathBuf>,
+
+        /// Restore files as hard links to their original rather than copies,
+        /// needing no extra space; the files of a group then share contents
+        #[arg(long)]
+        as_hardlinks: bool,
+
+        /// Only restore the files below this file or directory of the tree,
+        /// keeping the rest deduplicated and the store in place
+        #[arg(long, conflicts_with_all = ["per_subdir", "archive_state"])]
+        only: Vec<PathBuf>,
+
+        /// Only undo the actions recorded since this checkpoint, see
+        /// `mirage checkpoint`, keeping the store in place
+        #[arg(long, conflicts_with_all = ["per_subdir", "archive_state", "only"])]
+        to: Option<String>,
+    },
+
+    /// Apply deduplication, then keep watching the tree, deduplicating the
+    /// files created or modified in it as they appear, until interrupted
+    Watch {
+        /// Target directory path
+        #[arg(default_value = ".")]
+        path: String,
+
+        /// How often to look for new or modified files, e.g. 5s or 1m
+        #[arg(long, value_parser = parse_duration, default_value = "2s")]
+        interval: Duration,
+
+        /// Link duplicates to their original as symlinks, hardlinks or
+        /// reflinks, symlinking the ones on another device than the store
+        #[arg(long, value_parser = LinkMode::from_str)]
+        link_mode: Option<LinkMode>,
+
+        /// Number of state backups to keep, one taken before every run
+        #[arg(long, default_value_t = DEFAULT_BACKUPS)]
+        backups: usize,
+    },
+
+    /// Convert the managed links of a tree to another kind where possible
+    Migrate {
+        /// Target directory path
+        #[arg(default_value = ".")]
+        path: String,
+
+        /// Kind of link to convert to (symlink, hardlink, reflink)
+        #[arg(long, value_parser = LinkMode::from_str)]
+        to: LinkMode,
+    },
+
+    /// Deduplicate a tree restored from a backup again, from a copy of its WAL
+    Reapply {
+        /// Target directory path
+        #[arg(default_value = ".")]
+        path: String,
+
+        /// The recorded wal.json, or a directory holding it and its segments
+        /// such as an archived state
+        #[arg(long)]
+        from: PathBuf,
+    },
+
+    /// Record the digests of a tree's originals with another algorithm
+    Rehash {
+        /// Target directory path
+        #[arg(default_value = ".")]
+        path: String,
+
+        /// Algorithm to record digests with (md5, sha256, blake3), blake3
+        /// unless configured otherwise
+        #[arg(long, value_parser = HashAlgorithm::from_str)]
+        to: Option<HashAlgorithm>,
+    },
+
+    /// Move the originals of a flat store into subdirectories
+    Shard {
+        /// Target directory path
+        #[arg(default_value = ".")]
+        path: String,
+    },
+
+    /// Manage files already hard linked together without modifying them
+    AdoptHardlinks {
+        /// Target directory path
+        #[arg(default_value = ".")]
+        path: String,
+
+        /// Only list the groups that would be adopted
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Fold another managed tree's store into this one
+    Merge {
+        /// Root of the tree whose store is merged in
+        other: String,
+
+        /// Target directory path
+        #[arg(default_value = ".")]
+        path: String,
+    },
+
+    /// Replace managed links with independent copies
+    Unshare {
+        /// Managed files to unshare
+        #[arg(required = true)]
+        paths: Vec<String>,
+    },
+
+    /// Print every file with its canonical content file and content id
+    Manifest {
+        /// Target directory path
+        #[arg(default_value = ".")]
+        path: String,
+
+        /// Output format
+        #[arg(long, value_enum, default_value_t = ManifestFormat::Json)]
+        format: ManifestFormat,
+    },
+
+    /// Report zip and tar archives whose members duplicate other content
+    Archives {
+        /// Target directory path
+        #[arg(default_value = ".")]
+        path: String,
+    },
+
+    /// Show logical and physical usage and dedup ratio per directory
+    Du {
+        /// Target directory path
+        #[arg(default_value = ".")]
+        path: String,
+    },
+
+    /// List the duplicate groups of the store, each original with the
+    /// paths linked to it
+    List {
+        /// Target directory path
+        #[arg(default_value = ".")]
+        path: String,
+
+        /// List what an apply would deduplicate now instead, from a fresh
+        /// scan of the tree
+        #[arg(long)]
+        scan: bool,
+
+        /// Order of the groups (original, files, bytes)
+        #[arg(long, default_value = "original", value_parser = GroupOrder::from_str)]
+        sort: GroupOrder,
+    },
+
+    /// List directories whose whole contents are identical
+    Subtrees {
+        /// Target directory path
+        #[arg(default_value = ".")]
+        path: String,
+    },
+
+    /// Time the ways of comparing and hashing files over a sample of the
+    /// tree and recommend the fastest
+    Bench {
+        /// Target directory path
+        #[arg(default_value = ".")]
+        path: String,
+        /// Most files to sample
+        #[arg(long, default_value_t = BenchOptions::default().sample_files)]
+        files: usize,
+        /// Most bytes to sample, with an optional K, M, G or T suffix
+        #[arg(long, value_parser = parse_size)]
+        bytes: Option<u64>,
+    },
+
+    /// Render store internals readably
+    Inspect {
+        #[command(subcommand)]
+        what: Inspect,
+    },
+
+    /// Work with run reports saved with `apply --save-report`
+    Report {
+        #[command(subcommand)]
+        what: Report,
+    },
+
+    /// Name points in the history of a tree to revert to with `revert --to`
+    Checkpoint {
+        #[command(subcommand)]
+        what: Checkpoint,
+    },
+
+    /// Execute the actions left pending by an interrupted run
+    #[command(alias = "resume")]
+    Replay {
+        /// Target directory path
+        #[arg(default_value = ".")]
+        path: String,
+
+        /// Only print the pending actions and what no longer holds for them
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Print the actions of the WAL as a shell script of cp and ln commands
+    ExportScript {
+        /// Target directory path
+        #[arg(default_value = ".")]
+        path: String,
+
+        /// Shell to write the script for (sh, powershell)
+        #[arg(long, default_value = "sh", value_parser = Shell::from_str)]
+        shell: Shell,
+
+        /// Include the actions already executed, not only the pending ones
+        #[arg(long)]
+        all: bool,
+    },
+
+    /// Compare the tree with what its WAL says, exiting with 1 if they differ
+    Diff {
+        /// Target directory path
+        #[arg(default_value = ".")]
+        path: String,
+    },
+
+    /// Roll the state back to a backup taken before an apply or revert
+    RestoreState {
+        /// Target directory path
+        #[arg(default_value = ".")]
+        path: String,
+
+        /// Backup to restore, 1 for the most recent
+        #[arg(long, default_value_t = 1)]
+        backup: usize,
+
+        /// List the backups instead
+        #[arg(long)]
+        list: bool,
+    },
+
+    /// Check the store and the tree for inconsistencies, exiting with 1 if
+    /// any are left
+    Fsck {
+        /// Target directory path
+        #[arg(default_value = ".")]
+        path: String,
+
+        /// Repair what can be safely: redirections, orphans, missing links
+        #[arg(long)]
+        fix: bool,
+
+        /// Also restore missing originals from surviving linked copies and
+        /// forget paths gone along with their original, implies --fix
+        #[arg(long)]
+        repair: bool,
+    },
+
+    /// Show the duplicate groups, pending actions and savings of the store,
+    /// whether the tree is what its WAL says, and the progress of the
+    /// running or last apply
+    Status {
+        /// Target directory path
+        #[arg(default_value = ".")]
+        path: String,
+    },
+
+    /// Check that every redirection still links to an original in the
+    /// store, without modifying anything
+    Verify {
+        /// Target directory path
+        #[arg(default_value = ".")]
+        path: String,
+
+        /// Also hash every original and compare it with its recorded digest
+        #[arg(long)]
+        deep: bool,
+    },
+
+    /// Explain why the last apply run did or didn't deduplicate a file
+    Why {
+        /// File of a managed tree
+        path: String,
+    },
+
+    /// Print the journal of the last apply run as JSON lines
+    Journal {
+        /// Target directory path
+        #[arg(default_value = ".")]
+        path: String,
+
+        /// The journal of the run before the last instead
+        #[arg(long)]
+        previous: bool,
+    },
+
+    /// Show the space the tree takes and the savings accumulated across
+    /// every apply run
+    Stats {
+        /// Target directory path
+        #[arg(default_value = ".")]
+        path: String,
+
+        /// Show store size, bytes saved and files managed run after run
+        #[arg(long)]
+        history: bool,
+    },
+
+    /// Freeze a managed tree so nothing modifies it until unlocked
+    Lock {
+        /// Target directory path
+        #[arg(default_value = ".")]
+        path: String,
+
+        /// Why the tree is locked, kept for whoever finds it locked
+        #[arg(long)]
+        reason: Option<String>,
+    },
+
+    /// Lift a freeze set by lock
+    Unlock {
+        /// Target directory path
+        #[arg(default_value = ".")]
+        path: String,
+    },
+
+    /// Mark a managed tree read-only, so every mutating command is a dry run
+    Readonly {
+        /// Target directory path
+        #[arg(default_value = ".")]
+        path: String,
+
+        /// Clear the marker instead of setting it
+        #[arg(long)]
+        off: bool,
+    },
+
+    /// Delete managed links, dropping originals nothing else refers to
+    Rm {
+        /// Managed files to delete
+        #[arg(required = true)]
+        paths: Vec<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum Inspect {
+    /// List the actions of the WAL with their state
+    Wal {
+        /// Target directory path
+        #[arg(default_value = ".")]
+        path: String,
+
+        /// Only actions whose source or target contains this
+        #[arg(long)]
+        matching: Option<String>,
+
+        /// Only actions of this type (copy, symlink, nop)
+        #[arg(long = "type")]
+        action: Option<String>,
+    },
+
+    /// List the duplicate groups of the WAL and how far each got
+    Groups {
+        /// Target directory path
+        #[arg(default_value = ".")]
+        path: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum Checkpoint {
+    /// Record a checkpoint after the actions applied so far
+    Create {
+        /// Name of the checkpoint
+        name: String,
+
+        /// Target directory path
+        #[arg(default_value = ".")]
+        path: String,
+    },
+
+    /// List the checkpoints, oldest first
+    List {
+        /// Target directory path
+        #[arg(default_value = ".")]
+        path: String,
+    },
+
+    /// Forget a checkpoint, leaving the tree as it is
+    Delete {
+        /// Name of the checkpoint
+        name: String,
+
         /// Target directory path
         #[arg(default_value = ".")]
         path: String,
     },
 }
 
+#[derive(Subcommand)]
+enum Report {
+    /// Compare two run reports: new and resolved duplicate groups, and the
+    /// change in savings
+    Diff {
+        /// The earlier run report
+        before: PathBuf,
+
+        /// The later run report
+        after: PathBuf,
+    },
+}
+
+impl Commands {
+    /// Directories the command needs to write beneath, stores included.
+    fn writable_paths(&self) -> Vec<PathBuf> {
+        match self {
+            Commands::Apply {
+                path,
+                others,
+                metrics_file,
+                store_volume,
+                write_plan,
+                save_report,
+                ..
+            } => {
+                let mut paths = vec![PathBuf::from(path)];
+                paths.extend(others.iter().map(PathBuf::from));
+                // a file named without a directory goes in the current one
+                for dir in [write_plan, save_report]
+                    .into_iter()
+                    .filter_map(|file| file.as_ref()?.parent())
+                {
+                    let dir = if dir.as_os_str().is_empty() {
+                        Path::new(".")
+                    } else {
+                        dir
+                    };
+                    paths.push(dir.to_path_buf());
+                }
+                // replaced by renaming a file written next to it
+                if let Some(dir) = metrics_file.as_ref().and_then(|file| file.parent()) {
+                    paths.push(dir.to_path_buf());
+                }
+                paths.extend(store_volume.clone());
+                // subdirectories of a managed tree are applied in its store
+                paths.extend(find_store_root(path).ok());
+                paths
+            }
+            Commands::Revert {
+                path,
+                archive_state,
+                ..
+            } => {
+                let mut paths = vec![PathBuf::from(path)];
+                // parts of a managed tree are reverted in its store
+                paths.extend(find_store_root(path).ok());
+                // tarballs are created next to where they are asked for
+                if let Some(to) = archive_state {
+                    let tarball = to
+                        .extension()
+                        .is_some_and(|e| e == "tar" || e == "gz" || e == "tgz");
+                    match to.parent().filter(|_| tarball) {
+                        Some(dir) => paths.push(dir.to_path_buf()),
+                        None => paths.push(to.clone()),
+                    }
+                }
+                paths
+            }
+            Commands::Lock { path, .. }
+            | Commands::Unlock { path }
+            | Commands::Readonly { path, .. }
+            | Commands::Replay { path, .. }
+            | Commands::Migrate { path, .. }
+            | Commands::Reapply { path, .. }
+            | Commands::Rehash { path, .. }
+            | Commands::AdoptHardlinks { path, .. }
+            | Commands::Shard { path }
+            | Commands::Fsck { path, .. }
+            | Commands::Watch { path, .. }
+            | Commands::RestoreState { path, .. } => vec![PathBuf::from(path)],
+            Commands::Checkpoint {
+                what: Checkpoint::Create { path, .. } | Checkpoint::Delete { path, .. },
+            } => vec![PathBuf::from(path)],
+            Commands::Merge { other, path } => vec![PathBuf::from(path), PathBuf::from(other)],
+            Commands::Unshare { paths } | Commands::Rm { paths } => paths
+                .iter()
+                .map(|path| find_store_root(path).unwrap_or_else(|_| PathBuf::from(path)))
+                .collect(),
+            Commands::Manifest { .. }
+            | Commands::Archives { .. }
+            | Commands::Du { .. }
+            | Commands::List { .. }
+            | Commands::Subtrees { .. }
+            | Commands::Bench { .. }
+            | Commands::Diff { .. }
+            | Commands::ExportScript { .. }
+            | Commands::Stats { .. }
+            | Commands::Status { .. }
+            | Commands::Verify { .. }
+            | Commands::Why { .. }
+            | Commands::Journal { .. }
+            | Commands::Inspect { .. }
+            | Commands::Report { .. }
+            | Commands::Checkpoint {
+                what: Checkpoint::List { .. },
+            } => Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ManifestFormat {
+    Json,
+    Csv,
+}
+
+/// The configuration for the tree at `path`, exiting if it can't be read.
+fn load_config(path: &str) -> Config {
+    Config::load(path).unwrap_or_else(|err| {
+        errorln!("Error reading configuration: {:?}", err);
+        std::process::exit(1);
+    })
+}
+
+/// Parses a duration in seconds with an optional suffix (s, m, h, d).
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let (digits, unit) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => {
+            let unit = match c.to_ascii_lowercase() {
+                's' => 1,
+                'm' => 60,
+                'h' => 60 * 60,
+                'd' => 24 * 60 * 60,
+                _ => return Err(format!("unknown duration suffix {:?}", c)),
+            };
+            (&s[..i], unit)
+        }
+        _ => (s, 1),
+    };
+    let n: u64 = digits
+        .parse()
+        .map_err(|err| format!("invalid duration {:?}: {}", s, err))?;
+    n.checked_mul(unit)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("duration {:?} is too long", s))
+}
+
+fn print_planned(planned: &[PlannedAction]) {
+    outputln!("Dry run, {} actions would be taken:", planned.len());
+    for action in planned {
+        outputln!(
+            "  {} {} -> {}",
+            action.action,
+            shown(&action.source),
+            shown(&action.target)
+        );
+    }
+}
+
+/// `amount` per second over `time`, 0 for runs too short to time.
+fn rate(amount: u64, time: Duration) -> f64 {
+    if time.is_zero() {
+        return 0.0;
+    }
+    amount as f64 / time.as_secs_f64()
+}
+
+/// Prints how fast each phase of an apply went, telling whether the run
+/// was held up by reading files, by executing actions or by writing the WAL.
+fn print_throughput(report: &ApplyReport) {
+    const MB: f64 = 1_000_000.0;
+    let times = &report.times;
+    outputln!(
+        "Scanned {} files in {:.2?} ({:.0} files/s)",
+        report.files_scanned,
+        times.scanning,
+        rate(report.files_scanned, times.scanning)
+    );
+    outputln!(
+        "Compared in {:.2?}: {} bytes hashed ({:.1} MB/s), {} bytes compared ({:.1} MB/s)",
+        times.comparing,
+        report.bytes_hashed,
+        rate(report.bytes_hashed, times.comparing) / MB,
+        report.bytes_compared,
+        rate(report.bytes_compared, times.comparing) / MB
+    );
+    outputln!(
+        "Executed {} actions in {:.2?} ({:.0} actions/s), {} bytes copied",
+        report.actions_executed,
+        times.executing,
+        rate(report.actions_executed as u64, times.executing),
+        report.bytes_copied
+    );
+    outputln!(
+        "Took {:.2?} in all, {:.2?} of it committing the WAL",
+        times.total(),
+        times.committing
+    );
+}
+
+/// Shows a desktop notification, through notify-send on Linux and the BSDs
+/// and osascript on macOS. Failing to is never an error of the run.
+fn notify(summary: &str, body: &str) {
+    let body = redacted(body.to_string());
+    let mut command = if cfg!(target_os = "macos") {
+        let mut command = Command::new("osascript");
+        command.arg("-e").arg(format!(
+            "display notification {:?} with title {:?}",
+            body, summary
+        ));
+        command
+    } else {
+        let mut command = Command::new("notify-send");
+        command.args(["--app-name", "mirage", summary, &body]);
+        command
+    };
+    match command.status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => errorln!("Notifying failed: {}", status),
+        Err(err) => errorln!("Can't notify: {}", err),
+    }
+}
+
 fn main() {
-    pretty_env_logger::init();
     let cli = Cli::parse();
+    init_logging(cli.redact);
+    if let Some(redaction) = cli.redact {
+        REDACTION
+            .set(redaction)
+            .expect("redaction is only set once");
+    }
+    JSON.set(cli.json).expect("json is only set once");
+    if let Some(keyfile) = &cli.keyfile {
+        std::env::set_var(KEYFILE_ENV, keyfile);
+    }
+    if cli.sign_existing {
+        std::env::set_var(SIGN_EXISTING_ENV, "1");
+    }
+    // apply and revert are given these, the other commands find them here
+    if let Some(state_dir) = &cli.state_dir {
+        std::env::set_var(STATE_DIR_ENV, state_dir);
+    }
+    if let Some(store) = &cli.store {
+        std::env::set_var(STORE_ENV, store);
+    }
+    // opened before entering the sandbox, which needn't allow writing it
+    if let Some(file) = &cli.output {
+        let file = File::create(file).unwrap_or_else(|err| {
+            errorln!("Error creating {}: {:?}", shown(file), err);
+            std::process::exit(1);
+        });
+        OUTPUT.set(file).expect("output is only set once");
+    }
+    if cli.break_stale_lock {
+        for path in cli.command.writable_paths() {
+            match break_stale_lock(&path) {
+                Ok(Some(broken)) => {
+                    outputln!("Broke stale lock of {} on {}", broken, shown(&path))
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    errorln!("Error breaking lock on {}: {:?}", shown(&path), err);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+
+    if cli.sandbox {
+        let mut paths = cli.command.writable_paths();
+        for path in paths.clone() {
+            // originals may be kept on another volume
+            if let Ok(Some(dir)) = originals_dir(&path) {
+                paths.push(dir);
+            }
+        }
+        // stores kept elsewhere are created below it
+        if let Some(dir) = std::env::var_os(STORE_ENV).filter(|dir| !dir.is_empty()) {
+            if let Err(err) = std::fs::create_dir_all(&dir) {
+                errorln!("Error creating {}: {:?}", shown(&dir), err);
+                std::process::exit(1);
+            }
+            paths.push(PathBuf::from(dir));
+        }
+        sandbox(&paths).unwrap_or_else(|err| {
+            errorln!("Error entering sandbox: {:?}", err);
+            std::process::exit(1);
+        });
+    }
 
     match &cli.command {
-        Commands::Apply { path } => {
-            println!("Applying deduplication to path: {}", path);
-            apply(path).unwrap_or_else(|err| {
-                eprintln!("Error applying deduplication: {:?}", err);
+        Commands::Apply {
+            path,
+            upgrade: Some(mode),
+            ..
+        } => {
+            outputln!("Upgrading links of path: {}", shown(path));
+            handle_interrupts();
+            let report = upgrade(path, *mode).unwrap_or_else(|err| {
+                if matches!(err, MirageError::Interrupted) {
+                    errorln!("Interrupted, run the upgrade again to finish it");
+                    std::process::exit(130);
+                }
+                errorln!("Error upgrading links: {:?}", err);
+                std::process::exit(1);
+            });
+            if json_mode() {
+                emit("upgrade", &report);
+                if !report.unchanged.is_empty() {
+                    std::process::exit(1);
+                }
+                return;
+            }
+            outputln!("Upgraded {} links", report.upgraded);
+            if !report.unchanged.is_empty() {
+                errorln!("{} links couldn't be upgraded:", report.unchanged.len());
+                for path in &report.unchanged {
+                    errorln!("  {}", shown(path));
+                }
+                std::process::exit(1);
+            }
+        }
+        Commands::Apply {
+            path,
+            others,
+            adopt_nested,
+            per_subdir,
+            max_store_size,
+            snapshots,
+            ignore_metadata,
+            profile,
+            dry_run,
+            follow_symlinks,
+            max_depth,
+            one_file_system,
+            memory_budget,
+            no_default_ignores,
+            include_vcs,
+            no_ignore_files,
+            respect_gitignore,
+            include,
+            metrics_file,
+            notify: notify_done,
+            journal,
+            store_volume,
+            backups,
+            max_files,
+            max_duration,
+            upgrade: None,
+            plan,
+            write_plan,
+            save_report,
+            reflink_min_size,
+            hardlink_max_size,
+            files_from,
+            null,
+            jobs,
+            link_mode,
+            dedupe_extents,
+            min_size,
+        } => {
+            outputln!("Applying deduplication to path: {}", shown(path));
+            for other in others {
+                outputln!("  along with: {}", shown(other));
+            }
+            let config = load_config(path);
+            let files_from = files_from.as_ref().map(|list| {
+                read_file_list_at(list, *null).unwrap_or_else(|err| {
+                    errorln!("Error reading file list {}: {:?}", shown(list), err);
+                    std::process::exit(1);
+                })
+            });
+            let mut options = ApplyOptions {
+                adopt_nested: *adopt_nested,
+                per_subdirectory: *per_subdir,
+                max_store_size: *max_store_size,
+                snapshots: *snapshots,
+                ignore_metadata: *ignore_metadata,
+                dry_run: *dry_run || write_plan.is_some(),
+                follow_symlinks: *follow_symlinks,
+                max_depth: *max_depth,
+                one_file_system: *one_file_system,
+                memory_budget: *memory_budget,
+                no_default_ignores: *no_default_ignores,
+                include_vcs: *include_vcs,
+                no_ignore_files: *no_ignore_files,
+                respect_gitignore: *respect_gitignore,
+                include: include.clone(),
+                metrics_file: metrics_file.clone(),
+                journal: *journal,
+                store_volume: store_volume.clone(),
+                backups: Some(*backups),
+                max_files: *max_files,
+                max_duration: *max_duration,
+                redaction: cli.redact,
+                link_policy: (reflink_min_size.is_some() || hardlink_max_size.is_some()).then_some(
+                    LinkPolicy {
+                        reflink_min_size: *reflink_min_size,
+                        hardlink_max_size: *hardlink_max_size,
+                    },
+                ),
+                files_from,
+                jobs: *jobs,
+                link_mode: *link_mode,
+                dedupe_extents: *dedupe_extents,
+                min_size: *min_size,
+                other_roots: others.iter().map(PathBuf::from).collect(),
+                store: cli.store.clone(),
+                state_dir: cli.state_dir.clone(),
+                ..Default::default()
+            };
+            config.apply_to(&mut options, *profile);
+            handle_interrupts();
+            let applied = match plan {
+                Some(file) => std::fs::read_to_string(file)
+                    .map_err(MirageError::from)
+                    .and_then(|text| Plan::parse(&text))
+                    .and_then(|plan| apply_plan(path, &plan, &options)),
+                None => apply_with_options(path, &options),
+            };
+            let report = applied.unwrap_or_else(|err| {
+                if matches!(err, MirageError::Interrupted) {
+                    errorln!("Interrupted, resume with `mirage resume {}`", shown(path));
+                    std::process::exit(130);
+                }
+                errorln!("Error applying deduplication: {:?}", err);
+                if let MirageError::LinksUnsupported(_, "reflink", _) = err {
+                    errorln!(
+                        "Clones need a copy-on-write filesystem such as btrfs, XFS or APFS, \
+                         try --link-mode symlink or hardlink"
+                    );
+                }
+                if let MirageError::LinksUnsupported(_, "shared extent", _) = err {
+                    errorln!("Sharing extents needs a filesystem such as btrfs or XFS");
+                }
+                if *notify_done {
+                    notify("Deduplication failed", &format!("{}: {}", shown(path), err));
+                }
+                std::process::exit(1);
+            });
+            if *notify_done {
+                let body = if report.dry_run {
+                    format!(
+                        "{}: {} actions would be taken",
+                        shown(path),
+                        report.planned.len()
+                    )
+                } else {
+                    format!(
+                        "{}: {} bytes saved, store at {} bytes",
+                        shown(path),
+                        report.bytes_saved,
+                        report.store_size
+                    )
+                };
+                notify("Deduplication done", &body);
+            }
+            if json_mode() {
+                emit("apply", &report);
+            } else {
+                if !report.skipped_over_quota.is_empty() {
+                    outputln!(
+                        "Skipped {} duplicate groups that would exceed the store quota:",
+                        report.skipped_over_quota.len()
+                    );
+                    for path in &report.skipped_over_quota {
+                        outputln!("  {}", shown(path));
+                    }
+                }
+                for savings in &report.snapshot_savings {
+                    outputln!(
+                        "{}: {} files, {} bytes served from the store",
+                        shown(&savings.snapshot),
+                        savings.files,
+                        savings.bytes
+                    );
+                }
+                for rollback in &report.rolled_back {
+                    outputln!(
+                        "Rolled back the group of {}: {}",
+                        shown(&rollback.original),
+                        rollback.error
+                    );
+                }
+                print_throughput(&report);
+                if let Some(stopped_at) = &report.stopped_at {
+                    outputln!(
+                        "Stopped at {} once out of budget, run again to continue",
+                        shown(stopped_at)
+                    );
+                }
+                if !report.plan_skipped.is_empty() {
+                    outputln!(
+                        "Left {} files of the plan alone, they changed since:",
+                        report.plan_skipped.len()
+                    );
+                    for path in &report.plan_skipped {
+                        outputln!("  {}", shown(path));
+                    }
+                }
+            }
+            let root = std::fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+            if let Some(file) = save_report {
+                let written = std::fs::File::create(file)
+                    .map_err(MirageError::from)
+                    .and_then(|out| RunReport::new(&root, &report).write(out));
+                if let Err(err) = written {
+                    errorln!("Error saving report to {}: {:?}", shown(file), err);
+                    std::process::exit(1);
+                }
+                outputln!("Report saved to {}", shown(file));
+            }
+            if let Some(file) = write_plan {
+                let written = std::fs::File::create(file)
+                    .and_then(|out| Plan::from_planned(&root, &report.planned).write(out));
+                if let Err(err) = written {
+                    errorln!("Error writing plan to {}: {:?}", shown(file), err);
+                    std::process::exit(1);
+                }
+                outputln!("Plan written to {}", shown(file));
+            } else if report.dry_run && !json_mode() {
+                print_planned(&report.planned);
+            }
+        }
+        Commands::Revert {
+            path,
+            per_subdir,
+            dry_run,
+            notify: notify_done,
+            backups,
+            jobs,
+            verify,
+            force,
+            archive_state,
+            as_hardlinks,
+            only,
+            to,
+        } => {
+            outputln!("Reverting deduplication to path: {}", shown(path));
+            let options = RevertOptions {
+                per_subdirectory: *per_subdir,
+                dry_run: *dry_run,
+                backups: Some(*backups),
+                jobs: *jobs,
+                verify: *verify,
+                force: *force,
+                archive_state: archive_state.clone(),
+                as_hardlinks: *as_hardlinks,
+                only: only.clone(),
+                to: to.clone(),
+                store: cli.store.clone(),
+                state_dir: cli.state_dir.clone(),
+            };
+            if !*dry_run && !cli.yes {
+                let planned = RevertOptions {
+                    dry_run: true,
+                    ..options.clone()
+                };
+                let restoring = revert_with_options(path, &planned)
+                    .map(|report| report.planned.iter().filter(|a| a.action == "Copy").count())
+                    .unwrap_or_default();
+                if restoring >= CONFIRM_REVERT_FILES
+                    && !confirm(
+                        &format!("Restore {} files of {:?}?", restoring, path),
+                        cli.yes,
+                    )
+                {
+                    errorln!("Revert cancelled");
+                    std::process::exit(1);
+                }
+            }
+            handle_interrupts();
+            let report = revert_with_options(path, &options).unwrap_or_else(|err| {
+                if matches!(err, MirageError::Interrupted) {
+                    errorln!("Interrupted, finish with `mirage revert {}`", shown(path));
+                    std::process::exit(130);
+                }
+                errorln!("Error reverting deduplication: {:?}", err);
+                if *notify_done {
+                    notify("Revert failed", &format!("{}: {}", shown(path), err));
+                }
+                std::process::exit(1);
+            });
+            // with --json the report carries what the text would list
+            let text = !json_mode();
+            if !text {
+                emit("revert", &report);
+            }
+            if text && !report.dry_run {
+                outputln!(
+                    "Restored {} files, skipped {}, {} failed",
+                    report.restored,
+                    report.skipped.len(),
+                    report.failed.len()
+                );
+            }
+            if text {
+                for file in &report.skipped {
+                    outputln!("  skipped {}", shown(file));
+                }
+            }
+            if text && !report.lost.is_empty() {
+                errorln!(
+                    "{} files could not be restored, their originals are gone:",
+                    report.lost.len()
+                );
+                for file in &report.lost {
+                    errorln!("  {}", shown(file));
+                }
+            }
+            if !report.failed.is_empty() {
+                if text {
+                    errorln!(
+                        "{} files failed to be restored, the store was kept:",
+                        report.failed.len()
+                    );
+                    for failure in &report.failed {
+                        errorln!("  {}: {}", shown(&failure.path), failure.error);
+                    }
+                }
+                if *notify_done {
+                    notify(
+                        "Revert failed",
+                        &format!("{}: {} files failed", shown(path), report.failed.len()),
+                    );
+                }
+                std::process::exit(1);
+            }
+            if !report.mismatched.is_empty() {
+                if text {
+                    errorln!(
+                        "{} restored files differ from their recorded contents, the store was kept:",
+                        report.mismatched.len()
+                    );
+                    for file in &report.mismatched {
+                        errorln!("  {}", shown(file));
+                    }
+                }
+                if *notify_done {
+                    notify(
+                        "Revert failed",
+                        &format!("{}: {} files differ", shown(path), report.mismatched.len()),
+                    );
+                }
+                std::process::exit(1);
+            }
+            if *notify_done {
+                let body = if report.dry_run {
+                    format!(
+                        "{}: {} actions would be taken",
+                        shown(path),
+                        report.planned.len()
+                    )
+                } else if let Some(name) = to {
+                    format!("{}: back to checkpoint {}", shown(path), name)
+                } else {
+                    format!("{}: every file is independent again", shown(path))
+                };
+                notify("Revert done", &body);
+            }
+            // only a dry run gets this far short of space
+            let short = report.space.iter().any(|space| !space.fits());
+            if !text {
+                if short {
+                    std::process::exit(1);
+                }
+                return;
+            }
+            for archive in &report.archived_to {
+                outputln!("State archived to {}", shown(archive));
+            }
+            if *verify && !report.dry_run {
+                outputln!(
+                    "{} restored files verified, {} without recorded contents",
+                    report.verified,
+                    report.unverified
+                );
+            }
+            if report.dry_run {
+                print_planned(&report.planned);
+                let restoring = report.planned.iter().filter(|a| a.action == "Copy").count();
+                outputln!("{} files would be restored from the store", restoring);
+                for space in &report.space {
+                    outputln!(
+                        "  {} bytes needed on the filesystem of {}, {} free",
+                        space.needed,
+                        shown(&space.path),
+                        space.free
+                    );
+                }
+            }
+            if short {
+                errorln!("Not enough free space to revert");
+                std::process::exit(1);
+            }
+        }
+        Commands::Watch {
+            path,
+            interval,
+            link_mode,
+            backups,
+        } => {
+            let mut options = ApplyOptions {
+                backups: Some(*backups),
+                link_mode: *link_mode,
+                redaction: cli.redact,
+                store: cli.store.clone(),
+                state_dir: cli.state_dir.clone(),
+                ..Default::default()
+            };
+            load_config(path).apply_to(&mut options, None);
+            outputln!(
+                "Watching {} for new and modified files, interrupt to stop",
+                shown(path)
+            );
+            handle_interrupts();
+            watch(path, &options, *interval, |report| {
+                if json_mode() {
+                    emit("apply", report);
+                } else if report.actions_executed > 0 {
+                    outputln!(
+                        "{} actions executed, {} bytes saved, store at {} bytes",
+                        report.actions_executed,
+                        report.bytes_saved,
+                        report.store_size
+                    );
+                }
+                true
+            })
+            .unwrap_or_else(|err| {
+                if matches!(err, MirageError::Interrupted) {
+                    errorln!("Interrupted, resume with `mirage resume {}`", shown(path));
+                    std::process::exit(130);
+                }
+                errorln!("Error watching {}: {:?}", shown(path), err);
+                std::process::exit(1);
+            });
+            outputln!("Stopped watching {}", shown(path));
+        }
+        Commands::Migrate { path, to } => {
+            outputln!("Migrating links of path: {}", shown(path));
+            handle_interrupts();
+            let report = migrate(path, *to).unwrap_or_else(|err| {
+                if matches!(err, MirageError::Interrupted) {
+                    errorln!("Interrupted, run the migration again to finish it");
+                    std::process::exit(130);
+                }
+                errorln!("Error migrating links: {:?}", err);
+                std::process::exit(1);
+            });
+            if json_mode() {
+                emit("migrate", &report);
+                return;
+            }
+            outputln!(
+                "Migrated {} links, {} already were, {} left as they were",
+                report.migrated,
+                report.already,
+                report.remaining.len()
+            );
+            for (path, reason) in &report.remaining {
+                let reason = match reason {
+                    Unmigrated::OtherDevice => "original is on another device",
+                    Unmigrated::Unsupported => "filesystem can't clone files",
+                    Unmigrated::Diverged => "no longer links to its original",
+                    Unmigrated::Failed(err) => err,
+                };
+                outputln!("  {}: {}", shown(path), reason);
+            }
+        }
+        Commands::Reapply { path, from } => {
+            outputln!("Reapplying {} to path: {}", shown(from), shown(path));
+            handle_interrupts();
+            let report = reapply(path, from).unwrap_or_else(|err| {
+                if matches!(err, MirageError::Interrupted) {
+                    errorln!("Interrupted, run the reapply again to finish it");
+                    std::process::exit(130);
+                }
+                errorln!("Error reapplying the WAL: {:?}", err);
+                std::process::exit(1);
+            });
+            if json_mode() {
+                emit("reapply", &report);
+                if !report.mismatched.is_empty() || !report.lost.is_empty() {
+                    std::process::exit(1);
+                }
+                return;
+            }
+            outputln!(
+                "Linked {} files again, {} still were, {} originals recovered",
+                report.relinked,
+                report.intact,
+                report.recovered.len()
+            );
+            for file in &report.mismatched {
+                errorln!("  {} differs from its original, left as is", shown(file));
+            }
+            for file in &report.lost {
+                errorln!("  {} has lost its original, left as is", shown(file));
+            }
+            if !report.mismatched.is_empty() || !report.lost.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        Commands::Rehash { path, to } => {
+            let to = to
+                .or(load_config(path).hash_algorithm)
+                .unwrap_or(DEFAULT_HASH_ALGORITHM);
+            outputln!(
+                "Rehashing originals of path: {} with {}",
+                shown(path),
+                to.name()
+            );
+            handle_interrupts();
+            let report = rehash(path, to).unwrap_or_else(|err| {
+                if matches!(err, MirageError::Interrupted) {
+                    errorln!("Interrupted, run the rehash again to finish it");
+                    std::process::exit(130);
+                }
+                errorln!("Error rehashing originals: {:?}", err);
+                std::process::exit(1);
+            });
+            if json_mode() {
+                emit("rehash", &report);
+                if !report.mismatched.is_empty() {
+                    std::process::exit(1);
+                }
+                return;
+            }
+            outputln!(
+                "Rehashed {} originals, {} already were",
+                report.rehashed,
+                report.already
+            );
+            for file in &report.missing {
+                errorln!("  {} is gone from the store", shown(file));
+            }
+            for file in &report.mismatched {
+                errorln!("  {} no longer matches its digest, left as is", shown(file));
+            }
+            if !report.mismatched.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        Commands::Shard { path } => {
+            outputln!("Sharding originals store of path: {}", shown(path));
+            handle_interrupts();
+            let report = shard(path).unwrap_or_else(|err| {
+                if matches!(err, MirageError::Interrupted) {
+                    errorln!("Interrupted, run the shard again to finish it");
+                    std::process::exit(130);
+                }
+                errorln!("Error sharding originals store: {:?}", err);
+                std::process::exit(1);
+            });
+            if json_mode() {
+                emit("shard", &report);
+                return;
+            }
+            outputln!(
+                "Moved {} originals into subdirectories, {} already were",
+                report.moved,
+                report.already
+            );
+            for file in &report.missing {
+                errorln!("  {} is gone from the store", shown(file));
+            }
+        }
+        Commands::AdoptHardlinks { path, dry_run } => {
+            outputln!("Adopting hard links of path: {}", shown(path));
+            handle_interrupts();
+            let report = adopt_hardlinks(path, *dry_run).unwrap_or_else(|err| {
+                if matches!(err, MirageError::Interrupted) {
+                    errorln!("Interrupted, run adopt-hardlinks again to finish it");
+                    std::process::exit(130);
+                }
+                errorln!("Error adopting hard links: {:?}", err);
+                std::process::exit(1);
+            });
+            if json_mode() {
+                emit("adopt", &report);
+                return;
+            }
+            for group in &report.groups {
+                outputln!(
+                    "  {} files share {}",
+                    group.files.len(),
+                    shown(&group.files[0])
+                );
+            }
+            let saved = report.groups.iter().map(|group| group.saved).sum::<u64>();
+            outputln!(
+                "{} {} hard link groups saving {} bytes",
+                if report.dry_run {
+                    "Would adopt"
+                } else {
+                    "Adopted"
+                },
+                report.groups.len(),
+                saved
+            );
+            for file in &report.managed {
+                errorln!(
+                    "  {} is hard linked to a managed file, left out",
+                    shown(file)
+                );
+            }
+        }
+        Commands::Merge { other, path } => {
+            outputln!("Merging store of {} into {}", shown(other), shown(path));
+            merge(path, other).unwrap_or_else(|err| {
+                errorln!("Error merging stores: {:?}", err);
+                std::process::exit(1);
+            });
+        }
+        Commands::Unshare { paths } => {
+            for path in paths {
+                outputln!("Unsharing {}", shown(path));
+                unshare(path).unwrap_or_else(|err| {
+                    errorln!("Error unsharing {}: {:?}", shown(path), err);
+                    std::process::exit(1);
+                });
+            }
+        }
+        Commands::Manifest { path, format } => {
+            let mut entries = manifest(path).unwrap_or_else(|err| {
+                errorln!("Error building manifest: {:?}", err);
+                std::process::exit(1);
+            });
+            // relative paths of top-level files hold no separator to tell
+            if let Some(redaction) = REDACTION.get() {
+                for entry in &mut entries {
+                    entry.path = redaction.path(&entry.path);
+                    entry.content = redaction.path(&entry.content);
+                }
+            }
+            let written = match format {
+                ManifestFormat::Json => write_json(&entries),
+                ManifestFormat::Csv => {
+                    let mut out = BufWriter::new(output());
+                    write_manifest_csv(&mut out, &entries).and_then(|()| out.flush())
+                }
+            };
+            written.unwrap_or_else(|err| {
+                errorln!("Error writing manifest: {:?}", err);
+                std::process::exit(1);
+            });
+        }
+        Commands::Archives { path } => {
+            let reports = archive_report(path).unwrap_or_else(|err| {
+                errorln!("Error inspecting archives: {:?}", err);
+                std::process::exit(1);
+            });
+            if json_mode() {
+                for report in &reports {
+                    emit("archive", report);
+                }
+                return;
+            }
+            for report in &reports {
+                outputln!(
+                    "{}: {} of {} members duplicated{}",
+                    shown(&report.archive),
+                    report.duplicated.len(),
+                    report.members,
+                    if report.redundant { ", redundant" } else { "" }
+                );
+                for member in &report.duplicated {
+                    outputln!("  {} ({} bytes)", member.name, member.size);
+                    for location in &member.matches {
+                        match location {
+                            Location::File(path) => outputln!("    {}", shown(path)),
+                            Location::Member { archive, name } => {
+                                outputln!("    {}:{}", shown(archive), name)
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Commands::Du { path } => {
+            let usage = disk_usage(path).unwrap_or_else(|err| {
+                errorln!("Error computing usage: {:?}", err);
+                std::process::exit(1);
+            });
+            if json_mode() {
+                for dir in &usage {
+                    emit("usage", dir);
+                }
+                return;
+            }
+            outputln!(
+                "{:>14} {:>14} {:>7}  directory",
+                "logical",
+                "physical",
+                "ratio"
+            );
+            for dir in &usage {
+                outputln!(
+                    "{:>14} {:>14} {:>6.2}x  {}",
+                    dir.logical,
+                    dir.physical,
+                    dir.ratio(),
+                    shown(Path::new(".").join(&dir.dir))
+                );
+            }
+        }
+        Commands::List { path, scan, sort } => {
+            if *scan {
+                handle_interrupts();
+            }
+            let groups = list_groups(path, *scan, *sort).unwrap_or_else(|err| {
+                if matches!(err, MirageError::Interrupted) {
+                    errorln!("Interrupted");
+                    std::process::exit(130);
+                }
+                errorln!("Error listing groups: {:?}", err);
+                std::process::exit(1);
+            });
+            if json_mode() {
+                for group in &groups {
+                    emit("group", group);
+                }
+                return;
+            }
+            for group in &groups {
+                outputln!(
+                    "{} ({} files, {} bytes)",
+                    shown(&group.original),
+                    group.files.len(),
+                    group.total_bytes()
+                );
+                for file in &group.files {
+                    outputln!("  {}", shown(file));
+                }
+            }
+        }
+        Commands::Subtrees { path } => {
+            let groups = identical_subtrees(path).unwrap_or_else(|err| {
+                errorln!("Error hashing directories: {:?}", err);
+                std::process::exit(1);
+            });
+            if json_mode() {
+                for group in &groups {
+                    emit("subtrees", &serde_json::json!({ "dirs": group }));
+                }
+                return;
+            }
+            for group in &groups {
+                outputln!(
+                    "{} identical directories, {} files and {} bytes each:",
+                    group.len(),
+                    group[0].files,
+                    group[0].bytes
+                );
+                for subtree in group {
+                    outputln!("  {}", shown(Path::new(".").join(&subtree.dir)));
+                }
+            }
+        }
+        Commands::Bench { path, files, bytes } => {
+            let mut options = BenchOptions {
+                sample_files: *files,
+                ..Default::default()
+            };
+            if let Some(bytes) = bytes {
+                options.sample_bytes = *bytes;
+            }
+            let report = bench(path, &options).unwrap_or_else(|err| {
+                errorln!("Error benchmarking: {:?}", err);
+                std::process::exit(1);
+            });
+            if json_mode() {
+                emit("bench", &report);
+                return;
+            }
+            outputln!(
+                "{:<18} {:>6} {:>14} {:>12}",
+                "strategy",
+                "files",
+                "bytes",
+                "MB/s"
+            );
+            for timing in &report.timings {
+                outputln!(
+                    "{:<18} {:>6} {:>14} {:>12.1}",
+                    timing.strategy.name(),
+                    timing.files,
+                    timing.bytes,
+                    timing.throughput() / 1_000_000.0
+                );
+            }
+            if !report.cold {
+                outputln!("The page cache was not dropped between strategies, later ones read from memory");
+            }
+            match (report.compare, report.hash) {
+                (Some(compare), Some(hash)) => outputln!(
+                    "Recommended: {} for comparing, {} for hashing",
+                    compare.name(),
+                    hash.name()
+                ),
+                _ => outputln!("No files to benchmark in {}", shown(path)),
+            }
+        }
+        Commands::Inspect {
+            what:
+                Inspect::Wal {
+                    path,
+                    matching,
+                    action,
+                },
+        } => {
+            let filter = WalFilter {
+                path: matching.clone(),
+                action: action.clone(),
+            };
+            let entries = inspect_wal(path, &filter).unwrap_or_else(|err| {
+                errorln!("Error reading WAL: {:?}", err);
+                std::process::exit(1);
+            });
+            if json_mode() {
+                for entry in &entries {
+                    emit("action", entry);
+                }
+                return;
+            }
+            outputln!(
+                "{:>6}  {:<7}  {:<7}  source -> target",
+                "index",
+                "type",
+                "state"
+            );
+            for entry in &entries {
+                outputln!(
+                    "{:>6}  {:<7}  {:<7}  {} -> {}",
+                    entry.index,
+                    entry.action.action,
+                    if entry.applied { "applied" } else { "pending" },
+                    shown(&entry.action.source),
+                    shown(&entry.action.target)
+                );
+            }
+        }
+        Commands::Inspect {
+            what: Inspect::Groups { path },
+        } => {
+            let groups = inspect_groups(path).unwrap_or_else(|err| {
+                errorln!("Error reading WAL: {:?}", err);
                 std::process::exit(1);
             });
+            if json_mode() {
+                for group in &groups {
+                    emit("group", group);
+                }
+                return;
+            }
+            outputln!(
+                "{:>6}  {:<11}  {:>7}  original",
+                "group",
+                "state",
+                "applied"
+            );
+            for group in &groups {
+                outputln!(
+                    "{:>6}  {:<11}  {:>7}  {}",
+                    group.group,
+                    format!("{:?}", group.progress),
+                    format!("{}/{}", group.applied, group.actions),
+                    shown(&group.original)
+                );
+            }
         }
-        Commands::Revert { path } => {
-            println!("Reverting deduplication to path: {}", path);
-            revert(path).unwrap_or_else(|err| {
-                eprintln!("Error reverting deduplication: {:?}", err);
+        Commands::Report {
+            what: Report::Diff { before, after },
+        } => {
+            let read = |file: &PathBuf| {
+                RunReport::read(file).unwrap_or_else(|err| {
+                    errorln!("Error reading report {}: {:?}", shown(file), err);
+                    std::process::exit(1);
+                })
+            };
+            let diff = diff_reports(&read(before), &read(after));
+            if json_mode() {
+                emit("report_diff", &diff);
+                return;
+            }
+            outputln!(
+                "{} new duplicate groups, {} resolved, {} changed",
+                diff.new_groups.len(),
+                diff.resolved_groups.len(),
+                diff.changed_groups.len()
+            );
+            outputln!(
+                "Savings changed by {} bytes, store size by {} bytes",
+                diff.saved_change,
+                diff.store_size_change
+            );
+            for (label, groups) in [
+                ("new", &diff.new_groups),
+                ("resolved", &diff.resolved_groups),
+                ("changed", &diff.changed_groups),
+            ] {
+                for group in groups {
+                    outputln!(
+                        "  {:<8} {}  {} files of {} bytes",
+                        label,
+                        group.content,
+                        group.files.len(),
+                        group.size
+                    );
+                    for file in &group.files {
+                        outputln!("             {}", shown(file));
+                    }
+                }
+            }
+        }
+        Commands::Checkpoint {
+            what: Checkpoint::Create { name, path },
+        } => {
+            let checkpoint = create_checkpoint(path, name).unwrap_or_else(|err| {
+                errorln!("Error creating checkpoint: {:?}", err);
+                std::process::exit(1);
+            });
+            if json_mode() {
+                emit("checkpoint", &checkpoint);
+                return;
+            }
+            outputln!(
+                "Checkpoint {} created after {} actions",
+                checkpoint.name,
+                checkpoint.actions
+            );
+        }
+        Commands::Checkpoint {
+            what: Checkpoint::List { path },
+        } => {
+            let checkpoints = checkpoints(path).unwrap_or_else(|err| {
+                errorln!("Error reading checkpoints: {:?}", err);
+                std::process::exit(1);
+            });
+            if json_mode() {
+                for checkpoint in &checkpoints {
+                    emit("checkpoint", checkpoint);
+                }
+                return;
+            }
+            if checkpoints.is_empty() {
+                outputln!("No checkpoints in {}", shown(path));
+            }
+            for checkpoint in &checkpoints {
+                outputln!(
+                    "{:<20}  created {}  after {} actions",
+                    checkpoint.name,
+                    checkpoint.created,
+                    checkpoint.actions
+                );
+            }
+        }
+        Commands::Checkpoint {
+            what: Checkpoint::Delete { name, path },
+        } => {
+            delete_checkpoint(path, name).unwrap_or_else(|err| {
+                errorln!("Error deleting checkpoint: {:?}", err);
+                std::process::exit(1);
+            });
+            outputln!("Checkpoint {} deleted", name);
+        }
+        Commands::Replay { path, dry_run } => {
+            let steps = replay_plan(path).unwrap_or_else(|err| {
+                errorln!("Error reading pending actions: {:?}", err);
+                std::process::exit(1);
+            });
+            if json_mode() {
+                for step in &steps {
+                    emit("step", step);
+                }
+            } else {
+                outputln!("{} pending actions", steps.len());
+                for step in &steps {
+                    outputln!(
+                        "  #{} {} {} -> {}{}",
+                        step.index,
+                        step.action.action,
+                        shown(&step.action.source),
+                        shown(&step.action.target),
+                        step.hazard
+                            .map(|hazard| format!(" ({})", hazard))
+                            .unwrap_or_default()
+                    );
+                }
+            }
+            if !*dry_run {
+                outputln!("Replaying pending actions of {}", shown(path));
+                handle_interrupts();
+                replay(path).unwrap_or_else(|err| {
+                    if matches!(err, MirageError::Interrupted) {
+                        errorln!("Interrupted, resume with `mirage resume {}`", shown(path));
+                        std::process::exit(130);
+                    }
+                    errorln!("Error replaying pending actions: {:?}", err);
+                    std::process::exit(1);
+                });
+            }
+        }
+        Commands::ExportScript { path, shell, all } => {
+            export_script(path, *shell, *all, BufWriter::new(output())).unwrap_or_else(|err| {
+                errorln!("Error exporting the WAL of {}: {:?}", shown(path), err);
+                std::process::exit(1);
+            });
+        }
+        Commands::Diff { path } => {
+            let entries = diff(path).unwrap_or_else(|err| {
+                errorln!("Error comparing {} with its WAL: {:?}", shown(path), err);
+                std::process::exit(1);
+            });
+            if json_mode() {
+                for entry in &entries {
+                    emit("divergence", entry);
+                }
+            } else {
+                outputln!("{} paths diverge from the WAL", entries.len());
+                for entry in &entries {
+                    match &entry.original {
+                        Some(original) => outputln!(
+                            "  {}: {} (linked to {})",
+                            shown(&entry.path),
+                            entry.divergence,
+                            shown(original)
+                        ),
+                        None => outputln!("  {}: {}", shown(&entry.path), entry.divergence),
+                    }
+                }
+            }
+            if !entries.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        Commands::Fsck { path, fix, repair } => {
+            let options = FsckOptions {
+                fix: *fix,
+                repair: *repair,
+            };
+            let findings = fsck_with_options(path, &options).unwrap_or_else(|err| {
+                errorln!("Error checking {}: {:?}", shown(path), err);
                 std::process::exit(1);
             });
+            if json_mode() {
+                for finding in &findings {
+                    emit("finding", finding);
+                }
+            } else {
+                outputln!("{} problems found", findings.len());
+                for finding in &findings {
+                    outputln!(
+                        "  {}: {}{}",
+                        shown(&finding.path),
+                        finding.problem,
+                        if finding.fixed { " (fixed)" } else { "" }
+                    );
+                }
+            }
+            if findings.iter().any(|finding| !finding.fixed) {
+                std::process::exit(1);
+            }
         }
+        Commands::RestoreState { path, backup, list } => {
+            if *list {
+                let backups = state_backups(path).unwrap_or_else(|err| {
+                    errorln!("Error listing backups: {:?}", err);
+                    std::process::exit(1);
+                });
+                for backup in backups {
+                    if json_mode() {
+                        emit("backup", &backup);
+                        continue;
+                    }
+                    outputln!(
+                        "{}: before {} at {}, {} actions, {} applied",
+                        backup.number,
+                        backup.command,
+                        backup.taken,
+                        backup.actions,
+                        backup.checkpoint
+                    );
+                }
+                return;
+            }
+            restore_state(path, *backup).unwrap_or_else(|err| {
+                errorln!("Error restoring state: {:?}", err);
+                std::process::exit(1);
+            });
+            outputln!("Restored backup {} of {}", backup, shown(path));
+        }
+        Commands::Status { path } => {
+            let summary = match store_status(path) {
+                Ok(summary) => summary,
+                Err(MirageError::MissingStore(_)) => {
+                    outputln!("{} has not been applied yet", shown(path));
+                    return;
+                }
+                Err(err) => {
+                    errorln!("Error reading store: {:?}", err);
+                    std::process::exit(1);
+                }
+            };
+            let progress = status(path).unwrap_or_else(|err| {
+                errorln!("Error reading progress: {:?}", err);
+                std::process::exit(1);
+            });
+            if json_mode() {
+                emit(
+                    "status",
+                    &serde_json::json!({
+                        "consistent": summary.consistent(),
+                        "store": summary,
+                        "progress": progress,
+                    }),
+                );
+                return;
+            }
+            outputln!(
+                "{} duplicate groups, {} bytes saved, {} actions pending",
+                summary.groups,
+                summary.bytes_saved,
+                summary.pending
+            );
+            if summary.layout == StoreLayout::Sharded {
+                outputln!("Originals are sharded into subdirectories");
+            }
+            if summary.consistent() {
+                outputln!("The tree is what its WAL says");
+            } else {
+                outputln!(
+                    "{} paths differ from what the WAL says, see `mirage diff {}`",
+                    summary.divergences.len(),
+                    shown(path)
+                );
+            }
+            let Some(progress) = progress else {
+                return;
+            };
+            outputln!(
+                "{:?} (pid {}, started at {}, updated at {})",
+                progress.phase,
+                progress.pid,
+                progress.started,
+                progress.updated
+            );
+            outputln!(
+                "  {} files scanned, {} bytes saved",
+                progress.files_scanned,
+                progress.bytes_saved
+            );
+            outputln!(
+                "  {} bytes hashed, {} bytes compared, {} bytes copied",
+                progress.bytes_hashed,
+                progress.bytes_compared,
+                progress.bytes_copied
+            );
+            if let Some(current) = &progress.current {
+                outputln!("  at {}", shown(current));
+            }
+        }
+        Commands::Verify { path, deep } => {
+            handle_interrupts();
+            let report = verify(path, *deep).unwrap_or_else(|err| {
+                if matches!(err, MirageError::Interrupted) {
+                    errorln!("Interrupted, nothing was modified");
+                    std::process::exit(130);
+                }
+                errorln!("Error verifying {}: {:?}", shown(path), err);
+                std::process::exit(1);
+            });
+            if json_mode() {
+                for mismatch in &report.mismatches {
+                    emit("mismatch", mismatch);
+                }
+                emit(
+                    "verify",
+                    &serde_json::json!({
+                        "checked": report.checked,
+                        "hashed": report.hashed,
+                        "unrecorded": report.unrecorded,
+                        "clean": report.is_clean(),
+                    }),
+                );
+            } else {
+                outputln!(
+                    "{} redirections checked, {} originals hashed, {} mismatches",
+                    report.checked,
+                    report.hashed,
+                    report.mismatches.len()
+                );
+                if report.unrecorded > 0 {
+                    outputln!(
+                        "{} originals have no recorded digest to compare with",
+                        report.unrecorded
+                    );
+                }
+                for mismatch in &report.mismatches {
+                    if mismatch.path == mismatch.original {
+                        outputln!("  {}: {}", shown(&mismatch.path), mismatch.discrepancy);
+                    } else {
+                        outputln!(
+                            "  {}: {} (linked to {})",
+                            shown(&mismatch.path),
+                            mismatch.discrepancy,
+                            shown(&mismatch.original)
+                        );
+                    }
+                }
+            }
+            if !report.is_clean() {
+                std::process::exit(1);
+            }
+        }
+        Commands::Why { path } => {
+            let why = why(path).unwrap_or_else(|err| {
+                errorln!("Error explaining {}: {:?}", shown(path), err);
+                std::process::exit(1);
+            });
+            let compared = comparisons(path).unwrap_or_else(|err| {
+                errorln!("Error reading journal: {:?}", err);
+                std::process::exit(1);
+            });
+            if json_mode() {
+                emit(
+                    "why",
+                    &serde_json::json!({ "path": path, "why": why, "compared": compared }),
+                );
+                return;
+            }
+            outputln!("{}: {}", shown(path), why);
+            for (other, same) in compared.unwrap_or_default() {
+                outputln!(
+                    "  {} {}",
+                    if same { "matched" } else { "differs from" },
+                    shown(&other)
+                );
+            }
+        }
+        Commands::Journal { path, previous } => {
+            let entries = journal(path, *previous).unwrap_or_else(|err| {
+                errorln!("Error reading journal: {:?}", err);
+                std::process::exit(1);
+            });
+            let Some(entries) = entries else {
+                outputln!("No journal kept, apply with --journal to keep one");
+                return;
+            };
+            for entry in &entries {
+                if json_mode() {
+                    emit("journal", entry);
+                    continue;
+                }
+                match serde_json::to_string(entry) {
+                    Ok(line) => outputln!("{}", line),
+                    Err(err) => {
+                        errorln!("Error writing journal: {:?}", err);
+                        std::process::exit(1);
+                    }
+                }
+            }
+        }
+        Commands::Stats {
+            path,
+            history: true,
+        } => {
+            let history = stats_history(path).unwrap_or_else(|err| {
+                errorln!("Error reading stats: {:?}", err);
+                std::process::exit(1);
+            });
+            if json_mode() {
+                for point in &history {
+                    emit("history", point);
+                }
+                return;
+            }
+            let known = |value: Option<u64>| value.map_or("-".to_string(), |v| v.to_string());
+            outputln!(
+                "{:>12}  {:>14}  {:>14}  {:>8}",
+                "at",
+                "store size",
+                "bytes saved",
+                "managed"
+            );
+            for point in &history {
+                outputln!(
+                    "{:>12}  {:>14}  {:>14}  {:>8}",
+                    point.at,
+                    known(point.store_size),
+                    point.bytes_saved,
+                    known(point.files_managed)
+                );
+            }
+        }
+        Commands::Stats { path, .. } => {
+            let space = MirageState::open(path)
+                .and_then(|state| state.stats())
+                .unwrap_or_else(|err| {
+                    errorln!("Error reading stats: {:?}", err);
+                    std::process::exit(1);
+                });
+            if json_mode() {
+                emit("stats", &space);
+                return;
+            }
+            let stats = stats(path).unwrap_or_else(|err| {
+                errorln!("Error reading stats: {:?}", err);
+                std::process::exit(1);
+            });
+            outputln!(
+                "{} files in {} duplicate groups, {} bytes logical, {} physical, ratio {:.2}",
+                space.files_scanned,
+                space.duplicate_groups,
+                space.logical_bytes,
+                space.physical_bytes,
+                space.dedup_ratio
+            );
+            outputln!(
+                "{} bytes saved over {} files in {} sessions",
+                stats.bytes_saved,
+                stats.files_deduped,
+                stats.sessions.len()
+            );
+            for session in &stats.sessions {
+                outputln!(
+                    "  at {}: {} files, {} bytes",
+                    session.at,
+                    session.files,
+                    session.bytes
+                );
+            }
+        }
+        Commands::Lock { path, reason } => {
+            outputln!("Locking {}", shown(path));
+            lock(path, reason.as_deref()).unwrap_or_else(|err| {
+                errorln!("Error locking {}: {:?}", shown(path), err);
+                std::process::exit(1);
+            });
+        }
+        Commands::Unlock { path } => {
+            outputln!("Unlocking {}", shown(path));
+            unlock(path).unwrap_or_else(|err| {
+                errorln!("Error unlocking {}: {:?}", shown(path), err);
+                std::process::exit(1);
+            });
+        }
+        Commands::Readonly { path, off } => {
+            outputln!(
+                "{} read-only marker of {}",
+                if *off { "Clearing" } else { "Setting" },
+                shown(path)
+            );
+            set_read_only(path, !*off).unwrap_or_else(|err| {
+                errorln!(
+                    "Error changing read-only marker of {}: {:?}",
+                    shown(path),
+                    err
+                );
+                std::process::exit(1);
+            });
+        }
+        Commands::Rm { paths } => {
+            for path in paths {
+                outputln!("Removing {}", shown(path));
+                remove(path).unwrap_or_else(|err| {
+                    errorln!("Error removing {}: {:?}", shown(path), err);
+                    std::process::exit(1);
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Confirmation;
+
+    #[test]
+    fn confirmation_test() {
+        assert_eq!(Confirmation::new(true, true), Confirmation::GoAhead);
+        assert_eq!(Confirmation::new(false, true), Confirmation::Ask);
+        // cron and CI never go ahead on their own
+        assert_eq!(Confirmation::new(true, false), Confirmation::GoAhead);
+        assert_eq!(Confirmation::new(false, false), Confirmation::Refuse);
     }
 }
@@ -0,0 +1,253 @@
+//! Bloom filter of the contents of the originals in a store.
+//!
+//! Looking for an original matching a new file digests every original of
+//! the store the first time a run looks one up. The filter is kept in the
+//! store next to the WAL so that in a run over an already deduplicated tree
+//! most new files are known to be unique from their hash alone, without
+//! that. It holds digests of one algorithm and is
+//! rebuilt when a run hashes with another.
+
+use std::{
+    collections::BTreeSet,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use log::debug;
+
+use crate::{cache::Cache, Action, ActionType, HashAlgorithm, MirageError, MirageState};
+
+/// File in the store holding the filter.
+const BLOOM_FILE: &str = "bloom";
+
+/// Filters written before they recorded their algorithm are rebuilt.
+const MAGIC: &[u8; 4] = b"MBL2";
+
+/// Bits per expected entry, for about one false positive in a hundred.
+const BITS_PER_ENTRY: usize = 10;
+
+const HASHES: u32 = 7;
+
+pub(crate) struct Bloom {
+    bits: Vec<u64>,
+    /// Entries inserted, to tell when the filter is too full to be useful.
+    len: u64,
+    /// The WAL checkpoint and number of originals the filter was last
+    /// brought up to date with.
+    synced: (u64, u64),
+    /// Algorithm of the digests inserted, see [`algorithm_id`].
+    algorithm: u64,
+}
+
+/// How the algorithm of a filter is written.
+fn algorithm_id(algorithm: HashAlgorithm) -> u64 {
+    match algorithm {
+        HashAlgorithm::Md5 => 0,
+        HashAlgorithm::Sha256 => 1,
+        HashAlgorithm::Blake3 => 2,
+    }
+}
+
+impl Bloom {
+    fn with_capacity(entries: usize, algorithm: HashAlgorithm) -> Self {
+        let words = (entries.max(64) * BITS_PER_ENTRY).div_ceil(64);
+        Bloom {
+            bits: vec![0; words],
+            len: 0,
+            synced: (0, 0),
+            algorithm: algorithm_id(algorithm),
+        }
+    }
+
+    fn capacity(&self) -> u64 {
+        (self.bits.len() * 64 / BITS_PER_ENTRY) as u64
+    }
+
+    /// Bit positions of a content id, from the two halves of the digest.
+    fn positions(&self, content: &str) -> impl Iterator<Item = usize> {
+        let half = |range: std::ops::Range<usize>| {
+            content
+                .get(range)
+                .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+                .unwrap_or(0)
+        };
+        let (h1, h2) = (half(0..16), half(16..32) | 1);
+        let bits = self.bits.len() as u64 * 64;
+        (0..HASHES as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bits) as usize)
+    }
+
+    fn insert(&mut self, content: &str) {
+        for bit in self.positions(content).collect::<Vec<_>>() {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+        self.len += 1;
+    }
+
+    /// False if `content` was definitely never inserted.
+    pub fn contains(&self, content: &str) -> bool {
+        self.positions(content)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    fn read(path: &Path) -> io::Result<Bloom> {
+        let mut input = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 4];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a filter"));
+        }
+        let mut word = [0u8; 8];
+        let mut next = || -> io::Result<u64> {
+            input.read_exact(&mut word)?;
+            Ok(u64::from_le_bytes(word))
+        };
+        let synced = (next()?, next()?);
+        let algorithm = next()?;
+        let len = next()?;
+        let words = next()?;
+        let bits = (0..words).map(|_| next()).collect::<io::Result<Vec<_>>>()?;
+        if bits.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "empty filter"));
+        }
+        Ok(Bloom {
+            bits,
+            len,
+            synced,
+            algorithm,
+        })
+    }
+
+    fn write(&self, path: &Path) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(MAGIC)?;
+        for word in [
+            self.synced.0,
+            self.synced.1,
+            self.algorithm,
+            self.len,
+            self.bits.len() as u64,
+        ] {
+            out.write_all(&word.to_le_bytes())?;
+        }
+        for word in &self.bits {
+            out.write_all(&word.to_le_bytes())?;
+        }
+        out.flush()
+    }
+}
+
+/// The distinct originals of the store that are on disk.
+fn originals(state: &MirageState) -> BTreeSet<&PathBuf> {
+    state
+        .wal
+        .redirections
+        .originals()
+        .filter(|original| original.exists())
+        .collect()
+}
+
+fn build(
+    state: &MirageState,
+    originals: &BTreeSet<&PathBuf>,
+    cache: &mut Cache,
+) -> Result<Bloom, MirageError> {
+    debug!("Building the filter of {} originals", originals.len());
+    let mut bloom = Bloom::with_capacity(originals.len() * 2, cache.algorithm());
+    for original in originals {
+        bloom.insert(&cache.hash(original)?);
+    }
+    bloom.synced = (state.wal.checkpoint as u64, originals.len() as u64);
+    Ok(bloom)
+}
+
+/// The filter of the originals of the store, rebuilt when something other
+/// than an apply run changed them since it was written or `cache` hashes
+/// with another algorithm. `None` when the store has no originals to look
+/// through.
+pub(crate) fn known_contents(
+    state: &MirageState,
+    cache: &mut Cache,
+) -> Result<Option<Bloom>, MirageError> {
+    let originals = originals(state);
+    if originals.is_empty() {
+        return Ok(None);
+    }
+    let synced = (state.wal.checkpoint as u64, originals.len() as u64);
+    let bloom = match Bloom::read(&state.source_path.join(BLOOM_FILE)) {
+        Ok(bloom)
+            if bloom.synced == synced && bloom.algorithm == algorithm_id(cache.algorithm()) =>
+        {
+            bloom
+        }
+        Ok(_) => build(state, &originals, cache)?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => build(state, &originals, cache)?,
+        Err(err) => {
+            debug!("Discarding unreadable filter: {:?}", err);
+            build(state, &originals, cache)?
+        }
+    };
+    if !state.dry_run {
+        bloom.write(&state.source_path.join(BLOOM_FILE))?;
+    }
+    Ok(Some(bloom))
+}
+
+/// Adds the originals copied by the just executed `actions` to the filter
+/// and writes it out, synced with the state.
+pub(crate) fn record_originals(
+    state: &MirageState,
+    bloom: Option<Bloom>,
+    actions: &[Action],
+    cache: &mut Cache,
+) -> Result<(), MirageError> {
+    let originals = originals(state);
+    let copied = actions
+        .iter()
+        .filter(|action| matches!(action.action, ActionType::Copy) && action.target.exists())
+        .collect::<Vec<_>>();
+    let mut bloom = match bloom {
+        Some(bloom) if bloom.len + copied.len() as u64 <= bloom.capacity() => bloom,
+        _ => {
+            build(state, &originals, cache)?.write(&state.source_path.join(BLOOM_FILE))?;
+            return Ok(());
+        }
+    };
+    for action in copied {
+        bloom.insert(&cache.hash(&action.target)?);
+    }
+    bloom.synced = (state.wal.checkpoint as u64, originals.len() as u64);
+    bloom.write(&state.source_path.join(BLOOM_FILE))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::Bloom;
+    use crate::HashAlgorithm;
+
+    #[test]
+    fn round_trips_without_false_negatives() {
+        let contents = (0..500)
+            .map(|i| format!("{:x}", md5::compute(i.to_string())))
+            .collect::<Vec<_>>();
+        let mut bloom = Bloom::with_capacity(contents.len(), HashAlgorithm::Md5);
+        for content in &contents {
+            bloom.insert(content);
+        }
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("bloom");
+        bloom.write(&path).unwrap();
+        let bloom = Bloom::read(&path).unwrap();
+
+        assert!(contents.iter().all(|content| bloom.contains(content)));
+        let false_positives = (500..1500)
+            .map(|i| format!("{:x}", md5::compute(i.to_string())))
+            .filter(|content| bloom.contains(content))
+            .count();
+        assert!(false_positives < 50, "{} false positives", false_positives);
+    }
+}
@@ -0,0 +1,82 @@
+//! Caps on how much a single apply run does, for scheduled runs on trees too
+//! big to go through in one window.
+//!
+//! A run that hits its cap stops before the next file, executes what it
+//! planned so far and remembers the duplicate group it stopped at. The next
+//! run skips the groups before it, so successive runs sweep the whole tree
+//! and start over once one of them gets to the end.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use log::debug;
+
+use crate::{ApplyOptions, MirageError, MirageState};
+
+/// File in the store holding the group a capped run stopped at.
+const RESUME_FILE: &str = "resume.json";
+
+/// The work left to the running apply.
+pub(crate) struct Budget {
+    started: Instant,
+    max_files: Option<usize>,
+    max_duration: Option<Duration>,
+    files: usize,
+}
+
+impl Budget {
+    pub fn new(options: &ApplyOptions) -> Budget {
+        Budget {
+            started: Instant::now(),
+            max_files: options.max_files,
+            max_duration: options.max_duration,
+            files: 0,
+        }
+    }
+
+    /// Whether the run is out of budget before starting on another file.
+    pub fn exhausted(&self) -> bool {
+        self.max_files.is_some_and(|max| self.files >= max)
+            || self
+                .max_duration
+                .is_some_and(|max| self.started.elapsed() >= max)
+    }
+
+    /// Counts a file the run worked on.
+    pub fn spend(&mut self) {
+        self.files += 1;
+    }
+}
+
+/// The first path of the group the last capped run of `state` stopped at.
+pub(crate) fn resume_point(state: &MirageState) -> Result<Option<PathBuf>, MirageError> {
+    let path = state.source_path.join(RESUME_FILE);
+    if !path.is_file() {
+        return Ok(None);
+    }
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+/// Remembers where the next run should pick up, `None` once a run got
+/// through every group.
+pub(crate) fn save_resume_point(
+    state: &MirageState,
+    point: Option<&Path>,
+) -> Result<(), MirageError> {
+    if state.dry_run {
+        return Ok(());
+    }
+    let path = state.source_path.join(RESUME_FILE);
+    match point {
+        Some(point) => {
+            debug!("Next run resumes at {:?}", point);
+            fs::write(path, serde_json::to_vec(point)?)?;
+        }
+        None if path.exists() => fs::remove_file(path)?,
+        None => {}
+    }
+    Ok(())
+}
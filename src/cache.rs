@@ -0,0 +1,333 @@
+//! Cache of content digests and comparison results, keyed by file identity.
+//!
+//! A file is identified by its device, inode, modification time and size,
+//! which all stay put as long as its contents do. Runs over a mostly
+//! unchanged tree then don't read again what they already hashed or compared.
+//! The cache is the `index` of the store, stores that kept it in
+//! `cache.json` have it moved there the next time it is saved.
+//! Digests are kept per algorithm, a cache hashing with one doesn't answer
+//! with the digests of another.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+};
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::{parallel::hash_files, HashAlgorithm, MirageError, MirageState};
+
+/// File in the store holding the cache.
+const CACHE_FILE: &str = "index";
+
+/// File the cache was held in before, read when there is no index yet.
+const LEGACY_CACHE_FILE: &str = "cache.json";
+
+/// Bytes to hash below which it isn't worth starting workers for.
+const PARALLEL_MIN_BYTES: u64 = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+struct Identity {
+    dev: u64,
+    ino: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+    len: u64,
+}
+
+impl Identity {
+    #[cfg(unix)]
+    fn of(path: &Path) -> Result<Option<Identity>, MirageError> {
+        use std::os::unix::fs::MetadataExt;
+
+        let meta = std::fs::metadata(path)?;
+        Ok(Some(Identity {
+            dev: meta.dev(),
+            ino: meta.ino(),
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec(),
+            len: meta.len(),
+        }))
+    }
+
+    /// Files have no stable identity to key on here, nothing is cached.
+    #[cfg(not(unix))]
+    fn of(_path: &Path) -> Result<Option<Identity>, MirageError> {
+        Ok(None)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Digest {
+    #[serde(flatten)]
+    file: Identity,
+    /// Caches written before digests were kept per algorithm hold MD5s.
+    #[serde(default)]
+    algorithm: HashAlgorithm,
+    digest: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Comparison {
+    a: Identity,
+    b: Identity,
+    same: bool,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Stored {
+    digests: Vec<Digest>,
+    comparisons: Vec<Comparison>,
+}
+
+pub(crate) struct Cache {
+    /// Where the cache is saved, `None` when it isn't.
+    path: Option<PathBuf>,
+    /// What [`Cache::hash`] hashes with.
+    algorithm: HashAlgorithm,
+    digests: HashMap<(Identity, HashAlgorithm), String>,
+    comparisons: HashMap<(Identity, Identity), bool>,
+    dirty: bool,
+    /// Bytes read to hash files since the cache was loaded.
+    hashed: u64,
+    /// Bytes of the files compared since the cache was loaded.
+    compared: u64,
+}
+
+/// Bytes read comparing `here` with `there`, nothing when their sizes
+/// differ.
+fn compared_bytes(here: &Path, there: &Path) -> Result<u64, MirageError> {
+    let (here, there) = (
+        std::fs::metadata(here)?.len(),
+        std::fs::metadata(there)?.len(),
+    );
+    Ok(if here == there { here + there } else { 0 })
+}
+
+impl Cache {
+    /// The cache of the store of `state`, hashing with `algorithm`, only
+    /// saved back if `state` commits.
+    pub fn load(state: &MirageState, algorithm: HashAlgorithm) -> Result<Cache, MirageError> {
+        let path = state.source_path.join(CACHE_FILE);
+        let legacy = state.source_path.join(LEGACY_CACHE_FILE);
+        let migrate = !path.is_file() && legacy.is_file();
+        let read = if migrate { &legacy } else { &path };
+        let stored = if read.is_file() {
+            match serde_json::from_reader(BufReader::new(File::open(read)?)) {
+                Ok(stored) => stored,
+                Err(err) => {
+                    debug!("Discarding unreadable cache: {:?}", err);
+                    Stored::default()
+                }
+            }
+        } else {
+            Stored::default()
+        };
+        Ok(Cache {
+            path: (!state.dry_run).then_some(path),
+            algorithm,
+            digests: stored
+                .digests
+                .into_iter()
+                .map(|digest| ((digest.file, digest.algorithm), digest.digest))
+                .collect(),
+            comparisons: stored
+                .comparisons
+                .into_iter()
+                .map(|comparison| ((comparison.a, comparison.b), comparison.same))
+                .collect(),
+            dirty: migrate,
+            hashed: 0,
+            compared: 0,
+        })
+    }
+
+    /// The algorithm the cache hashes with.
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    /// Like [`HashAlgorithm::hash_file`], without reading files hashed
+    /// before.
+    pub fn hash(&mut self, path: &Path) -> Result<String, MirageError> {
+        let Some(file) = Identity::of(path)? else {
+            self.hashed += std::fs::metadata(path)?.len();
+            return self.algorithm.hash_file(path);
+        };
+        if let Some(digest) = self.digests.get(&(file, self.algorithm)) {
+            return Ok(digest.clone());
+        }
+        let digest = self.algorithm.hash_file(path)?;
+        self.hashed += file.len;
+        self.digests.insert((file, self.algorithm), digest.clone());
+        self.dirty = true;
+        Ok(digest)
+    }
+
+    /// Like [`Cache::hash`] for every file of `paths`, hashing the ones not
+    /// hashed before on up to `jobs` workers.
+    pub fn hash_all(&mut self, paths: &[&Path], jobs: usize) -> Result<Vec<String>, MirageError> {
+        let mut missing = Vec::new();
+        for &path in paths {
+            if let Some(file) = Identity::of(path)? {
+                if !self.digests.contains_key(&(file, self.algorithm)) {
+                    missing.push((path, file));
+                }
+            }
+        }
+        let bytes = missing.iter().map(|(_, file)| file.len).sum::<u64>();
+        if jobs > 1 && missing.len() > 1 && bytes >= PARALLEL_MIN_BYTES {
+            let files = missing.iter().map(|&(path, _)| path).collect::<Vec<_>>();
+            let digests = hash_files(&files, jobs, self.algorithm);
+            for ((_, file), digest) in missing.iter().zip(digests) {
+                // kept for the files as they were before being read, changed
+                // ones are hashed again below
+                self.digests.insert((*file, self.algorithm), digest?);
+                self.hashed += file.len;
+                self.dirty = true;
+            }
+        }
+        paths.iter().map(|path| self.hash(path)).collect()
+    }
+
+    /// Whether `here` and `there` have the same contents, running `compare`
+    /// only if the files weren't compared or hashed before.
+    pub fn same<F>(&mut self, here: &Path, there: &Path, compare: F) -> Result<bool, MirageError>
+    where
+        F: FnOnce() -> Result<bool, MirageError>,
+    {
+        let (Some(here), Some(there)) = (Identity::of(here)?, Identity::of(there)?) else {
+            self.count_compared(here, there)?;
+            return compare();
+        };
+        let pair = (here.min(there), here.max(there));
+        if let Some(same) = self.comparisons.get(&pair) {
+            return Ok(*same);
+        }
+        if let (Some(a), Some(b)) = (
+            self.digests.get(&(here, self.algorithm)),
+            self.digests.get(&(there, self.algorithm)),
+        ) {
+            if a != b {
+                return Ok(false);
+            }
+        }
+        self.compared += if here.len == there.len {
+            here.len + there.len
+        } else {
+            0
+        };
+        let same = compare()?;
+        self.comparisons.insert(pair, same);
+        self.dirty = true;
+        Ok(same)
+    }
+
+    /// Counts a comparison of `here` with `there` made without the cache.
+    pub fn count_compared(&mut self, here: &Path, there: &Path) -> Result<(), MirageError> {
+        self.compared += compared_bytes(here, there)?;
+        Ok(())
+    }
+
+    /// Bytes read to hash files, the ones found in the cache aside.
+    pub fn bytes_hashed(&self) -> u64 {
+        self.hashed
+    }
+
+    /// Bytes of the files compared, the comparisons found in the cache aside.
+    pub fn bytes_compared(&self) -> u64 {
+        self.compared
+    }
+
+    /// Writes the cache back to the store if anything was added to it.
+    pub fn save(&self) -> Result<(), MirageError> {
+        let Some(path) = self.path.as_ref().filter(|_| self.dirty) else {
+            return Ok(());
+        };
+        let mut stored = Stored {
+            digests: self
+                .digests
+                .iter()
+                .map(|(&(file, algorithm), digest)| Digest {
+                    file,
+                    algorithm,
+                    digest: digest.clone(),
+                })
+                .collect(),
+            comparisons: self
+                .comparisons
+                .iter()
+                .map(|(&(a, b), &same)| Comparison { a, b, same })
+                .collect(),
+        };
+        stored
+            .digests
+            .sort_by_key(|digest| (digest.file, digest.algorithm.name()));
+        stored
+            .comparisons
+            .sort_by_key(|comparison| (comparison.a, comparison.b));
+        serde_json::to_writer(BufWriter::new(File::create(path)?), &stored)?;
+        let legacy = path.with_file_name(LEGACY_CACHE_FILE);
+        if legacy.is_file() {
+            debug!("Moved the cache from {:?} to {:?}", legacy, path);
+            fs::remove_file(legacy)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{cell::Cell, fs};
+
+    use tempfile::tempdir;
+
+    use super::Cache;
+    use crate::HashAlgorithm;
+
+    #[test]
+    fn reuses_results_until_files_change() {
+        let dir = tempdir().unwrap();
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        fs::write(&a, "same").unwrap();
+        fs::write(&b, "same").unwrap();
+        let mut cache = Cache {
+            path: Some(dir.path().join("index")),
+            algorithm: HashAlgorithm::Blake3,
+            digests: Default::default(),
+            comparisons: Default::default(),
+            dirty: false,
+            hashed: 0,
+            compared: 0,
+        };
+
+        let compared = Cell::new(0);
+        let compare = || {
+            compared.set(compared.get() + 1);
+            Ok(true)
+        };
+        assert!(cache.same(&a, &b, compare).unwrap());
+        assert!(cache.same(&b, &a, compare).unwrap());
+        assert_eq!(compared.get(), 1);
+
+        let digest = cache.hash(&a).unwrap();
+        fs::write(&a, "different").unwrap();
+        assert_ne!(cache.hash(&a).unwrap(), digest);
+        assert!(cache.same(&a, &b, compare).unwrap());
+        assert_eq!(compared.get(), 2);
+
+        // only what was actually read counts, files of different sizes
+        // aren't read to be compared
+        assert_eq!(cache.bytes_hashed(), 4 + 9);
+        assert_eq!(cache.bytes_compared(), 4 + 4);
+
+        // digests of another algorithm aren't reused
+        let digest = cache.hash(&a).unwrap();
+        cache.algorithm = HashAlgorithm::Md5;
+        assert_ne!(cache.hash(&a).unwrap(), digest);
+        assert_eq!(cache.bytes_hashed(), 4 + 9 + 9);
+    }
+}
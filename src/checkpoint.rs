@@ -0,0 +1,260 @@
+//! Named checkpoints in the history of a store. A checkpoint remembers how
+//! many actions the WAL held when it was created, and reverting to it
+//! undoes only the actions recorded since: the files they linked are
+//! restored, or linked again as they were before, and the originals they
+//! copied into the store are deleted.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, trace, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    back_up, ensure_space, execute, mark_lost,
+    parallel::{default_jobs, stages, undo_stages},
+    remove_missing_originals, space_needed,
+    unshare::open_store,
+    verify_restored, Action, ActionType, MirageError, MirageState, PlannedAction, RevertFailure,
+    RevertOptions, RevertReport, StoreLocation, DEFAULT_BACKUPS,
+};
+
+/// A named point in the history of a store.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub name: String,
+    /// Number of actions the WAL held when it was created.
+    pub actions: usize,
+    /// Seconds since the epoch when it was created.
+    pub created: u64,
+}
+
+/// Records a checkpoint named `name` at the current end of the WAL of the
+/// tree at `target_dir`, to revert to later with [`RevertOptions::to`].
+pub fn create_checkpoint<T: AsRef<Path>>(
+    target_dir: T,
+    name: &str,
+) -> Result<Checkpoint, MirageError> {
+    let mut state = open_store(target_dir.as_ref(), &StoreLocation::from_env())?;
+    if state.wal.named_checkpoints.iter().any(|c| c.name == name) {
+        return Err(MirageError::CheckpointExists(name.to_string()));
+    }
+    let checkpoint = Checkpoint {
+        name: name.to_string(),
+        actions: state.wal.actions.len(),
+        created: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+    };
+    if state.dry_run {
+        warn!("Store is read-only, not creating checkpoint {:?}", name);
+        return Ok(checkpoint);
+    }
+    debug!("Checkpoint {:?} at {} actions", name, checkpoint.actions);
+    state.wal.named_checkpoints.push(checkpoint.clone());
+    state.commit()?;
+    Ok(checkpoint)
+}
+
+/// The checkpoints of the tree at `target_dir`, oldest first.
+pub fn checkpoints<T: AsRef<Path>>(target_dir: T) -> Result<Vec<Checkpoint>, MirageError> {
+    Ok(MirageState::open(&target_dir)?.wal.named_checkpoints)
+}
+
+/// Forgets the checkpoint named `name` of the tree at `target_dir`. Nothing
+/// else changes.
+pub fn delete_checkpoint<T: AsRef<Path>>(target_dir: T, name: &str) -> Result<(), MirageError> {
+    let mut state = open_store(target_dir.as_ref(), &StoreLocation::from_env())?;
+    let before = state.wal.named_checkpoints.len();
+    state.wal.named_checkpoints.retain(|c| c.name != name);
+    if state.wal.named_checkpoints.len() == before {
+        return Err(MirageError::UnknownCheckpoint(name.to_string()));
+    }
+    if state.dry_run {
+        warn!("Store is read-only, not deleting checkpoint {:?}", name);
+        return Ok(());
+    }
+    state.commit()
+}
+
+/// Undoes the actions the tree at `root` recorded since the checkpoint named
+/// `name`, leaving the tree and its WAL as they were when it was created.
+/// Checkpoints created after it are forgotten, it itself is kept.
+pub(crate) fn revert_to(
+    root: &Path,
+    name: &str,
+    options: &RevertOptions,
+) -> Result<RevertReport, MirageError> {
+    let mut state = open_store(root, &options.location())?;
+    state.dry_run |= options.dry_run;
+    let at = state
+        .wal
+        .named_checkpoints
+        .iter()
+        .find(|c| c.name == name)
+        .ok_or_else(|| MirageError::UnknownCheckpoint(name.to_string()))?
+        .actions
+        .min(state.wal.actions.len());
+    let (earlier, later) = state.wal.actions.split_at(at);
+    // the last link each path got before the checkpoint, made again once
+    // what came after is undone
+    let mut linked = HashMap::new();
+    for action in earlier.iter().filter(|action| action.action.links()) {
+        linked.insert(&action.source, action);
+    }
+    let touched = later
+        .iter()
+        .filter(|action| action.action.links())
+        .map(|action| &action.source)
+        .collect::<HashSet<_>>();
+    let mut relinks = touched
+        .iter()
+        .filter_map(|&path| linked.get(path))
+        .map(|action| {
+            Action::new(
+                action.action.clone(),
+                action.source.clone(),
+                action.target.clone(),
+            )
+        })
+        .collect::<Vec<_>>();
+    relinks.sort_by(|a, b| a.source.cmp(&b.source));
+    let inverted = later
+        .iter()
+        .rev()
+        .map(|action| action.invert())
+        .collect::<Vec<_>>();
+    // originals copied since, none of the earlier actions refer to
+    let referenced = earlier
+        .iter()
+        .filter(|action| action.action.links() || matches!(action.action, ActionType::Copy))
+        .map(|action| &action.target)
+        .collect::<HashSet<_>>();
+    let copied = later
+        .iter()
+        .filter(|action| matches!(action.action, ActionType::Copy))
+        .map(|action| action.target.clone())
+        .filter(|original| !referenced.contains(original))
+        .collect::<Vec<_>>();
+    // where the paths linked since point once they are linked again
+    let redirected = touched
+        .iter()
+        .map(|&path| {
+            let original = linked.get(path).map(|action| action.target.clone());
+            (path.clone(), original)
+        })
+        .collect::<Vec<_>>();
+    let space = space_needed(&inverted, options.as_hardlinks)?;
+
+    if state.dry_run {
+        return Ok(RevertReport {
+            dry_run: true,
+            planned: inverted
+                .iter()
+                .chain(&relinks)
+                .map(PlannedAction::from)
+                .collect(),
+            space,
+            ..Default::default()
+        });
+    }
+    ensure_space(&space)?;
+
+    back_up(&state, "revert", options.backups.unwrap_or(DEFAULT_BACKUPS))?;
+    let mut stages = stages(inverted.into_iter());
+    let missing = remove_missing_originals(&mut stages);
+    if let Some(action) = missing.first().filter(|_| !options.force) {
+        return Err(MirageError::MissingOriginal(action.source.clone()));
+    }
+    let restored = stages
+        .iter()
+        .flatten()
+        .flatten()
+        .filter(|action| matches!(action.action, ActionType::Copy))
+        .map(|action| (action.target.clone(), action.source.clone()))
+        .collect::<HashMap<_, _>>();
+    let mut report = undo_stages(
+        stages,
+        options.jobs.unwrap_or_else(default_jobs),
+        options.as_hardlinks,
+    )?;
+    report.space = space;
+    for action in missing {
+        if !restored.contains_key(&action.target) && mark_lost(&action)? {
+            report.lost.push(action.target);
+        }
+    }
+    if options.verify {
+        verify_restored(&state, restored, &mut report)?;
+    }
+    for (index, action) in relinks.iter().enumerate() {
+        trace!("Linking {:?} to {:?} again", action.source, action.target);
+        if let Err(err) = execute(action, index, &state.source_path) {
+            report.failed.push(RevertFailure {
+                path: action.source.clone(),
+                error: format!("{:?}", err),
+            });
+        }
+    }
+    // the WAL is left as is, for the revert to be run again
+    if !report.failed.is_empty() || !report.mismatched.is_empty() {
+        warn!(
+            "Not every file came back, keeping the actions since {:?}",
+            name
+        );
+        return Ok(report);
+    }
+
+    for original in copied.iter().filter(|original| original.exists()) {
+        debug!("Removing original {:?}, copied after {:?}", original, name);
+        fs::remove_file(original)?;
+    }
+    let wal = &mut state.wal;
+    let forgotten = (at..wal.actions.len()).collect::<Vec<_>>();
+    wal.actions.truncate(at);
+    wal.checkpoint = at;
+    wal.shrink_segments(&forgotten);
+    for (path, original) in redirected {
+        match original {
+            Some(original) => wal.redirections.insert(path, original),
+            None => wal.redirections.remove(&path),
+        };
+    }
+    wal.named_checkpoints.retain(|c| c.actions <= at);
+    state.commit()?;
+    Ok(report)
+}
+
+/// The checkpoints of `checkpoints` moved back past `forgotten`, the
+/// positions of actions removed from the WAL.
+pub(crate) fn shift_checkpoints(checkpoints: &mut [Checkpoint], forgotten: &[usize]) {
+    for checkpoint in checkpoints {
+        checkpoint.actions -= forgotten
+            .iter()
+            .filter(|&&index| index < checkpoint.actions)
+            .count();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{shift_checkpoints, Checkpoint};
+
+    #[test]
+    fn shifts_past_forgotten_actions() {
+        let at = |actions: usize| Checkpoint {
+            name: actions.to_string(),
+            actions,
+            created: 0,
+        };
+        let mut checkpoints = vec![at(0), at(2), at(5)];
+        shift_checkpoints(&mut checkpoints, &[1, 2, 6]);
+        let actions = checkpoints.iter().map(|c| c.actions).collect::<Vec<_>>();
+        assert_eq!(actions, vec![0, 1, 3]);
+    }
+}
@@ -0,0 +1,655 @@
+//! Defaults for the options of a run, so that the same flags needn't be
+//! given every time. They are read from the `config.toml` of the user,
+//! `$XDG_CONFIG_HOME/mirage/config.toml` or `~/.config/mirage/config.toml`,
+//! then from the `config.toml` in the store of the tree, each key of the
+//! latter winning over the same key of the former. Flags given to a run win
+//! over both.
+//!
+//! The files hold `key = value` lines of TOML, with strings, integers,
+//! booleans and arrays of strings as values and `#` starting comments. The
+//! keys are those of the flags they stand for:
+//!
+//! ```toml
+//! exclude-extensions = ["iso", "part"]
+//! include = ["media/**"]
+//! min-size = "4K"
+//! link-mode = "hardlink"
+//! hash-algorithm = "sha256"
+//! jobs = 4
+//! profile = "photos"
+//!
+//! [profile.photos]
+//! min-size = "64K"
+//! link-mode = "reflink"
+//! ```
+//!
+//! `profile` picks the [`Profile`] of runs not given one. The keys of a
+//! `[profile.<name>]` table win over the defaults of that profile and the
+//! keys outside of tables whenever it is used.
+
+use std::{
+    collections::HashMap,
+    env, fs, io,
+    iter::Peekable,
+    path::{Path, PathBuf},
+    str::{Chars, FromStr},
+};
+
+use crate::{store_path, ApplyOptions, HashAlgorithm, LinkMode, MirageError, Profile};
+
+/// Name of the configuration files.
+pub const CONFIG_FILE: &str = "config.toml";
+
+/// Parses a byte count with an optional binary suffix (K, M, G, T).
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, shift) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => {
+            let shift = match c.to_ascii_uppercase() {
+                'K' => 10,
+                'M' => 20,
+                'G' => 30,
+                'T' => 40,
+                _ => return Err(format!("unknown size suffix {:?}", c)),
+            };
+            (&s[..i], shift)
+        }
+        _ => (s, 0),
+    };
+    let n: u64 = digits
+        .parse()
+        .map_err(|err| format!("invalid size {:?}: {}", s, err))?;
+    n.checked_shl(shift)
+        .filter(|v| v >> shift == n)
+        .ok_or_else(|| format!("size {:?} is too large", s))
+}
+
+/// A value of a configuration file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    fn kind(&self) -> &'static str {
+        match self {
+            Value::String(_) => "a string",
+            Value::Integer(_) => "an integer",
+            Value::Boolean(_) => "a boolean",
+            Value::Array(_) => "an array",
+        }
+    }
+}
+
+/// Reads the `key = value` lines of a configuration file, failing with the
+/// line and what is wrong with it.
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+}
+
+type Parsed<T> = Result<T, (usize, String)>;
+
+impl Parser<'_> {
+    fn fail<T>(&self, message: impl Into<String>) -> Parsed<T> {
+        Err((self.line, message.into()))
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c == Some('\n') {
+            self.line += 1;
+        }
+        c
+    }
+
+    /// Skips spaces and tabs, and with `newlines` line breaks and comments
+    /// too.
+    fn skip(&mut self, newlines: bool) {
+        while let Some(&c) = self.chars.peek() {
+            match c {
+                ' ' | '\t' | '\r' => {}
+                '\n' if newlines => {}
+                '#' if newlines => {
+                    while self.chars.peek().is_some_and(|&c| c != '\n') {
+                        self.bump();
+                    }
+                    continue;
+                }
+                _ => return,
+            }
+            self.bump();
+        }
+    }
+
+    /// Expects the end of the line, a comment aside.
+    fn end_of_line(&mut self) -> Parsed<()> {
+        self.skip(false);
+        match self.chars.peek().copied() {
+            None | Some('\n') | Some('#') => Ok(()),
+            Some(c) => self.fail(format!("expected the end of the line, found {:?}", c)),
+        }
+    }
+
+    fn key(&mut self) -> Parsed<String> {
+        match self.chars.peek() {
+            Some('"') | Some('\'') => self.string(),
+            _ => {
+                let mut key = String::new();
+                while let Some(&c) = self.chars.peek() {
+                    if !(c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+                        break;
+                    }
+                    key.push(c);
+                    self.bump();
+                }
+                if key.is_empty() {
+                    return self.fail("expected a key");
+                }
+                Ok(key)
+            }
+        }
+    }
+
+    fn string(&mut self) -> Parsed<String> {
+        let quote = self.bump();
+        let mut s = String::new();
+        loop {
+            let c = match self.chars.peek() {
+                None | Some('\n') => return self.fail("unterminated string"),
+                Some(&c) => c,
+            };
+            self.bump();
+            match c {
+                c if Some(c) == quote => return Ok(s),
+                // literal strings, between single quotes, have no escapes
+                '\\' if quote == Some('"') => {
+                    let escaped = match self.bump() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some(c) => return self.fail(format!("unknown escape \\{}", c)),
+                        None => return self.fail("unterminated string"),
+                    };
+                    s.push(escaped);
+                }
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn value(&mut self) -> Parsed<Value> {
+        match self.chars.peek() {
+            Some('"') | Some('\'') => self.string().map(Value::String),
+            Some('[') => {
+                self.bump();
+                let mut values = Vec::new();
+                loop {
+                    self.skip(true);
+                    if self.chars.peek() == Some(&']') {
+                        self.bump();
+                        return Ok(Value::Array(values));
+                    }
+                    values.push(self.value()?);
+                    self.skip(true);
+                    match self.bump() {
+                        Some(',') => {}
+                        Some(']') => return Ok(Value::Array(values)),
+                        _ => return self.fail("expected , or ] in the array"),
+                    }
+                }
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&c) = self.chars.peek() {
+                    if !(c.is_ascii_alphanumeric() || matches!(c, '-' | '+' | '_')) {
+                        break;
+                    }
+                    word.push(c);
+                    self.bump();
+                }
+                match word.as_str() {
+                    "true" => Ok(Value::Boolean(true)),
+                    "false" => Ok(Value::Boolean(false)),
+                    "" => self.fail("expected a value"),
+                    _ => match word.replace('_', "").parse() {
+                        Ok(n) => Ok(Value::Integer(n)),
+                        Err(_) => self.fail(format!("invalid value {:?}", word)),
+                    },
+                }
+            }
+        }
+    }
+
+    /// The dotted name of a `[table]` header, up to its closing bracket.
+    fn table(&mut self) -> Parsed<Vec<String>> {
+        self.bump();
+        let mut name = Vec::new();
+        loop {
+            self.skip(false);
+            name.push(self.key()?);
+            self.skip(false);
+            match self.chars.peek() {
+                Some('.') => {}
+                Some(']') => {
+                    self.bump();
+                    return Ok(name);
+                }
+                _ => return self.fail("expected . or ] in the table name"),
+            }
+            self.bump();
+        }
+    }
+
+    /// Every key of the file with its line, value and the table it is in,
+    /// empty for the keys before the first table, and the line of every
+    /// table.
+    fn entries(&mut self) -> Parsed<(Vec<Entry>, Tables)> {
+        let mut entries: Vec<Entry> = Vec::new();
+        let mut tables = vec![(0, Vec::new())];
+        loop {
+            self.skip(true);
+            if self.chars.peek().is_none() {
+                return Ok((entries, tables.split_off(1)));
+            }
+            let line = self.line;
+            if self.chars.peek() == Some(&'[') {
+                let table = self.table()?;
+                self.end_of_line()?;
+                if tables.iter().any(|(_, other)| *other == table) {
+                    return self.fail(format!("[{}] is defined twice", table.join(".")));
+                }
+                tables.push((line, table));
+                continue;
+            }
+            let (_, table) = tables.last().expect("the keys before any table");
+            let key = self.key()?;
+            if entries
+                .iter()
+                .any(|entry| entry.table == *table && entry.key == key)
+            {
+                return self.fail(format!("{} is set twice", key));
+            }
+            self.skip(false);
+            if self.bump() != Some('=') {
+                return self.fail(format!("expected = after {}", key));
+            }
+            self.skip(false);
+            let value = self.value()?;
+            self.end_of_line()?;
+            entries.push(Entry {
+                line,
+                table: table.clone(),
+                key,
+                value,
+            });
+        }
+    }
+}
+
+/// The tables of a configuration file with the lines they start on.
+type Tables = Vec<(usize, Vec<String>)>;
+
+/// A key of a configuration file.
+struct Entry {
+    line: usize,
+    table: Vec<String>,
+    key: String,
+    value: Value,
+}
+
+/// Defaults read from a configuration file, `None` for the keys it leaves
+/// out.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Config {
+    /// Extensions of files never deduplicated, see
+    /// [`ApplyOptions::exclude_extensions`].
+    pub exclude_extensions: Option<Vec<String>>,
+    /// See [`ApplyOptions::include`].
+    pub include: Option<Vec<String>>,
+    /// See [`ApplyOptions::min_size`].
+    pub min_size: Option<u64>,
+    /// See [`ApplyOptions::link_mode`].
+    pub link_mode: Option<LinkMode>,
+    /// See [`ApplyOptions::hash_algorithm`], also what `mirage rehash`
+    /// records digests with by default.
+    pub hash_algorithm: Option<HashAlgorithm>,
+    /// See [`ApplyOptions::jobs`].
+    pub jobs: Option<usize>,
+    /// Profile of the runs not given one.
+    pub profile: Option<Profile>,
+    /// Keys of the `[profile.<name>]` tables, by profile.
+    pub profiles: HashMap<Profile, Config>,
+}
+
+fn strings(value: Value) -> Result<Vec<String>, String> {
+    match value {
+        Value::Array(values) => values
+            .into_iter()
+            .map(|value| match value {
+                Value::String(s) => Ok(s),
+                value => Err(format!("expected strings, found {}", value.kind())),
+            })
+            .collect(),
+        value => Err(format!("expected an array, found {}", value.kind())),
+    }
+}
+
+fn string(value: Value) -> Result<String, String> {
+    match value {
+        Value::String(s) => Ok(s),
+        value => Err(format!("expected a string, found {}", value.kind())),
+    }
+}
+
+impl Config {
+    /// Parses the configuration file at `path` holding `text`.
+    pub fn parse(path: &Path, text: &str) -> Result<Config, MirageError> {
+        let invalid =
+            |(line, message)| MirageError::InvalidConfig(path.to_path_buf(), line, message);
+        let mut parser = Parser {
+            chars: text.chars().peekable(),
+            line: 1,
+        };
+        let mut config = Config::default();
+        let (entries, tables) = parser.entries().map_err(invalid)?;
+        // tables are checked where they start, even when they hold no key
+        for (line, table) in tables {
+            let known = match table.as_slice() {
+                [table, name] if table == "profile" => Profile::from_str(name).map(drop),
+                table => Err(format!("unknown table [{}]", table.join("."))),
+            };
+            known.map_err(|message| invalid((line, message)))?;
+        }
+        for entry in entries {
+            let set = match entry.table.as_slice() {
+                [] if entry.key == "profile" => string(entry.value)
+                    .and_then(|s| Profile::from_str(&s))
+                    .map(|profile| config.profile = Some(profile)),
+                [] => config.set(&entry.key, entry.value),
+                [table, name] if table == "profile" => {
+                    Profile::from_str(name).and_then(|profile| {
+                        let overrides = config.profiles.entry(profile).or_default();
+                        overrides.set(&entry.key, entry.value)
+                    })
+                }
+                table => Err(format!("unknown table [{}]", table.join("."))),
+            };
+            set.map_err(|message| invalid((entry.line, message)))?;
+        }
+        Ok(config)
+    }
+
+    /// Sets `key` to `value`, failing with what is wrong with either.
+    fn set(&mut self, key: &str, value: Value) -> Result<(), String> {
+        match key {
+            "exclude-extensions" => strings(value).map(|exts| {
+                let exts = exts
+                    .iter()
+                    .map(|ext| ext.trim_start_matches('.').to_string())
+                    .collect();
+                self.exclude_extensions = Some(exts)
+            }),
+            "include" => strings(value).map(|globs| self.include = Some(globs)),
+            "min-size" => match value {
+                Value::Integer(n) => u64::try_from(n)
+                    .map(|n| self.min_size = Some(n))
+                    .map_err(|_| "min-size can't be negative".to_string()),
+                value => string(value)
+                    .and_then(|s| parse_size(&s))
+                    .map(|n| self.min_size = Some(n)),
+            },
+            "link-mode" => string(value)
+                .and_then(|s| LinkMode::from_str(&s))
+                .map(|mode| self.link_mode = Some(mode)),
+            "hash-algorithm" => string(value)
+                .and_then(|s| HashAlgorithm::from_str(&s))
+                .map(|algorithm| self.hash_algorithm = Some(algorithm)),
+            "jobs" => match value {
+                Value::Integer(n) if n > 0 => {
+                    self.jobs = usize::try_from(n).ok();
+                    Ok(())
+                }
+                Value::Integer(_) => Err("jobs has to be at least 1".to_string()),
+                value => Err(format!("expected an integer, found {}", value.kind())),
+            },
+            _ => Err(format!("unknown key {}", key)),
+        }
+    }
+
+    /// Reads the configuration file at `path`, the defaults if there is
+    /// none.
+    pub fn read(path: &Path) -> Result<Config, MirageError> {
+        match fs::read_to_string(path) {
+            Ok(text) => Config::parse(path, &text),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Where the configuration file of the user is, if the environment
+    /// tells.
+    pub fn user_path() -> Option<PathBuf> {
+        let dir = match env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+            Some(dir) => PathBuf::from(dir),
+            None => {
+                PathBuf::from(env::var_os("HOME").filter(|dir| !dir.is_empty())?).join(".config")
+            }
+        };
+        Some(dir.join("mirage").join(CONFIG_FILE))
+    }
+
+    /// The configuration for the tree at `target_dir`: that of the user,
+    /// overridden by that of the store of the tree.
+    pub fn load<T: AsRef<Path>>(target_dir: T) -> Result<Config, MirageError> {
+        let user = match Config::user_path() {
+            Some(path) => Config::read(&path)?,
+            None => Config::default(),
+        };
+        let tree = Config::read(&store_path(target_dir.as_ref()).join(CONFIG_FILE))?;
+        Ok(user.overridden_by(tree))
+    }
+
+    /// This configuration with the keys `other` sets taken from it, table
+    /// by table.
+    pub fn overridden_by(self, other: Config) -> Config {
+        let mut profiles = self.profiles;
+        for (profile, overrides) in other.profiles {
+            let merged = match profiles.remove(&profile) {
+                Some(ours) => ours.overridden_by(overrides),
+                None => overrides,
+            };
+            profiles.insert(profile, merged);
+        }
+        Config {
+            exclude_extensions: other.exclude_extensions.or(self.exclude_extensions),
+            include: other.include.or(self.include),
+            min_size: other.min_size.or(self.min_size),
+            link_mode: other.link_mode.or(self.link_mode),
+            hash_algorithm: other.hash_algorithm.or(self.hash_algorithm),
+            jobs: other.jobs.or(self.jobs),
+            profile: other.profile.or(self.profile),
+            profiles,
+        }
+    }
+
+    /// Fills in the options a run was given with these defaults, leaving
+    /// alone those set already. With `profile`, or the profile of this
+    /// configuration without one, the keys of its table come first, then
+    /// the defaults of the profile, then the keys outside of tables.
+    pub fn apply_to(&self, options: &mut ApplyOptions, profile: Option<Profile>) {
+        if let Some(profile) = profile.or(self.profile) {
+            if let Some(overrides) = self.profiles.get(&profile) {
+                overrides.fill(options);
+            }
+            profile.apply_to(options);
+        }
+        self.fill(options);
+    }
+
+    /// Fills in the options left unset with the keys outside of tables.
+    /// Excluded extensions are added to those of the options.
+    fn fill(&self, options: &mut ApplyOptions) {
+        if let Some(exts) = &self.exclude_extensions {
+            options.exclude_extensions.extend(exts.iter().cloned());
+        }
+        if options.include.is_empty() {
+            options.include = self.include.clone().unwrap_or_default();
+        }
+        options.min_size = options.min_size.or(self.min_size);
+        // sharing extents links nothing
+        if !options.dedupe_extents {
+            options.link_mode = options.link_mode.or(self.link_mode);
+        }
+        options.jobs = options.jobs.or(self.jobs);
+        options.hash_algorithm = options.hash_algorithm.or(self.hash_algorithm);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{parse_size, Config};
+    use crate::{ApplyOptions, HashAlgorithm, LinkMode, MirageError, Profile};
+
+    fn parse(text: &str) -> Result<Config, MirageError> {
+        Config::parse(Path::new("config.toml"), text)
+    }
+
+    #[test]
+    fn parses_config() {
+        let config = parse(
+            "# defaults\n\
+             exclude-extensions = [\"iso\", '.part',]\n\
+             include = [\n  \"media/**\", # videos\n  \"*.jpg\"\n]\n\
+             min-size = \"4K\"\n\
+             \"link-mode\" = 'hardlink'  # same disk\n\
+             hash-algorithm = \"sha256\"\n\
+             jobs = 1_2\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            Config {
+                exclude_extensions: Some(vec!["iso".to_string(), "part".to_string()]),
+                include: Some(vec!["media/**".to_string(), "*.jpg".to_string()]),
+                min_size: Some(4096),
+                link_mode: Some(LinkMode::Hardlink),
+                hash_algorithm: Some(HashAlgorithm::Sha256),
+                jobs: Some(12),
+                ..Default::default()
+            }
+        );
+        assert_eq!(parse("min-size = 100").unwrap().min_size, Some(100));
+        assert_eq!(parse("").unwrap(), Config::default());
+
+        let line_of = |text| match parse(text) {
+            Err(MirageError::InvalidConfig(_, line, _)) => line,
+            parsed => panic!("{:?} parsed as {:?}", text, parsed),
+        };
+        assert_eq!(line_of("jobs = 2\n\nmin-sise = 1"), 3);
+        assert_eq!(line_of("jobs = 0"), 1);
+        assert_eq!(line_of("jobs = \"2\""), 1);
+        assert_eq!(line_of("jobs = 2\njobs = 3"), 2);
+        assert_eq!(line_of("[apply]\njobs = 2"), 1);
+        assert_eq!(line_of("profile = \"music\""), 1);
+        assert_eq!(line_of("jobs = 2\n[profile.music]\njobs = 2"), 2);
+        assert_eq!(line_of("[profile.code]\nprofile = \"code\""), 2);
+        assert_eq!(line_of("[profile.code]\n[profile.code]"), 2);
+        assert_eq!(line_of("[profile.code\njobs = 2"), 1);
+        assert_eq!(line_of("link-mode = \"copy\""), 1);
+        assert_eq!(line_of("include = [\"a\" \"b\"]"), 1);
+        assert_eq!(line_of("include = \"a\""), 1);
+        assert_eq!(line_of("jobs = 2 3"), 1);
+        assert_eq!(line_of("include = [\"a\"\n,\n\"unterminated]"), 3);
+    }
+
+    #[test]
+    fn flags_win_over_config() {
+        let user =
+            parse("jobs = 2\nmin-size = 10\nlink-mode = \"reflink\"\nhash-algorithm = \"sha256\"")
+                .unwrap();
+        let tree = parse("min-size = 20\nexclude-extensions = [\"tmp\"]").unwrap();
+        let config = user.overridden_by(tree);
+        assert_eq!(config.jobs, Some(2));
+        assert_eq!(config.min_size, Some(20));
+
+        let mut options = ApplyOptions {
+            jobs: Some(8),
+            include: vec!["*.iso".to_string()],
+            exclude_extensions: vec!["bak".to_string()],
+            ..Default::default()
+        };
+        config.apply_to(&mut options, None);
+        assert_eq!(options.jobs, Some(8));
+        assert_eq!(options.min_size, Some(20));
+        assert_eq!(options.link_mode, Some(LinkMode::Reflink));
+        assert_eq!(options.hash_algorithm, Some(HashAlgorithm::Sha256));
+        assert_eq!(options.include, vec!["*.iso".to_string()]);
+        assert_eq!(options.exclude_extensions, vec!["bak", "tmp"]);
+
+        let mut sharing = ApplyOptions {
+            dedupe_extents: true,
+            ..Default::default()
+        };
+        config.apply_to(&mut sharing, None);
+        assert_eq!(sharing.link_mode, None);
+    }
+
+    #[test]
+    fn profile_tables_win_over_profiles() {
+        let user = parse(
+            "min-size = 1\n\
+             link-mode = \"symlink\"\n\
+             profile = \"media\"\n\
+             [profile.media]\n\
+             min-size = \"1M\"\n\
+             [profile.'code']\n\
+             link-mode = \"hardlink\"\n",
+        )
+        .unwrap();
+        let tree = parse("[ profile . media ]\nlink-mode = \"reflink\"").unwrap();
+        let config = user.overridden_by(tree);
+        assert_eq!(config.profile, Some(Profile::Media));
+        assert_eq!(config.profiles[&Profile::Media].min_size, Some(1 << 20));
+
+        // the profile of the configuration, its table first
+        let mut options = ApplyOptions::default();
+        config.apply_to(&mut options, None);
+        assert!(options.ignore_metadata);
+        assert_eq!(options.min_size, Some(1 << 20));
+        assert_eq!(options.link_mode, Some(LinkMode::Reflink));
+
+        // the one a run is given, its defaults winning over the keys
+        // outside of tables
+        let mut options = ApplyOptions::default();
+        config.apply_to(&mut options, Some(Profile::Code));
+        assert!(!options.ignore_metadata);
+        assert_eq!(options.min_size, Some(4 << 10));
+        assert_eq!(options.link_mode, Some(LinkMode::Hardlink));
+
+        // and flags over all of them
+        let mut options = ApplyOptions {
+            min_size: Some(7),
+            ..Default::default()
+        };
+        config.apply_to(&mut options, Some(Profile::Photos));
+        assert_eq!(options.min_size, Some(7));
+        assert_eq!(options.link_mode, Some(LinkMode::Symlink));
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("4k"), Ok(4096));
+        assert_eq!(parse_size("1M"), Ok(1 << 20));
+        assert!(parse_size("1X").is_err());
+        assert!(parse_size("99999999999T").is_err());
+    }
+}
@@ -0,0 +1,164 @@
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+
+use crate::{walstream::WalStream, Action, ActionType, MirageError, MirageState};
+
+/// How a path of a managed tree differs from what its WAL says.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub enum Divergence {
+    /// A linked path is gone.
+    Missing,
+    /// A link was replaced by an independent file.
+    Replaced,
+    /// A symlink points somewhere other than its original.
+    Retargeted { actual: PathBuf },
+    /// A path deleted or moved away exists again.
+    Reappeared,
+    /// An original of the store is gone, every path linked to it is broken.
+    OriginalMissing,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Divergence::Missing => f.write_str("missing"),
+            Divergence::Replaced => f.write_str("no longer a link"),
+            Divergence::Retargeted { actual } => write!(f, "points at {}", actual.display()),
+            Divergence::Reappeared => f.write_str("exists again"),
+            Divergence::OriginalMissing => f.write_str("original is missing"),
+        }
+    }
+}
+
+/// A path that isn't what the WAL expects.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct DiffEntry {
+    pub path: PathBuf,
+    /// The original the path should share its contents with, if any.
+    pub original: Option<PathBuf>,
+    pub divergence: Divergence,
+}
+
+/// What the applied actions leave at a path.
+enum Expected {
+    Symlink(PathBuf),
+    Hardlink(PathBuf),
+    Reflink(PathBuf),
+    Original,
+    Present,
+    Absent,
+}
+
+#[cfg(unix)]
+pub(crate) fn same_inode(a: &fs::Metadata, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    fs::metadata(b).is_ok_and(|b| a.dev() == b.dev() && a.ino() == b.ino())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn same_inode(_a: &fs::Metadata, _b: &Path) -> bool {
+    true
+}
+
+fn check(path: &Path, expected: &Expected) -> Option<Divergence> {
+    let meta = fs::symlink_metadata(path).ok();
+    match (expected, meta) {
+        (Expected::Absent, Some(_)) => Some(Divergence::Reappeared),
+        (Expected::Absent, None) => None,
+        (Expected::Original, None) => Some(Divergence::OriginalMissing),
+        (_, None) => Some(Divergence::Missing),
+        (Expected::Symlink(original), Some(meta)) => {
+            if !meta.file_type().is_symlink() {
+                return Some(Divergence::Replaced);
+            }
+            let actual = fs::read_link(path).ok()?;
+            let resolved = fs::canonicalize(path).ok();
+            (actual != *original && resolved.as_ref() != Some(original))
+                .then_some(Divergence::Retargeted { actual })
+        }
+        (Expected::Hardlink(original), Some(meta)) => {
+            (!meta.is_file() || !same_inode(&meta, original)).then_some(Divergence::Replaced)
+        }
+        (Expected::Reflink(_), Some(meta)) => (!meta.is_file()).then_some(Divergence::Replaced),
+        (Expected::Original | Expected::Present, Some(_)) => None,
+    }
+}
+
+/// Compares the tree at `target_dir` with what the executed actions of its
+/// WAL left behind, listing every path that diverges in path order. Nothing
+/// is modified.
+pub fn diff<T: AsRef<Path>>(target_dir: T) -> Result<Vec<DiffEntry>, MirageError> {
+    let wal = WalStream::open(&target_dir)?;
+    let mut expected = BTreeMap::new();
+    wal.for_each_action(|index, action| {
+        if index < wal.checkpoint() {
+            expect(&mut expected, &action);
+        }
+        Ok(())
+    })?;
+    Ok(divergences(&expected))
+}
+
+pub(crate) fn diverging(state: &MirageState) -> Vec<DiffEntry> {
+    let mut expected = BTreeMap::new();
+    for action in state.wal.actions.iter().take(state.wal.checkpoint) {
+        expect(&mut expected, action);
+    }
+    divergences(&expected)
+}
+
+/// Records what the executed `action` left behind.
+fn expect(expected: &mut BTreeMap<PathBuf, Expected>, action: &Action) {
+    let (source, target) = (action.source.clone(), action.target.clone());
+    match &action.action {
+        ActionType::Copy => {
+            expected.insert(target, Expected::Original);
+        }
+        ActionType::Symlink => {
+            expected.insert(source, Expected::Symlink(target));
+        }
+        ActionType::Hardlink => {
+            expected.insert(source, Expected::Hardlink(target));
+        }
+        ActionType::Reflink => {
+            expected.insert(source, Expected::Reflink(target));
+        }
+        ActionType::Delete => {
+            expected.insert(source, Expected::Absent);
+        }
+        ActionType::Move => {
+            expected.insert(source, Expected::Absent);
+            expected.insert(target, Expected::Present);
+        }
+        ActionType::Dedupe => {
+            expected.insert(source, Expected::Present);
+            expected.insert(target, Expected::Present);
+        }
+        ActionType::NOP | ActionType::Unknown(_) => {}
+    }
+}
+
+fn divergences(expected: &BTreeMap<PathBuf, Expected>) -> Vec<DiffEntry> {
+    expected
+        .iter()
+        .filter_map(|(path, expected)| {
+            let divergence = check(path, expected)?;
+            let original = match expected {
+                Expected::Symlink(original)
+                | Expected::Hardlink(original)
+                | Expected::Reflink(original) => Some(original.clone()),
+                _ => None,
+            };
+            Some(DiffEntry {
+                path: path.clone(),
+                original,
+                divergence,
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,564 @@
+//! SHA-256 and HMAC-SHA-256, used to sign the WAL, and BLAKE3, one of the
+//! algorithms digests of originals can be recorded with.
+
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::MirageError;
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// Incremental SHA-256.
+#[derive(Clone)]
+pub struct Sha256 {
+    state: [u32; 8],
+    buffer: [u8; 64],
+    buffered: usize,
+    length: u64,
+}
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Sha256 {
+            state: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+                0x5be0cd19,
+            ],
+            buffer: [0; 64],
+            buffered: 0,
+            length: 0,
+        }
+    }
+}
+
+impl Sha256 {
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.length += data.len() as u64;
+        if self.buffered > 0 {
+            let take = data.len().min(64 - self.buffered);
+            self.buffer[self.buffered..self.buffered + take].copy_from_slice(&data[..take]);
+            self.buffered += take;
+            data = &data[take..];
+            if self.buffered < 64 {
+                return;
+            }
+            let block = self.buffer;
+            self.compress(&block);
+            self.buffered = 0;
+        }
+        let mut blocks = data.chunks_exact(64);
+        for block in &mut blocks {
+            self.compress(block.try_into().unwrap());
+        }
+        let rest = blocks.remainder();
+        self.buffer[..rest.len()].copy_from_slice(rest);
+        self.buffered = rest.len();
+    }
+
+    pub fn finish(mut self) -> [u8; 32] {
+        let bits = self.length.wrapping_mul(8);
+        self.update(&[0x80]);
+        while self.buffered != 56 {
+            self.update(&[0]);
+        }
+        self.update(&bits.to_be_bytes());
+        let mut out = [0; 32];
+        for (chunk, word) in out.chunks_exact_mut(4).zip(self.state) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        out
+    }
+
+    fn compress(&mut self, block: &[u8; 64]) {
+        let mut w = [0u32; 64];
+        for (i, chunk) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes(chunk.try_into().unwrap());
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (word, add) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *word = word.wrapping_add(add);
+        }
+    }
+}
+
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::default();
+    hasher.update(data);
+    hasher.finish()
+}
+
+/// Incremental HMAC-SHA-256 (RFC 2104).
+pub struct HmacSha256 {
+    block: [u8; 64],
+    inner: Sha256,
+}
+
+impl HmacSha256 {
+    pub fn new(key: &[u8]) -> HmacSha256 {
+        let mut block = [0u8; 64];
+        if key.len() > 64 {
+            block[..32].copy_from_slice(&sha256(key));
+        } else {
+            block[..key.len()].copy_from_slice(key);
+        }
+        let mut inner = Sha256::default();
+        inner.update(&block.map(|b| b ^ 0x36));
+        HmacSha256 { block, inner }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
+
+    pub fn finish(self) -> [u8; 32] {
+        let mut outer = Sha256::default();
+        outer.update(&self.block.map(|b| b ^ 0x5c));
+        outer.update(&self.inner.finish());
+        outer.finish()
+    }
+}
+
+/// HMAC-SHA-256 of `data` under `key`.
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut hmac = HmacSha256::new(key);
+    hmac.update(data);
+    hmac.finish()
+}
+
+const BLAKE3_IV: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+const BLAKE3_PERMUTATION: [usize; 16] = [2, 6, 3, 10, 7, 0, 4, 13, 1, 11, 12, 5, 9, 14, 15, 8];
+const BLAKE3_CHUNK_LEN: usize = 1024;
+const CHUNK_START: u32 = 1;
+const CHUNK_END: u32 = 2;
+const PARENT: u32 = 4;
+const ROOT: u32 = 8;
+
+fn blake3_g(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize, x: u32, y: u32) {
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(x);
+    state[d] = (state[d] ^ state[a]).rotate_right(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(12);
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(y);
+    state[d] = (state[d] ^ state[a]).rotate_right(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(7);
+}
+
+/// The BLAKE3 compression function, returning the whole state.
+fn blake3_compress(
+    cv: &[u32; 8],
+    block: &[u32; 16],
+    counter: u64,
+    block_len: u32,
+    flags: u32,
+) -> [u32; 16] {
+    let mut state = [
+        cv[0],
+        cv[1],
+        cv[2],
+        cv[3],
+        cv[4],
+        cv[5],
+        cv[6],
+        cv[7],
+        BLAKE3_IV[0],
+        BLAKE3_IV[1],
+        BLAKE3_IV[2],
+        BLAKE3_IV[3],
+        counter as u32,
+        (counter >> 32) as u32,
+        block_len,
+        flags,
+    ];
+    let mut m = *block;
+    for round in 0..7 {
+        blake3_g(&mut state, 0, 4, 8, 12, m[0], m[1]);
+        blake3_g(&mut state, 1, 5, 9, 13, m[2], m[3]);
+        blake3_g(&mut state, 2, 6, 10, 14, m[4], m[5]);
+        blake3_g(&mut state, 3, 7, 11, 15, m[6], m[7]);
+        blake3_g(&mut state, 0, 5, 10, 15, m[8], m[9]);
+        blake3_g(&mut state, 1, 6, 11, 12, m[10], m[11]);
+        blake3_g(&mut state, 2, 7, 8, 13, m[12], m[13]);
+        blake3_g(&mut state, 3, 4, 9, 14, m[14], m[15]);
+        if round < 6 {
+            m = BLAKE3_PERMUTATION.map(|i| m[i]);
+        }
+    }
+    for i in 0..8 {
+        state[i] ^= state[i + 8];
+        state[i + 8] ^= cv[i];
+    }
+    state
+}
+
+fn first_8(words: [u32; 16]) -> [u32; 8] {
+    let mut cv = [0; 8];
+    cv.copy_from_slice(&words[..8]);
+    cv
+}
+
+fn block_words(block: &[u8; 64]) -> [u32; 16] {
+    let mut words = [0; 16];
+    for (word, bytes) in words.iter_mut().zip(block.chunks_exact(4)) {
+        *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+    words
+}
+
+/// The last compression of a node, made a chaining value or, at the root,
+/// the digest.
+struct Blake3Output {
+    cv: [u32; 8],
+    block: [u32; 16],
+    counter: u64,
+    block_len: u32,
+    flags: u32,
+}
+
+impl Blake3Output {
+    fn chaining_value(&self) -> [u32; 8] {
+        first_8(blake3_compress(
+            &self.cv,
+            &self.block,
+            self.counter,
+            self.block_len,
+            self.flags,
+        ))
+    }
+
+    fn root(&self) -> [u8; 32] {
+        let words = blake3_compress(&self.cv, &self.block, 0, self.block_len, self.flags | ROOT);
+        let mut out = [0; 32];
+        for (bytes, word) in out.chunks_exact_mut(4).zip(words) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        out
+    }
+}
+
+fn parent_output(left: [u32; 8], right: [u32; 8]) -> Blake3Output {
+    let mut block = [0; 16];
+    block[..8].copy_from_slice(&left);
+    block[8..].copy_from_slice(&right);
+    Blake3Output {
+        cv: BLAKE3_IV,
+        block,
+        counter: 0,
+        block_len: 64,
+        flags: PARENT,
+    }
+}
+
+/// The 1 KiB chunk being hashed.
+#[derive(Clone)]
+struct ChunkState {
+    cv: [u32; 8],
+    counter: u64,
+    block: [u8; 64],
+    block_len: usize,
+    blocks_compressed: usize,
+}
+
+impl ChunkState {
+    fn new(counter: u64) -> Self {
+        ChunkState {
+            cv: BLAKE3_IV,
+            counter,
+            block: [0; 64],
+            block_len: 0,
+            blocks_compressed: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        64 * self.blocks_compressed + self.block_len
+    }
+
+    fn start_flag(&self) -> u32 {
+        if self.blocks_compressed == 0 {
+            CHUNK_START
+        } else {
+            0
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            // a full block is only compressed once more input follows it
+            if self.block_len == 64 {
+                let words = block_words(&self.block);
+                self.cv = first_8(blake3_compress(
+                    &self.cv,
+                    &words,
+                    self.counter,
+                    64,
+                    self.start_flag(),
+                ));
+                self.blocks_compressed += 1;
+                self.block = [0; 64];
+                self.block_len = 0;
+            }
+            let take = (64 - self.block_len).min(data.len());
+            self.block[self.block_len..self.block_len + take].copy_from_slice(&data[..take]);
+            self.block_len += take;
+            data = &data[take..];
+        }
+    }
+
+    fn output(&self) -> Blake3Output {
+        Blake3Output {
+            cv: self.cv,
+            block: block_words(&self.block),
+            counter: self.counter,
+            block_len: self.block_len as u32,
+            flags: self.start_flag() | CHUNK_END,
+        }
+    }
+}
+
+/// Incremental BLAKE3, 32 bytes of output.
+#[derive(Clone)]
+pub struct Blake3 {
+    chunk: ChunkState,
+    // chaining values of the complete subtrees on the left
+    stack: Vec<[u32; 8]>,
+}
+
+impl Default for Blake3 {
+    fn default() -> Self {
+        Blake3 {
+            chunk: ChunkState::new(0),
+            stack: Vec::new(),
+        }
+    }
+}
+
+impl Blake3 {
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            if self.chunk.len() == BLAKE3_CHUNK_LEN {
+                let mut cv = self.chunk.output().chaining_value();
+                let mut chunks = self.chunk.counter + 1;
+                // merge every subtree this chunk completes
+                while chunks & 1 == 0 {
+                    cv = parent_output(self.stack.pop().unwrap_or_default(), cv).chaining_value();
+                    chunks >>= 1;
+                }
+                self.stack.push(cv);
+                self.chunk = ChunkState::new(self.chunk.counter + 1);
+            }
+            let take = (BLAKE3_CHUNK_LEN - self.chunk.len()).min(data.len());
+            self.chunk.update(&data[..take]);
+            data = &data[take..];
+        }
+    }
+
+    pub fn finish(self) -> [u8; 32] {
+        let mut output = self.chunk.output();
+        for left in self.stack.into_iter().rev() {
+            output = parent_output(left, output.chaining_value());
+        }
+        output.root()
+    }
+}
+
+#[cfg(test)]
+pub fn blake3(data: &[u8]) -> [u8; 32] {
+    let mut hasher = Blake3::default();
+    hasher.update(data);
+    hasher.finish()
+}
+
+/// An algorithm the digests of originals are recorded with.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    /// What digests were recorded with before the algorithm was.
+    #[default]
+    Md5,
+    Sha256,
+    Blake3,
+}
+
+/// Algorithm files are hashed with unless configured otherwise.
+pub const DEFAULT_HASH_ALGORITHM: HashAlgorithm = HashAlgorithm::Blake3;
+
+impl HashAlgorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Md5 => "md5",
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    /// Hex digest of the contents of `path`.
+    pub fn hash_file(&self, path: &Path) -> Result<String, MirageError> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut buf = [0; 10000];
+        let mut read = |update: &mut dyn FnMut(&[u8])| -> Result<(), MirageError> {
+            loop {
+                let n = reader.read(&mut buf)?;
+                if n == 0 {
+                    return Ok(());
+                }
+                update(&buf[..n]);
+            }
+        };
+        Ok(match self {
+            HashAlgorithm::Md5 => {
+                let mut context = md5::Context::new();
+                read(&mut |data| context.consume(data))?;
+                format!("{:x}", context.compute())
+            }
+            HashAlgorithm::Sha256 => {
+                let mut hasher = Sha256::default();
+                read(&mut |data| hasher.update(data))?;
+                to_hex(&hasher.finish())
+            }
+            HashAlgorithm::Blake3 => {
+                let mut hasher = Blake3::default();
+                read(&mut |data| hasher.update(data))?;
+                to_hex(&hasher.finish())
+            }
+        })
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "md5" => Ok(HashAlgorithm::Md5),
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            _ => Err(format!("unknown hash algorithm {:?}", s)),
+        }
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Whether `a` and `b` are equal, in a time that depends on their lengths
+/// only, so that comparing a MAC doesn't tell how much of it was right.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let diff = a.iter().zip(b).fold(0u8, |diff, (a, b)| diff | (a ^ b));
+    std::hint::black_box(diff) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{blake3, constant_time_eq, hmac_sha256, sha256, to_hex, Blake3};
+
+    #[test]
+    fn compares_in_constant_time() {
+        assert!(constant_time_eq(b"", b""));
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"ab"));
+    }
+
+    #[test]
+    fn known_vectors() {
+        assert_eq!(
+            to_hex(&sha256(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            to_hex(&sha256(&[b'a'; 1000])),
+            "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3"
+        );
+        assert_eq!(
+            to_hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+
+        // from the official test vectors, input bytes counting up mod 251
+        let input = (0..2049u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        for (len, digest) in [
+            (
+                0,
+                "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262",
+            ),
+            (
+                1,
+                "2d3adedff11b61f14c886e35afa036736dcd87a74d27b5c1510225d0f592e213",
+            ),
+            (
+                1023,
+                "10108970eeda3eb932baac1428c7a2163b0e924c9a9e25b35bba72b28f70bd11",
+            ),
+            (
+                1024,
+                "42214739f095a406f3fc83deb889744ac00df831c10daa55189b5d121c855af7",
+            ),
+            (
+                1025,
+                "d00278ae47eb27b34faecf67b4fe263f82d5412916c1ffd97c8cb7fb814b8444",
+            ),
+            (
+                2048,
+                "e776b6028c7cd22a4d0ba182a8bf62205d2ef576467e838ed6f2529b85fba24a",
+            ),
+        ] {
+            assert_eq!(to_hex(&blake3(&input[..len])), digest, "{} bytes", len);
+        }
+        // fed in pieces that straddle blocks and chunks
+        let mut hasher = Blake3::default();
+        for piece in input[..2048].chunks(100) {
+            hasher.update(piece);
+        }
+        assert_eq!(to_hex(&hasher.finish()), to_hex(&blake3(&input[..2048])));
+    }
+}
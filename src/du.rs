@@ -0,0 +1,90 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use log::trace;
+use serde::Serialize;
+
+use crate::{
+    canonicalize_link, walk, walstream::WalStream, warn_walk_error, ApplyOptions, MirageError,
+};
+
+/// Space used by one directory of a managed tree, subdirectories included.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct DiskUsage {
+    /// Relative to the root of the tree, empty for the root itself.
+    pub dir: PathBuf,
+    /// Size of every file as seen through its link.
+    pub logical: u64,
+    /// Size of the unique contents, each original counted once.
+    pub physical: u64,
+}
+
+impl DiskUsage {
+    /// How many times over the unique contents are used, 1 without duplicates.
+    pub fn ratio(&self) -> f64 {
+        if self.physical == 0 {
+            1.0
+        } else {
+            self.logical as f64 / self.physical as f64
+        }
+    }
+}
+
+/// Logical and physical usage of every directory of the tree at
+/// `target_dir`, root first, following the redirections of its store.
+pub fn disk_usage<T: AsRef<Path>>(target_dir: T) -> Result<Vec<DiskUsage>, MirageError> {
+    // the actions aren't needed, only where each path leads
+    let redirections = WalStream::open(&target_dir)?.redirections()?;
+    let root = fs::canonicalize(&target_dir)?;
+
+    let mut sizes: HashMap<PathBuf, u64> = HashMap::new();
+    let mut dirs: BTreeMap<PathBuf, (u64, HashSet<PathBuf>)> = BTreeMap::new();
+    for entry in walk(&root, &ApplyOptions::everything()) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(x) => {
+                warn_walk_error(&x);
+                continue;
+            }
+        };
+        let path = canonicalize_link(entry.path())?;
+        let relative = path.strip_prefix(&root).unwrap_or(&path).to_path_buf();
+        if entry.file_type().is_dir() {
+            dirs.entry(relative).or_default();
+            continue;
+        }
+        let content = match redirections.get(&path) {
+            Some(original) => original.clone(),
+            None if entry.path_is_symlink() => {
+                trace!("Skipping unmanaged symlink {:?}", path);
+                continue;
+            }
+            None => path.clone(),
+        };
+        let size = match sizes.get(&content) {
+            Some(size) => *size,
+            None => {
+                let size = fs::metadata(&content)?.len();
+                sizes.insert(content.clone(), size);
+                size
+            }
+        };
+        for dir in relative.ancestors().skip(1) {
+            let (logical, contents) = dirs.entry(dir.to_path_buf()).or_default();
+            *logical += size;
+            contents.insert(content.clone());
+        }
+    }
+
+    Ok(dirs
+        .into_iter()
+        .map(|(dir, (logical, contents))| DiskUsage {
+            dir,
+            logical,
+            physical: contents.iter().map(|content| sizes[content]).sum(),
+        })
+        .collect())
+}
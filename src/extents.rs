@@ -0,0 +1,156 @@
+//! Sharing the extents of identical files in place, on filesystems that can
+//! deduplicate blocks. Both files stay where they are, ordinary files to
+//! every program reading them, while their blocks are stored once.
+
+use std::{io, path::Path};
+
+use log::debug;
+
+use crate::{
+    index::DuplicateIndex,
+    why::{Decision, Decisions},
+    Action, ActionType, MirageError, MirageState,
+};
+
+/// Queues sharing the extents of the first file of every class of `index`
+/// with the other files of the class. Files an earlier run linked to an
+/// original are left linked.
+pub(crate) fn plan_sharing(
+    state: &mut MirageState,
+    index: &DuplicateIndex,
+    decisions: &mut Decisions,
+) -> Result<(), MirageError> {
+    for class in index.classes() {
+        let files = class
+            .into_iter()
+            .filter(|file| !state.wal.redirections.contains_key(*file))
+            .collect::<Vec<_>>();
+        let [seed, rest @ ..] = files.as_slice() else {
+            continue;
+        };
+        if let Some(first) = rest.first() {
+            decisions.record(seed, || Decision::ExtentsShared {
+                with: first.to_path_buf(),
+            });
+        }
+        for file in rest {
+            debug!("Sharing the extents of {:?} with {:?}", seed, file);
+            state.wal.push(Action::new(
+                ActionType::Dedupe,
+                file.to_path_buf(),
+                seed.to_path_buf(),
+            ));
+            decisions.record(file, || Decision::ExtentsShared {
+                with: seed.to_path_buf(),
+            });
+        }
+        state.commit()?;
+    }
+    Ok(())
+}
+
+/// Makes `dst` share the extents of `src`, which must hold the same
+/// contents, returning the number of bytes shared. The kernel compares the
+/// blocks itself and refuses to share ranges that differ, failing with
+/// [`io::ErrorKind::InvalidData`] then. Fails with
+/// [`io::ErrorKind::Unsupported`] where the filesystem or platform can't
+/// deduplicate.
+#[cfg(target_os = "linux")]
+pub(crate) fn share_extents(src: &Path, dst: &Path) -> io::Result<u64> {
+    use std::{fs::File, os::fd::AsRawFd};
+
+    // struct file_dedupe_range of linux/fs.h, the flexible array of
+    // destinations ending it left out
+    #[repr(C)]
+    struct DedupeRange {
+        src_offset: u64,
+        src_length: u64,
+        dest_count: u16,
+        reserved1: u16,
+        reserved2: u32,
+    }
+
+    // struct file_dedupe_range_info
+    #[repr(C)]
+    struct DedupeRangeInfo {
+        dest_fd: i64,
+        dest_offset: u64,
+        bytes_deduped: u64,
+        status: i32,
+        reserved: u32,
+    }
+
+    #[repr(C)]
+    struct Request {
+        range: DedupeRange,
+        info: DedupeRangeInfo,
+    }
+
+    const FIDEDUPERANGE: libc::Ioctl = libc::_IOWR::<DedupeRange>(0x94, 54) as libc::Ioctl;
+    const FILE_DEDUPE_RANGE_DIFFERS: i32 = 1;
+    // the kernel caps what one call shares, at 16 MiB on most filesystems
+    const CHUNK: u64 = 16 << 20;
+
+    let src_file = File::open(src)?;
+    let dst_file = File::options().write(true).open(dst)?;
+    let len = src_file.metadata()?.len();
+    let mut offset = 0;
+    while offset < len {
+        let mut request = Request {
+            range: DedupeRange {
+                src_offset: offset,
+                src_length: CHUNK.min(len - offset),
+                dest_count: 1,
+                reserved1: 0,
+                reserved2: 0,
+            },
+            info: DedupeRangeInfo {
+                dest_fd: dst_file.as_raw_fd() as i64,
+                dest_offset: offset,
+                bytes_deduped: 0,
+                status: 0,
+                reserved: 0,
+            },
+        };
+        let ret = unsafe { libc::ioctl(src_file.as_raw_fd(), FIDEDUPERANGE, &mut request) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            return Err(match err.raw_os_error() {
+                Some(libc::EOPNOTSUPP)
+                | Some(libc::ENOTTY)
+                | Some(libc::EXDEV)
+                | Some(libc::EINVAL) => io::Error::new(io::ErrorKind::Unsupported, err),
+                _ => err,
+            });
+        }
+        match request.info.status {
+            FILE_DEDUPE_RANGE_DIFFERS => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{:?} and {:?} differ at byte {}", src, dst, offset),
+                ))
+            }
+            status if status < 0 => {
+                let err = io::Error::from_raw_os_error(-status);
+                return Err(match -status {
+                    libc::EOPNOTSUPP | libc::EINVAL => {
+                        io::Error::new(io::ErrorKind::Unsupported, err)
+                    }
+                    _ => err,
+                });
+            }
+            _ => {}
+        }
+        if request.info.bytes_deduped == 0 {
+            // the kernel shares whole blocks only, a tail it can't is left
+            break;
+        }
+        offset += request.info.bytes_deduped;
+    }
+    Ok(offset)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn share_extents(_src: &Path, _dst: &Path) -> io::Result<u64> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
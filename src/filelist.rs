@@ -0,0 +1,93 @@
+//! Lists of candidate files handed to apply by another tool, such as
+//! `find -print0` or `fd -0`, so a run is restricted to exactly the paths it
+//! selected.
+
+use std::{
+    collections::HashSet,
+    fs,
+    io::{BufReader, Read},
+    path::{Path, PathBuf},
+};
+
+use log::warn;
+
+use crate::MirageError;
+
+/// The entries of the list `text`, split at newlines, or at NUL bytes with
+/// `nul`.
+fn split(text: &[u8], nul: bool) -> impl Iterator<Item = &[u8]> {
+    let separator = if nul { b'\0' } else { b'\n' };
+    text.split(move |&b| b == separator).map(move |line| {
+        if nul {
+            line
+        } else {
+            line.strip_suffix(b"\r").unwrap_or(line)
+        }
+    })
+}
+
+#[cfg(unix)]
+fn to_path(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+fn to_path(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// The path apply walks `path` as: its directory resolved, its name kept,
+/// so that a listed symlink stays the link rather than what it points at.
+fn resolve(path: &Path) -> std::io::Result<PathBuf> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| std::io::Error::other("not a file name"))?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    Ok(fs::canonicalize(dir)?.join(name))
+}
+
+/// Reads the files listed in `reader`, one per line or, with `nul`,
+/// separated by NUL bytes. Relative paths are taken from the current
+/// directory. Listed paths that don't exist are reported and left out.
+pub fn read_file_list<R: Read>(reader: R, nul: bool) -> Result<HashSet<PathBuf>, MirageError> {
+    let mut text = Vec::new();
+    BufReader::new(reader).read_to_end(&mut text)?;
+    let mut files = HashSet::new();
+    for entry in split(&text, nul).filter(|entry| !entry.is_empty()) {
+        let path = to_path(entry);
+        match resolve(&path) {
+            Ok(resolved) if fs::symlink_metadata(&resolved).is_ok() => {
+                files.insert(resolved);
+            }
+            Ok(_) => warn!("Listed file {:?} doesn't exist, leaving it out", path),
+            Err(err) => warn!("Can't find listed file {:?}, leaving it out: {}", path, err),
+        }
+    }
+    Ok(files)
+}
+
+/// Reads the file list at `path`, `-` standing for stdin.
+pub fn read_file_list_at(path: &Path, nul: bool) -> Result<HashSet<PathBuf>, MirageError> {
+    if path == Path::new("-") {
+        read_file_list(std::io::stdin().lock(), nul)
+    } else {
+        read_file_list(fs::File::open(path)?, nul)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::split;
+
+    #[test]
+    fn splits_lists() {
+        let lines = split(b"a\r\nb c\n\n", false).collect::<Vec<_>>();
+        assert_eq!(lines, vec![&b"a"[..], b"b c", b"", b""]);
+        let entries = split(b"a\nb\0c\0", true).collect::<Vec<_>>();
+        assert_eq!(entries, vec![&b"a\nb"[..], b"c", b""]);
+    }
+}
@@ -0,0 +1,80 @@
+use std::{fs, path::Path};
+
+use log::debug;
+
+use crate::{store_path, MirageError, MirageState};
+
+/// Marker inside the store whose presence freezes the tree.
+const FROZEN_FILE: &str = "frozen";
+
+/// Marker inside the store whose presence turns every mutating operation
+/// into a dry run.
+const READ_ONLY_FILE: &str = "readonly";
+
+impl MirageState {
+    /// Whether the tree has been frozen with [`lock`].
+    pub fn is_frozen(&self) -> bool {
+        self.source_path.join(FROZEN_FILE).exists()
+    }
+
+    /// Whether the store carries the read-only marker set by
+    /// [`set_read_only`].
+    pub fn is_read_only(&self) -> bool {
+        self.source_path.join(READ_ONLY_FILE).exists()
+    }
+
+    /// Fails with [`MirageError::Frozen`] if the tree has been frozen, for
+    /// every operation that changes the tree or its store.
+    pub(crate) fn ensure_unfrozen(&self) -> Result<(), MirageError> {
+        if self.is_frozen() {
+            return Err(MirageError::Frozen(self.root.clone()));
+        }
+        Ok(())
+    }
+}
+
+/// Freezes a managed tree: apply, revert and every other command that would
+/// modify it refuse to run until [`unlock`] is called. `reason` is kept in the
+/// marker for whoever finds the tree locked.
+pub fn lock<T: AsRef<Path>>(target_dir: T, reason: Option<&str>) -> Result<(), MirageError> {
+    let state = MirageState::open(&target_dir)?;
+    debug!("Freezing {:?}", state.source_path);
+    fs::write(
+        state.source_path.join(FROZEN_FILE),
+        reason.unwrap_or_default(),
+    )?;
+    Ok(())
+}
+
+/// Lifts a freeze set by [`lock`]. Unlocking a tree that isn't locked is fine.
+pub fn unlock<T: AsRef<Path>>(target_dir: T) -> Result<(), MirageError> {
+    let state = MirageState::open(&target_dir)?;
+    let marker = state.source_path.join(FROZEN_FILE);
+    if marker.exists() {
+        debug!("Unfreezing {:?}", state.source_path);
+        fs::remove_file(marker)?;
+    }
+    Ok(())
+}
+
+/// Whether the tree at `target_dir` is managed and marked read-only.
+pub fn is_read_only<T: AsRef<Path>>(target_dir: T) -> bool {
+    store_path(target_dir.as_ref())
+        .join(READ_ONLY_FILE)
+        .exists()
+}
+
+/// Sets or clears the read-only marker of a managed tree. While set, apply,
+/// revert and every other mutating command only report what they would do.
+pub fn set_read_only<T: AsRef<Path>>(target_dir: T, read_only: bool) -> Result<(), MirageError> {
+    let state = MirageState::open(&target_dir)?;
+    let marker = state.source_path.join(READ_ONLY_FILE);
+    if read_only {
+        debug!("Marking {:?} read-only", state.source_path);
+        fs::write(marker, "")?;
+    } else if marker.exists() {
+        debug!("Clearing read-only marker of {:?}", state.source_path);
+        fs::remove_file(marker)?;
+    }
+    Ok(())
+}
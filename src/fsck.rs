@@ -0,0 +1,303 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use log::{debug, warn};
+use serde::Serialize;
+
+use crate::{
+    diff::diverging, execute, Action, ActionType, DiffEntry, Divergence, MirageError, MirageState,
+};
+
+/// Options controlling what [`fsck_with_options`] repairs.
+#[derive(Debug, Clone, Default)]
+pub struct FsckOptions {
+    /// Repair what can be without risking data, see [`fsck`].
+    pub fix: bool,
+    /// Also restore missing originals from the files still holding their
+    /// contents, and forget the links of paths gone along with their
+    /// original. Implies `fix`.
+    pub repair: bool,
+}
+
+/// An inconsistency between the WAL, its redirections, the originals store
+/// and the tree.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub enum Problem {
+    /// The checkpoint points past the last action.
+    CheckpointPastEnd,
+    /// Actions were planned but never executed, `replay` runs them.
+    PendingActions(usize),
+    /// A redirection no linking action accounts for.
+    StaleRedirection,
+    /// A linking action without its redirection.
+    MissingRedirection,
+    /// A file of the originals store nothing refers to.
+    OrphanOriginal,
+    /// The tree differs from what the WAL says.
+    Diverged(Divergence),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::CheckpointPastEnd => f.write_str("checkpoint is past the last action"),
+            Problem::PendingActions(n) => write!(f, "{} actions were never executed", n),
+            Problem::StaleRedirection => f.write_str("redirection without an action"),
+            Problem::MissingRedirection => f.write_str("action without a redirection"),
+            Problem::OrphanOriginal => f.write_str("original nothing refers to"),
+            Problem::Diverged(divergence) => divergence.fmt(f),
+        }
+    }
+}
+
+/// A problem found by [`fsck`] and whether it was repaired.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct Finding {
+    /// The path concerned, the store itself for problems of the WAL.
+    pub path: PathBuf,
+    pub problem: Problem,
+    pub fixed: bool,
+}
+
+/// Cross-checks the WAL of the tree at `target_dir` with its redirections,
+/// its originals store and the tree itself. With `fix`, repairs what can be
+/// without risking data: the checkpoint is clamped, redirections are rebuilt
+/// from the actions, unreferenced originals are deleted and missing links are
+/// recreated. Everything else is only reported.
+pub fn fsck<T: AsRef<Path>>(target_dir: T, fix: bool) -> Result<Vec<Finding>, MirageError> {
+    fsck_with_options(
+        target_dir,
+        &FsckOptions {
+            fix,
+            ..Default::default()
+        },
+    )
+}
+
+/// Like [`fsck`], and with [`FsckOptions::repair`] also puts back missing
+/// originals from a hard link or clone of theirs, or from any file linked to
+/// them whose contents still match their recorded digest, then forgets the
+/// paths gone for good: missing, with no original left to link them to.
+pub fn fsck_with_options<T: AsRef<Path>>(
+    target_dir: T,
+    options: &FsckOptions,
+) -> Result<Vec<Finding>, MirageError> {
+    let fix = options.fix || options.repair;
+    let mut state = MirageState::open(&target_dir)?;
+    if fix {
+        state.ensure_unfrozen()?;
+        if state.dry_run {
+            warn!("Store is read-only, not fixing anything");
+        }
+    }
+    let fix = fix && !state.dry_run;
+    let repair = options.repair && fix;
+    if fix {
+        state.lock_run()?;
+    }
+    let mut findings = Vec::new();
+    let mut report = |path: &Path, problem, fixed| {
+        findings.push(Finding {
+            path: path.to_path_buf(),
+            problem,
+            fixed,
+        })
+    };
+
+    if state.wal.checkpoint > state.wal.actions.len() {
+        if fix {
+            state.wal.checkpoint = state.wal.actions.len();
+        }
+        report(&state.source_path, Problem::CheckpointPastEnd, fix);
+    }
+    let pending = state.wal.actions.len().saturating_sub(state.wal.checkpoint);
+    if pending > 0 {
+        report(&state.source_path, Problem::PendingActions(pending), false);
+    }
+
+    let linked = state
+        .wal
+        .actions
+        .iter()
+        .filter(|action| action.action.links())
+        .map(|action| (action.source.clone(), action.target.clone()))
+        .collect::<HashMap<_, _>>();
+    let mut stale = state
+        .wal
+        .redirections
+        .iter()
+        .filter(|(path, original)| linked.get(*path) != Some(original))
+        .map(|(path, _)| path.clone())
+        .collect::<Vec<_>>();
+    stale.sort();
+    for path in stale {
+        report(&path, Problem::StaleRedirection, fix);
+    }
+    let mut missing = linked
+        .iter()
+        .filter(|(path, original)| state.wal.redirections.get(*path) != Some(original))
+        .map(|(path, _)| path.clone())
+        .collect::<Vec<_>>();
+    missing.sort();
+    for path in missing {
+        report(&path, Problem::MissingRedirection, fix);
+    }
+    if fix {
+        state.wal.redirections.replace(linked);
+    }
+
+    let referenced = state
+        .wal
+        .redirections
+        .values()
+        .chain(
+            state
+                .wal
+                .actions
+                .iter()
+                .filter(|action| matches!(action.action, ActionType::Copy))
+                .map(|action| &action.target),
+        )
+        .cloned()
+        .collect::<HashSet<_>>();
+    let mut orphans = Vec::new();
+    if state.originals_path().is_dir() {
+        for entry in walkdir::WalkDir::new(state.originals_path()) {
+            let entry = entry?;
+            if entry.file_type().is_file() && !referenced.contains(entry.path()) {
+                orphans.push(entry.into_path());
+            }
+        }
+    }
+    orphans.sort();
+    for orphan in orphans {
+        if fix {
+            let _lock = state.lock_original(&orphan)?;
+            debug!("Deleting orphan original {:?}", orphan);
+            fs::remove_file(&orphan)?;
+        }
+        report(&orphan, Problem::OrphanOriginal, fix);
+    }
+
+    let diverged = diverging(&state);
+    // originals first, so that the paths linking to them are relinked
+    let mut restored = HashSet::new();
+    if repair {
+        for entry in &diverged {
+            if entry.divergence == Divergence::OriginalMissing
+                && restore_original(&state, &entry.path)?
+            {
+                restored.insert(entry.path.clone());
+            }
+        }
+    }
+    for DiffEntry {
+        path,
+        original,
+        divergence,
+    } in diverged
+    {
+        let fixed = match (&divergence, original) {
+            (Divergence::OriginalMissing, _) => restored.contains(&path),
+            (Divergence::Missing, Some(original)) if fix && original.exists() => {
+                relink(&state, &path, original)?
+            }
+            (Divergence::Missing, Some(_)) if repair => {
+                debug!("Forgetting {:?}, gone along with its original", path);
+                if state.wal.redirections.contains_key(&path) {
+                    state.release(&path)?;
+                } else {
+                    state.forget_actions(|a| a.action.links() && a.source == path);
+                }
+                true
+            }
+            _ => false,
+        };
+        report(&path, Problem::Diverged(divergence), fixed);
+    }
+
+    if fix {
+        state.commit()?;
+    }
+    Ok(findings)
+}
+
+/// Links `path` to `original` again the way the WAL last did.
+fn relink(state: &MirageState, path: &Path, original: PathBuf) -> Result<bool, MirageError> {
+    let applied = &state.wal.actions[..state.wal.checkpoint];
+    let Some((index, action)) = applied
+        .iter()
+        .enumerate()
+        .rev()
+        .find(|(_, action)| action.action.links() && action.source == path)
+    else {
+        return Ok(false);
+    };
+    let _lock = state.lock_original(&original)?;
+    debug!("Relinking {:?} to {:?}", path, original);
+    execute(
+        &Action::new(action.action.clone(), path.to_path_buf(), original),
+        index,
+        &state.source_path,
+    )?;
+    Ok(true)
+}
+
+/// Puts `original` back from one of the paths linked to it: a hard link or
+/// clone of it, whose contents are the original's, or any file matching the
+/// digest recorded when the original was copied. A hard link is linked
+/// back, so that the others stay linked too.
+fn restore_original(state: &MirageState, original: &Path) -> Result<bool, MirageError> {
+    let applied = &state.wal.actions[..state.wal.checkpoint];
+    let Some((index, copy)) =
+        applied.iter().enumerate().rev().find(|(_, action)| {
+            matches!(action.action, ActionType::Copy) && action.target == original
+        })
+    else {
+        return Ok(false);
+    };
+    let mut linked = state
+        .wal
+        .redirections
+        .iter()
+        .filter(|(_, target)| *target == original)
+        .map(|(path, _)| path)
+        .collect::<Vec<_>>();
+    linked.sort();
+    for path in linked {
+        if !fs::symlink_metadata(path).is_ok_and(|meta| meta.is_file()) {
+            continue;
+        }
+        let link = applied
+            .iter()
+            .rev()
+            .find(|action| action.action.links() && action.source == *path)
+            .map(|action| &action.action);
+        let intact = match copy.recorded_digest() {
+            Some((digest, algorithm)) => algorithm.hash_file(path)? == digest,
+            None => matches!(link, Some(ActionType::Hardlink | ActionType::Reflink)),
+        };
+        if !intact {
+            continue;
+        }
+        let _lock = state.lock_original(original)?;
+        debug!("Restoring original {:?} from {:?}", original, path);
+        if let Some(ActionType::Hardlink) = link {
+            if let Some(parent) = original.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::hard_link(path, original)?;
+        } else {
+            execute(
+                &Action::new(ActionType::Copy, path.clone(), original.to_path_buf()),
+                index,
+                &state.source_path,
+            )?;
+        }
+        return Ok(true);
+    }
+    Ok(false)
+}
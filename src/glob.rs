@@ -0,0 +1,135 @@
+//! Shell-style globs selecting files of a tree: `*` matches within a path
+//! component, `**` across components, `?` any one character and `[a-z]` or
+//! `[!a-z]` a character of a set. A glob without a `/` is matched against
+//! the name of a file, one with against its path from the root of the tree.
+
+use std::path::{Component, Path};
+
+/// Whether `text` matches the glob `pattern`, `/` separating components.
+fn matches(pattern: &[char], text: &[char]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        ['*', '*', '/', rest @ ..] => (0..=text.len())
+            // whole directories, or none at all
+            .filter(|&skip| skip == 0 || text[skip - 1] == '/')
+            .any(|skip| matches(rest, &text[skip..])),
+        ['*', '*', rest @ ..] => (0..=text.len()).any(|skip| matches(rest, &text[skip..])),
+        ['*', rest @ ..] => (0..=text.len())
+            .take_while(|&skip| skip == 0 || text[skip - 1] != '/')
+            .any(|skip| matches(rest, &text[skip..])),
+        ['?', rest @ ..] => matches!(text, [c, ..] if *c != '/') && matches(rest, &text[1..]),
+        ['[', rest @ ..] => match class(rest) {
+            Some((set, negated, rest)) => match text {
+                [c, text @ ..] => *c != '/' && in_set(set, *c) != negated && matches(rest, text),
+                [] => false,
+            },
+            // an unclosed `[` is just a character
+            None => literal('[', rest, text),
+        },
+        ['\\', c, rest @ ..] => literal(*c, rest, text),
+        [c, rest @ ..] => literal(*c, rest, text),
+    }
+}
+
+fn literal(c: char, rest: &[char], text: &[char]) -> bool {
+    matches!(text, [t, ..] if *t == c) && matches(rest, &text[1..])
+}
+
+/// The character set opening `pattern` after its `[`, whether it is
+/// negated, and the rest of the pattern. `None` if it is never closed.
+fn class(pattern: &[char]) -> Option<(&[char], bool, &[char])> {
+    let (negated, body) = match pattern {
+        ['!' | '^', body @ ..] => (true, body),
+        body => (false, body),
+    };
+    // a `]` right after the opening is part of the set
+    let end = body.iter().skip(1).position(|&c| c == ']')? + 1;
+    Some((&body[..end], negated, &body[end + 1..]))
+}
+
+/// Whether `c` is in `set`, made of characters and `a-z` ranges.
+fn in_set(set: &[char], c: char) -> bool {
+    match set {
+        [] => false,
+        [from, '-', to, rest @ ..] => (*from <= c && c <= *to) || in_set(rest, c),
+        [first, rest @ ..] => *first == c || in_set(rest, c),
+    }
+}
+
+/// A glob selecting files of a tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Glob {
+    pattern: Vec<char>,
+    /// Matched against the whole path rather than the file name.
+    anchored: bool,
+}
+
+impl Glob {
+    pub(crate) fn new(pattern: &str) -> Glob {
+        // a leading `/` only anchors the glob, as in `/build`
+        Glob {
+            anchored: pattern.contains('/'),
+            pattern: pattern
+                .strip_prefix('/')
+                .unwrap_or(pattern)
+                .chars()
+                .collect(),
+        }
+    }
+
+    /// Whether the file at `relative`, its path from the root of the tree,
+    /// matches.
+    pub(crate) fn matches(&self, relative: &Path) -> bool {
+        let text = if self.anchored {
+            relative
+                .components()
+                .filter_map(|component| match component {
+                    Component::Normal(name) => Some(name.to_string_lossy()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+                .join("/")
+        } else {
+            match relative.file_name() {
+                Some(name) => name.to_string_lossy().into_owned(),
+                None => return false,
+            }
+        };
+        matches(&self.pattern, &text.chars().collect::<Vec<_>>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::Glob;
+
+    #[test]
+    fn matches_names_and_paths() {
+        let matches = |pattern: &str, path: &str| Glob::new(pattern).matches(Path::new(path));
+        assert!(matches("*.iso", "disk.iso"));
+        assert!(matches("*.iso", "images/2024/disk.iso"));
+        assert!(!matches("*.iso", "disk.iso.part"));
+        assert!(matches("disk?.img", "disk1.img"));
+        assert!(!matches("disk?.img", "disk10.img"));
+        assert!(matches("[a-c]*.mkv", "b-roll.mkv"));
+        assert!(!matches("[!a-c]*.mkv", "b-roll.mkv"));
+        assert!(matches("[]x]", "]"));
+        assert!(matches("[unclosed", "[unclosed"));
+        assert!(matches("\\*", "*"));
+        assert!(!matches("\\*", "a"));
+
+        // with a `/` the whole path has to match
+        assert!(matches("images/*.iso", "images/disk.iso"));
+        assert!(!matches("images/*.iso", "images/2024/disk.iso"));
+        assert!(!matches("images/*.iso", "old/images/disk.iso"));
+        assert!(matches("/images/*.iso", "images/disk.iso"));
+        assert!(matches("images/**/*.iso", "images/disk.iso"));
+        assert!(matches("images/**/*.iso", "images/2024/01/disk.iso"));
+        assert!(matches("**/cache/*", "a/b/cache/file"));
+        assert!(matches("**/cache/*", "cache/file"));
+        assert!(!matches("**/cache/*", "a/bcache/file"));
+        assert!(matches("logs/**", "logs/2024/01/app.log"));
+    }
+}
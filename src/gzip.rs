@@ -0,0 +1,531 @@
+//! Just enough gzip to keep the scan journal small: a streaming writer using
+//! fixed Huffman codes, and a reader of whole files in any deflate flavour,
+//! so journals recompressed with gzip itself still read.
+
+use std::io::{self, Write};
+
+const WINDOW: usize = 32 * 1024;
+/// Input collected before it is compressed as one block.
+const CHUNK: usize = 64 * 1024;
+/// Candidates tried per position, trading ratio for speed.
+const MAX_CHAIN: usize = 64;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: u32 = 15;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// Order in which the code lengths of the code length code are stored.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+const HEADER: [u8; 10] = [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255];
+
+fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid gzip: {}", what),
+    )
+}
+
+#[derive(Default)]
+struct BitWriter {
+    out: Vec<u8>,
+    acc: u64,
+    len: u32,
+}
+
+impl BitWriter {
+    fn put(&mut self, value: u32, bits: u32) {
+        self.acc |= u64::from(value) << self.len;
+        self.len += bits;
+        while self.len >= 8 {
+            self.out.push(self.acc as u8);
+            self.acc >>= 8;
+            self.len -= 8;
+        }
+    }
+
+    /// Writes a Huffman code, which goes out most significant bit first.
+    fn code(&mut self, code: u32, bits: u32) {
+        self.put(code.reverse_bits() >> (32 - bits), bits);
+    }
+
+    fn symbol(&mut self, symbol: u32) {
+        match symbol {
+            0..=143 => self.code(0x30 + symbol, 8),
+            144..=255 => self.code(0x190 + symbol - 144, 9),
+            256..=279 => self.code(symbol - 256, 7),
+            _ => self.code(0xc0 + symbol - 280, 8),
+        }
+    }
+
+    fn align(&mut self) {
+        if self.len > 0 {
+            self.put(0, 8 - self.len);
+        }
+    }
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let h = (u32::from(bytes[0]) << 10) ^ (u32::from(bytes[1]) << 5) ^ u32::from(bytes[2]);
+    (h & ((1 << HASH_BITS) - 1)) as usize
+}
+
+/// Chains position `i` of `data` into the candidates for matches.
+fn insert(data: &[u8], head: &mut [usize], prev: &mut [usize], i: usize) {
+    if i + MIN_MATCH <= data.len() {
+        let h = hash(&data[i..]);
+        prev[i] = head[h];
+        head[h] = i;
+    }
+}
+
+/// Compresses `data[start..]` as one fixed Huffman block, matching back into
+/// the history before `start` too.
+fn deflate_block(data: &[u8], start: usize, bits: &mut BitWriter) {
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; data.len()];
+    for i in 0..start {
+        insert(data, &mut head, &mut prev, i);
+    }
+
+    bits.put(0, 1);
+    bits.put(1, 2);
+    let mut i = start;
+    while i < data.len() {
+        let max = (data.len() - i).min(MAX_MATCH);
+        let (mut best, mut distance) = (0, 0);
+        if max >= MIN_MATCH {
+            let mut candidate = head[hash(&data[i..])];
+            let mut chain = 0;
+            while candidate != usize::MAX && chain < MAX_CHAIN && i - candidate <= WINDOW {
+                let len = data[candidate..]
+                    .iter()
+                    .zip(&data[i..i + max])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if len > best {
+                    (best, distance) = (len, i - candidate);
+                    if len == max {
+                        break;
+                    }
+                }
+                candidate = prev[candidate];
+                chain += 1;
+            }
+        }
+        if best >= MIN_MATCH {
+            let code = LENGTH_BASE
+                .iter()
+                .rposition(|&b| usize::from(b) <= best)
+                .unwrap();
+            bits.symbol(257 + code as u32);
+            bits.put(
+                (best - usize::from(LENGTH_BASE[code])) as u32,
+                LENGTH_EXTRA[code].into(),
+            );
+            let code = DIST_BASE
+                .iter()
+                .rposition(|&b| usize::from(b) <= distance)
+                .unwrap();
+            bits.code(code as u32, 5);
+            bits.put(
+                (distance - usize::from(DIST_BASE[code])) as u32,
+                DIST_EXTRA[code].into(),
+            );
+            for j in i..i + best {
+                insert(data, &mut head, &mut prev, j);
+            }
+            i += best;
+        } else {
+            bits.symbol(data[i].into());
+            insert(data, &mut head, &mut prev, i);
+            i += 1;
+        }
+    }
+    bits.symbol(256);
+}
+
+/// Compresses everything written to it into `inner`, which only holds a
+/// complete gzip file once [`GzipWriter::finish`] returns.
+pub(crate) struct GzipWriter<W: Write> {
+    inner: W,
+    bits: BitWriter,
+    /// The window of already compressed input, then the pending input.
+    buf: Vec<u8>,
+    history: usize,
+    crc: u32,
+    size: u32,
+}
+
+impl<W: Write> GzipWriter<W> {
+    pub fn new(mut inner: W) -> io::Result<GzipWriter<W>> {
+        inner.write_all(&HEADER)?;
+        Ok(GzipWriter {
+            inner,
+            bits: BitWriter::default(),
+            buf: Vec::new(),
+            history: 0,
+            crc: 0,
+            size: 0,
+        })
+    }
+
+    fn block(&mut self) -> io::Result<()> {
+        deflate_block(&self.buf, self.history, &mut self.bits);
+        self.inner.write_all(&self.bits.out)?;
+        self.bits.out.clear();
+        self.buf.drain(..self.buf.len().saturating_sub(WINDOW));
+        self.history = self.buf.len();
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.buf.len() > self.history {
+            self.block()?;
+        }
+        // an empty last block
+        self.bits.put(1, 1);
+        self.bits.put(1, 2);
+        self.bits.symbol(256);
+        self.bits.align();
+        self.inner.write_all(&self.bits.out)?;
+        self.inner.write_all(&self.crc.to_le_bytes())?;
+        self.inner.write_all(&self.size.to_le_bytes())?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for GzipWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        self.crc = crc32(self.crc, data);
+        self.size = self.size.wrapping_add(data.len() as u32);
+        if self.buf.len() - self.history >= CHUNK {
+            self.block()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    acc: u32,
+    len: u32,
+}
+
+impl BitReader<'_> {
+    fn bits(&mut self, bits: u32) -> io::Result<u32> {
+        while self.len < bits {
+            let byte = *self
+                .data
+                .get(self.pos)
+                .ok_or_else(|| invalid("truncated"))?;
+            self.pos += 1;
+            self.acc |= u32::from(byte) << self.len;
+            self.len += 8;
+        }
+        let value = self.acc & ((1 << bits) - 1);
+        self.acc >>= bits;
+        self.len -= bits;
+        Ok(value)
+    }
+
+    fn align(&mut self) {
+        self.acc = 0;
+        self.len = 0;
+    }
+
+    fn bytes(&mut self, n: usize) -> io::Result<&[u8]> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + n)
+            .ok_or_else(|| invalid("truncated"))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn decode(&mut self, huffman: &Huffman) -> io::Result<usize> {
+        let (mut code, mut first, mut index) = (0, 0, 0);
+        for len in 1..16 {
+            code |= self.bits(1)? as usize;
+            let count = usize::from(huffman.count[len]);
+            if code < first + count {
+                return Ok(huffman.symbol[index + code - first]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(invalid("bad code"))
+    }
+}
+
+/// A canonical Huffman code, as the number of codes of every length and the
+/// symbols ordered by code.
+struct Huffman {
+    count: [u16; 16],
+    symbol: Vec<usize>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut count = [0u16; 16];
+        for &len in lengths {
+            count[usize::from(len)] += 1;
+        }
+        count[0] = 0;
+        let mut offsets = [0u16; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + count[len];
+        }
+        let mut symbol = vec![0; lengths.len()];
+        for (s, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbol[usize::from(offsets[usize::from(len)])] = s;
+                offsets[usize::from(len)] += 1;
+            }
+        }
+        Huffman { count, symbol }
+    }
+
+    fn fixed() -> (Huffman, Huffman) {
+        let mut lengths = [8u8; 288];
+        lengths[144..256].fill(9);
+        lengths[256..280].fill(7);
+        (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+    }
+}
+
+fn inflate_codes(
+    bits: &mut BitReader,
+    out: &mut Vec<u8>,
+    literals: &Huffman,
+    distances: &Huffman,
+) -> io::Result<()> {
+    loop {
+        let symbol = bits.decode(literals)?;
+        match symbol {
+            0..=255 => out.push(symbol as u8),
+            256 => return Ok(()),
+            _ => {
+                let code = symbol - 257;
+                let base = *LENGTH_BASE.get(code).ok_or_else(|| invalid("bad length"))?;
+                let len = usize::from(base) + bits.bits(LENGTH_EXTRA[code].into())? as usize;
+                let code = bits.decode(distances)?;
+                let base = *DIST_BASE.get(code).ok_or_else(|| invalid("bad distance"))?;
+                let distance = usize::from(base) + bits.bits(DIST_EXTRA[code].into())? as usize;
+                if distance > out.len() {
+                    return Err(invalid("distance too far back"));
+                }
+                for _ in 0..len {
+                    out.push(out[out.len() - distance]);
+                }
+            }
+        }
+    }
+}
+
+fn dynamic(bits: &mut BitReader) -> io::Result<(Huffman, Huffman)> {
+    let literals = bits.bits(5)? as usize + 257;
+    let distances = bits.bits(5)? as usize + 1;
+    let codes = bits.bits(4)? as usize + 4;
+    let mut lengths = [0u8; 19];
+    for &index in &CODE_LENGTH_ORDER[..codes] {
+        lengths[index] = bits.bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&lengths);
+
+    let mut lengths = vec![0u8; literals + distances];
+    let mut i = 0;
+    while i < lengths.len() {
+        let symbol = bits.decode(&code_lengths)?;
+        let (len, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 if i > 0 => (lengths[i - 1], 3 + bits.bits(2)? as usize),
+            17 => (0, 3 + bits.bits(3)? as usize),
+            18 => (0, 11 + bits.bits(7)? as usize),
+            _ => return Err(invalid("bad code lengths")),
+        };
+        let run = lengths
+            .get_mut(i..i + repeat)
+            .ok_or_else(|| invalid("bad code lengths"))?;
+        run.fill(len);
+        i += repeat;
+    }
+    Ok((
+        Huffman::new(&lengths[..literals]),
+        Huffman::new(&lengths[literals..]),
+    ))
+}
+
+/// Decompresses a whole gzip file.
+pub(crate) fn decompress(data: &[u8]) -> io::Result<Vec<u8>> {
+    if data.len() < HEADER.len() || data[..3] != HEADER[..3] {
+        return Err(invalid("not gzip"));
+    }
+    let flags = data[3];
+    let mut bits = BitReader {
+        data,
+        pos: HEADER.len(),
+        acc: 0,
+        len: 0,
+    };
+    if flags & 4 != 0 {
+        let extra = bits.bytes(2)?;
+        let extra = usize::from(u16::from_le_bytes([extra[0], extra[1]]));
+        bits.bytes(extra)?;
+    }
+    // the file name and the comment
+    for flag in [8, 16] {
+        if flags & flag != 0 {
+            while bits.bytes(1)?[0] != 0 {}
+        }
+    }
+    if flags & 2 != 0 {
+        bits.bytes(2)?;
+    }
+
+    let mut out = Vec::new();
+    loop {
+        let last = bits.bits(1)? == 1;
+        match bits.bits(2)? {
+            0 => {
+                bits.align();
+                let header = bits.bytes(4)?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                if len != !u16::from_le_bytes([header[2], header[3]]) {
+                    return Err(invalid("bad stored block"));
+                }
+                out.extend_from_slice(bits.bytes(len.into())?);
+            }
+            1 => {
+                let (literals, distances) = Huffman::fixed();
+                inflate_codes(&mut bits, &mut out, &literals, &distances)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic(&mut bits)?;
+                inflate_codes(&mut bits, &mut out, &literals, &distances)?;
+            }
+            _ => return Err(invalid("bad block type")),
+        }
+        if last {
+            break;
+        }
+    }
+    bits.align();
+    let trailer = bits.bytes(8)?;
+    let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    let size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+    if crc != crc32(0, &out) || size != out.len() as u32 {
+        return Err(invalid("checksum mismatch"));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::{decompress, GzipWriter, CHUNK};
+
+    fn compress(data: &[u8]) -> Vec<u8> {
+        let mut writer = GzipWriter::new(Vec::new()).unwrap();
+        // in pieces, so blocks match back into earlier ones
+        for piece in data.chunks(1000) {
+            writer.write_all(piece).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn round_trips() {
+        assert_eq!(decompress(&compress(b"")).unwrap(), b"");
+        assert_eq!(decompress(&compress(b"a")).unwrap(), b"a");
+
+        let lines = (0..10000)
+            .flat_map(|i| format!("/data/photos/{}.jpg same\n", i % 700).into_bytes())
+            .collect::<Vec<_>>();
+        assert!(lines.len() > 2 * CHUNK);
+        let compressed = compress(&lines);
+        assert!(compressed.len() < lines.len() / 4);
+        assert_eq!(decompress(&compressed).unwrap(), lines);
+
+        // hardly compressible
+        let mut state = 1u32;
+        let noise = (0..100_000)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(decompress(&compress(&noise)).unwrap(), noise);
+    }
+
+    #[test]
+    fn reads_gzip_output() {
+        // what gzip -9 makes of the lines below, in a dynamic Huffman block
+        let compressed = [
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x95, 0x94, 0x4b, 0x0a,
+            0xc2, 0x40, 0x10, 0x44, 0xf7, 0x1e, 0xa3, 0xd7, 0xe2, 0x64, 0xba, 0xf3, 0xbf, 0xcd,
+            0x80, 0xd1, 0x20, 0x4a, 0x06, 0x33, 0xae, 0x42, 0xee, 0xee, 0x5a, 0x88, 0xf0, 0x7a,
+            0x5f, 0x54, 0x17, 0xfd, 0xa8, 0xda, 0x24, 0xa7, 0x32, 0xcb, 0x28, 0xe1, 0x9a, 0x4a,
+            0x0a, 0x79, 0x5e, 0xca, 0xb2, 0x86, 0xea, 0xf2, 0xc8, 0x77, 0x39, 0xcb, 0x9a, 0x5e,
+            0x93, 0x8c, 0xb7, 0xf4, 0x5c, 0xa7, 0xfd, 0xb4, 0x1d, 0x4a, 0xe3, 0x8f, 0xb4, 0xbc,
+            0x3f, 0x7f, 0x95, 0x8a, 0x95, 0xc6, 0xcf, 0xd7, 0xd8, 0xb4, 0xc1, 0xca, 0x96, 0x9f,
+            0xef, 0xb0, 0x69, 0x8f, 0x95, 0x83, 0xe3, 0xf9, 0x15, 0x76, 0x8d, 0x1c, 0x54, 0x54,
+            0x47, 0x02, 0xe3, 0xb6, 0x9c, 0x55, 0x6c, 0x1c, 0x09, 0x5a, 0x6e, 0xcb, 0x71, 0xc5,
+            0xde, 0x91, 0x60, 0xe0, 0x1d, 0xe0, 0xc0, 0x34, 0xf2, 0x04, 0xca, 0xbb, 0xa5, 0x1c,
+            0x98, 0xd6, 0x8e, 0x04, 0xbc, 0x5e, 0xca, 0x81, 0x69, 0xe7, 0x48, 0xc0, 0x1b, 0xa6,
+            0x1c, 0x98, 0x39, 0xa6, 0xd0, 0x78, 0xc5, 0xcc, 0x31, 0x86, 0x8e, 0x35, 0x34, 0x5e,
+            0x31, 0xe3, 0xc0, 0xcc, 0x31, 0x88, 0xc6, 0x2b, 0x66, 0x1c, 0x98, 0x1d, 0x6e, 0xe2,
+            0x17, 0xb1, 0x0b, 0xfe, 0x3a, 0xbc, 0x06, 0x00, 0x00,
+        ];
+        let lines = (0..40)
+            .map(|i| {
+                format!(
+                    "{{\"path\":\"/data/photos/{}.jpg\",\"same\":{}}}\n",
+                    i,
+                    i % 3 != 0
+                )
+            })
+            .collect::<String>();
+        assert_eq!(decompress(&compressed).unwrap(), lines.as_bytes());
+    }
+}
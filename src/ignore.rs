@@ -0,0 +1,213 @@
+//! `.mirageignore` files, keeping files of a tree from being deduplicated
+//! run after run without repeating the same exclusions every time. They go
+//! in the root of the tree or any directory below and follow the syntax of
+//! `.gitignore`.
+//!
+//! Every line is a glob, see [`Glob`], blank lines and lines starting with
+//! `#` aside. A glob ending with `/` only matches directories, one starting
+//! with `!` includes again what an earlier line ignored. Globs with a `/`
+//! are matched against paths from the directory of their file, others
+//! against names anywhere below it. Later lines win over earlier ones and
+//! files deeper in the tree over those above, and nothing is walked inside
+//! an ignored directory, whatever the files below it say.
+//!
+//! `.gitignore` files can be read the same way, their rules coming before
+//! those of the `.mirageignore` of the same directory.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use log::warn;
+
+use crate::glob::Glob;
+
+/// Name of the files holding the rules.
+pub const IGNORE_FILE: &str = ".mirageignore";
+
+/// Name of the ignore files of git.
+pub(crate) const GITIGNORE_FILE: &str = ".gitignore";
+
+/// Names of the ignore files to read, `.gitignore` files if `git` and
+/// `.mirageignore` files if `mirage`.
+pub(crate) fn ignore_files(git: bool, mirage: bool) -> Vec<&'static str> {
+    let mut files = Vec::new();
+    if git {
+        files.push(GITIGNORE_FILE);
+    }
+    if mirage {
+        files.push(IGNORE_FILE);
+    }
+    files
+}
+
+/// A line of an ignore file.
+struct Rule {
+    /// Name of the file holding it.
+    file: &'static str,
+    glob: Glob,
+    /// Includes again what earlier rules ignored.
+    negated: bool,
+    /// Only matches directories.
+    dir_only: bool,
+}
+
+fn parse(file: &'static str, text: &str) -> Vec<Rule> {
+    text.lines()
+        .filter_map(|line| {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                return None;
+            }
+            // `\#` and `\!` are escapes the glob understands itself
+            let (negated, line) = match line.strip_prefix('!') {
+                Some(line) => (true, line),
+                None => (false, line),
+            };
+            let (dir_only, line) = match line.strip_suffix('/') {
+                Some(line) => (true, line),
+                None => (false, line),
+            };
+            (!line.is_empty()).then(|| Rule {
+                file,
+                glob: Glob::new(line),
+                negated,
+                dir_only,
+            })
+        })
+        .collect()
+}
+
+/// The rules of the ignore files of a tree, read as its directories are
+/// walked.
+pub(crate) struct Ignores {
+    root: PathBuf,
+    /// Names of the ignore files read, in the order their rules apply.
+    files: Vec<&'static str>,
+    /// Rules of every directory looked at so far, none for those without an
+    /// ignore file.
+    rules: HashMap<PathBuf, Vec<Rule>>,
+}
+
+impl Ignores {
+    /// The ignore files of the tree at `root` named `files`, `None` if no
+    /// files are to be read.
+    pub(crate) fn new(root: &Path, files: Vec<&'static str>) -> Option<Ignores> {
+        (!files.is_empty()).then(|| Ignores {
+            root: root.to_path_buf(),
+            files,
+            rules: HashMap::new(),
+        })
+    }
+
+    fn rules_of(&mut self, dir: &Path) -> &[Rule] {
+        let files = &self.files;
+        self.rules.entry(dir.to_path_buf()).or_insert_with(|| {
+            let mut rules = Vec::new();
+            for &name in files {
+                let file = dir.join(name);
+                match fs::read_to_string(&file) {
+                    Ok(text) => rules.extend(parse(name, &text)),
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                    Err(err) => {
+                        warn!("Can't read {:?}, ignoring nothing by it: {}", file, err)
+                    }
+                }
+            }
+            rules
+        })
+    }
+
+    /// The ignore file keeping `path` of the tree out, a directory if
+    /// `is_dir`. Whether one of the directories above it is ignored isn't
+    /// looked at. Ignore files keep themselves out, so that editing one
+    /// never edits another through a link.
+    pub(crate) fn ignoring(&mut self, path: &Path, is_dir: bool) -> Option<PathBuf> {
+        let relative = path.strip_prefix(&self.root).ok()?;
+        let name = relative.file_name()?;
+        if !is_dir && self.files.iter().any(|file| name == *file) {
+            return Some(path.to_path_buf());
+        }
+        // the directories from the root down to the one holding `path`
+        let mut dirs = vec![self.root.clone()];
+        for name in relative.parent()? {
+            let below = dirs[dirs.len() - 1].join(name);
+            dirs.push(below);
+        }
+        let mut ignoring = None;
+        for dir in dirs {
+            let Ok(below) = path.strip_prefix(&dir) else {
+                continue;
+            };
+            let below = below.to_path_buf();
+            for rule in self.rules_of(&dir) {
+                if (is_dir || !rule.dir_only) && rule.glob.matches(&below) {
+                    ignoring = (!rule.negated).then(|| dir.join(rule.file));
+                }
+            }
+        }
+        ignoring
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::tempdir;
+
+    use super::{Ignores, GITIGNORE_FILE, IGNORE_FILE};
+
+    #[test]
+    fn follows_gitignore_rules() {
+        let dir = tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("media/raw")).unwrap();
+        fs::write(
+            root.join(IGNORE_FILE),
+            "# scratch files\n*.tmp\n!keep.tmp\n/build/\ncache/\nmedia/*.part\n",
+        )
+        .unwrap();
+        fs::write(root.join("media").join(IGNORE_FILE), "raw/\n!*.tmp\n").unwrap();
+
+        let mut ignores = Ignores::new(root, vec![IGNORE_FILE]).unwrap();
+        let mut ignored = |path: &str, is_dir| ignores.ignoring(&root.join(path), is_dir);
+        let top = Some(root.join(IGNORE_FILE));
+        let media = Some(root.join("media").join(IGNORE_FILE));
+        assert_eq!(ignored("a.tmp", false), top);
+        assert_eq!(ignored("docs/b.tmp", false), top);
+        assert_eq!(ignored("keep.tmp", false), None);
+        assert_eq!(ignored("build", true), top);
+        // anchored to the root, and only directories
+        assert_eq!(ignored("src/build", true), None);
+        assert_eq!(ignored("build", false), None);
+        assert_eq!(ignored("src/cache", true), top);
+        assert_eq!(ignored("media/film.part", false), top);
+        assert_eq!(ignored("media/old/film.part", false), None);
+        // deeper files win
+        assert_eq!(ignored("media/raw", true), media);
+        assert_eq!(ignored("media/a.tmp", false), None);
+        assert_eq!(ignored("notes.txt", false), None);
+        assert_eq!(ignored("media/.mirageignore", false), media);
+        assert_eq!(ignored("", true), None);
+    }
+
+    #[test]
+    fn reads_gitignore_first() {
+        let dir = tempdir().unwrap();
+        let root = dir.path();
+        fs::write(root.join(GITIGNORE_FILE), "target/\n*.log\n").unwrap();
+        fs::write(root.join(IGNORE_FILE), "!keep.log\n").unwrap();
+
+        let mut ignores = Ignores::new(root, vec![GITIGNORE_FILE, IGNORE_FILE]).unwrap();
+        let mut ignored = |path: &str, is_dir| ignores.ignoring(&root.join(path), is_dir);
+        let git = Some(root.join(GITIGNORE_FILE));
+        assert_eq!(ignored("crate/target", true), git);
+        assert_eq!(ignored("build.log", false), git);
+        assert_eq!(ignored("keep.log", false), None);
+        assert_eq!(ignored(".gitignore", false), git);
+        assert!(Ignores::new(root, Vec::new()).is_none());
+    }
+}
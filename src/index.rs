@@ -0,0 +1,100 @@
+//! Sorting the files of a size group into classes of identical contents.
+//!
+//! Every file is hashed once, the digests landing in the cache, and only
+//! the files whose digests collide are compared byte for byte, each with the
+//! first file of its class rather than with every other file of the group.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+};
+
+use log::trace;
+
+use crate::{cache::Cache, files_match, metadata::payload_hash, ApplyOptions, MirageError};
+
+/// The classes of identical files of one size group.
+#[derive(Debug, Default)]
+pub(crate) struct DuplicateIndex {
+    /// Classes of two files or more, each in the order of the group.
+    classes: Vec<Vec<PathBuf>>,
+}
+
+impl DuplicateIndex {
+    /// Sorts the files of `group` into classes. With
+    /// [`ApplyOptions::ignore_metadata`] files are hashed without their
+    /// metadata, as files that only match with it ignored differ in digest.
+    /// The files are hashed on up to `jobs` workers.
+    pub(crate) fn build(
+        group: &[PathBuf],
+        options: &ApplyOptions,
+        jobs: usize,
+        cache: &mut Cache,
+    ) -> Result<Self, MirageError> {
+        let mut index = DuplicateIndex::default();
+        if group.len() < 2 {
+            return Ok(index);
+        }
+        let digests = if options.ignore_metadata {
+            group
+                .iter()
+                .map(|path| payload_hash(path))
+                .collect::<Result<Vec<_>, _>>()?
+        } else {
+            let paths = group.iter().map(PathBuf::as_path).collect::<Vec<_>>();
+            cache.hash_all(&paths, jobs)?
+        };
+        let mut buckets: BTreeMap<String, Vec<&PathBuf>> = BTreeMap::new();
+        for (path, digest) in group.iter().zip(digests) {
+            buckets.entry(digest).or_default().push(path);
+        }
+
+        let mut ids: HashMap<&PathBuf, usize> = HashMap::new();
+        let mut next = 0;
+        for bucket in buckets.into_values() {
+            if bucket.len() < 2 {
+                continue;
+            }
+            // the first file of each class in the bucket
+            let mut firsts: Vec<(&PathBuf, usize)> = Vec::new();
+            for path in bucket {
+                let mut class = None;
+                for &(first, id) in &firsts {
+                    if files_match(path, first, options, cache)? {
+                        class = Some(id);
+                        break;
+                    }
+                }
+                let class = class.unwrap_or_else(|| {
+                    trace!("{:?} starts a class of its own", path);
+                    firsts.push((path, next));
+                    next += 1;
+                    next - 1
+                });
+                ids.insert(path, class);
+            }
+        }
+
+        let mut slots = HashMap::new();
+        for path in group {
+            if let Some(id) = ids.get(path) {
+                let slot = *slots.entry(id).or_insert_with(|| {
+                    index.classes.push(Vec::new());
+                    index.classes.len() - 1
+                });
+                index.classes[slot].push(path.clone());
+            }
+        }
+        index.classes.retain(|class| class.len() > 1);
+        Ok(index)
+    }
+
+    /// The classes of two files or more, each in the order of the group,
+    /// in the order of their first files.
+    pub(crate) fn classes(&self) -> Vec<Vec<&PathBuf>> {
+        self.classes
+            .iter()
+            .map(|class| class.iter().collect())
+            .collect()
+    }
+}
@@ -0,0 +1,120 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+
+use crate::{walstream::WalStream, MirageError, PlannedAction};
+
+/// One action of the WAL and whether it has been executed.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct WalEntry {
+    pub index: usize,
+    pub action: PlannedAction,
+    /// Before the checkpoint, as opposed to pending.
+    pub applied: bool,
+}
+
+/// Which WAL entries [`inspect_wal`] returns. Unset fields match everything.
+#[derive(Debug, Clone, Default)]
+pub struct WalFilter {
+    /// Keep actions whose source or target contains this.
+    pub path: Option<String>,
+    /// Keep actions of this type, case insensitive.
+    pub action: Option<String>,
+}
+
+impl WalFilter {
+    fn matches(&self, action: &PlannedAction) -> bool {
+        let path_matches = self.path.as_ref().is_none_or(|needle| {
+            [&action.source, &action.target]
+                .iter()
+                .any(|path| path.to_string_lossy().contains(needle.as_str()))
+        });
+        let action_matches = self
+            .action
+            .as_ref()
+            .is_none_or(|kind| kind.eq_ignore_ascii_case(&action.action));
+        path_matches && action_matches
+    }
+}
+
+/// Entries of the WAL of the tree at `target_dir` selected by `filter`, in
+/// WAL order.
+pub fn inspect_wal<T: AsRef<Path>>(
+    target_dir: T,
+    filter: &WalFilter,
+) -> Result<Vec<WalEntry>, MirageError> {
+    let wal = WalStream::open(&target_dir)?;
+    let mut entries = Vec::new();
+    wal.for_each_action(|index, action| {
+        let entry = WalEntry {
+            index,
+            action: PlannedAction::from(&action),
+            applied: index < wal.checkpoint(),
+        };
+        if filter.matches(&entry.action) {
+            entries.push(entry);
+        }
+        Ok(())
+    })?;
+    Ok(entries)
+}
+
+/// How far the actions of a duplicate group got.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+pub enum GroupProgress {
+    NotStarted,
+    /// Interrupted or failed midway, some of the group's paths are linked
+    /// and some aren't.
+    Partial,
+    Applied,
+}
+
+/// The actions the WAL holds for one duplicate group.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct GroupStatus {
+    pub group: u64,
+    /// The original the group's actions share.
+    pub original: PathBuf,
+    pub applied: usize,
+    pub actions: usize,
+    pub progress: GroupProgress,
+}
+
+/// Every duplicate group of the WAL of the tree at `target_dir` and how far
+/// it got, in the order the groups were planned. Actions planned before
+/// groups were recorded aren't part of any.
+pub fn inspect_groups<T: AsRef<Path>>(target_dir: T) -> Result<Vec<GroupStatus>, MirageError> {
+    let wal = WalStream::open(&target_dir)?;
+    let mut groups: BTreeMap<u64, GroupStatus> = BTreeMap::new();
+    wal.for_each_action(|index, action| {
+        let Some(group) = action.group else {
+            return Ok(());
+        };
+        let status = groups.entry(group).or_insert_with(|| GroupStatus {
+            group,
+            original: action.target.clone(),
+            applied: 0,
+            actions: 0,
+            progress: GroupProgress::NotStarted,
+        });
+        status.actions += 1;
+        if index < wal.checkpoint() {
+            status.applied += 1;
+        }
+        Ok(())
+    })?;
+    Ok(groups
+        .into_values()
+        .map(|mut status| {
+            status.progress = match status.applied {
+                0 => GroupProgress::NotStarted,
+                n if n == status.actions => GroupProgress::Applied,
+                _ => GroupProgress::Partial,
+            };
+            status
+        })
+        .collect())
+}
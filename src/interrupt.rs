@@ -0,0 +1,77 @@
+//! Stopping cleanly on SIGINT and SIGTERM. Once asked to, the commands that
+//! modify a tree stop between two actions, or between two duplicate groups
+//! when applying, with the WAL committed up to the last executed action,
+//! instead of being killed in the middle of a copy.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::warn;
+
+use crate::MirageError;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn on_signal(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Makes SIGINT and SIGTERM stop the running command at the next safe point
+/// rather than kill it. The handler only fires once, a second signal kills
+/// the process as usual.
+#[cfg(unix)]
+pub fn handle_interrupts() {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+        let ret = unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART | libc::SA_RESETHAND;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signal, &action, std::ptr::null_mut())
+        };
+        if ret != 0 {
+            warn!(
+                "Couldn't handle signal {}: {}",
+                signal,
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn handle_interrupts() {}
+
+#[cfg(test)]
+thread_local! {
+    /// Stands in for a signal in tests, which share the process: the number
+    /// of checks to pass before seeing it.
+    static RAISED: std::cell::Cell<Option<usize>> = const { std::cell::Cell::new(None) };
+}
+
+/// Makes the calling thread see an interrupt, or no longer see it.
+#[cfg(test)]
+pub(crate) fn raise(raised: bool) {
+    RAISED.set(raised.then_some(0));
+}
+
+/// Makes the calling thread see an interrupt once it checked `checks` times.
+#[cfg(test)]
+pub(crate) fn raise_after(checks: usize) {
+    RAISED.set(Some(checks));
+}
+
+/// Fails with [`MirageError::Interrupted`] once a handled signal came in.
+pub(crate) fn check_interrupted() -> Result<(), MirageError> {
+    #[cfg(test)]
+    match RAISED.get() {
+        Some(0) => return Err(MirageError::Interrupted),
+        Some(checks) => RAISED.set(Some(checks - 1)),
+        None => {}
+    }
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        return Err(MirageError::Interrupted);
+    }
+    Ok(())
+}
@@ -0,0 +1,129 @@
+//! The optional journal of every comparison and decision of an apply run,
+//! gzipped JSON lines in the store. The journal of the run before is kept
+//! next to it, so two runs can be told apart with `zdiff`.
+
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    canonicalize_link, find_store_root,
+    gzip::{decompress, GzipWriter},
+    Decision, MirageError, MirageState,
+};
+
+/// File in the store holding the journal of the last run.
+const JOURNAL_FILE: &str = "journal.jsonl.gz";
+
+/// File in the store holding the journal of the run before the last.
+const PREVIOUS_JOURNAL_FILE: &str = "journal.prev.jsonl.gz";
+
+/// One line of the journal.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "entry", rename_all = "snake_case")]
+pub enum JournalEntry {
+    /// Two candidates of the same size were compared.
+    Compared {
+        candidate: PathBuf,
+        with: PathBuf,
+        same: bool,
+    },
+    /// What the run made of a file in the end.
+    Decided { path: PathBuf, decision: Decision },
+}
+
+/// The journal being written by the running apply.
+pub(crate) struct Journal {
+    writer: GzipWriter<BufWriter<File>>,
+}
+
+impl Journal {
+    /// Starts the journal of a run, keeping the one of the run before.
+    pub fn create(state: &MirageState) -> Result<Journal, MirageError> {
+        let path = state.source_path.join(JOURNAL_FILE);
+        if path.exists() {
+            fs::rename(&path, state.source_path.join(PREVIOUS_JOURNAL_FILE))?;
+        }
+        debug!("Journaling the run to {:?}", path);
+        Ok(Journal {
+            writer: GzipWriter::new(BufWriter::new(File::create(path)?))?,
+        })
+    }
+
+    pub fn write(&mut self, entry: &JournalEntry) -> Result<(), MirageError> {
+        serde_json::to_writer(&mut self.writer, entry)?;
+        self.writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(), MirageError> {
+        self.writer.finish()?;
+        Ok(())
+    }
+}
+
+fn read(path: &Path) -> Result<Vec<JournalEntry>, MirageError> {
+    let data = decompress(&fs::read(path)?)?;
+    let mut entries = Vec::new();
+    for line in data.split(|&byte| byte == b'\n') {
+        if !line.is_empty() {
+            entries.push(serde_json::from_slice(line)?);
+        }
+    }
+    Ok(entries)
+}
+
+/// The journal of the last apply run of the tree at `target_dir`, or of the
+/// run before it with `previous`. `None` if that run kept no journal.
+pub fn journal<T: AsRef<Path>>(
+    target_dir: T,
+    previous: bool,
+) -> Result<Option<Vec<JournalEntry>>, MirageError> {
+    let state = MirageState::open(&target_dir)?;
+    let name = if previous {
+        PREVIOUS_JOURNAL_FILE
+    } else {
+        JOURNAL_FILE
+    };
+    let path = state.source_path.join(name);
+    if !path.is_file() {
+        return Ok(None);
+    }
+    Ok(Some(read(&path)?))
+}
+
+/// The comparisons the last apply run journaled for `path`, as the other
+/// file and whether they matched, in path order. `None` if the run kept no
+/// journal.
+pub fn comparisons<T: AsRef<Path>>(path: T) -> Result<Option<Vec<(PathBuf, bool)>>, MirageError> {
+    let root = find_store_root(&path)?;
+    let path = canonicalize_link(path.as_ref())?;
+    let Some(entries) = journal(root, false)? else {
+        return Ok(None);
+    };
+    // pairs are compared both ways round
+    let mut compared: Vec<_> = entries
+        .into_iter()
+        .filter_map(|entry| match entry {
+            JournalEntry::Compared {
+                candidate,
+                with,
+                same,
+            } if candidate == path => Some((with, same)),
+            JournalEntry::Compared {
+                candidate,
+                with,
+                same,
+            } if with == path => Some((candidate, same)),
+            _ => None,
+        })
+        .collect();
+    compared.sort();
+    compared.dedup();
+    Ok(Some(compared))
+}
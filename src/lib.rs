@@ -1,8 +1,10 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::{OsStr, OsString},
     fs::{self, create_dir, File, OpenOptions},
-    io::{self, BufReader, BufWriter, Read},
+    io::{self, BufReader, Read, Write},
     path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
 
 use log::{debug, trace, warn};
@@ -11,11 +13,193 @@ use symlink::symlink_file;
 use thiserror::Error;
 use walkdir::DirEntry;
 
-#[derive(Debug, Serialize, Deserialize)]
+mod adopt;
+mod archive;
+mod backup;
+mod bench;
+mod bloom;
+mod budget;
+mod cache;
+mod checkpoint;
+mod config;
+mod diff;
+mod digest;
+mod du;
+mod extents;
+mod filelist;
+mod freeze;
+mod fsck;
+mod glob;
+mod gzip;
+mod ignore;
+mod index;
+mod inspect;
+mod interrupt;
+mod journal;
+mod list;
+mod locks;
+mod manifest;
+mod merge;
+mod merkle;
+mod metadata;
+mod metrics;
+mod originals;
+mod ownership;
+mod parallel;
+mod plan;
+mod policy;
+mod probe;
+mod profile;
+mod progress;
+mod reapply;
+mod redact;
+mod redirections;
+mod reflink;
+mod rehash;
+mod replay;
+mod report;
+mod runlock;
+mod sandbox;
+mod schema;
+mod script;
+mod segment;
+mod shard;
+mod space;
+mod spill;
+mod state_archive;
+mod stats;
+mod transaction;
+mod unshare;
+mod upgrade;
+mod verify;
+mod walstream;
+mod watch;
+mod why;
+
+pub use adopt::{adopt_hardlinks, AdoptReport};
+pub use archive::{archive_report, ArchiveReport, Location, MemberDuplicate};
+use backup::back_up;
+pub use backup::{restore_state, state_backups, StateBackup, DEFAULT_BACKUPS};
+pub use bench::{bench, BenchOptions, BenchReport, Strategy, StrategyTiming};
+use bloom::{known_contents, record_originals, Bloom};
+use budget::{resume_point, save_resume_point, Budget};
+use cache::Cache;
+pub use checkpoint::{checkpoints, create_checkpoint, delete_checkpoint, Checkpoint};
+use checkpoint::{revert_to, shift_checkpoints};
+pub use config::{parse_size, Config, CONFIG_FILE};
+pub use diff::{diff, DiffEntry, Divergence};
+use digest::{constant_time_eq, hmac_sha256, to_hex, HmacSha256};
+pub use digest::{HashAlgorithm, DEFAULT_HASH_ALGORITHM};
+pub use du::{disk_usage, DiskUsage};
+use extents::{plan_sharing, share_extents};
+pub use filelist::{read_file_list, read_file_list_at};
+pub use freeze::{is_read_only, lock, set_read_only, unlock};
+pub use fsck::{fsck, fsck_with_options, Finding, FsckOptions, Problem};
+use glob::Glob;
+pub use ignore::IGNORE_FILE;
+use ignore::{ignore_files, Ignores};
+use index::DuplicateIndex;
+pub use inspect::{inspect_groups, inspect_wal, GroupProgress, GroupStatus, WalEntry, WalFilter};
+use interrupt::check_interrupted;
+pub use interrupt::handle_interrupts;
+pub use journal::{comparisons, journal, JournalEntry};
+pub use list::{list_groups, GroupOrder, ListedGroup};
+pub use manifest::{manifest, write_manifest_csv, ManifestEntry};
+pub use merge::merge;
+use merge::merge_into;
+pub use merkle::{identical_subtrees, subtree_hashes, SubtreeHash};
+pub use metadata::same_ignoring_metadata;
+use metrics::RunMetrics;
+use originals::{content_digest, content_len, OriginalIndex};
+use ownership::{owner_of, restore_owner};
+use parallel::{default_jobs, stages, undo_stages};
+pub use plan::{apply_plan, Plan, PlanGroup};
+pub use policy::LinkPolicy;
+use policy::{same_device, Linker};
+use profile::date_score;
+pub use profile::Profile;
+use progress::ProgressWriter;
+pub use progress::{status, Phase, PhaseTimes, Progress};
+pub use reapply::{reapply, ReapplyReport};
+pub use redact::Redaction;
+use redirections::{RedirectionFile, Redirections};
+use reflink::reflink;
+pub use rehash::{rehash, RehashReport};
+pub use replay::{replay, replay_plan, Hazard, ReplayStep};
+pub use report::{diff_reports, DuplicateGroup, ReportDiff, RunReport};
+use report::{drop_rolled_back, duplicate_groups};
+use runlock::RunLock;
+pub use runlock::{break_stale_lock, BrokenLock, LockOwner};
+pub use sandbox::sandbox;
+use schema::read_wal;
+pub use schema::WAL_VERSION;
+pub use script::{export_script, Shell};
+use segment::Segment;
+pub use shard::{shard, ShardReport, StoreLayout};
+use space::space_needed;
+pub use space::SpaceNeeded;
+use spill::Grouper;
+pub use spill::DEFAULT_MEMORY_BUDGET;
+use state_archive::{archive_path_for, archive_state};
+use stats::record_session;
+pub use stats::{
+    stats, stats_history, store_status, HistoryPoint, Session, SpaceStats, Stats, StoreStatus,
+};
+use transaction::execute_transactions;
+pub use transaction::Rollback;
+pub use unshare::{find_store_root, remove, unshare};
+use unshare::{find_store_root_at, revert_only};
+pub use upgrade::{migrate, upgrade, LinkMode, MigrateReport, Unmigrated, UpgradeReport};
+pub use verify::{verify, Discrepancy, Mismatch, VerifyReport};
+pub use watch::watch;
+use why::Decisions;
+pub use why::{why, Decision, Exclusion, Why};
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 enum ActionType {
+    /// Copies `source` to `target`.
     Copy,
+    /// Replaces `source` with a symlink to the original at `target`.
     Symlink,
     NOP,
+    /// Replaces `source` with a hard link to the original at `target`.
+    Hardlink,
+    /// Replaces `source` with a copy-on-write clone of the original at
+    /// `target`.
+    Reflink,
+    /// Deletes `source`, whose contents are kept by the original at `target`.
+    Delete,
+    /// Renames `source` to `target`.
+    Move,
+    /// Shares the extents of `target` with `source`, which holds the same
+    /// contents, leaving both in place as independent files.
+    Dedupe,
+    /// An action type written by a newer mirage, kept verbatim so it
+    /// survives rewriting the WAL. It is never executed and skipped on revert.
+    #[serde(untagged)]
+    Unknown(serde_json::Value),
+}
+
+impl ActionType {
+    /// Whether the action makes `source` refer to the original at `target`.
+    fn links(&self) -> bool {
+        matches!(
+            self,
+            ActionType::Symlink | ActionType::Hardlink | ActionType::Reflink
+        )
+    }
+
+    fn name(&self) -> String {
+        match self {
+            ActionType::Unknown(serde_json::Value::String(name)) => name.clone(),
+            ActionType::Unknown(serde_json::Value::Object(map)) if map.len() == 1 => {
+                map.keys().next().cloned().unwrap_or_default()
+            }
+            ActionType::Unknown(_) => "Unknown".to_string(),
+            known => format!("{:?}", known),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -23,6 +207,19 @@ struct Action {
     action: ActionType,
     source: PathBuf,
     target: PathBuf,
+    /// The duplicate group the action was planned in, see [`WAL::push`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    group: Option<u64>,
+    /// Content id of the file a copy into the store copied, as planned, so
+    /// that a revert can tell the files it restores came back unchanged.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    digest: Option<String>,
+    /// Algorithm `digest` was taken with, MD5 when not recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    algorithm: Option<HashAlgorithm>,
+    /// Fields written by a newer mirage, kept verbatim.
+    #[serde(flatten)]
+    extra: serde_json::Map<String, serde_json::Value>,
 }
 
 impl Action {
@@ -31,56 +228,457 @@ impl Action {
             action,
             source,
             target,
+            group: None,
+            digest: None,
+            algorithm: None,
+            extra: serde_json::Map::new(),
         }
     }
 
+    /// The digest recorded for the file a copy copied, with its algorithm.
+    fn recorded_digest(&self) -> Option<(&str, HashAlgorithm)> {
+        let digest = self.digest.as_deref()?;
+        Some((digest, self.algorithm.unwrap_or_default()))
+    }
+
     pub fn invert(&self) -> Self {
-        return match self.action {
-            ActionType::Copy => Action {
-                action: ActionType::NOP,
-                source: self.target.clone(),
-                target: self.source.clone(),
-            },
-            ActionType::Symlink => Action {
-                action: ActionType::Copy,
-                source: self.target.clone(),
-                target: self.source.clone(),
-            },
-            ActionType::NOP => Action {
-                action: ActionType::NOP,
+        match self.action {
+            ActionType::Copy => {
+                Action::new(ActionType::NOP, self.target.clone(), self.source.clone())
+            }
+            ActionType::Symlink => {
+                Action::new(ActionType::Copy, self.target.clone(), self.source.clone())
+            }
+            ActionType::NOP => {
+                Action::new(ActionType::NOP, self.source.clone(), self.target.clone())
+            }
+            ActionType::Hardlink | ActionType::Delete => {
+                Action::new(ActionType::Copy, self.target.clone(), self.source.clone())
+            }
+            // the clone already is an independent file, as are files
+            // sharing extents
+            ActionType::Reflink | ActionType::Dedupe => {
+                Action::new(ActionType::NOP, self.source.clone(), self.target.clone())
+            }
+            ActionType::Move => {
+                Action::new(ActionType::Move, self.target.clone(), self.source.clone())
+            }
+            // nothing is known about how to undo it, leave it to the reader
+            ActionType::Unknown(_) => Action {
+                action: self.action.clone(),
                 source: self.source.clone(),
                 target: self.target.clone(),
+                group: self.group,
+                digest: self.digest.clone(),
+                algorithm: self.algorithm,
+                extra: self.extra.clone(),
             },
-        };
+        }
     }
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Serialize, Deserialize, Default)]
 struct WAL {
+    /// Version of the format the WAL was read in, see [`schema`].
+    #[serde(default)]
+    version: u64,
     actions: Vec<Action>,
-    redirections: HashMap<PathBuf, PathBuf>,
+    /// Redirections of a WAL written before they got files of their own,
+    /// moved to [`WAL::redirections`] once read, see [`redirections`].
+    #[serde(
+        default,
+        rename = "redirections",
+        skip_serializing_if = "HashMap::is_empty"
+    )]
+    inline_redirections: HashMap<PathBuf, PathBuf>,
+    /// Every managed path and its original, read from and written to the
+    /// files listed by `redirection_files`.
+    #[serde(skip)]
+    redirections: Redirections,
     checkpoint: usize,
+    /// Duplicate groups undone after one of their actions failed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    rollbacks: Vec<Rollback>,
+    /// Id of the next duplicate group.
+    #[serde(default)]
+    next_group: u64,
+    /// Where the originals are kept when not in the store, see
+    /// [`ApplyOptions::store_volume`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    originals_dir: Option<PathBuf>,
+    /// Where the store is when kept outside of the tree, see [`STORE_ENV`].
+    /// Originals are linked to by their absolute path, so a store found
+    /// anywhere else was moved from under its links.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    store: Option<PathBuf>,
+    /// How the originals are laid out in the store, see [`shard`].
+    #[serde(default, skip_serializing_if = "StoreLayout::is_flat")]
+    layout: StoreLayout,
+    /// Points in the history to revert to, see [`create_checkpoint`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    named_checkpoints: Vec<Checkpoint>,
+    /// Files holding the sealed actions, in order, see [`segment`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    segments: Vec<Segment>,
+    /// Number of the last segment file written.
+    #[serde(default)]
+    next_segment: u64,
+    /// Files holding the redirections, one per bucket, see [`redirections`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    redirection_files: Vec<RedirectionFile>,
+    /// Number of the last redirections file written.
+    #[serde(default)]
+    next_redirection_file: u64,
+    /// Segment and redirections files to delete once `wal.json` no longer
+    /// lists them.
+    #[serde(skip)]
+    obsolete: Vec<String>,
+    /// Groups of the pending actions by their original.
+    #[serde(skip)]
+    open_groups: HashMap<PathBuf, u64>,
+    /// Fields written by a newer mirage, kept verbatim.
+    #[serde(flatten)]
+    extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl WAL {
+    /// Queues `action` in a duplicate group: the pending actions sharing an
+    /// original belong to the same group, so that how far a group got can be
+    /// told from the checkpoint even after a crash.
+    fn push(&mut self, mut action: Action) {
+        if self.open_groups.is_empty() {
+            for pending in self.actions.iter().skip(self.checkpoint) {
+                if let Some(group) = pending.group {
+                    self.open_groups.insert(pending.target.clone(), group);
+                }
+            }
+        }
+        let next_group = &mut self.next_group;
+        let group = *self
+            .open_groups
+            .entry(action.target.clone())
+            .or_insert_with(|| {
+                *next_group += 1;
+                *next_group - 1
+            });
+        action.group = Some(group);
+        self.actions.push(action);
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct MirageState {
     source_path: PathBuf,
+    /// Root of the tree the state belongs to, which its store needn't be
+    /// in, see [`ApplyOptions::store`].
+    #[serde(skip)]
+    root: PathBuf,
     wal: WAL,
+    /// Key the WAL is signed with on every commit, if signing is enabled.
+    #[serde(skip)]
+    key: Option<Vec<u8>>,
+    /// Plan only: nothing is committed or executed.
+    #[serde(skip)]
+    dry_run: bool,
+    /// Held while the state may modify the store, see [`MirageState::lock_run`].
+    #[serde(skip)]
+    run_lock: Option<RunLock>,
+    /// Time spent in [`MirageState::commit`] so far.
+    #[serde(skip)]
+    committing: Duration,
+    /// The originals by contents, see [`MirageState::originals_like`].
+    #[serde(skip)]
+    original_index: Option<OriginalIndex>,
+}
+
+/// Environment variable naming the keyfile used to sign the WAL.
+pub const KEYFILE_ENV: &str = "MIRAGE_KEYFILE";
+
+/// Environment variable which, when set, lets a key start signing a WAL
+/// written before there was one. Without it such a WAL is refused.
+pub const SIGN_EXISTING_ENV: &str = "MIRAGE_SIGN_EXISTING";
+
+/// Environment variable naming the directory holding the state of a
+/// managed tree, [`DEFAULT_STATE_DIR`] if unset.
+pub const STATE_DIR_ENV: &str = "MIRAGE_STATE_DIR";
+
+/// Name of the directory at the root of a managed tree holding its state.
+pub const DEFAULT_STATE_DIR: &str = ".mirage";
+
+/// Name of the state directory, as set by [`STATE_DIR_ENV`]. Anything but a
+/// plain file name falls back to [`DEFAULT_STATE_DIR`].
+pub(crate) fn state_dir_name() -> OsString {
+    plain_state_dir(std::env::var_os(STATE_DIR_ENV))
+}
+
+/// `name` if it is a plain file name, [`DEFAULT_STATE_DIR`] if it isn't or
+/// is `None`.
+fn plain_state_dir(name: Option<OsString>) -> OsString {
+    match name {
+        Some(name)
+            if matches!(
+                Path::new(&name).components().collect::<Vec<_>>()[..],
+                [std::path::Component::Normal(_)]
+            ) =>
+        {
+            name
+        }
+        Some(name) => {
+            warn!("Ignoring state directory {:?}, it isn't a plain name", name);
+            OsString::from(DEFAULT_STATE_DIR)
+        }
+        None => OsString::from(DEFAULT_STATE_DIR),
+    }
+}
+
+/// Environment variable naming a directory to keep the state of managed
+/// trees in, each tree in a directory of its own, instead of inside them.
+pub const STORE_ENV: &str = "MIRAGE_STORE";
+
+/// The state directory of the tree at `root` kept below `dir`, named after
+/// the tree and a digest of its path so that trees sharing `dir` keep apart.
+fn external_store_path(dir: &Path, root: &Path) -> PathBuf {
+    let root = fs::canonicalize(root).unwrap_or_else(|_| root.to_path_buf());
+    let name = root
+        .file_name()
+        .map_or_else(|| "root".into(), |name| name.to_string_lossy());
+    // originals are linked to by absolute paths
+    let dir = std::path::absolute(dir).unwrap_or_else(|_| dir.to_path_buf());
+    dir.join(format!(
+        "{}-{:x}",
+        name,
+        md5::compute(root.as_os_str().as_encoded_bytes())
+    ))
+}
+
+/// Where the state of a tree is kept, inside of it or below a directory of
+/// stores, see [`ApplyOptions::store`] and [`ApplyOptions::state_dir`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct StoreLocation {
+    /// Directory keeping the state of trees outside of them, each in a
+    /// directory of its own.
+    external: Option<PathBuf>,
+    /// Name of the state directory inside a tree, and of those of the trees
+    /// nested in it.
+    name: OsString,
+}
+
+impl Default for StoreLocation {
+    /// Inside the tree, in a [`DEFAULT_STATE_DIR`].
+    fn default() -> StoreLocation {
+        StoreLocation {
+            external: None,
+            name: OsString::from(DEFAULT_STATE_DIR),
+        }
+    }
+}
+
+impl StoreLocation {
+    /// The location of the state of trees with `store` as their directory
+    /// of stores and `state_dir` as the name of their state directory,
+    /// each falling back to the one the environment sets if `None`.
+    pub(crate) fn new(store: Option<&Path>, state_dir: Option<&OsStr>) -> StoreLocation {
+        let external = match store {
+            Some(dir) => Some(dir.to_path_buf()),
+            None => std::env::var_os(STORE_ENV)
+                .filter(|dir| !dir.is_empty())
+                .map(PathBuf::from),
+        };
+        let name = match state_dir {
+            Some(name) => plain_state_dir(Some(name.to_os_string())),
+            None => state_dir_name(),
+        };
+        StoreLocation { external, name }
+    }
+
+    /// The location the environment sets, see [`STORE_ENV`] and
+    /// [`STATE_DIR_ENV`].
+    pub(crate) fn from_env() -> StoreLocation {
+        StoreLocation::new(None, None)
+    }
+
+    /// The same location for a tree keeping its state inside of it, as
+    /// nested trees do.
+    pub(crate) fn inside(&self) -> StoreLocation {
+        StoreLocation {
+            external: None,
+            name: self.name.clone(),
+        }
+    }
+
+    /// Name of the state directories inside trees.
+    pub(crate) fn name(&self) -> &OsStr {
+        &self.name
+    }
+
+    /// The state directory of the tree at `root`.
+    pub(crate) fn store(&self, root: &Path) -> PathBuf {
+        match &self.external {
+            Some(dir) => external_store_path(dir, root),
+            None => root.join(&self.name),
+        }
+    }
+}
+
+/// The state directory of the tree at `root`, below the directory named by
+/// [`STORE_ENV`] if it is set.
+pub(crate) fn store_path(root: &Path) -> PathBuf {
+    StoreLocation::from_env().store(root)
+}
+
+/// File next to `wal.json` holding the hex HMAC-SHA-256 of its contents.
+const SIGNATURE_FILE: &str = "wal.json.hmac";
+
+/// `wal.json` as written by a commit, before it is renamed into place.
+const STAGED_WAL_FILE: &str = "wal.json.tmp";
+
+/// [`SIGNATURE_FILE`] as written by a commit, before it is renamed into
+/// place.
+const STAGED_SIGNATURE_FILE: &str = "wal.json.hmac.tmp";
+
+/// Writes `bytes` to `path` and syncs them to disk.
+pub(crate) fn write_synced(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(bytes)?;
+    file.sync_all()
+}
+
+/// Syncs the entries of `dir`, such as a file just renamed into it.
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+/// Finishes or undoes a commit to the store at `mirage_path` that crashed
+/// before renaming everything into place. The staged WAL is rolled forward
+/// to when the signature already renamed into place is its own, and dropped
+/// when nothing was renamed yet, as it may be partly written then. Without
+/// `key` a signed store is left for a run that has it.
+fn recover_commit(mirage_path: &Path, key: Option<&[u8]>) -> Result<(), MirageError> {
+    let staged = mirage_path.join(STAGED_WAL_FILE);
+    let staged_signature = mirage_path.join(STAGED_SIGNATURE_FILE);
+    let signature = mirage_path.join(SIGNATURE_FILE);
+    if staged.exists() {
+        let finished = match key {
+            _ if !signature.exists() || staged_signature.exists() => false,
+            Some(key) => constant_time_eq(
+                fs::read_to_string(&signature)?.trim().as_bytes(),
+                to_hex(&hmac_sha256(key, &fs::read(&staged)?)).as_bytes(),
+            ),
+            None => return Ok(()),
+        };
+        if finished {
+            warn!("Finishing the interrupted commit of {:?}", mirage_path);
+            fs::rename(&staged, mirage_path.join("wal.json"))?;
+            sync_dir(mirage_path)?;
+        } else {
+            debug!("Dropping the interrupted commit of {:?}", mirage_path);
+            fs::remove_file(&staged)?;
+        }
+    }
+    if staged_signature.exists() {
+        fs::remove_file(&staged_signature)?;
+    }
+    Ok(())
+}
+
+/// Reads the WAL signing key from the keyfile named by [`KEYFILE_ENV`].
+fn signing_key() -> Result<Option<Vec<u8>>, MirageError> {
+    match std::env::var_os(KEYFILE_ENV) {
+        Some(path) => Ok(Some(fs::read(path)?)),
+        None => Ok(None),
+    }
+}
+
+/// Whether [`SIGN_EXISTING_ENV`] asks to sign a WAL that isn't signed yet.
+fn signing_existing() -> bool {
+    std::env::var_os(SIGN_EXISTING_ENV).is_some()
+}
+
+/// Checks the WAL against its signature before anything acts on it. A store
+/// that has never been signed is accepted without a key. With one it is
+/// taken as tampered with, its signature deleted, unless `sign_existing`
+/// says to sign it from now on.
+fn verify_signature(
+    mirage_path: &Path,
+    mut wal: impl Read,
+    key: Option<&[u8]>,
+    sign_existing: bool,
+) -> Result<(), MirageError> {
+    let signature_path = mirage_path.join(SIGNATURE_FILE);
+    if !signature_path.exists() {
+        if key.is_some() {
+            if !sign_existing {
+                warn!(
+                    "WAL at {:?} is not signed, set {} to sign it from now on",
+                    mirage_path, SIGN_EXISTING_ENV
+                );
+                return Err(MirageError::WALTampered);
+            }
+            warn!(
+                "WAL at {:?} is not signed yet, signing it from now on",
+                mirage_path
+            );
+        }
+        return Ok(());
+    }
+    let key = key.ok_or(MirageError::KeyRequired)?;
+    let signature = fs::read_to_string(&signature_path)?;
+    let mut hmac = HmacSha256::new(key);
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let read = wal.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hmac.update(&buf[..read]);
+    }
+    if !constant_time_eq(
+        signature.trim().as_bytes(),
+        to_hex(&hmac.finish()).as_bytes(),
+    ) {
+        return Err(MirageError::WALTampered);
+    }
+    Ok(())
 }
 
 impl MirageState {
     pub fn get<T: AsRef<Path>>(target_dir: T) -> Result<MirageState, MirageError> {
+        MirageState::get_at(target_dir, &StoreLocation::from_env())
+    }
+
+    /// Like [`MirageState::get`], with the state kept at `location`.
+    pub(crate) fn get_at<T: AsRef<Path>>(
+        target_dir: T,
+        location: &StoreLocation,
+    ) -> Result<MirageState, MirageError> {
+        MirageState::get_keyed(target_dir, signing_key()?, signing_existing(), location)
+    }
+
+    /// Like [`MirageState::get_at`], signing and verifying the WAL with `key`,
+    /// and starting to sign one that isn't signed yet if `sign_existing`.
+    fn get_keyed<T: AsRef<Path>>(
+        target_dir: T,
+        key: Option<Vec<u8>>,
+        sign_existing: bool,
+        location: &StoreLocation,
+    ) -> Result<MirageState, MirageError> {
         // convert path to absolute path
         let target_dir = fs::canonicalize(target_dir.as_ref())?;
         debug!("Target dir is {:?}", target_dir);
 
         // create .mirage if does not exist
-        let mirage_path = target_dir.join(".mirage");
+        let mirage_path = location.store(&target_dir);
         if mirage_path.exists() && !mirage_path.is_dir() {
             return Err(MirageError::DotMirageError);
         }
         if !mirage_path.exists() {
-            create_dir(&mirage_path)?;
+            // an external store may be the first in its directory
+            fs::create_dir_all(&mirage_path)?;
         }
         if !(mirage_path.exists() && mirage_path.is_dir()) {
             return Err(MirageError::DotMirageInInconsistentState);
@@ -97,6 +695,7 @@ impl MirageState {
 
         // now create .mirage/wal.json
 
+        recover_commit(&mirage_path, key.as_deref())?;
         let wal_path = mirage_path.join("wal.json");
 
         if wal_path.exists() && !wal_path.is_file() {
@@ -109,37 +708,295 @@ impl MirageState {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(&wal_path)?;
 
         debug!("Reading wal file {:?}", wal_path);
+        let external = (!mirage_path.starts_with(&target_dir)).then(|| mirage_path.clone());
 
         if file.metadata()?.len() == 0 {
             debug!("File is empty, creating new wal");
-            let wal = WAL::default();
-            serde_json::to_writer_pretty(BufWriter::new(file), &wal)?;
-            return Ok(MirageState {
+            drop(file);
+            let mut state = MirageState {
                 source_path: mirage_path,
-                wal,
-            });
+                root: target_dir,
+                wal: WAL {
+                    store: external,
+                    ..Default::default()
+                },
+                key,
+                dry_run: false,
+                run_lock: None,
+                committing: Duration::ZERO,
+                original_index: None,
+            };
+            state.commit()?;
+            Ok(state)
         } else {
             debug!("File is not empty, reading wal");
 
-            let wal = serde_json::from_reader(BufReader::new(file))?;
+            let mut bytes = Vec::new();
+            BufReader::new(file).read_to_end(&mut bytes)?;
+            verify_signature(&mirage_path, &bytes[..], key.as_deref(), sign_existing)?;
+
+            let mut wal = read_wal(&bytes)?;
+            wal.load_segments(&mirage_path)?;
+            wal.load_redirections(&mirage_path)?;
+            if let Some(recorded) = wal.store.as_ref().filter(|store| **store != mirage_path) {
+                warn!(
+                    "Store of {:?} was moved from {:?}, its links may be broken, see `mirage fsck`",
+                    target_dir, recorded
+                );
+            }
+            // recorded with the next commit
+            wal.store = external;
 
-            Ok(MirageState {
+            let mut state = MirageState {
                 source_path: mirage_path,
+                root: target_dir,
                 wal,
-            })
+                key,
+                dry_run: false,
+                run_lock: None,
+                committing: Duration::ZERO,
+                original_index: None,
+            };
+            if state.is_read_only() {
+                warn!(
+                    "{:?} is read-only, nothing will be modified",
+                    state.source_path
+                );
+                state.dry_run = true;
+            }
+            Ok(state)
         }
     }
 
-    pub fn commit(&self) -> Result<(), MirageError> {
-        let wal_path = self.source_path.join("wal.json");
-        let file = OpenOptions::new()
-            .truncate(true)
-            .write(true)
-            .open(wal_path)?;
-        serde_json::to_writer_pretty(BufWriter::new(file), &self.wal)?;
+    /// Opens the state of an already managed tree, without creating one.
+    pub fn open<T: AsRef<Path>>(target_dir: T) -> Result<MirageState, MirageError> {
+        MirageState::open_at(target_dir, &StoreLocation::from_env())
+    }
+
+    /// Like [`MirageState::open`], with the state kept at `location`.
+    pub(crate) fn open_at<T: AsRef<Path>>(
+        target_dir: T,
+        location: &StoreLocation,
+    ) -> Result<MirageState, MirageError> {
+        let wal_path = location.store(target_dir.as_ref()).join("wal.json");
+        if !wal_path.is_file() {
+            return Err(MirageError::MissingStore(target_dir.as_ref().to_path_buf()));
+        }
+        MirageState::get_at(target_dir, location)
+    }
+
+    /// Opens the state of a tree for planning only, without creating a store
+    /// if there is none. The returned state never commits or executes.
+    pub fn peek<T: AsRef<Path>>(target_dir: T) -> Result<MirageState, MirageError> {
+        MirageState::peek_at(target_dir, &StoreLocation::from_env())
+    }
+
+    /// Like [`MirageState::peek`], with the state kept at `location`.
+    pub(crate) fn peek_at<T: AsRef<Path>>(
+        target_dir: T,
+        location: &StoreLocation,
+    ) -> Result<MirageState, MirageError> {
+        let mut state = if location
+            .store(target_dir.as_ref())
+            .join("wal.json")
+            .is_file()
+        {
+            MirageState::get_at(&target_dir, location)?
+        } else {
+            let root = fs::canonicalize(target_dir.as_ref())?;
+            MirageState {
+                source_path: location.store(&root),
+                root,
+                wal: WAL::default(),
+                key: None,
+                dry_run: true,
+                run_lock: None,
+                committing: Duration::ZERO,
+                original_index: None,
+            }
+        };
+        state.dry_run = true;
+        Ok(state)
+    }
+
+    fn originals_path(&self) -> PathBuf {
+        self.wal
+            .originals_dir
+            .clone()
+            .unwrap_or_else(|| self.source_path.join("originals"))
+    }
+
+    /// Keeps the originals of the tree at `root` in a directory of their own
+    /// below `volume` from now on. Only a store without any actions yet can
+    /// be moved to another volume.
+    fn place_originals(&mut self, volume: &Path, root: &Path) -> Result<(), MirageError> {
+        let volume = fs::canonicalize(volume)?;
+        if volume.starts_with(root) {
+            return Err(MirageError::StoreVolumeInTree(volume));
+        }
+        // trees sharing a volume keep apart
+        let name = format!(
+            "mirage-{:x}",
+            md5::compute(root.as_os_str().as_encoded_bytes())
+        );
+        let dir = volume.join(name);
+        if self.wal.originals_dir.as_ref() == Some(&dir) {
+            return Ok(());
+        }
+        if !self.wal.actions.is_empty() {
+            return Err(MirageError::StoreVolumeChanged(root.to_path_buf()));
+        }
+        debug!("Keeping the originals of {:?} in {:?}", root, dir);
+        self.wal.originals_dir = Some(dir.clone());
+        if !self.dry_run {
+            fs::create_dir_all(&dir)?;
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    /// Removes the store, originals kept on another volume included.
+    fn remove_store(&self) -> Result<(), MirageError> {
+        if let Some(dir) = self.wal.originals_dir.as_ref().filter(|dir| dir.exists()) {
+            debug!("Removing originals {:?}", dir);
+            fs::remove_dir_all(dir)?;
+        }
+        if self.source_path.exists() {
+            fs::remove_dir_all(&self.source_path)?;
+        }
+        Ok(())
+    }
+
+    /// Number of redirected paths that currently point at `original`.
+    pub fn refcount(&self, original: &Path) -> usize {
+        self.wal.redirections.links(original)
+    }
+
+    /// Stops managing `path`: drops its redirection and the actions that
+    /// linked it, then deletes its original once nothing refers to it any
+    /// more. Whatever is left at `path` on disk is up to the caller.
+    fn release(&mut self, path: &Path) -> Result<(), MirageError> {
+        let original = self
+            .wal
+            .redirections
+            .remove(path)
+            .ok_or_else(|| MirageError::NotManaged(path.to_path_buf()))?;
+        let _lock = self.lock_original(&original)?;
+        self.forget_actions(|a| a.action.links() && a.source == path);
+
+        if self.refcount(&original) == 0 {
+            debug!("Collecting unreferenced original {:?}", original);
+            self.forget_actions(|a| matches!(a.action, ActionType::Copy) && a.target == original);
+            if original.exists() {
+                fs::remove_file(&original)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes already applied actions from the WAL, keeping the checkpoint
+    /// pointing at the same pending action.
+    fn forget_actions<F: Fn(&Action) -> bool>(&mut self, pred: F) {
+        let checkpoint = self.wal.checkpoint;
+        let mut index = 0;
+        let mut forgotten = Vec::new();
+        self.wal.actions.retain(|a| {
+            let forget = index < checkpoint && pred(a);
+            if forget {
+                forgotten.push(index);
+            }
+            index += 1;
+            !forget
+        });
+        self.wal.checkpoint -= forgotten.len();
+        shift_checkpoints(&mut self.wal.named_checkpoints, &forgotten);
+        self.wal.shrink_segments(&forgotten);
+    }
+
+    /// Total size in bytes of the files currently in the originals store.
+    pub fn store_size(&self) -> Result<u64, MirageError> {
+        let mut size = 0;
+        if !self.originals_path().exists() {
+            return Ok(size);
+        }
+        for entry in walkdir::WalkDir::new(self.originals_path()) {
+            let entry = entry?;
+            if entry.file_type().is_file() {
+                size += entry.metadata()?.len();
+            }
+        }
+        Ok(size)
+    }
+
+    /// Picks a path in the originals store for a file called `name` holding
+    /// `content` that is neither on disk nor already claimed by a pending
+    /// action.
+    fn new_original_path(&self, name: &OsStr, content: &str) -> PathBuf {
+        let claimed = |path: &Path| path.exists() || self.wal.redirections.links(path) > 0;
+        let dir = self.wal.layout.dir(&self.originals_path(), content);
+        let candidate = dir.join(name);
+        if !claimed(&candidate) {
+            return candidate;
+        }
+        // keep the extension last so the original's format stays recognisable
+        let (stem, extension) = {
+            let name = Path::new(name);
+            (
+                name.file_stem().unwrap_or(name.as_os_str()),
+                name.extension(),
+            )
+        };
+        (1..)
+            .map(|i| {
+                let mut suffixed = stem.to_os_string();
+                suffixed.push(format!(".{}", i));
+                if let Some(extension) = extension {
+                    suffixed.push(".");
+                    suffixed.push(extension);
+                }
+                dir.join(suffixed)
+            })
+            .find(|path| !claimed(path))
+            .unwrap()
+    }
+
+    pub fn commit(&mut self) -> Result<(), MirageError> {
+        if self.dry_run {
+            return Ok(());
+        }
+        let started = Instant::now();
+        self.wal.write_segments(&self.source_path)?;
+        self.wal.write_redirections(&self.source_path)?;
+        // segments are written in the current format too, a newer version
+        // is kept along with what only it knows
+        self.wal.version = self.wal.version.max(WAL_VERSION);
+        // wal.json only holds the actions after the segments
+        let recent = self.wal.actions.split_off(self.wal.sealed());
+        let sealed = std::mem::replace(&mut self.wal.actions, recent);
+        let bytes = serde_json::to_vec_pretty(&self.wal);
+        let recent = std::mem::replace(&mut self.wal.actions, sealed);
+        self.wal.actions.extend(recent);
+        let bytes = bytes?;
+
+        // both are written aside and renamed into place, the signature
+        // first, so a crash leaves the last commit or one to roll forward
+        // to, see `recover_commit`
+        let staged = self.source_path.join(STAGED_WAL_FILE);
+        write_synced(&staged, &bytes)?;
+        if let Some(key) = &self.key {
+            let signature = to_hex(&hmac_sha256(key, &bytes));
+            let staged_signature = self.source_path.join(STAGED_SIGNATURE_FILE);
+            write_synced(&staged_signature, signature.as_bytes())?;
+            fs::rename(staged_signature, self.source_path.join(SIGNATURE_FILE))?;
+        }
+        fs::rename(staged, self.source_path.join("wal.json"))?;
+        sync_dir(&self.source_path)?;
+        self.wal.remove_obsolete(&self.source_path)?;
+        self.committing += started.elapsed();
         Ok(())
     }
 }
@@ -158,428 +1015,7114 @@ pub enum MirageError {
     JsonError(#[from] serde_json::Error),
     #[error("error in listing files")]
     WalkDirError(#[from] walkdir::Error),
+    #[error("nested mirage store found at {0:?}, revert it first or adopt it")]
+    NestedStore(PathBuf),
+    #[error("no mirage store found at {0:?}")]
+    MissingStore(PathBuf),
+    #[error("tree is already managed by the mirage store at {0:?}")]
+    AncestorStore(PathBuf),
+    #[error("mirage store at {0:?} has pending actions, apply it first")]
+    PendingActions(PathBuf),
+    #[error("{0:?} is not managed by mirage")]
+    NotManaged(PathBuf),
+    #[error("tree at {0:?} is locked, run `mirage unlock` first")]
+    Frozen(PathBuf),
+    #[error("wal.json does not match its signature, refusing to use it")]
+    WALTampered,
+    #[error("the store is signed, set MIRAGE_KEYFILE to its keyfile")]
+    KeyRequired,
+    #[error("action {0} of the WAL was written by a newer mirage, use it to continue")]
+    UnknownAction(usize),
+    #[error("sandbox can't be enforced here: {0}")]
+    SandboxUnavailable(io::Error),
+    #[error("tree at {0:?} is being modified by {1}")]
+    Running(PathBuf, String),
+    #[error("tree at {0:?} was locked by {1}, which died, rerun with --break-stale-lock")]
+    StaleLock(PathBuf, String),
+    #[error("store volume {0:?} is inside the tree")]
+    StoreVolumeInTree(PathBuf),
+    #[error("originals of {0:?} are kept elsewhere already, revert it to move them")]
+    StoreVolumeChanged(PathBuf),
+    #[error("no state backup {0} found, see `mirage restore-state --list`")]
+    MissingBackup(usize),
+    #[error("interrupted, the WAL is committed up to the last finished action")]
+    Interrupted,
+    #[error("original {0:?} is missing from the store, revert with --force to restore the rest")]
+    MissingOriginal(PathBuf),
+    #[error("WAL records paths outside of {0:?}, it belongs to another tree")]
+    ForeignWal(PathBuf),
+    #[error("plan is invalid at line {0}: {1}")]
+    InvalidPlan(usize, String),
+    #[error("the filesystem of {0:?} can't hold {1}s: {2}")]
+    LinksUnsupported(PathBuf, &'static str, String),
+    #[error("configuration {0:?} is invalid at line {1}: {2}")]
+    InvalidConfig(PathBuf, usize, String),
+    #[error("{0:?} and {1:?} overlap, deduplicate the outer one alone")]
+    OverlappingRoots(PathBuf, PathBuf),
+    #[error("restoring needs {1} bytes on the filesystem of {0:?}, only {2} are free")]
+    NotEnoughSpace(PathBuf, u64, u64),
+    #[error("checkpoint {0:?} exists already")]
+    CheckpointExists(String),
+    #[error("no checkpoint {0:?} found, see `mirage checkpoint list`")]
+    UnknownCheckpoint(String),
+    #[error("store {0:?} is read-only, clear its marker first")]
+    ReadOnly(PathBuf),
 }
 
-pub fn apply<T: AsRef<Path>>(target_dir: T) -> Result<(), MirageError> {
-    let mut state = MirageState::get(&target_dir)?;
+/// Options controlling how [`apply_with_options`] deduplicates a tree.
+#[derive(Debug, Clone, Default)]
+pub struct ApplyOptions {
+    /// Merge any `.mirage` stores found below the target into the target's
+    /// store, instead of refusing to run.
+    pub adopt_nested: bool,
+    /// Give every top-level subdirectory of the target its own independent
+    /// store. Files directly inside the target are left alone.
+    pub per_subdirectory: bool,
+    /// Maximum size in bytes the originals store may grow to. Duplicate groups
+    /// whose original would push the store past it are skipped.
+    pub max_store_size: Option<u64>,
+    /// Treat the top-level directories as dated snapshots of the same data
+    /// (`backup-2024-01-01`, ...) whose names sort chronologically: originals
+    /// are taken from the newest one and savings are reported per snapshot.
+    pub snapshots: bool,
+    /// File extensions, without the dot and compared case-insensitively, of
+    /// files that are never deduplicated.
+    pub exclude_extensions: Vec<String>,
+    /// Globs such as `*.iso` or `media/**/*.mkv`, only files matching one of
+    /// which are deduplicated, every file if empty. A glob without a `/` is
+    /// matched against file names, one with against paths from the target.
+    /// Files the other filters exclude stay excluded.
+    pub include: Vec<String>,
+    /// Only group files that share the same extension.
+    pub same_extension_only: bool,
+    /// Name originals after the group member living under the most
+    /// date-named directories (`2024`, `2024-01-15`, ...).
+    pub prefer_dated_dirs: bool,
+    /// Consider media files equal when they differ only in embedded tags
+    /// (EXIF in JPEG, ID3 in MP3). The tags of the file that becomes the
+    /// original are the ones every linked path sees afterwards.
+    pub ignore_metadata: bool,
+    /// Only plan: report the actions that would be taken without touching
+    /// the tree or creating a store.
+    pub dry_run: bool,
+    /// Follow symlinked directories while walking the tree. Loops are
+    /// reported and not followed, and files resolving outside the tree are
+    /// left alone.
+    pub follow_symlinks: bool,
+    /// Only walk this many levels below the target, 1 keeping to the files
+    /// directly inside it.
+    pub max_depth: Option<usize>,
+    /// Don't cross into other filesystems, whether mounted below the target
+    /// or reached through a followed symlink.
+    pub one_file_system: bool,
+    /// Most candidate files held in memory while grouping them by size,
+    /// past which they are spilled to `.mirage/tmp`. Defaults to
+    /// [`DEFAULT_MEMORY_BUDGET`].
+    pub memory_budget: Option<usize>,
+    /// Also deduplicate the junk files in [`DEFAULT_IGNORES`], which are
+    /// skipped otherwise.
+    pub no_default_ignores: bool,
+    /// Also deduplicate inside the [`VCS_DIRS`], which are skipped otherwise.
+    /// Their objects are content-addressed and repositories break when they
+    /// become links.
+    pub include_vcs: bool,
+    /// Don't read the [`IGNORE_FILE`]s of the tree, deduplicating the files
+    /// they ignore too.
+    pub no_ignore_files: bool,
+    /// Also skip what the `.gitignore` files of the tree ignore, such as the
+    /// build output of repositories, with the rules of an [`IGNORE_FILE`]
+    /// winning over those of the `.gitignore` next to it.
+    pub respect_gitignore: bool,
+    /// node_exporter textfile collector file to write the metrics of the run
+    /// to when it ends, whether it succeeded or not. Every tree needs a file
+    /// of its own, each run replaces it.
+    pub metrics_file: Option<PathBuf>,
+    /// Record every comparison and decision of the run in a compressed
+    /// journal in the store, see [`journal`].
+    pub journal: bool,
+    /// Keep the originals in a directory of their own below this one, e.g. on
+    /// a big slow disk while the tree lives on fast storage. Files link to
+    /// them by absolute symlinks, which cross filesystems. Only taken on the
+    /// first run of a tree, later runs find the originals through the WAL.
+    pub store_volume: Option<PathBuf>,
+    /// Number of backups of the state to keep, one taken before the run,
+    /// [`DEFAULT_BACKUPS`] if unset. Zero takes none.
+    pub backups: Option<usize>,
+    /// Stop after working on this many files, see [`ApplyReport::stopped_at`].
+    /// Files already managed or without a file of their size don't count.
+    pub max_files: Option<usize>,
+    /// Stop once the run has taken this long, scanning included, see
+    /// [`ApplyReport::stopped_at`].
+    pub max_duration: Option<Duration>,
+    /// Redact the path of the tree in the metrics written to
+    /// [`ApplyOptions::metrics_file`].
+    pub redaction: Option<Redaction>,
+    /// Hard link or clone files by size instead of symlinking every one,
+    /// where the filesystem can.
+    pub link_policy: Option<LinkPolicy>,
+    /// How duplicates refer to their original, symlinks if unset. Files a
+    /// hard link or clone can't reach, on another device than the store,
+    /// are symlinked. Every action records what it made, so revert knows
+    /// how to undo it.
+    pub link_mode: Option<LinkMode>,
+    /// Only consider these files, as resolved by [`read_file_list`]. Every
+    /// other file of the tree is left alone, though the listed ones may
+    /// still be linked to originals already in the store.
+    pub files_from: Option<HashSet<PathBuf>>,
+    /// Number of files hashed at once, one per CPU if unset.
+    pub jobs: Option<usize>,
+    /// Share the blocks of duplicates in place instead of linking them to
+    /// an original, on filesystems that can deduplicate extents. Every file
+    /// stays a regular file and nothing is copied into the store.
+    pub dedupe_extents: bool,
+    /// Files smaller than this many bytes are never deduplicated, the space
+    /// a store entry and a link take being worth more than what they save.
+    pub min_size: Option<u64>,
+    /// Further trees deduplicated along with the target, their files linked
+    /// to the same originals. The store of the target records them all, so
+    /// reverting the target restores them too. Files of trees on another
+    /// device than the store are symlinked, hard links and clones being
+    /// unable to reach it. Not combined with
+    /// [`ApplyOptions::per_subdirectory`].
+    pub other_roots: Vec<PathBuf>,
+    /// Keep the state of the tree in a directory of its own below this one,
+    /// named after the tree, instead of inside it. Defaults to the directory
+    /// named by [`STORE_ENV`]. Every later run has to be given it too.
+    pub store: Option<PathBuf>,
+    /// Name of the directory keeping the state inside the tree, and the one
+    /// nested stores are found by. Defaults to the name [`STATE_DIR_ENV`]
+    /// sets, or else [`DEFAULT_STATE_DIR`]. Anything but a plain file name
+    /// falls back to the latter. Every later run has to be given it too.
+    pub state_dir: Option<OsString>,
+    /// Algorithm files are grouped by and the digests of originals recorded
+    /// with, [`DEFAULT_HASH_ALGORITHM`] if unset.
+    pub hash_algorithm: Option<HashAlgorithm>,
+}
 
-    fn is_mirage(entry: &DirEntry) -> bool {
-        entry
-            .file_name()
-            .to_str()
-            .map(|s| s.starts_with(".mirage"))
-            .unwrap_or(false)
+impl ApplyOptions {
+    /// Where the state of the tree is kept.
+    pub(crate) fn location(&self) -> StoreLocation {
+        StoreLocation::new(self.store.as_deref(), self.state_dir.as_deref())
     }
 
-    for here in walkdir::WalkDir::new(&target_dir)
-        .sort_by_file_name()
-        .into_iter()
-        .filter_entry(|f| !is_mirage(f))
-    {
-        debug!("Try Processing file {:?}", here);
-        // handle soft errors here
-        if let Err(x) = here {
-            warn!("Can't access {:?} due to {:?}", x.path(), x.io_error());
-            continue;
-        }
-        let here = here.unwrap();
-        if here.path_is_symlink() {
-            trace!("Skipping symlink {:?}", here.path());
-            continue;
-        }
-        if here.file_type().is_dir() {
-            trace!("Skipping dir {:?}", here.path());
-            continue;
-        }
-        let here = fs::canonicalize(here.path())?;
-        debug!("Processing file {}", here.display());
-        // compare with hash of other entries
-        for there in walkdir::WalkDir::new(&target_dir)
-            .sort_by_file_name()
-            .into_iter()
-            .filter_entry(|f| !is_mirage(f))
-        {
-            debug!("Try Comparing file {:?}", here);
-            if let Err(x) = there {
-                warn!("Can't access {:?} due to {:?}", x.path(), x.io_error());
-                continue;
-            }
-            let there = there.unwrap();
-            if there.path_is_symlink() {
-                trace!("Skipping symlink {:?}", there.path());
-                continue;
-            }
+    /// What files are hashed with, see [`ApplyOptions::hash_algorithm`].
+    pub(crate) fn algorithm(&self) -> HashAlgorithm {
+        self.hash_algorithm.unwrap_or(DEFAULT_HASH_ALGORITHM)
+    }
 
-            if there.file_type().is_dir() {
-                trace!("Skipping dir {:?}", there.path());
-                continue;
-            }
-            let there: PathBuf = fs::canonicalize(there.path())?;
-            if here.as_path() == there.as_path() {
-                continue;
-            }
-            debug!("Comparing file {} with {}", here.display(), there.display());
-            let is_same = check_if_files_are_same(here.as_path(), there.as_path())?;
-            if is_same {
-                trace!("Files are same {:?} {:?}", here.as_path(), there.as_path());
+    /// Options walking every file of a tree, for reports about it.
+    pub(crate) fn everything() -> ApplyOptions {
+        ApplyOptions {
+            no_default_ignores: true,
+            include_vcs: true,
+            no_ignore_files: true,
+            ..Default::default()
+        }
+    }
+}
 
-                // first check if redirection exists
+/// Names of files that operating systems drop all over a tree, matched case
+/// insensitively. They look identical across directories, but tools like
+/// Finder break when they are replaced with links.
+pub const DEFAULT_IGNORES: &[&str] = &[".DS_Store", "Thumbs.db", "desktop.ini", ".localized"];
 
-                let contains_1 = state.wal.redirections.contains_key(here.as_path());
-                let contains_2 = state.wal.redirections.contains_key(there.as_path());
+/// Directories where version control systems keep their internals.
+pub const VCS_DIRS: &[&str] = &[".git", ".hg", ".svn"];
 
-                if contains_1 && contains_2 {
-                    debug!("Redirection exists, skipping {:?}", here.as_path());
-                    continue;
-                } else if contains_1 {
-                    // just create a symlink to where here points to for there
-                    let here_pt = state.wal.redirections.get(here.as_path()).unwrap();
-                    let action = Action::new(
-                        ActionType::Symlink,
-                        there.as_path().to_path_buf(),
-                        here_pt.clone(),
-                    );
-                    state.wal.actions.push(action);
-                    state
-                        .wal
-                        .redirections
-                        .insert(there.as_path().to_path_buf(), here_pt.clone());
-                    state.commit()?;
-                    debug!("Redirection exists, using it {:?}", here.as_path());
-                    continue;
-                } else if contains_2 {
-                    // just create a symlink to where there points to for here
-                    let there_pt = state.wal.redirections.get(there.as_path()).unwrap();
-                    let action = Action::new(
-                        ActionType::Symlink,
-                        here.as_path().to_path_buf(),
-                        there_pt.clone(),
-                    );
-                    state.wal.actions.push(action);
-                    state
-                        .wal
-                        .redirections
-                        .insert(here.as_path().to_path_buf(), there_pt.clone());
-                    state.commit()?;
-                    debug!("Redirection exists, using it {:?}", there.as_path());
-                    continue;
-                }
+fn is_vcs(entry: &DirEntry) -> bool {
+    entry
+        .file_name()
+        .to_str()
+        .is_some_and(|name| VCS_DIRS.contains(&name))
+}
 
-                // move first file into originals and point both files using symlinks
-                // first write to WAL
-                let original_path = state.source_path.join("originals");
+fn is_junk(entry: &DirEntry) -> bool {
+    entry.file_type().is_file()
+        && entry.file_name().to_str().is_some_and(|name| {
+            DEFAULT_IGNORES
+                .iter()
+                .any(|junk| junk.eq_ignore_ascii_case(name))
+        })
+}
 
-                //TODO handle this unwrap nicely
-                let original_path = original_path.join(here.as_path().file_name().unwrap());
+/// Whether `path` of the tree at `root` matches one of the `included`
+/// globs, if there are any.
+fn is_included(path: &Path, root: &Path, included: &[Glob]) -> bool {
+    included.is_empty()
+        || path
+            .strip_prefix(root)
+            .is_ok_and(|relative| included.iter().any(|glob| glob.matches(relative)))
+}
 
-                let action = Action::new(
-                    ActionType::Copy,
-                    here.as_path().to_path_buf(),
-                    original_path.clone(),
-                );
+/// Lowercased extension of a path, if it has one.
+fn extension_of(path: &Path) -> Option<String> {
+    path.extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+}
 
-                state.wal.actions.push(action);
+/// An action as shown to the user, e.g. when planning a dry run.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PlannedAction {
+    pub action: String,
+    pub source: PathBuf,
+    pub target: PathBuf,
+}
 
-                let action = Action::new(
-                    ActionType::Symlink,
-                    here.as_path().to_path_buf(),
-                    original_path.clone(),
-                );
+impl From<&Action> for PlannedAction {
+    fn from(action: &Action) -> Self {
+        PlannedAction {
+            action: action.action.name(),
+            source: action.source.clone(),
+            target: action.target.clone(),
+        }
+    }
+}
 
-                state.wal.actions.push(action);
+/// A file a revert failed to restore.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct RevertFailure {
+    pub path: PathBuf,
+    pub error: String,
+}
 
-                let action = Action::new(
-                    ActionType::Symlink,
-                    there.as_path().to_path_buf(),
-                    original_path.clone(),
-                );
+/// Summary of what a revert run did, for reporting back to the user.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RevertReport {
+    /// Nothing was modified, because of [`RevertOptions::dry_run`] or a
+    /// read-only store.
+    pub dry_run: bool,
+    /// The actions a dry run would have executed, in order.
+    pub planned: Vec<PlannedAction>,
+    /// Number of restored files found to hold what their original held when
+    /// it was copied into the store, with [`RevertOptions::verify`].
+    pub verified: usize,
+    /// Restored files whose contents differ from the recorded ones. The
+    /// store is kept when there are any.
+    pub mismatched: Vec<PathBuf>,
+    /// Number of restored files whose original has no recorded contents,
+    /// as with stores applied by an older mirage.
+    pub unverified: usize,
+    /// Number of files restored and moved back.
+    pub restored: usize,
+    /// Files left as they are because nothing is known about undoing what
+    /// was done to them.
+    pub skipped: Vec<PathBuf>,
+    /// Files that failed to be restored, the others are restored regardless.
+    /// The store is kept when there are any, to revert again once they are
+    /// seen to.
+    pub failed: Vec<RevertFailure>,
+    /// Files whose original was missing, with [`RevertOptions::force`]. Each
+    /// is replaced by a `.mirage-lost` file next to it telling what is gone.
+    pub lost: Vec<PathBuf>,
+    /// Where the state was archived to, with [`RevertOptions::archive_state`].
+    pub archived_to: Vec<PathBuf>,
+    /// The room the restored files take on each filesystem they go to. A
+    /// revert fails before restoring anything when one of them is short of
+    /// it, a dry run only tells.
+    pub space: Vec<SpaceNeeded>,
+}
 
-                state.wal.actions.push(action);
+/// Fails with [`MirageError::NotEnoughSpace`] if a filesystem of `space`
+/// can't hold what is restored to it.
+fn ensure_space(space: &[SpaceNeeded]) -> Result<(), MirageError> {
+    match space.iter().find(|space| !space.fits()) {
+        Some(short) => Err(MirageError::NotEnoughSpace(
+            short.path.clone(),
+            short.needed,
+            short.free,
+        )),
+        None => Ok(()),
+    }
+}
 
-                state
-                    .wal
-                    .redirections
-                    .insert(here.as_path().to_path_buf(), original_path.clone());
+/// Summary of what an apply run did, for reporting back to the user.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ApplyReport {
+    /// First file of every duplicate group left alone because its original
+    /// would not fit in [`ApplyOptions::max_store_size`].
+    pub skipped_over_quota: Vec<PathBuf>,
+    /// Space served from the store per snapshot, newest first. Only filled in
+    /// with [`ApplyOptions::snapshots`].
+    pub snapshot_savings: Vec<SnapshotSavings>,
+    /// Nothing was modified, because of [`ApplyOptions::dry_run`] or a
+    /// read-only store.
+    pub dry_run: bool,
+    /// The actions a dry run would have executed, in order.
+    pub planned: Vec<PlannedAction>,
+    /// Duplicate groups undone because one of their actions failed.
+    pub rolled_back: Vec<Rollback>,
+    /// Bytes the executed actions saved.
+    pub bytes_saved: u64,
+    /// Bytes read to hash files, leaving out the ones hashed by earlier runs.
+    pub bytes_hashed: u64,
+    /// Bytes of the files whose contents were compared.
+    pub bytes_compared: u64,
+    /// Bytes copied into the store as new originals.
+    pub bytes_copied: u64,
+    /// Number of files walked, candidates or not.
+    pub files_scanned: u64,
+    /// Number of actions executed, rolled back ones included.
+    pub actions_executed: usize,
+    /// Wall-clock time spent in each phase of the run.
+    pub times: PhaseTimes,
+    /// Size of the originals store after the run.
+    pub store_size: u64,
+    /// The group the run stopped at once out of the budget set by
+    /// [`ApplyOptions::max_files`] or [`ApplyOptions::max_duration`], where
+    /// the next run picks up. `None` if it got through the whole tree.
+    pub stopped_at: Option<PathBuf>,
+    /// Files of a plan applied with [`apply_plan`] that were left alone:
+    /// gone, managed already, or not holding what their group's original
+    /// holds.
+    pub plan_skipped: Vec<PathBuf>,
+    /// Duplicate groups the run deduplicated, or would have on a dry run.
+    pub groups: Vec<DuplicateGroup>,
+}
 
-                state
-                    .wal
-                    .redirections
-                    .insert(there.as_path().to_path_buf(), original_path.clone());
+/// How much of a snapshot directory is served from the store.
+#[derive(Debug, Clone, Default, Serialize, PartialEq, Eq)]
+pub struct SnapshotSavings {
+    pub snapshot: PathBuf,
+    /// Number of files in the snapshot that link to an original.
+    pub files: usize,
+    /// Total size of those files.
+    pub bytes: u64,
+}
 
-                state.commit()?;
-            }
-        }
+impl ApplyReport {
+    fn extend(&mut self, other: ApplyReport) {
+        self.skipped_over_quota.extend(other.skipped_over_quota);
+        self.snapshot_savings.extend(other.snapshot_savings);
+        self.dry_run |= other.dry_run;
+        self.planned.extend(other.planned);
+        self.rolled_back.extend(other.rolled_back);
+        self.bytes_saved += other.bytes_saved;
+        self.bytes_hashed += other.bytes_hashed;
+        self.bytes_compared += other.bytes_compared;
+        self.bytes_copied += other.bytes_copied;
+        self.files_scanned += other.files_scanned;
+        self.actions_executed += other.actions_executed;
+        self.times.add(&other.times);
+        self.store_size += other.store_size;
+        self.stopped_at = self.stopped_at.take().or(other.stopped_at);
+        self.plan_skipped.extend(other.plan_skipped);
+        self.groups.extend(other.groups);
     }
+}
 
-    for action in state.wal.actions.iter().skip(state.wal.checkpoint) {
-        match action.action {
-            ActionType::Copy => {
-                debug!(
-                    "Copying file from {:?} to {:?}",
-                    action.source, action.target
-                );
-                fs::copy(action.source.as_path(), action.target.as_path())?;
-            }
-            ActionType::Symlink => {
-                debug!(
-                    "Creating symlink from {:?} to {:?}",
-                    action.source, action.target
-                );
-                if action.source.exists() {
-                    fs::remove_file(action.source.as_path())?;
-                }
-                // horrible convention should fix
-                symlink_file(action.target.as_path(), action.source.as_path())?;
-            }
-            ActionType::NOP => {
-                // do nothing
-                debug!("NOP action, doing nothing");
-            }
+/// Adds up, for every top-level directory of the tree at `root`, the
+/// redirected files it contains and their size.
+fn snapshot_savings(
+    state: &MirageState,
+    root: &Path,
+    location: &StoreLocation,
+) -> Result<Vec<SnapshotSavings>, MirageError> {
+    let mut savings = subdirectory_roots_at(root, location)?
+        .into_iter()
+        .rev()
+        .map(|snapshot| SnapshotSavings {
+            snapshot,
+            ..Default::default()
+        })
+        .collect::<Vec<_>>();
+    for (path, original) in state.wal.redirections.iter() {
+        if let Some(entry) = savings.iter_mut().find(|s| path.starts_with(&s.snapshot)) {
+            entry.files += 1;
+            entry.bytes += fs::metadata(original).map(|m| m.len()).unwrap_or(0);
         }
-        state.wal.checkpoint += 1;
-        state.commit()?;
     }
+    Ok(savings)
+}
 
-    Ok(())
+/// Options controlling how [`revert_with_options`] restores a tree.
+#[derive(Debug, Clone, Default)]
+pub struct RevertOptions {
+    /// Revert the independent store of every top-level subdirectory, as
+    /// created by [`ApplyOptions::per_subdirectory`].
+    pub per_subdirectory: bool,
+    /// Only plan: report the actions that would be taken without touching
+    /// the tree.
+    pub dry_run: bool,
+    /// Number of backups of the state to keep, see [`ApplyOptions::backups`].
+    pub backups: Option<usize>,
+    /// Number of duplicate groups restored at once, one per CPU if unset.
+    pub jobs: Option<usize>,
+    /// Hash every restored file once done and compare it with the contents
+    /// recorded when its original was copied into the store, see
+    /// [`RevertReport::mismatched`].
+    pub verify: bool,
+    /// Restore what can be when originals are missing from the store, rather
+    /// than fail before touching anything, see [`RevertReport::lost`].
+    pub force: bool,
+    /// Archive the WAL, its backups and the logs of the store here before
+    /// deleting it: a `.tar`, `.tar.gz` or `.tgz` file, or else a directory
+    /// to keep it in below a name of its own.
+    pub archive_state: Option<PathBuf>,
+    /// Restore files as hard links to their original instead of copies of
+    /// it, so that the files of a group keep sharing their space once the
+    /// store is gone, and reverting needs no more space than the tree took.
+    /// They share their contents too: writing to one changes them all.
+    /// Originals on another filesystem are copied as usual.
+    pub as_hardlinks: bool,
+    /// Only restore the managed files below these paths, files or
+    /// directories of the tree, leaving the rest of it deduplicated and the
+    /// store in place. Not combined with
+    /// [`RevertOptions::per_subdirectory`] or
+    /// [`RevertOptions::archive_state`].
+    pub only: Vec<PathBuf>,
+    /// Only undo the actions recorded since the checkpoint of this name,
+    /// see [`create_checkpoint`], leaving the tree as it was when it was
+    /// created. Not combined with the other ways to revert part of a tree.
+    pub to: Option<String>,
+    /// Where the state of the tree is kept, see [`ApplyOptions::store`].
+    pub store: Option<PathBuf>,
+    /// Name of the state directory, see [`ApplyOptions::state_dir`].
+    pub state_dir: Option<OsString>,
 }
 
-pub fn revert<T: AsRef<Path>>(target_dir: T) -> Result<(), MirageError> {
-    let state = MirageState::get(&target_dir)?;
+impl RevertOptions {
+    /// Where the state of the tree is kept.
+    pub(crate) fn location(&self) -> StoreLocation {
+        StoreLocation::new(self.store.as_deref(), self.state_dir.as_deref())
+    }
+}
 
-    for action in state
-        .wal
-        .actions
-        .iter()
-        .rev()
-        .skip(state.wal.actions.len() - state.wal.checkpoint)
-        .map(|f| f.invert())
+/// Lists the top-level subdirectories of `target_dir` that act as independent
+/// roots in per-subdirectory mode.
+pub fn subdirectory_roots<T: AsRef<Path>>(target_dir: T) -> Result<Vec<PathBuf>, MirageError> {
+    subdirectory_roots_at(target_dir, &StoreLocation::from_env())
+}
+
+/// Like [`subdirectory_roots`], with the state of trees kept at `location`.
+fn subdirectory_roots_at<T: AsRef<Path>>(
+    target_dir: T,
+    location: &StoreLocation,
+) -> Result<Vec<PathBuf>, MirageError> {
+    let mut roots = Vec::new();
+    let store = location.store(target_dir.as_ref());
+    for entry in walkdir::WalkDir::new(&target_dir)
+        .min_depth(1)
+        .max_depth(1)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|f| f.path() != store)
     {
-        match action.action {
-            ActionType::Copy => {
-                debug!(
-                    "Copying file from {:?} to {:?}",
-                    action.source, action.target
-                );
-                // TODO: this shouldn't be dangerous as target will always be symlinks
-                if action.target.exists() {
-                    fs::remove_file(action.target.as_path())?;
-                }
-                fs::copy(action.source.as_path(), action.target.as_path())?;
-            }
-            ActionType::Symlink => {
-                symlink_file(action.source.as_path(), action.target.as_path())?;
-            }
-            ActionType::NOP => {
-                // do nothing
-                debug!("NOP action, doing nothing");
-            }
+        let entry = entry?;
+        if entry.file_type().is_dir() {
+            roots.push(entry.path().to_path_buf());
         }
     }
+    Ok(roots)
+}
 
-    // remove .mirage directory
-
-    let mirage_path = state.source_path;
-    if mirage_path.exists() {
-        fs::remove_dir_all(mirage_path)?;
+/// Runs `op` on every root, carrying on past failures so that one broken
+/// store never blocks the others, and returns the first error.
+fn for_each_root<F, R>(roots: Vec<PathBuf>, mut op: F) -> Result<Vec<R>, MirageError>
+where
+    F: FnMut(&Path) -> Result<R, MirageError>,
+{
+    let mut first_err = None;
+    let mut results = Vec::new();
+    for root in roots {
+        debug!("Processing independent root {:?}", root);
+        match op(&root) {
+            Ok(result) => results.push(result),
+            Err(err) => {
+                warn!("Failed to process {:?}: {:?}", root, err);
+                first_err.get_or_insert(err);
+            }
+        }
     }
+    first_err.map_or(Ok(results), Err)
+}
 
-    Ok(())
+/// Whether `entry` is the store at `own`, or the store of a tree nested in
+/// it. Other names merely starting like a store are files like any other.
+fn is_store(entry: &DirEntry, own: &Path, name: &OsStr) -> bool {
+    entry.path() == own
+        || (entry.file_type().is_dir()
+            && entry.file_name() == name
+            && entry.path().join("wal.json").is_file())
 }
 
-pub fn check_if_files_are_same(here: &Path, there: &Path) -> Result<bool, MirageError> {
-    // compare hashes of files
-    let h_meta = here.metadata()?;
-    let t_meta = there.metadata()?;
-    if h_meta.len() != t_meta.len() {
-        return Ok(false);
+/// Canonicalizes a path without resolving it if it is itself a symlink.
+fn canonicalize_link(path: &Path) -> io::Result<PathBuf> {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) if !parent.as_os_str().is_empty() => {
+            Ok(fs::canonicalize(parent)?.join(name))
+        }
+        _ => fs::canonicalize(path),
     }
-    return full_match(here, there);
-    // Ok(here_hash == there_hash)
 }
 
-pub fn full_match(here: &Path, there: &Path) -> Result<bool, MirageError> {
-    let file1 = File::open(here)?;
-    let mut reader1 = BufReader::new(file1);
-    let file2 = File::open(there)?;
-    let mut reader2 = BufReader::new(file2);
-    let mut buf1 = [0; 10000];
-    let mut buf2 = [0; 10000];
-    loop {
-        if let Result::Ok(n1) = reader1.read(&mut buf1) {
-            if n1 > 0 {
-                if let Result::Ok(n2) = reader2.read(&mut buf2) {
-                    if n1 == n2 {
-                        if buf1 == buf2 {
-                            continue;
-                        }
-                    }
-                    trace!("not equal");
-                    return Ok(false);
+/// Finds redirected paths that have disappeared since the last run and re-keys
+/// them onto the path they were renamed to, so that a moved file is not treated
+/// as a brand new duplicate with its own original.
+///
+/// A rename is recognised either by a symlink that still points at the
+/// original, or by a regular file whose contents match the original.
+fn detect_renames<T: AsRef<Path>>(
+    state: &mut MirageState,
+    target_dir: T,
+    location: &StoreLocation,
+) -> Result<(), MirageError> {
+    let mut missing = state
+        .wal
+        .redirections
+        .iter()
+        .filter(|(path, _)| fs::symlink_metadata(path).is_err())
+        .map(|(path, original)| (path.clone(), original.clone()))
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    missing.sort();
+    debug!("{} redirected paths are missing", missing.len());
+
+    let (store, name) = (state.source_path.clone(), location.name());
+    for here in walkdir::WalkDir::new(&target_dir)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|f| !is_store(f, &store, name))
+    {
+        if missing.is_empty() {
+            break;
+        }
+        if let Err(x) = here {
+            warn_walk_error(&x);
+            continue;
+        }
+        let here = here.unwrap();
+        if here.file_type().is_dir() {
+            continue;
+        }
+        let path = canonicalize_link(here.path())?;
+        if state.wal.redirections.contains_key(&path) {
+            continue;
+        }
+
+        let found = if here.path_is_symlink() {
+            let pointee = fs::read_link(here.path())?;
+            missing
+                .iter()
+                .position(|(_, original)| *original == pointee)
+        } else {
+            let mut found = None;
+            for (i, (_, original)) in missing.iter().enumerate() {
+                if original.exists() && check_if_files_are_same(&path, original)? {
+                    found = Some(i);
+                    break;
                 }
-            } else {
-                break;
             }
-        } else {
-            break;
+            found
+        };
+        let Some(i) = found else {
+            continue;
+        };
+
+        let (old, original) = missing.remove(i);
+        debug!("Detected rename of {:?} to {:?}", old, path);
+        for index in 0..state.wal.actions.len() {
+            if state.wal.actions[index].source == old {
+                state.wal.actions[index].source = path.clone();
+                state.wal.touch(index);
+            }
+        }
+        state.wal.redirections.remove(&old);
+        state
+            .wal
+            .redirections
+            .insert(path.clone(), original.clone());
+        if !here.path_is_symlink() {
+            // a renamed hard link or clone stays what it was, a link replaced
+            // by a real copy is pointed back at the original the way it was
+            let mode = state
+                .wal
+                .actions
+                .iter()
+                .rev()
+                .find(|a| a.source == path && a.action.links())
+                .map_or(ActionType::Symlink, |a| a.action.clone());
+            let relink = match mode {
+                ActionType::Hardlink => !diff::same_inode(&here.metadata()?, &original),
+                ActionType::Reflink => false,
+                _ => true,
+            };
+            if relink {
+                state.wal.push(Action::new(mode, path, original));
+            }
         }
+        state.commit()?;
     }
-    trace!("equal");
-    return Ok(true);
+
+    Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use std::fs::{self, read_link, File};
-    use std::io::Read;
-    use std::io::Write;
-    use std::path::{Path, PathBuf};
+/// Walks the files of `target_dir` that apply considers, skipping the store.
+///
+/// In snapshot mode the top-level directories are visited newest first, so
+/// that files of the latest snapshot become the canonical originals.
+fn walk(
+    target_dir: &Path,
+    options: &ApplyOptions,
+) -> impl Iterator<Item = walkdir::Result<DirEntry>> {
+    let snapshots = options.snapshots;
+    let ignore_junk = !options.no_default_ignores;
+    let ignore_vcs = !options.include_vcs;
+    let location = options.location();
+    let (store, name) = (location.store(target_dir), location.name().to_os_string());
+    let excluded = options
+        .exclude_extensions
+        .iter()
+        .map(|extension| extension.to_lowercase())
+        .collect::<Vec<_>>();
+    let included = options
+        .include
+        .iter()
+        .map(|pattern| Glob::new(pattern))
+        .collect::<Vec<_>>();
+    let root = target_dir.to_path_buf();
+    let mut ignores = Ignores::new(
+        target_dir,
+        ignore_files(options.respect_gitignore, !options.no_ignore_files),
+    );
+    walkdir::WalkDir::new(target_dir)
+        .follow_links(options.follow_symlinks)
+        .max_depth(options.max_depth.unwrap_or(usize::MAX))
+        .same_file_system(options.one_file_system)
+        .sort_by(move |a, b| {
+            let order = a.file_name().cmp(b.file_name());
+            if snapshots && a.depth() == 1 {
+                order.reverse()
+            } else {
+                order
+            }
+        })
+        .into_iter()
+        .filter_entry(move |f| {
+            !is_store(f, &store, &name)
+                && (!ignore_junk || !is_junk(f))
+                && (!ignore_vcs || !is_vcs(f))
+                && (f.file_type().is_dir()
+                    || (extension_of(f.path()).is_none_or(|e| !excluded.contains(&e))
+                        && is_included(f.path(), &root, &included)))
+                && ignores.as_mut().is_none_or(|ignores| {
+                    ignores.ignoring(f.path(), f.file_type().is_dir()).is_none()
+                })
+        })
+}
 
-    use log::debug;
-    use tempfile::tempdir;
+/// Logs a soft error met while walking a tree, spelling out symlink loops so
+/// the user knows which link to fix.
+fn warn_walk_error(err: &walkdir::Error) {
+    #[cfg(unix)]
+    let looping = err
+        .io_error()
+        .is_some_and(|err| err.raw_os_error() == Some(libc::ELOOP));
+    #[cfg(not(unix))]
+    let looping = false;
+    match (err.path(), err.loop_ancestor()) {
+        (Some(path), Some(ancestor)) => warn!(
+            "Symlink loop at {:?} leads back to {:?}, not following it",
+            path, ancestor
+        ),
+        (Some(path), None) if looping => {
+            warn!("Symlink cycle at {:?}, not following it", path)
+        }
+        _ => warn!("Can't access {:?} due to {:?}", err.path(), err.io_error()),
+    }
+}
 
-    use crate::{apply, revert};
+/// Lists the roots of mirage stores nested anywhere below `target_dir`.
+pub fn find_nested_stores<T: AsRef<Path>>(target_dir: T) -> Result<Vec<PathBuf>, MirageError> {
+    nested_stores(target_dir, &state_dir_name())
+}
 
-    enum TestFsObject {
-        File {
-            name: String,
-            contents: String,
-        },
-        Dir {
-            name: String,
-            contents: Vec<TestFsObject>,
-        },
+/// Like [`find_nested_stores`], with state directories called `name`.
+fn nested_stores<T: AsRef<Path>>(target_dir: T, name: &OsStr) -> Result<Vec<PathBuf>, MirageError> {
+    let mut nested = Vec::new();
+    let mut walker = walkdir::WalkDir::new(&target_dir)
+        .min_depth(1)
+        .sort_by_file_name()
+        .into_iter();
+    while let Some(entry) = walker.next() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(x) => {
+                warn_walk_error(&x);
+                continue;
+            }
+        };
+        if !entry.file_type().is_dir() || entry.file_name() != name {
+            continue;
+        }
+        // never look inside a store
+        walker.skip_current_dir();
+        if entry.depth() > 1 {
+            if let Some(root) = entry.path().parent() {
+                debug!("Found nested store at {:?}", root);
+                nested.push(root.to_path_buf());
+            }
+        }
     }
+    Ok(nested)
+}
 
-    struct TestFsView<'a> {
-        base_obj: &'a TestFsObject,
-        base_path: PathBuf,
+/// Where the tree at `target_dir` keeps its originals when they are on
+/// another volume, `None` if they are in its store or it isn't managed.
+pub fn originals_dir<T: AsRef<Path>>(target_dir: T) -> Result<Option<PathBuf>, MirageError> {
+    if !store_path(target_dir.as_ref()).join("wal.json").is_file() {
+        return Ok(None);
     }
+    Ok(MirageState::open(target_dir)?.wal.originals_dir)
+}
 
-    impl<'a> TestFsView<'a> {
-        fn new(base_obj: &'a TestFsObject, base_path: PathBuf) -> Self {
-            TestFsView {
-                base_obj,
-                base_path,
-            }
+pub fn apply<T: AsRef<Path>>(target_dir: T) -> Result<ApplyReport, MirageError> {
+    apply_with_options(target_dir, &ApplyOptions::default())
+}
+
+/// The root of the tree whose store at `location` manages `target_dir`: the
+/// nearest ancestor with a store when `target_dir` has none of its own, so
+/// that applying to a subdirectory of a managed tree links to its originals.
+fn managing_root(target_dir: &Path, location: &StoreLocation) -> PathBuf {
+    if location.store(target_dir).join("wal.json").is_file() {
+        return target_dir.to_path_buf();
+    }
+    match find_store_root_at(target_dir, location) {
+        Ok(root) => {
+            debug!("{:?} is managed by the store at {:?}", target_dir, root);
+            root
         }
+        Err(_) => target_dir.to_path_buf(),
+    }
+}
 
-        fn get_children(&self) -> Vec<TestFsView> {
-            self.base_obj
-                .get_children()
-                .into_iter()
-                .map(|f| TestFsView::new(f, self.base_path.join(self.base_obj.get_name())))
-                .collect::<Vec<_>>()
+/// The canonical paths of `others`, failing if one of them overlaps `root` or
+/// another one, or is managed by a store other than `store_root`.
+fn other_roots(
+    root: &Path,
+    store_root: &Path,
+    others: &[PathBuf],
+    location: &StoreLocation,
+) -> Result<Vec<PathBuf>, MirageError> {
+    let mut roots = vec![root.to_path_buf()];
+    for other in others {
+        let other = fs::canonicalize(other)?;
+        if let Some(overlapping) = roots
+            .iter()
+            .find(|root| other.starts_with(root) || root.starts_with(&other))
+        {
+            return Err(MirageError::OverlappingRoots(overlapping.clone(), other));
+        }
+        let managing = managing_root(&other, location);
+        if managing != store_root && location.store(&managing).join("wal.json").is_file() {
+            return Err(MirageError::AncestorStore(managing));
         }
+        roots.push(other);
+    }
+    roots.remove(0);
+    Ok(roots)
+}
 
-        fn is_symlink(&self) -> bool {
-            self.base_obj.is_symlink(&self.base_path)
+pub fn apply_with_options<T: AsRef<Path>>(
+    target_dir: T,
+    options: &ApplyOptions,
+) -> Result<ApplyReport, MirageError> {
+    if let Some(file) = &options.metrics_file {
+        let options = ApplyOptions {
+            metrics_file: None,
+            ..options.clone()
+        };
+        let run = RunMetrics::start();
+        let result = apply_with_options(target_dir.as_ref(), &options);
+        run.write(
+            file,
+            target_dir.as_ref(),
+            &options.location(),
+            options.redaction,
+            &result,
+        )?;
+        return result;
+    }
+    let location = options.location();
+    let store_root = managing_root(target_dir.as_ref(), &location);
+    if options.per_subdirectory {
+        if location.store(&store_root).join("wal.json").exists() {
+            return Err(MirageError::AncestorStore(store_root));
         }
+        let options = ApplyOptions {
+            per_subdirectory: false,
+            ..options.clone()
+        };
+        let reports = for_each_root(subdirectory_roots_at(&target_dir, &location)?, |root| {
+            apply_with_options(root, &options)
+        })?;
+        let mut report = ApplyReport::default();
+        for other in reports {
+            report.extend(other);
+        }
+        return Ok(report);
+    }
 
-        fn get_fs_metadata(&self) -> fs::Metadata {
-            self.base_obj.get_path(&self.base_path).metadata().unwrap()
+    // followed links may lead anywhere, only the trees themselves are touched
+    let root = fs::canonicalize(&target_dir)?;
+    let others = other_roots(&root, &store_root, &options.other_roots, &location)?;
+    let mut nested = nested_stores(&target_dir, location.name())?;
+    for other in &others {
+        nested.extend(nested_stores(other, location.name())?);
+    }
+    for root in nested {
+        if !options.adopt_nested {
+            return Err(MirageError::NestedStore(root));
+        }
+        if options.dry_run {
+            warn!("Dry run, not adopting nested store at {:?}", root);
+            continue;
         }
+        warn!("Adopting nested store at {:?}", root);
+        // nested stores are found inside their tree
+        merge_into(
+            &store_root,
+            &location,
+            &root,
+            &location.inside(),
+            options.algorithm(),
+        )?;
+    }
 
-        fn get_full_path(&self) -> PathBuf {
-            self.base_obj.get_path(&self.base_path)
+    let mut state = if options.dry_run {
+        MirageState::peek_at(&store_root, &location)?
+    } else {
+        MirageState::get_at(&store_root, &location)?
+    };
+    state.ensure_unfrozen()?;
+    state.lock_run()?;
+    state.probe_links(LinkMode::Symlink)?;
+    if let Some(mode) = options.link_mode {
+        state.require_links(mode)?;
+    }
+    if options.dedupe_extents {
+        state.require_extent_sharing()?;
+    }
+    let linker = Linker::new(&state, options.link_policy.as_ref(), options.link_mode);
+    if options
+        .link_mode
+        .is_some_and(|mode| mode != LinkMode::Symlink)
+        || options.link_policy.is_some()
+    {
+        for other in others
+            .iter()
+            .filter(|other| !same_device(other, &state.source_path))
+        {
+            warn!(
+                "{:?} is on another device than the store, its files are symlinked",
+                other
+            );
         }
+    }
+    let mut work = Budget::new(options);
+    back_up(&state, "apply", options.backups.unwrap_or(DEFAULT_BACKUPS))?;
+    let mut report = ApplyReport {
+        dry_run: state.dry_run,
+        ..Default::default()
+    };
 
-        fn verify(&self) {
-            // verify that the structure is correct that files are not symlinks
-            // verify that the contents are correct
-            // verify that directories are not symlinks
+    if let Some(volume) = &options.store_volume {
+        state.place_originals(volume, &fs::canonicalize(&store_root)?)?;
+    }
 
-            // verify that the contents are correct
-            if self.base_obj.is_symlink(&self.base_path) {
-                panic!("Object is a symlink");
+    detect_renames(&mut state, &store_root, &location)?;
+    for other in &others {
+        detect_renames(&mut state, other, &location)?;
+    }
+    state.hint_sharding();
+
+    let mut store_size = state.store_size()?;
+    let mut over_quota: HashSet<PathBuf> = HashSet::new();
+    let in_tree = |path: &Path| {
+        (path.starts_with(&root) || others.iter().any(|other| path.starts_with(other)))
+            && !path.starts_with(&state.source_path)
+    };
+
+    // only files of the same size can match, unless metadata is ignored
+    let budget = options.memory_budget.unwrap_or(DEFAULT_MEMORY_BUDGET);
+    let spill_dir = (!state.dry_run).then(|| state.source_path.join("tmp"));
+    let mut grouper = Grouper::new(budget, spill_dir);
+    let mut progress = ProgressWriter::new(&state);
+    let mut decisions = Decisions::new(&state, options)?;
+    let entries = walk(target_dir.as_ref(), options)
+        .chain(others.iter().flat_map(|other| walk(other, options)));
+    for entry in entries {
+        check_interrupted()?;
+        debug!("Try Processing file {:?}", entry);
+        // handle soft errors here
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(x) => {
+                warn_walk_error(&x);
+                continue;
             }
-            if self.get_fs_metadata().file_type().is_dir() {
-                for child in self.get_children() {
-                    child.verify();
+        };
+        if entry.path_is_symlink() {
+            trace!("Skipping symlink {:?}", entry.path());
+            continue;
+        }
+        if entry.file_type().is_dir() {
+            trace!("Skipping dir {:?}", entry.path());
+            continue;
+        }
+        let path = fs::canonicalize(entry.path())?;
+        if !in_tree(&path) {
+            trace!("Skipping {:?}, it resolves outside the tree", path);
+            decisions.record(&path, || Decision::OutsideTree);
+            continue;
+        }
+        if options
+            .files_from
+            .as_ref()
+            .is_some_and(|listed| !listed.contains(&path))
+        {
+            trace!("Skipping {:?}, it isn't listed", path);
+            decisions.record(&path, || Decision::NotListed);
+            continue;
+        }
+        let len = entry.metadata()?.len();
+        if options.min_size.is_some_and(|min| len < min) {
+            trace!("Skipping {:?}, it is smaller than the minimum size", path);
+            decisions.record(&path, || Decision::TooSmall);
+            continue;
+        }
+        // files only matching with metadata ignored may differ in size,
+        // not in the length of what is compared of them
+        let size = if options.ignore_metadata {
+            metadata::payload_len(&path)?
+        } else {
+            len
+        };
+        progress.scanned(&path)?;
+        grouper.push(size, path)?;
+    }
+
+    progress.enter(&state, Phase::Comparing)?;
+    let mut cache = Cache::load(&state, options.algorithm())?;
+    let known = known_contents(&state, &mut cache)?;
+    let resume = resume_point(&state)?;
+    let jobs = options.jobs.unwrap_or_else(default_jobs);
+    'groups: for group in grouper.finish()? {
+        // the last run got through the groups before the one it stopped at
+        if resume.as_ref().is_some_and(|from| group[0] < *from) {
+            continue;
+        }
+        let index = if work.exhausted() {
+            DuplicateIndex::default()
+        } else {
+            DuplicateIndex::build(&group, options, jobs, &mut cache)?
+        };
+        if options.dedupe_extents && !work.exhausted() {
+            plan_sharing(&mut state, &index, &mut decisions)?;
+            for here in &group {
+                decisions.record(here, || match state.wal.redirections.get(here) {
+                    Some(original) => Decision::AlreadyManaged {
+                        original: original.clone(),
+                    },
+                    None if group.len() == 1 => Decision::UniqueSize,
+                    None => Decision::NoMatch {
+                        compared: group.len() - 1,
+                    },
+                });
+            }
+            continue;
+        }
+        // files of other formats aren't compared when asked not to
+        let mut classes = Vec::new();
+        for class in index.classes() {
+            if options.same_extension_only {
+                let mut formats: BTreeMap<Option<String>, Vec<&PathBuf>> = BTreeMap::new();
+                for path in class {
+                    formats.entry(extension_of(path)).or_default().push(path);
                 }
+                classes.extend(formats.into_values().filter(|class| class.len() > 1));
             } else {
-                let mut file = File::open(self.get_full_path()).unwrap();
-                let mut contents = String::new();
-                file.read_to_string(&mut contents).unwrap();
-                assert_eq!(contents, self.base_obj.get_contents());
+                classes.push(class);
             }
         }
-    }
-
-    impl TestFsObject {
-        fn get_view(&self, base_path: &Path) -> TestFsView {
-            TestFsView::new(self, base_path.to_path_buf())
+        // the rest of a class is linked along with its first file
+        let followers = classes
+            .iter()
+            .flat_map(|class| class[1..].iter().copied())
+            .collect::<HashSet<_>>();
+        let mut processed = HashSet::new();
+        let mut managed = HashMap::new();
+        let mut stopped = false;
+        for here in &group {
+            check_interrupted()?;
+            if work.exhausted() {
+                debug!("Out of budget, stopping before {:?}", here);
+                report.stopped_at = Some(group[0].clone());
+                stopped = true;
+                break;
+            }
+            if over_quota.contains(here) {
+                trace!("Skipping {:?}, its group does not fit in the store", here);
+                decisions.record(here, || Decision::OverQuota);
+                continue;
+            }
+            debug!("Processing file {:?}", here.as_path());
+            progress.comparing(&state, &cache, here)?;
+            processed.insert(here);
+            if let Some(original) = state.wal.redirections.get(here) {
+                managed.insert(here, original.clone());
+                continue;
+            }
+            if group.len() > 1 && !followers.contains(here) {
+                work.spend();
+            }
+            // link to an original already in the store if there is one
+            if let Some(original) =
+                find_original(&mut state, here, options, known.as_ref(), &mut cache)?
+            {
+                debug!("Found existing original {:?} for {:?}", original, here);
+                state.wal.push(Action::new(
+                    linker.action(here, &original),
+                    here.clone(),
+                    original.clone(),
+                ));
+                decisions.record(here, || Decision::LinkedToOriginal {
+                    original: original.clone(),
+                });
+                state.wal.redirections.insert(here.clone(), original);
+                state.commit()?;
+            }
         }
+        // link every file of a class to one original
+        for class in classes {
+            let first = class[0];
+            if over_quota.contains(first) || !processed.contains(first) {
+                continue;
+            }
+            for there in &class[1..] {
+                debug!("{:?} is the same as {:?}", there, first);
+                decisions.compared(first, there, true)?;
+            }
+            let existing = class
+                .iter()
+                .find_map(|&path| state.wal.redirections.get(path).cloned());
+            let original = match existing {
+                Some(original) => original,
+                None => {
+                    let size = first.metadata()?.len();
+                    if let Some(max) = options.max_store_size {
+                        if store_size + size > max {
+                            warn!("Store quota reached, skipping group of {:?}", first);
+                            report.skipped_over_quota.push(first.clone());
+                            // every copy of it is skipped
+                            over_quota.extend(class.iter().map(|&path| path.clone()));
+                            continue;
+                        }
+                    }
+                    store_size += size;
 
-        fn create(&self, base_path: &Path) {
-            match self {
-                TestFsObject::File { name, contents } => {
-                    let file_path = base_path.join(name);
-                    let mut file = File::create(file_path).unwrap();
-                    file.write_all(contents.as_bytes()).unwrap();
-                    file.flush().unwrap();
-                    file.sync_all().unwrap();
-                }
-                TestFsObject::Dir { name, contents } => {
-                    let dir_path = base_path.join(name);
-                    fs::create_dir(&dir_path).unwrap();
-                    for content in contents {
-                        content.create(&dir_path);
+                    // copy the first file into originals, named after the
+                    // most dated one of the class if asked to
+                    let mut seed = first;
+                    if options.prefer_dated_dirs {
+                        for &path in &class[1..] {
+                            if date_score(path) > date_score(seed) {
+                                seed = path;
+                            }
+                        }
                     }
+                    let content = cache.hash(first)?;
+                    let name = seed
+                        .file_name()
+                        .ok_or(MirageError::DotMirageInInconsistentState)?;
+                    let original_path = state.new_original_path(name, &content);
+
+                    let mut action =
+                        Action::new(ActionType::Copy, first.clone(), original_path.clone());
+                    action.digest = Some(content);
+                    action.algorithm = Some(cache.algorithm());
+                    state.wal.push(action);
+                    state.index_original(
+                        content_len(first, options)?,
+                        content_digest(first, options, &mut cache)?,
+                        original_path.clone(),
+                    );
+                    original_path
+                }
+            };
+            for &path in &class {
+                if state.wal.redirections.contains_key(path) {
+                    continue;
                 }
+                state.wal.push(Action::new(
+                    linker.action(path, &original),
+                    path.to_path_buf(),
+                    original.clone(),
+                ));
+                state
+                    .wal
+                    .redirections
+                    .insert(path.to_path_buf(), original.clone());
+                decisions.record(path, || Decision::Deduplicated {
+                    original: original.clone(),
+                });
             }
+            state.commit()?;
+        }
+        if stopped {
+            break 'groups;
+        }
+        let mut formats: HashMap<Option<String>, usize> = HashMap::new();
+        if options.same_extension_only {
+            for path in &group {
+                *formats.entry(extension_of(path)).or_default() += 1;
+            }
+        }
+        for here in &group {
+            decisions.record(here, || {
+                if over_quota.contains(here) {
+                    Decision::OverQuota
+                } else if let Some(original) = managed.remove(here) {
+                    Decision::AlreadyManaged { original }
+                } else if let Some(original) = state.wal.redirections.get(here) {
+                    Decision::Deduplicated {
+                        original: original.clone(),
+                    }
+                } else if group.len() == 1 {
+                    Decision::UniqueSize
+                } else if options.same_extension_only {
+                    Decision::NoMatch {
+                        compared: formats[&extension_of(here)] - 1,
+                    }
+                } else {
+                    Decision::NoMatch {
+                        compared: group.len() - 1,
+                    }
+                }
+            });
+        }
+    }
+
+    if state.dry_run {
+        report.planned = state.wal.actions[state.wal.checkpoint..]
+            .iter()
+            .map(PlannedAction::from)
+            .collect();
+    }
+    let executed = state.wal.checkpoint;
+    report.groups = duplicate_groups(&state.wal.actions[executed..], &mut cache)?;
+    progress.tally(&cache);
+    progress.enter(&state, Phase::Executing)?;
+    report.rolled_back = execute_transactions(&mut state, &mut progress)?;
+    drop_rolled_back(&mut report.groups, &report.rolled_back);
+    progress.enter(&state, Phase::Done)?;
+    let done = progress.progress();
+    report.bytes_hashed = done.bytes_hashed;
+    report.bytes_compared = done.bytes_compared;
+    report.bytes_copied = done.bytes_copied;
+    report.files_scanned = done.files_scanned;
+    report.actions_executed = progress.executed_actions();
+    report.times = PhaseTimes {
+        committing: state.committing,
+        ..progress.times()
+    };
+    decisions.rolled_back(&report.rolled_back);
+    decisions.save()?;
+    save_resume_point(&state, report.stopped_at.as_deref())?;
+    report.store_size = state.store_size()?;
+    if !state.dry_run {
+        report.bytes_saved =
+            record_session(&state, &state.wal.actions[executed..], report.store_size)?;
+        record_originals(&state, known, &state.wal.actions[executed..], &mut cache)?;
+    }
+    cache.save()?;
+
+    if options.snapshots {
+        report.snapshot_savings = snapshot_savings(&state, &state.root, &location)?;
+    }
+
+    Ok(report)
+}
+
+/// Finds an original in the store with the same contents as `path`.
+fn find_original(
+    state: &mut MirageState,
+    path: &Path,
+    options: &ApplyOptions,
+    known: Option<&Bloom>,
+    cache: &mut Cache,
+) -> Result<Option<PathBuf>, MirageError> {
+    // the filter holds whole contents, which metadata-blind matching ignores
+    if let Some(known) = known.filter(|_| !options.ignore_metadata) {
+        if !known.contains(&cache.hash(path)?) {
+            trace!("{:?} is unlike every original", path);
+            return Ok(None);
+        }
+    }
+    for original in state.originals_like(path, options, cache)? {
+        if options.same_extension_only && extension_of(path) != extension_of(&original) {
+            continue;
+        }
+        if original.exists() && files_match(path, &original, options, cache)? {
+            return Ok(Some(original));
+        }
+    }
+    Ok(None)
+}
+
+/// Executes every action past the checkpoint, committing after each one.
+fn execute_pending(state: &mut MirageState) -> Result<(), MirageError> {
+    if state.dry_run {
+        debug!("Dry run, not executing pending actions");
+        return Ok(());
+    }
+    while state.wal.checkpoint < state.wal.actions.len() {
+        check_interrupted()?;
+        execute(
+            &state.wal.actions[state.wal.checkpoint],
+            state.wal.checkpoint,
+            &state.source_path,
+        )?;
+        state.wal.checkpoint += 1;
+        state.commit()?;
+    }
+
+    Ok(())
+}
+
+/// Copies `source` to `target` through a file staged in `dir`, synced
+/// before it is renamed into place, so `target` is either missing or whole
+/// whenever anything links to it. Returns the number of bytes copied.
+fn publish_copy(
+    source: &Path,
+    target: &Path,
+    dir: &Path,
+    index: usize,
+) -> Result<u64, MirageError> {
+    fs::create_dir_all(dir)?;
+    // missing for the first original of a shard
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let staged = dir.join(format!("copy-{}.tmp", index));
+    let published = (|| {
+        let copied = fs::copy(source, &staged)?;
+        // originals stay with whoever owned the file they came from
+        restore_owner(&staged, owner_of(source))?;
+        File::open(&staged)?.sync_all()?;
+        fs::rename(&staged, target)?;
+        // the rename itself must reach the disk before links to it do
+        #[cfg(unix)]
+        if let Some(parent) = target.parent() {
+            File::open(parent)?.sync_all()?;
+        }
+        Ok(copied)
+    })();
+    if published.is_err() && fs::symlink_metadata(&staged).is_ok() {
+        fs::remove_file(&staged)?;
+    }
+    // shared with spilled runs, whoever leaves it last removes it
+    let _ = fs::remove_dir(dir);
+    published
+}
+
+/// Executes one action, the one at `index` of the WAL of the store at
+/// `state_dir`, returning the number of bytes it copied.
+fn execute(action: &Action, index: usize, state_dir: &Path) -> Result<u64, MirageError> {
+    let mut copied = 0;
+    match action.action {
+        ActionType::Copy => {
+            debug!(
+                "Copying file from {:?} to {:?}",
+                action.source, action.target
+            );
+            // staged in the store's tmp unless the originals are kept on
+            // another volume, which a rename can't cross
+            let dir = if action.target.starts_with(state_dir) {
+                state_dir.join("tmp")
+            } else {
+                action
+                    .target
+                    .parent()
+                    .ok_or(MirageError::DotMirageInInconsistentState)?
+                    .to_path_buf()
+            };
+            copied = publish_copy(&action.source, &action.target, &dir, index)?;
+        }
+        ActionType::Symlink => {
+            debug!(
+                "Creating symlink from {:?} to {:?}",
+                action.source, action.target
+            );
+            let owner = owner_of(&action.source);
+            if fs::symlink_metadata(action.source.as_path()).is_ok() {
+                fs::remove_file(action.source.as_path())?;
+            }
+            // horrible convention should fix
+            symlink_file(action.target.as_path(), action.source.as_path())?;
+            restore_owner(&action.source, owner)?;
+        }
+        ActionType::NOP => {
+            // do nothing
+            debug!("NOP action, doing nothing");
+        }
+        ActionType::Hardlink => {
+            debug!(
+                "Creating hard link from {:?} to {:?}",
+                action.source, action.target
+            );
+            if fs::symlink_metadata(action.source.as_path()).is_ok() {
+                fs::remove_file(action.source.as_path())?;
+            }
+            // shares the inode, and so the owner, of the original
+            fs::hard_link(action.target.as_path(), action.source.as_path())?;
+        }
+        ActionType::Reflink => {
+            debug!("Cloning {:?} over {:?}", action.target, action.source);
+            // clone next to the file and rename over it, cloning may be
+            // unsupported by the filesystem
+            let mut tmp_name = OsString::from(".");
+            tmp_name.push(action.source.file_name().unwrap_or_default());
+            tmp_name.push(".mirage-tmp");
+            let tmp = action.source.with_file_name(tmp_name);
+            let owner = owner_of(&action.source);
+            reflink(&action.target, &tmp)?;
+            restore_owner(&tmp, owner)?;
+            fs::rename(&tmp, &action.source)?;
+        }
+        ActionType::Delete => {
+            debug!("Deleting {:?}, kept as {:?}", action.source, action.target);
+            if fs::symlink_metadata(action.source.as_path()).is_ok() {
+                fs::remove_file(action.source.as_path())?;
+            }
+        }
+        ActionType::Move => {
+            debug!("Moving {:?} to {:?}", action.source, action.target);
+            fs::rename(action.source.as_path(), action.target.as_path())?;
+        }
+        ActionType::Dedupe => {
+            debug!(
+                "Sharing the extents of {:?} with {:?}",
+                action.target, action.source
+            );
+            share_extents(&action.target, &action.source)?;
+        }
+        ActionType::Unknown(_) => {
+            return Err(MirageError::UnknownAction(index));
+        }
+    }
+    Ok(copied)
+}
+
+pub fn revert<T: AsRef<Path>>(target_dir: T) -> Result<RevertReport, MirageError> {
+    revert_with_options(target_dir, &RevertOptions::default())
+}
+
+pub fn revert_with_options<T: AsRef<Path>>(
+    target_dir: T,
+    options: &RevertOptions,
+) -> Result<RevertReport, MirageError> {
+    let location = options.location();
+    if options.per_subdirectory {
+        let roots = subdirectory_roots_at(&target_dir, &location)?
+            .into_iter()
+            .filter(|root| location.store(root).exists())
+            .collect();
+        let options = RevertOptions {
+            per_subdirectory: false,
+            ..options.clone()
+        };
+        let reports = for_each_root(roots, |root| {
+            let options = RevertOptions {
+                archive_state: options
+                    .archive_state
+                    .as_ref()
+                    .map(|to| archive_path_for(to, root)),
+                ..options.clone()
+            };
+            revert_with_options(root, &options)
+        })?;
+        let mut report = RevertReport {
+            dry_run: options.dry_run,
+            ..Default::default()
+        };
+        for other in reports {
+            report.dry_run |= other.dry_run;
+            report.planned.extend(other.planned);
+            report.verified += other.verified;
+            report.mismatched.extend(other.mismatched);
+            report.unverified += other.unverified;
+            report.lost.extend(other.lost);
+            report.restored += other.restored;
+            report.skipped.extend(other.skipped);
+            report.failed.extend(other.failed);
+            report.archived_to.extend(other.archived_to);
+            report.space.extend(other.space);
+        }
+        return Ok(report);
+    }
+
+    let root = managing_root(target_dir.as_ref(), &location);
+    if let Some(name) = &options.to {
+        return revert_to(&root, name, options);
+    }
+    if !options.only.is_empty() {
+        return revert_only(&root, &options.only, options);
+    }
+    // a path below a managed tree reverts just that part of it
+    if root != target_dir.as_ref() {
+        return revert_only(&root, &[target_dir.as_ref().to_path_buf()], options);
+    }
+
+    // a dry run leaves a tree without a store without one
+    let mut state = if options.dry_run {
+        MirageState::peek_at(&target_dir, &location)?
+    } else {
+        MirageState::get_at(&target_dir, &location)?
+    };
+    state.ensure_unfrozen()?;
+    state.lock_run()?;
+
+    let inverted = state
+        .wal
+        .actions
+        .iter()
+        .rev()
+        .skip(state.wal.actions.len() - state.wal.checkpoint)
+        .map(|f| f.invert())
+        .collect::<Vec<_>>();
+    let space = space_needed(&inverted, options.as_hardlinks)?;
+
+    if state.dry_run {
+        return Ok(RevertReport {
+            dry_run: true,
+            planned: inverted.iter().map(PlannedAction::from).collect(),
+            space,
+            ..Default::default()
+        });
+    }
+    ensure_space(&space)?;
+
+    back_up(&state, "revert", options.backups.unwrap_or(DEFAULT_BACKUPS))?;
+    let mut stages = stages(inverted.into_iter());
+    let missing = remove_missing_originals(&mut stages);
+    if let Some(action) = missing.first().filter(|_| !options.force) {
+        return Err(MirageError::MissingOriginal(action.source.clone()));
+    }
+    // the original each file ends up restored from
+    let restored = stages
+        .iter()
+        .flatten()
+        .flatten()
+        .filter(|action| matches!(action.action, ActionType::Copy))
+        .map(|action| (action.target.clone(), action.source.clone()))
+        .collect::<HashMap<_, _>>();
+    // undoing again is harmless, a new revert starts over
+    let mut report = undo_stages(
+        stages,
+        options.jobs.unwrap_or_else(default_jobs),
+        options.as_hardlinks,
+    )?;
+    report.space = space;
+    for action in missing {
+        if !restored.contains_key(&action.target) && mark_lost(&action)? {
+            report.lost.push(action.target);
+        }
+    }
+    report.lost.sort();
+    report.lost.dedup();
+    if !report.failed.is_empty() {
+        warn!(
+            "{} files failed to be restored, keeping {:?}",
+            report.failed.len(),
+            state.source_path
+        );
+        return Ok(report);
+    }
+    if options.verify {
+        verify_restored(&state, restored, &mut report)?;
+        if !report.mismatched.is_empty() {
+            warn!(
+                "{} restored files differ from their originals, keeping {:?}",
+                report.mismatched.len(),
+                state.source_path
+            );
+            return Ok(report);
+        }
+    }
+    if let Some(to) = &options.archive_state {
+        report.archived_to.push(archive_state(&state, to)?);
+    }
+    state.remove_store()?;
+
+    Ok(report)
+}
+
+/// Takes the restores from originals missing from the store out of
+/// `stages`, returning them.
+fn remove_missing_originals(stages: &mut [Vec<Vec<Action>>]) -> Vec<Action> {
+    let mut missing = Vec::new();
+    for group in stages.iter_mut().flatten() {
+        let (gone, kept) = std::mem::take(group).into_iter().partition(|action| {
+            matches!(action.action, ActionType::Copy) && !action.source.exists()
+        });
+        *group = kept;
+        missing.extend::<Vec<_>>(gone);
+    }
+    for action in &missing {
+        warn!(
+            "Original {:?} of {:?} is missing",
+            action.source, action.target
+        );
+    }
+    missing
+}
+
+/// Leaves a marker next to a file whose original is gone, in place of the
+/// link to it. Files that hold their contents themselves, like hard links,
+/// are whole already and left alone. Whether the file was lost.
+fn mark_lost(action: &Action) -> Result<bool, MirageError> {
+    let path = &action.target;
+    if !path.is_symlink() && path.exists() {
+        debug!("{:?} outlived its original {:?}", path, action.source);
+        return Ok(false);
+    }
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".mirage-lost");
+    let marker = path.with_file_name(name);
+    debug!("Marking {:?} as lost in {:?}", path, marker);
+    if path.is_symlink() {
+        fs::remove_file(path)?;
+    }
+    fs::write(
+        marker,
+        format!(
+            "{} could not be restored by mirage: its original {} was missing from the store\n",
+            path.display(),
+            action.source.display()
+        ),
+    )?;
+    Ok(true)
+}
+
+/// Hashes every file of `restored`, mapped to the original it was restored
+/// from, against the contents recorded when that original was copied.
+fn verify_restored(
+    state: &MirageState,
+    restored: HashMap<PathBuf, PathBuf>,
+    report: &mut RevertReport,
+) -> Result<(), MirageError> {
+    let recorded = state.wal.actions[..state.wal.checkpoint]
+        .iter()
+        .filter(|action| matches!(action.action, ActionType::Copy))
+        .filter_map(|action| Some((action.target.as_path(), action.recorded_digest()?)))
+        .collect::<HashMap<_, _>>();
+    let mut restored = restored.into_iter().collect::<Vec<_>>();
+    restored.sort();
+    for (file, original) in restored {
+        check_interrupted()?;
+        let Some(&(digest, algorithm)) = recorded.get(original.as_path()) else {
+            report.unverified += 1;
+            continue;
+        };
+        if algorithm.hash_file(&file)? == digest {
+            trace!("{:?} came back unchanged", file);
+            report.verified += 1;
+        } else {
+            warn!("{:?} differs from what {:?} held", file, original);
+            report.mismatched.push(file);
+        }
+    }
+    Ok(())
+}
+
+/// Executes an action inverted by [`Action::invert`], undoing the original.
+/// With `hard_link`, files are restored as hard links to their original
+/// where the filesystem allows rather than as copies of it. Whether there
+/// was anything to do, rather than the action being skipped.
+fn undo(action: &Action, hard_link: bool) -> Result<bool, MirageError> {
+    match action.action {
+        ActionType::Copy => {
+            debug!(
+                "Copying file from {:?} to {:?}",
+                action.source, action.target
+            );
+            // TODO: this shouldn't be dangerous as target will always be symlinks
+            // the link carries the owner of the file it replaced
+            let owner = owner_of(&action.target);
+            if action.target.exists() {
+                fs::remove_file(action.target.as_path())?;
+            }
+            if hard_link {
+                match fs::hard_link(&action.source, &action.target) {
+                    // shares the inode, and so the owner, of the original
+                    Ok(()) => return Ok(true),
+                    Err(err) => debug!("Can't link {:?}, copying it: {}", action.target, err),
+                }
+            }
+            fs::copy(action.source.as_path(), action.target.as_path())?;
+            restore_owner(&action.target, owner)?;
+        }
+        ActionType::Symlink => {
+            symlink_file(action.source.as_path(), action.target.as_path())?;
+        }
+        ActionType::NOP => {
+            // do nothing
+            debug!("NOP action, doing nothing");
+        }
+        ActionType::Move => {
+            debug!("Moving {:?} back to {:?}", action.source, action.target);
+            fs::rename(action.source.as_path(), action.target.as_path())?;
+        }
+        ActionType::Hardlink | ActionType::Reflink | ActionType::Delete | ActionType::Dedupe => {
+            // no action inverts to these
+            warn!(
+                "Unexpected {} while reverting, skipping it",
+                action.action.name()
+            );
+            return Ok(false);
+        }
+        ActionType::Unknown(_) => {
+            warn!(
+                "Can't undo {} of {:?}, leaving it as is",
+                action.action.name(),
+                action.target
+            );
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Compares two files with the comparator selected by `options`.
+fn files_match(
+    here: &Path,
+    there: &Path,
+    options: &ApplyOptions,
+    cache: &mut Cache,
+) -> Result<bool, MirageError> {
+    if options.ignore_metadata {
+        // cached comparisons are of the whole contents
+        cache.count_compared(here, there)?;
+        same_ignoring_metadata(here, there)
+    } else {
+        cache.same(here, there, || check_if_files_are_same(here, there))
+    }
+}
+
+pub fn check_if_files_are_same(here: &Path, there: &Path) -> Result<bool, MirageError> {
+    // compare hashes of files
+    let h_meta = here.metadata()?;
+    let t_meta = there.metadata()?;
+    if h_meta.len() != t_meta.len() {
+        return Ok(false);
+    }
+    full_match(here, there)
+    // Ok(here_hash == there_hash)
+}
+
+/// Computes the content id of a file, the hex digest of its contents.
+pub fn hash_file(path: &Path) -> Result<String, MirageError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut context = md5::Context::new();
+    let mut buf = [0; 10000];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        context.consume(&buf[..n]);
+    }
+    Ok(format!("{:x}", context.compute()))
+}
+
+pub fn full_match(here: &Path, there: &Path) -> Result<bool, MirageError> {
+    let file1 = File::open(here)?;
+    let mut reader1 = BufReader::new(file1);
+    let file2 = File::open(there)?;
+    let mut reader2 = BufReader::new(file2);
+    let mut buf1 = [0; 10000];
+    let mut buf2 = [0; 10000];
+    while let Result::Ok(n1) = reader1.read(&mut buf1) {
+        if n1 == 0 {
+            break;
+        }
+        if let Result::Ok(n2) = reader2.read(&mut buf2) {
+            if n1 == n2 && buf1 == buf2 {
+                continue;
+            }
+            trace!("not equal");
+            return Ok(false);
+        }
+    }
+    trace!("equal");
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, read_link, File};
+    use std::io::Read;
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+
+    use log::debug;
+    use symlink::symlink_file;
+    use tempfile::tempdir;
+
+    use crate::digest::{blake3, to_hex};
+
+    use crate::{
+        adopt_hardlinks, apply, apply_plan, apply_with_options, break_stale_lock, checkpoints,
+        comparisons, create_checkpoint, delete_checkpoint, diff, diff_reports, disk_usage,
+        ensure_space, execute, execute_pending, execute_transactions, export_script,
+        external_store_path, fsck, fsck_with_options, identical_subtrees, inspect_groups,
+        inspect_wal, journal, list_groups, lock, manifest, merge, migrate, originals_dir,
+        publish_copy, read_file_list, reapply, rehash, remove, replay, replay_plan, restore_state,
+        revert, revert_with_options, set_read_only, shard, state_backups, stats, stats_history,
+        status, store_path, store_status, unlock, unshare, upgrade, verify, watch, why,
+        write_manifest_csv, Action, ActionType, ApplyOptions, BrokenLock, Config, Decision,
+        DiffEntry, Discrepancy, Divergence, DuplicateGroup, Exclusion, FsckOptions, GroupOrder,
+        GroupProgress, HashAlgorithm, Hazard, JournalEntry, LinkMode, LinkPolicy, ListedGroup,
+        LockOwner, MirageError, MirageState, Mismatch, Phase, Plan, Problem, Profile,
+        ProgressWriter, RevertOptions, RunReport, Shell, SnapshotSavings, SpaceNeeded, StoreLayout,
+        StoreLocation, SubtreeHash, Unmigrated, WalFilter, Why, CONFIG_FILE,
+        DEFAULT_HASH_ALGORITHM, IGNORE_FILE, WAL_VERSION,
+    };
+
+    enum TestFsObject {
+        File {
+            name: String,
+            contents: String,
+        },
+        Dir {
+            name: String,
+            contents: Vec<TestFsObject>,
+        },
+    }
+
+    struct TestFsView<'a> {
+        base_obj: &'a TestFsObject,
+        base_path: PathBuf,
+    }
+
+    impl<'a> TestFsView<'a> {
+        fn new(base_obj: &'a TestFsObject, base_path: PathBuf) -> Self {
+            TestFsView {
+                base_obj,
+                base_path,
+            }
+        }
+
+        fn get_children(&self) -> Vec<TestFsView<'_>> {
+            self.base_obj
+                .get_children()
+                .iter()
+                .map(|f| TestFsView::new(f, self.base_path.join(self.base_obj.get_name())))
+                .collect::<Vec<_>>()
+        }
+
+        fn is_symlink(&self) -> bool {
+            self.base_obj.is_symlink(&self.base_path)
+        }
+
+        fn get_fs_metadata(&self) -> fs::Metadata {
+            self.base_obj.get_path(&self.base_path).metadata().unwrap()
+        }
+
+        fn get_full_path(&self) -> PathBuf {
+            self.base_obj.get_path(&self.base_path)
+        }
+
+        fn verify(&self) {
+            // verify that the structure is correct that files are not symlinks
+            // verify that the contents are correct
+            // verify that directories are not symlinks
+
+            // verify that the contents are correct
+            if self.base_obj.is_symlink(&self.base_path) {
+                panic!("Object is a symlink");
+            }
+            if self.get_fs_metadata().file_type().is_dir() {
+                for child in self.get_children() {
+                    child.verify();
+                }
+            } else {
+                let mut file = File::open(self.get_full_path()).unwrap();
+                let mut contents = String::new();
+                file.read_to_string(&mut contents).unwrap();
+                assert_eq!(contents, self.base_obj.get_contents());
+            }
+        }
+    }
+
+    impl TestFsObject {
+        fn get_view(&self, base_path: &Path) -> TestFsView<'_> {
+            TestFsView::new(self, base_path.to_path_buf())
+        }
+
+        fn create(&self, base_path: &Path) {
+            match self {
+                TestFsObject::File { name, contents } => {
+                    let file_path = base_path.join(name);
+                    let mut file = File::create(file_path).unwrap();
+                    file.write_all(contents.as_bytes()).unwrap();
+                    file.flush().unwrap();
+                    file.sync_all().unwrap();
+                }
+                TestFsObject::Dir { name, contents } => {
+                    let dir_path = base_path.join(name);
+                    fs::create_dir(&dir_path).unwrap();
+                    for content in contents {
+                        content.create(&dir_path);
+                    }
+                }
+            }
+        }
+
+        fn get_path(&self, base_path: &Path) -> PathBuf {
+            match self {
+                TestFsObject::File { name, .. } => base_path.join(name),
+                TestFsObject::Dir { name, .. } => base_path.join(name),
+            }
+        }
+
+        fn get_contents(&self) -> &str {
+            match self {
+                TestFsObject::File { contents, .. } => contents.as_str(),
+                TestFsObject::Dir { .. } => panic!("Cannot get contents of a directory"),
+            }
+        }
+
+        fn get_children(&self) -> &[TestFsObject] {
+            match self {
+                TestFsObject::Dir { contents, .. } => contents.as_slice(),
+                TestFsObject::File { .. } => panic!("Cannot get children of a file"),
+            }
+        }
+
+        fn is_symlink(&self, base_path: &Path) -> bool {
+            match self {
+                TestFsObject::File { name, .. } => {
+                    debug!("Checking if file symlink for {:?} {:?}", base_path, name);
+                    let file_path = base_path.join(name);
+                    fs::symlink_metadata(file_path)
+                        .map(|meta| meta.file_type().is_symlink())
+                        .expect("Error checking symlink metadata")
+                }
+                TestFsObject::Dir { name, .. } => {
+                    debug!("Checking if dir symlink for {:?} {:?}", base_path, name);
+                    let dir_path = base_path.join(name);
+                    fs::symlink_metadata(dir_path)
+                        .map(|meta| meta.file_type().is_symlink())
+                        .expect("Error checking symlink metadata")
+                }
+            }
+        }
+
+        fn get_name(&self) -> &str {
+            match self {
+                TestFsObject::File { name, .. } => name,
+                TestFsObject::Dir { name, .. } => name,
+            }
+        }
+    }
+
+    #[test]
+    #[allow(clippy::needless_borrows_for_generic_args)]
+    fn simple_test() {
+        // pretty_env_logger::init();
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file2.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file3.txt".to_string(),
+                    contents: "unique content".to_string(),
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let test_view = test_dir.get_view(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+
+        apply(&dir_path).unwrap();
+
+        let originals_dir = dir_path.join(".mirage/originals");
+
+        // file1 should now be in .mirage/originals
+        let orig1 = originals_dir.join("file1.txt");
+        assert!(orig1.exists());
+
+        // file1 should now be a symlink to file1 in .mirage/originals
+        assert!(test_view.get_children()[0].is_symlink());
+
+        assert!(test_view.get_children()[1].is_symlink());
+
+        assert_eq!(
+            fs::canonicalize(read_link(&test_view.get_children()[0].get_full_path()).unwrap())
+                .unwrap(),
+            fs::canonicalize(&orig1).unwrap()
+        );
+
+        assert_eq!(
+            fs::canonicalize(read_link(&test_view.get_children()[1].get_full_path()).unwrap())
+                .unwrap(),
+            fs::canonicalize(&orig1).unwrap()
+        );
+
+        assert!(&test_view.get_children()[2]
+            .get_fs_metadata()
+            .file_type()
+            .is_file());
+
+        revert(&dir_path).unwrap();
+
+        test_view.verify();
+
+        // Check if mirage directory is removed
+
+        assert!(!dir_path.join(".mirage").exists());
+        assert!(!dir_path.join(".mirage/originals").exists());
+        assert!(!dir_path.join(".mirage/wal.json").exists());
+    }
+
+    #[test]
+    #[allow(clippy::needless_borrows_for_generic_args)]
+    fn complex_test() {
+        // pretty_env_logger::init();
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file2.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file3.txt".to_string(),
+                    contents: "unique content".to_string(),
+                },
+                TestFsObject::Dir {
+                    name: "subdir".to_string(),
+                    contents: vec![
+                        TestFsObject::File {
+                            name: "file4.txt".to_string(),
+                            contents: "duplicate content".to_string(),
+                        },
+                        TestFsObject::File {
+                            name: "file5.txt".to_string(),
+                            contents: "unique content".to_string(),
+                        },
+                    ],
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let test_view = test_dir.get_view(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+
+        apply(&dir_path).unwrap();
+
+        let originals_dir = dir_path.join(".mirage/originals");
+
+        // file1 should now be in .mirage/originals
+        let orig1 = originals_dir.join("file1.txt");
+        assert!(orig1.exists());
+
+        let orig3 = originals_dir.join("file3.txt");
+        assert!(orig3.exists());
+
+        // file1 should now be a symlink to file1 in .mirage/originals
+        assert!(test_view.get_children()[0].is_symlink());
+
+        assert!(test_view.get_children()[1].is_symlink());
+
+        assert!(test_view.get_children()[2].is_symlink());
+
+        assert!(test_view.get_children()[3].get_children()[0].is_symlink());
+
+        assert!(test_view.get_children()[3].get_children()[1].is_symlink());
+
+        assert_eq!(
+            fs::canonicalize(read_link(&test_view.get_children()[0].get_full_path()).unwrap())
+                .unwrap(),
+            fs::canonicalize(&orig1).unwrap()
+        );
+
+        assert_eq!(
+            fs::canonicalize(read_link(&test_view.get_children()[1].get_full_path()).unwrap())
+                .unwrap(),
+            fs::canonicalize(&orig1).unwrap()
+        );
+
+        assert_eq!(
+            fs::canonicalize(
+                read_link(&test_view.get_children()[3].get_children()[0].get_full_path()).unwrap()
+            )
+            .unwrap(),
+            fs::canonicalize(&orig1).unwrap()
+        );
+
+        assert_eq!(
+            fs::canonicalize(read_link(&test_view.get_children()[2].get_full_path()).unwrap())
+                .unwrap(),
+            fs::canonicalize(&orig3).unwrap()
+        );
+
+        assert_eq!(
+            fs::canonicalize(
+                read_link(&test_view.get_children()[3].get_children()[1].get_full_path()).unwrap()
+            )
+            .unwrap(),
+            fs::canonicalize(&orig3).unwrap()
+        );
+
+        revert(&dir_path).unwrap();
+
+        test_view.verify();
+
+        // Check if mirage directory is removed
+
+        assert!(!dir_path.join(".mirage").exists());
+        assert!(!dir_path.join(".mirage/originals").exists());
+        assert!(!dir_path.join(".mirage/wal.json").exists());
+    }
+
+    #[test]
+    fn rename_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file2.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file3.txt".to_string(),
+                    contents: "unique content".to_string(),
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let dir_path = fs::canonicalize(test_dir.get_path(dir_path)).unwrap();
+
+        apply(&dir_path).unwrap();
+
+        // move one link and replace the other with a real copy under a new name
+        fs::rename(dir_path.join("file1.txt"), dir_path.join("renamed.txt")).unwrap();
+        fs::remove_file(dir_path.join("file2.txt")).unwrap();
+        fs::write(dir_path.join("copied.txt"), "duplicate content").unwrap();
+
+        apply(&dir_path).unwrap();
+
+        let state = MirageState::get(&dir_path).unwrap();
+        let mut redirected = state.wal.redirections.keys().cloned().collect::<Vec<_>>();
+        redirected.sort();
+        assert_eq!(
+            redirected,
+            vec![dir_path.join("copied.txt"), dir_path.join("renamed.txt")]
+        );
+
+        // no second original was created
+        let originals = fs::read_dir(dir_path.join(".mirage/originals")).unwrap();
+        assert_eq!(originals.count(), 1);
+        assert!(fs::symlink_metadata(dir_path.join("copied.txt"))
+            .unwrap()
+            .file_type()
+            .is_symlink());
+
+        revert(&dir_path).unwrap();
+
+        assert!(!dir_path.join("file1.txt").exists());
+        assert!(!dir_path.join("file2.txt").exists());
+        for name in ["renamed.txt", "copied.txt"] {
+            let path = dir_path.join(name);
+            assert!(!fs::symlink_metadata(&path)
+                .unwrap()
+                .file_type()
+                .is_symlink());
+            assert_eq!(fs::read_to_string(&path).unwrap(), "duplicate content");
+        }
+        assert!(!dir_path.join(".mirage").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rename_hardlink_test() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = tempdir().unwrap();
+        let dir_path = fs::canonicalize(dir.path()).unwrap();
+        let file = |name: &str| dir_path.join(name);
+        for name in ["file1.txt", "file2.txt"] {
+            fs::write(file(name), "duplicate content").unwrap();
+        }
+        let options = ApplyOptions {
+            link_mode: Some(LinkMode::Hardlink),
+            ..Default::default()
+        };
+        apply_with_options(&dir_path, &options).unwrap();
+
+        // move one link and replace the other with a real copy under a new name
+        fs::rename(file("file1.txt"), file("renamed.txt")).unwrap();
+        fs::remove_file(file("file2.txt")).unwrap();
+        fs::write(file("copied.txt"), "duplicate content").unwrap();
+        apply_with_options(&dir_path, &options).unwrap();
+
+        let inode = fs::metadata(file("renamed.txt")).unwrap().ino();
+        for name in ["renamed.txt", "copied.txt"] {
+            assert!(!file(name).is_symlink());
+            assert_eq!(fs::metadata(file(name)).unwrap().ino(), inode);
+        }
+        let state = MirageState::open(&dir_path).unwrap();
+        assert!(state
+            .wal
+            .actions
+            .iter()
+            .filter(|a| a.action.links())
+            .all(|a| matches!(a.action, ActionType::Hardlink)));
+        // the renamed link needed nothing done
+        let relinked = state
+            .wal
+            .actions
+            .iter()
+            .filter(|a| a.source == file("renamed.txt") && a.action.links());
+        assert_eq!(relinked.count(), 1);
+        drop(state);
+
+        revert(&dir_path).unwrap();
+        for name in ["renamed.txt", "copied.txt"] {
+            assert_eq!(fs::metadata(file(name)).unwrap().nlink(), 1);
+            assert_eq!(fs::read_to_string(file(name)).unwrap(), "duplicate content");
+        }
+    }
+
+    #[test]
+    fn nested_store_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::Dir {
+                    name: "subdir".to_string(),
+                    contents: vec![
+                        TestFsObject::File {
+                            name: "file2.txt".to_string(),
+                            contents: "duplicate content".to_string(),
+                        },
+                        TestFsObject::File {
+                            name: "file3.txt".to_string(),
+                            contents: "duplicate content".to_string(),
+                        },
+                    ],
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let test_view = test_dir.get_view(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+        let subdir_path = dir_path.join("subdir");
+
+        apply(&subdir_path).unwrap();
+
+        assert!(matches!(
+            apply(&dir_path),
+            Err(MirageError::NestedStore(root)) if root == subdir_path
+        ));
+        assert!(!dir_path.join(".mirage").exists());
+
+        let options = ApplyOptions {
+            adopt_nested: true,
+            ..Default::default()
+        };
+        apply_with_options(&dir_path, &options).unwrap();
+
+        assert!(!subdir_path.join(".mirage").exists());
+        assert!(test_view.get_children()[0].is_symlink());
+        assert!(test_view.get_children()[1].get_children()[0].is_symlink());
+        assert!(test_view.get_children()[1].get_children()[1].is_symlink());
+
+        revert(&dir_path).unwrap();
+
+        test_view.verify();
+    }
+
+    #[test]
+    fn merge_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let first = TestFsObject::Dir {
+            name: "first".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file2.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+            ],
+        };
+        let second = TestFsObject::Dir {
+            name: "second".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file3.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file4.txt".to_string(),
+                    contents: "other content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file5.txt".to_string(),
+                    contents: "other content".to_string(),
+                },
+            ],
+        };
+
+        first.create(dir_path);
+        second.create(dir_path);
+
+        let first_view = first.get_view(dir_path);
+        let second_view = second.get_view(dir_path);
+
+        let first_path = first.get_path(dir_path);
+        let second_path = second.get_path(dir_path);
+
+        apply(&first_path).unwrap();
+        apply(&second_path).unwrap();
+
+        merge(&first_path, &second_path).unwrap();
+
+        assert!(!second_path.join(".mirage").exists());
+
+        // the shared content is not stored twice, the name clash is resolved
+        let originals_dir = first_path.join(".mirage/originals");
+        let mut originals = fs::read_dir(&originals_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        originals.sort();
+        assert_eq!(originals, vec!["file1.txt", "file4.txt"]);
+
+        for child in second_view.get_children() {
+            assert!(child.is_symlink());
+            let pointee = read_link(child.get_full_path()).unwrap();
+            assert_eq!(
+                pointee.parent().unwrap(),
+                fs::canonicalize(&originals_dir).unwrap()
+            );
+        }
+
+        revert(&first_path).unwrap();
+
+        first_view.verify();
+        second_view.verify();
+    }
+
+    #[test]
+    fn merge_hardlinks_test() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let tree = |name: &str, contents: &str| TestFsObject::Dir {
+            name: name.to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: contents.to_string(),
+                },
+                TestFsObject::File {
+                    name: "file2.txt".to_string(),
+                    contents: contents.to_string(),
+                },
+            ],
+        };
+        let first = tree("first", "duplicate content");
+        let second = tree("second", "other content");
+        first.create(dir_path);
+        second.create(dir_path);
+        let second_view = second.get_view(dir_path);
+        let first_path = first.get_path(dir_path);
+        let second_path = second.get_path(dir_path);
+
+        apply(&first_path).unwrap();
+        let options = ApplyOptions {
+            link_mode: Some(LinkMode::Hardlink),
+            ..Default::default()
+        };
+        apply_with_options(&second_path, &options).unwrap();
+
+        set_read_only(&second_path, true).unwrap();
+        assert!(matches!(
+            merge(&first_path, &second_path),
+            Err(MirageError::ReadOnly(_))
+        ));
+        set_read_only(&second_path, false).unwrap();
+
+        merge(&first_path, &second_path).unwrap();
+        let file = |name: &str| second_path.join(name);
+        assert!(!file("file1.txt").is_symlink());
+        assert_eq!(
+            fs::metadata(file("file1.txt")).unwrap().ino(),
+            fs::metadata(file("file2.txt")).unwrap().ino()
+        );
+        let state = MirageState::open(&first_path).unwrap();
+        let copy = state
+            .wal
+            .actions
+            .iter()
+            .rev()
+            .find(|a| matches!(a.action, ActionType::Copy))
+            .unwrap();
+        assert!(copy.digest.is_some());
+        drop(state);
+
+        let options = RevertOptions {
+            verify: true,
+            ..Default::default()
+        };
+        let report = revert_with_options(&first_path, &options).unwrap();
+        assert_eq!(report.unverified, 0);
+        assert!(report.mismatched.is_empty());
+        second_view.verify();
+    }
+
+    #[test]
+    fn per_subdirectory_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let user_dir = |name: &str| TestFsObject::Dir {
+            name: name.to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file2.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+            ],
+        };
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                user_dir("alice"),
+                user_dir("bob"),
+                TestFsObject::File {
+                    name: "file3.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let test_view = test_dir.get_view(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+
+        let options = ApplyOptions {
+            per_subdirectory: true,
+            ..Default::default()
+        };
+        apply_with_options(&dir_path, &options).unwrap();
+
+        assert!(!dir_path.join(".mirage").exists());
+        for (i, user) in ["alice", "bob"].iter().enumerate() {
+            let originals_dir = dir_path.join(user).join(".mirage/originals");
+            for child in test_view.get_children()[i].get_children() {
+                assert!(child.is_symlink());
+                let pointee = read_link(child.get_full_path()).unwrap();
+                assert!(pointee.starts_with(fs::canonicalize(&originals_dir).unwrap()));
+            }
+        }
+        assert!(!test_view.get_children()[2].is_symlink());
+
+        // reverting one area leaves the other alone
+        revert(dir_path.join("alice")).unwrap();
+        test_view.get_children()[0].verify();
+        assert!(test_view.get_children()[1].get_children()[0].is_symlink());
+
+        let options = RevertOptions {
+            per_subdirectory: true,
+            ..Default::default()
+        };
+        revert_with_options(&dir_path, &options).unwrap();
+
+        test_view.verify();
+    }
+
+    #[test]
+    fn parallel_revert_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let mut contents = Vec::new();
+        for group in 0..6 {
+            for copy in 0..3 {
+                contents.push(TestFsObject::File {
+                    name: format!("file{}_{}.txt", group, copy),
+                    contents: format!("content of group {}", group),
+                });
+            }
+        }
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents,
+        };
+
+        test_dir.create(dir_path);
+
+        let test_view = test_dir.get_view(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+
+        apply(&dir_path).unwrap();
+        assert!(test_view
+            .get_children()
+            .iter()
+            .all(|child| child.is_symlink()));
+
+        // every group restored by one of the workers
+        let options = RevertOptions {
+            jobs: Some(4),
+            ..Default::default()
+        };
+        revert_with_options(&dir_path, &options).unwrap();
+
+        test_view.verify();
+        assert!(!dir_path.join(".mirage").exists());
+    }
+
+    #[test]
+    fn verified_revert_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let file = |name: &str, contents: &str| TestFsObject::File {
+            name: name.to_string(),
+            contents: contents.to_string(),
+        };
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                file("a1.txt", "first content"),
+                file("a2.txt", "first content"),
+                file("b1.txt", "second content"),
+                file("b2.txt", "second content"),
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let test_view = test_dir.get_view(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+
+        apply(&dir_path).unwrap();
+        let options = RevertOptions {
+            verify: true,
+            ..Default::default()
+        };
+
+        // an original changed behind mirage's back is caught, and the store
+        // kept to look into it
+        fs::write(dir_path.join("b1.txt"), "tampered content").unwrap();
+        let report = revert_with_options(&dir_path, &options).unwrap();
+        assert_eq!(report.verified, 2);
+        assert_eq!(report.unverified, 0);
+        assert_eq!(
+            report.mismatched,
+            vec![
+                fs::canonicalize(dir_path.join("b1.txt")).unwrap(),
+                fs::canonicalize(dir_path.join("b2.txt")).unwrap()
+            ]
+        );
+        assert!(dir_path.join(".mirage").exists());
+
+        // reverting again once the original is put right lets the store go
+        let b1 = fs::canonicalize(dir_path.join("b1.txt")).unwrap();
+        let original = MirageState::get(&dir_path).unwrap().wal.redirections[&b1].clone();
+        fs::write(original, "second content").unwrap();
+        let report = revert_with_options(&dir_path, &options).unwrap();
+        assert_eq!((report.verified, report.mismatched.len()), (4, 0));
+        assert!(!dir_path.join(".mirage").exists());
+        test_view.verify();
+    }
+
+    #[test]
+    fn forced_revert_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let file = |name: &str, contents: &str| TestFsObject::File {
+            name: name.to_string(),
+            contents: contents.to_string(),
+        };
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                file("a1.txt", "first content"),
+                file("a2.txt", "first content"),
+                file("b1.txt", "second content"),
+                file("b2.txt", "second content"),
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+
+        apply(&dir_path).unwrap();
+        let b1 = fs::canonicalize(&dir_path).unwrap().join("b1.txt");
+        let original = MirageState::get(&dir_path).unwrap().wal.redirections[&b1].clone();
+        fs::remove_file(&original).unwrap();
+
+        // nothing is touched unless forced
+        assert!(matches!(
+            revert(&dir_path),
+            Err(MirageError::MissingOriginal(missing)) if missing == original
+        ));
+        assert!(dir_path.join("a1.txt").is_symlink());
+
+        let options = RevertOptions {
+            force: true,
+            ..Default::default()
+        };
+        let report = revert_with_options(&dir_path, &options).unwrap();
+        let b2 = fs::canonicalize(&dir_path).unwrap().join("b2.txt");
+        assert_eq!(report.lost, vec![b1.clone(), b2]);
+        for name in ["a1.txt", "a2.txt"] {
+            let path = dir_path.join(name);
+            assert!(!path.is_symlink());
+            assert_eq!(fs::read_to_string(path).unwrap(), "first content");
+        }
+        for name in ["b1.txt", "b2.txt"] {
+            assert!(fs::symlink_metadata(dir_path.join(name)).is_err());
+            let marker = fs::read_to_string(dir_path.join(format!("{}.mirage-lost", name)));
+            assert!(marker.unwrap().contains(&original.display().to_string()));
+        }
+        assert!(!dir_path.join(".mirage").exists());
+    }
+
+    #[test]
+    fn partial_revert_failure_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let file = |name: &str, contents: &str| TestFsObject::File {
+            name: name.to_string(),
+            contents: contents.to_string(),
+        };
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                file("a1.txt", "first content"),
+                file("a2.txt", "first content"),
+                file("b1.txt", "second content"),
+                file("b2.txt", "second content"),
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+
+        apply(&dir_path).unwrap();
+        // a directory in the way can't be restored over
+        fs::remove_file(dir_path.join("a1.txt")).unwrap();
+        fs::create_dir(dir_path.join("a1.txt")).unwrap();
+        fs::write(dir_path.join("a1.txt").join("inside"), "").unwrap();
+
+        let report = revert(&dir_path).unwrap();
+        assert_eq!(report.restored, 3);
+        assert!(report.skipped.is_empty());
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(
+            report.failed[0].path,
+            fs::canonicalize(dir_path.join("a1.txt")).unwrap()
+        );
+        for name in ["a2.txt", "b1.txt", "b2.txt"] {
+            assert!(!dir_path.join(name).is_symlink());
+        }
+        assert!(dir_path.join(".mirage").exists());
+
+        // once out of the way, reverting again finishes the job
+        fs::remove_dir_all(dir_path.join("a1.txt")).unwrap();
+        let report = revert(&dir_path).unwrap();
+        assert_eq!((report.restored, report.failed.len()), (4, 0));
+        assert_eq!(
+            fs::read_to_string(dir_path.join("a1.txt")).unwrap(),
+            "first content"
+        );
+        assert!(!dir_path.join(".mirage").exists());
+    }
+
+    #[test]
+    fn archived_state_revert_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file2.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let test_view = test_dir.get_view(dir_path);
+
+        let archives = dir_path.join("archives");
+        let dir_path = test_dir.get_path(dir_path);
+
+        // into a directory, everything but the originals
+        apply(&dir_path).unwrap();
+        let options = RevertOptions {
+            archive_state: Some(archives.clone()),
+            ..Default::default()
+        };
+        let report = revert_with_options(&dir_path, &options).unwrap();
+        let archived = &report.archived_to[0];
+        assert!(archived.starts_with(&archives));
+        assert!(archived
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with("test_dir-"));
+        let wal: serde_json::Value =
+            serde_json::from_slice(&fs::read(archived.join("wal.json")).unwrap()).unwrap();
+        assert_eq!(wal["checkpoint"], 3);
+        assert!(!archived.join("originals").exists());
+        assert!(!dir_path.join(".mirage").exists());
+        test_view.verify();
+
+        // into a tarball
+        apply(&dir_path).unwrap();
+        let tarball = archives.join("state.tar.gz");
+        let options = RevertOptions {
+            archive_state: Some(tarball.clone()),
+            ..Default::default()
+        };
+        let report = revert_with_options(&dir_path, &options).unwrap();
+        assert_eq!(report.archived_to, vec![tarball.clone()]);
+        let tar = crate::gzip::decompress(&fs::read(&tarball).unwrap()).unwrap();
+        assert_eq!(tar.len() % 512, 0);
+        let names = tar
+            .chunks(512)
+            .filter(|block| block[257..262] == *b"ustar")
+            .map(|block| {
+                let end = block.iter().position(|&b| b == 0).unwrap();
+                String::from_utf8(block[..end].to_vec()).unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert!(names.iter().all(|name| name.starts_with("test_dir-")));
+        assert!(names.iter().any(|name| name.ends_with("/wal.json")));
+        assert!(!names.iter().any(|name| name.contains("originals")));
+        test_view.verify();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hardlink_revert_test() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: (1..=3)
+                .map(|i| TestFsObject::File {
+                    name: format!("file{}.txt", i),
+                    contents: "duplicate content".to_string(),
+                })
+                .collect(),
+        };
+
+        test_dir.create(dir_path);
+
+        let test_view = test_dir.get_view(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+
+        apply(&dir_path).unwrap();
+        let options = RevertOptions {
+            as_hardlinks: true,
+            verify: true,
+            ..Default::default()
+        };
+        let report = revert_with_options(&dir_path, &options).unwrap();
+        assert_eq!(report.verified, 3);
+
+        // the files outlive the store as one inode
+        test_view.verify();
+        assert!(!dir_path.join(".mirage").exists());
+        let inodes = (1..=3)
+            .map(|i| fs::metadata(dir_path.join(format!("file{}.txt", i))).unwrap())
+            .map(|meta| (meta.ino(), meta.nlink()))
+            .collect::<Vec<_>>();
+        assert!(inodes.iter().all(|&inode| inode == (inodes[0].0, 3)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn upgrade_test() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: (1..=3)
+                .map(|i| TestFsObject::File {
+                    name: format!("file{}.txt", i),
+                    contents: "duplicate content".to_string(),
+                })
+                .collect(),
+        };
+
+        test_dir.create(dir_path);
+
+        let test_view = test_dir.get_view(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+
+        apply(&dir_path).unwrap();
+        let report = upgrade(&dir_path, LinkMode::Hardlink).unwrap();
+        assert_eq!(report.upgraded, 3);
+        assert!(report.unchanged.is_empty());
+
+        // every file is the original's inode, and the WAL says so
+        test_view.verify();
+        for i in 1..=3 {
+            let path = dir_path.join(format!("file{}.txt", i));
+            assert!(!fs::symlink_metadata(&path).unwrap().is_symlink());
+            assert_eq!(fs::metadata(&path).unwrap().nlink(), 4);
+        }
+        let state = MirageState::open(&dir_path).unwrap();
+        let mut linked = state.wal.actions.iter().filter(|a| a.action.links());
+        assert_eq!(linked.clone().count(), 3);
+        assert!(linked.all(|a| matches!(a.action, ActionType::Hardlink)));
+        drop(state);
+
+        // nothing left to upgrade
+        assert_eq!(upgrade(&dir_path, LinkMode::Hardlink).unwrap().upgraded, 0);
+
+        revert(&dir_path).unwrap();
+        test_view.verify();
+        for i in 1..=3 {
+            let path = dir_path.join(format!("file{}.txt", i));
+            assert_eq!(fs::metadata(&path).unwrap().nlink(), 1);
+        }
+    }
+
+    #[test]
+    fn migrate_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: (1..=3)
+                .map(|i| TestFsObject::File {
+                    name: format!("file{}.txt", i),
+                    contents: "duplicate content".to_string(),
+                })
+                .collect(),
+        };
+
+        test_dir.create(dir_path);
+
+        let test_view = test_dir.get_view(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+
+        apply(&dir_path).unwrap();
+        let report = migrate(&dir_path, LinkMode::Hardlink).unwrap();
+        assert_eq!((report.migrated, report.already), (3, 0));
+        assert!(report.remaining.is_empty());
+        test_view.verify();
+
+        // clones either work or are reported, the files stay as they are
+        let report = migrate(&dir_path, LinkMode::Reflink).unwrap();
+        assert_eq!(report.migrated + report.remaining.len(), 3);
+        assert!(report
+            .remaining
+            .iter()
+            .all(|(_, reason)| *reason == Unmigrated::Unsupported));
+        test_view.verify();
+        let report = migrate(&dir_path, LinkMode::Hardlink).unwrap();
+        assert_eq!(report.migrated + report.already, 3);
+
+        // a file replaced since is left alone
+        let replaced = dir_path.join("file3.txt");
+        fs::remove_file(&replaced).unwrap();
+        fs::write(&replaced, "new content").unwrap();
+        let report = migrate(&dir_path, LinkMode::Symlink).unwrap();
+        assert_eq!(report.migrated, 2);
+        assert_eq!(
+            report.remaining,
+            vec![(replaced.clone(), Unmigrated::Diverged)]
+        );
+        for i in 1..=2 {
+            let path = dir_path.join(format!("file{}.txt", i));
+            assert!(fs::symlink_metadata(&path).unwrap().is_symlink());
+        }
+        assert_eq!(fs::read_to_string(&replaced).unwrap(), "new content");
+    }
+
+    #[test]
+    fn reapply_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: (1..=4)
+                .map(|i| TestFsObject::File {
+                    name: format!("file{}.txt", i),
+                    contents: "duplicate content".to_string(),
+                })
+                .collect(),
+        };
+
+        test_dir.create(dir_path);
+
+        let export = dir_path.join("export");
+        let dir_path = test_dir.get_path(dir_path);
+
+        apply(&dir_path).unwrap();
+        fs::create_dir(&export).unwrap();
+        // wal.json along with the files it lists
+        for entry in fs::read_dir(dir_path.join(".mirage")).unwrap() {
+            let entry = entry.unwrap();
+            if entry.file_name().to_string_lossy().ends_with(".json") {
+                fs::copy(entry.path(), export.join(entry.file_name())).unwrap();
+            }
+        }
+
+        // restored without the store, links as files or not at all, one
+        // file changed since
+        fs::remove_dir_all(dir_path.join(".mirage")).unwrap();
+        let file = |i: usize| dir_path.join(format!("file{}.txt", i));
+        for i in [1, 3] {
+            fs::remove_file(file(i)).unwrap();
+            fs::write(file(i), "duplicate content").unwrap();
+        }
+        fs::remove_file(file(2)).unwrap();
+        fs::remove_file(file(4)).unwrap();
+        fs::write(file(4), "changed content").unwrap();
+
+        let report = reapply(&dir_path, &export).unwrap();
+        assert_eq!(report.recovered.len(), 1);
+        assert_eq!(report.relinked + report.intact, 3);
+        assert_eq!(report.mismatched, vec![file(4)]);
+        assert!(report.lost.is_empty());
+        for i in 1..=3 {
+            assert!(fs::symlink_metadata(file(i)).unwrap().is_symlink());
+            assert_eq!(fs::read_to_string(file(i)).unwrap(), "duplicate content");
+        }
+        assert_eq!(fs::read_to_string(file(4)).unwrap(), "changed content");
+
+        // a second go finds everything linked
+        let report = reapply(&dir_path, export.join("wal.json")).unwrap();
+        assert_eq!((report.relinked, report.intact), (0, 3));
+
+        // another tree's WAL is refused
+        let other = tempdir().unwrap();
+        assert!(matches!(
+            reapply(other.path(), &export),
+            Err(MirageError::ForeignWal(_))
+        ));
+        assert!(!other.path().join(".mirage").exists());
+    }
+
+    #[test]
+    fn files_from_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file2.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file3.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let test_view = test_dir.get_view(dir_path);
+
+        let dir_path = fs::canonicalize(test_dir.get_path(dir_path)).unwrap();
+        let file = |name: &str| dir_path.join(name);
+        let list = format!(
+            "{}\0{}\0{}\0",
+            file("file1.txt").display(),
+            file("file3.txt").display(),
+            file("missing.txt").display()
+        );
+        let listed = read_file_list(list.as_bytes(), true).unwrap();
+        assert_eq!(listed.len(), 2);
+
+        let options = ApplyOptions {
+            files_from: Some(listed),
+            ..Default::default()
+        };
+        apply_with_options(&dir_path, &options).unwrap();
+        assert!(file("file1.txt").is_symlink());
+        assert!(file("file3.txt").is_symlink());
+        assert!(!file("file2.txt").is_symlink());
+        assert_eq!(
+            why(file("file2.txt")).unwrap(),
+            Why::Decided(Decision::NotListed)
+        );
+
+        revert(&dir_path).unwrap();
+        test_view.verify();
+    }
+
+    #[test]
+    fn shard_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file2.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file4.txt".to_string(),
+                    contents: "other content".to_string(),
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let test_view = test_dir.get_view(dir_path);
+
+        let dir_path = fs::canonicalize(test_dir.get_path(dir_path)).unwrap();
+        let file = |name: &str| dir_path.join(name);
+        apply(&dir_path).unwrap();
+
+        let originals = MirageState::open(&dir_path).unwrap().originals_path();
+        let flat = originals.join("file1.txt");
+        assert_eq!(fs::read_link(file("file2.txt")).unwrap(), flat);
+
+        let report = shard(&dir_path).unwrap();
+        assert_eq!((report.moved, report.already), (1, 0));
+        assert!(!flat.exists());
+        let content = DEFAULT_HASH_ALGORITHM
+            .hash_file(&file("file1.txt"))
+            .unwrap();
+        let sharded = originals
+            .join(&content[..2])
+            .join(&content[2..4])
+            .join("file1.txt");
+        for name in ["file1.txt", "file2.txt"] {
+            assert_eq!(fs::read_link(file(name)).unwrap(), sharded);
+        }
+        assert_eq!(diff(&dir_path).unwrap(), vec![]);
+        assert_eq!(
+            store_status(&dir_path).unwrap().layout,
+            StoreLayout::Sharded
+        );
+        assert_eq!(shard(&dir_path).unwrap().already, 1);
+
+        // new originals go to their shard right away
+        fs::write(file("file3.txt"), "other content").unwrap();
+        apply(&dir_path).unwrap();
+        let content = DEFAULT_HASH_ALGORITHM
+            .hash_file(&file("file3.txt"))
+            .unwrap();
+        assert_eq!(
+            fs::read_link(file("file4.txt")).unwrap().parent().unwrap(),
+            originals.join(&content[..2]).join(&content[2..4])
+        );
+        assert_eq!(fsck(&dir_path, false).unwrap(), vec![]);
+
+        revert(&dir_path).unwrap();
+        test_view.verify();
+    }
+
+    #[test]
+    fn rehash_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: (1..=3)
+                .map(|i| TestFsObject::File {
+                    name: format!("file{}.txt", i),
+                    contents: "duplicate content".to_string(),
+                })
+                .collect(),
+        };
+
+        test_dir.create(dir_path);
+
+        let test_view = test_dir.get_view(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+
+        let options = ApplyOptions {
+            hash_algorithm: Some(HashAlgorithm::Md5),
+            ..Default::default()
+        };
+        apply_with_options(&dir_path, &options).unwrap();
+        let report = rehash(&dir_path, HashAlgorithm::Blake3).unwrap();
+        assert_eq!((report.rehashed, report.already), (1, 0));
+        assert!(report.mismatched.is_empty() && report.missing.is_empty());
+
+        let state = MirageState::open(&dir_path).unwrap();
+        let copy = state
+            .wal
+            .actions
+            .iter()
+            .find(|a| matches!(a.action, ActionType::Copy))
+            .unwrap();
+        assert_eq!(copy.algorithm, Some(HashAlgorithm::Blake3));
+        assert_eq!(
+            copy.digest.as_deref(),
+            Some(to_hex(&blake3(b"duplicate content")).as_str())
+        );
+        drop(state);
+
+        let report = rehash(&dir_path, HashAlgorithm::Blake3).unwrap();
+        assert_eq!((report.rehashed, report.already), (0, 1));
+
+        // reverting checks the files against the new digests
+        let options = RevertOptions {
+            verify: true,
+            ..Default::default()
+        };
+        let report = revert_with_options(&dir_path, &options).unwrap();
+        assert_eq!((report.verified, report.mismatched.len()), (3, 0));
+        test_view.verify();
+    }
+
+    #[test]
+    fn hash_algorithm_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = fs::canonicalize(dir.path()).unwrap();
+        for name in ["file1.txt", "file2.txt"] {
+            fs::write(dir_path.join(name), "duplicate content").unwrap();
+        }
+        let copy = || {
+            let state = MirageState::open(&dir_path).unwrap();
+            let copy = state
+                .wal
+                .actions
+                .iter()
+                .find(|a| matches!(a.action, ActionType::Copy))
+                .unwrap();
+            (copy.algorithm, copy.digest.clone())
+        };
+        let verify = RevertOptions {
+            verify: true,
+            ..Default::default()
+        };
+
+        apply(&dir_path).unwrap();
+        assert_eq!(
+            copy(),
+            (
+                Some(HashAlgorithm::Blake3),
+                Some(to_hex(&blake3(b"duplicate content")))
+            )
+        );
+        let report = revert_with_options(&dir_path, &verify).unwrap();
+        assert_eq!((report.verified, report.unverified), (2, 0));
+
+        // the configured algorithm is the one files are hashed with
+        let config = Config::parse(Path::new(CONFIG_FILE), "hash-algorithm = \"sha256\"").unwrap();
+        let mut options = ApplyOptions::default();
+        config.apply_to(&mut options, None);
+        apply_with_options(&dir_path, &options).unwrap();
+        let (algorithm, digest) = copy();
+        assert_eq!(algorithm, Some(HashAlgorithm::Sha256));
+        assert_eq!(
+            digest,
+            Some(
+                HashAlgorithm::Sha256
+                    .hash_file(&dir_path.join("file1.txt"))
+                    .unwrap()
+            )
+        );
+        let report = revert_with_options(&dir_path, &verify).unwrap();
+        assert_eq!((report.verified, report.unverified), (2, 0));
+    }
+
+    #[test]
+    fn run_report_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let file = |name: &str, contents: &str| TestFsObject::File {
+            name: name.to_string(),
+            contents: contents.to_string(),
+        };
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                file("a1.txt", "aaaa"),
+                file("a2.txt", "aaaa"),
+                file("b1.txt", "bb"),
+                file("b2.txt", "bb"),
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+
+        let options = ApplyOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let planned = apply_with_options(&dir_path, &options).unwrap();
+        let first = apply(&dir_path).unwrap();
+        assert_eq!(planned.groups, first.groups);
+        assert_eq!(first.groups.len(), 2);
+        let first = RunReport::new(&dir_path, &first);
+        assert_eq!(first.saved(), 6);
+
+        // one more copy of a known file, and a new duplicate pair
+        fs::write(dir_path.join("a3.txt"), "aaaa").unwrap();
+        fs::write(dir_path.join("c1.txt"), "ccc").unwrap();
+        fs::write(dir_path.join("c2.txt"), "ccc").unwrap();
+        let second = RunReport::new(&dir_path, &apply(&dir_path).unwrap());
+
+        let mut saved = Vec::new();
+        second.write(&mut saved).unwrap();
+        let report_file = dir.path().join("second.json");
+        fs::write(&report_file, saved).unwrap();
+        assert_eq!(RunReport::read(&report_file).unwrap(), second);
+
+        let diff = diff_reports(&first, &second);
+        let files = |groups: &[DuplicateGroup]| {
+            groups
+                .iter()
+                .map(|group| group.files.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            files(&diff.new_groups),
+            vec![vec![dir_path.join("c1.txt"), dir_path.join("c2.txt")]]
+        );
+        assert_eq!(
+            files(&diff.resolved_groups),
+            vec![vec![dir_path.join("b1.txt"), dir_path.join("b2.txt")]]
+        );
+        assert_eq!(
+            files(&diff.changed_groups),
+            vec![vec![dir_path.join("a3.txt")]]
+        );
+        // the third copy of a saves it whole, c saves one copy
+        assert_eq!(diff.saved_change, 4 + 3 - 6);
+
+        revert(&dir_path).unwrap();
+    }
+
+    #[test]
+    fn publish_copy_test() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("source.txt");
+        let target = dir.path().join("original.txt");
+        let staging = dir.path().join("tmp");
+        fs::write(&source, "duplicate content").unwrap();
+
+        // a staged copy left behind by a crash is written over
+        fs::create_dir(&staging).unwrap();
+        fs::write(staging.join("copy-0.tmp"), "trunc").unwrap();
+        assert_eq!(publish_copy(&source, &target, &staging, 0).unwrap(), 17);
+        assert_eq!(fs::read_to_string(&target).unwrap(), "duplicate content");
+        assert!(!staging.exists());
+
+        // failing leaves neither a target nor a staged copy
+        let missing = dir.path().join("missing.txt");
+        let other = dir.path().join("other.txt");
+        assert!(publish_copy(&missing, &other, &staging, 1).is_err());
+        assert!(!other.exists());
+        assert!(!staging.exists());
+    }
+
+    #[test]
+    fn probe_links_test() {
+        let dir = tempdir().unwrap();
+        let state = MirageState::get(dir.path()).unwrap();
+        let before = fs::read_dir(&state.source_path).unwrap().count();
+        for mode in [LinkMode::Symlink, LinkMode::Hardlink, LinkMode::Reflink] {
+            state.probe_links(mode).unwrap();
+        }
+        // the probe leaves nothing behind
+        assert_eq!(fs::read_dir(&state.source_path).unwrap().count(), before);
+    }
+
+    #[test]
+    fn link_policy_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let file = |name: &str, contents: &str| TestFsObject::File {
+            name: name.to_string(),
+            contents: contents.to_string(),
+        };
+        let big = "big content ".repeat(10);
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                file("big1.txt", &big),
+                file("big2.txt", &big),
+                file("small1.txt", "tiny"),
+                file("small2.txt", "tiny"),
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let test_view = test_dir.get_view(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+
+        let options = ApplyOptions {
+            link_policy: Some(LinkPolicy {
+                reflink_min_size: Some(100),
+                hardlink_max_size: Some(10),
+            }),
+            ..Default::default()
+        };
+        apply_with_options(&dir_path, &options).unwrap();
+
+        let state = MirageState::open(&dir_path).unwrap();
+        let can_reflink = state.can_link(LinkMode::Reflink);
+        let linked = |name: &str| {
+            state
+                .wal
+                .actions
+                .iter()
+                .rfind(|a| a.action.links() && a.source == dir_path.join(name))
+                .map(|a| a.action.clone())
+                .unwrap()
+        };
+        // big files are cloned where the filesystem can, symlinked otherwise
+        for name in ["big1.txt", "big2.txt"] {
+            match linked(name) {
+                ActionType::Reflink => assert!(can_reflink),
+                ActionType::Symlink => assert!(!can_reflink),
+                other => panic!("{} got {:?}", name, other),
+            }
+        }
+        for name in ["small1.txt", "small2.txt"] {
+            assert!(matches!(linked(name), ActionType::Hardlink));
+            assert!(!fs::symlink_metadata(dir_path.join(name))
+                .unwrap()
+                .is_symlink());
+        }
+        drop(state);
+
+        revert(&dir_path).unwrap();
+        test_view.verify();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn link_mode_test() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file2.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let test_view = test_dir.get_view(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+        let file = |name: &str| dir_path.join(name);
+
+        let options = ApplyOptions {
+            link_mode: Some(LinkMode::Hardlink),
+            ..Default::default()
+        };
+        apply_with_options(&dir_path, &options).unwrap();
+        // a duplicate of an original already in the store
+        fs::write(file("file3.txt"), "duplicate content").unwrap();
+        apply_with_options(&dir_path, &options).unwrap();
+
+        let inode = fs::metadata(file("file1.txt")).unwrap().ino();
+        for name in ["file1.txt", "file2.txt", "file3.txt"] {
+            assert!(!file(name).is_symlink());
+            assert_eq!(fs::metadata(file(name)).unwrap().ino(), inode);
+        }
+        let state = MirageState::open(&dir_path).unwrap();
+        assert!(state
+            .wal
+            .actions
+            .iter()
+            .filter(|a| a.action.links())
+            .all(|a| matches!(a.action, ActionType::Hardlink)));
+        drop(state);
+
+        revert(&dir_path).unwrap();
+        test_view.verify();
+        for name in ["file1.txt", "file2.txt", "file3.txt"] {
+            assert_eq!(fs::metadata(file(name)).unwrap().nlink(), 1);
+        }
+    }
+
+    #[test]
+    fn reflink_mode_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file2.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let test_view = test_dir.get_view(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+
+        let options = ApplyOptions {
+            link_mode: Some(LinkMode::Reflink),
+            ..Default::default()
+        };
+        let can_reflink = MirageState::get(&dir_path)
+            .unwrap()
+            .can_link(LinkMode::Reflink);
+        match apply_with_options(&dir_path, &options) {
+            Ok(_) => {
+                assert!(can_reflink);
+                for name in ["file1.txt", "file2.txt"] {
+                    assert!(!dir_path.join(name).is_symlink());
+                }
+                revert(&dir_path).unwrap();
+            }
+            // nothing is touched where clones can't be made
+            Err(MirageError::LinksUnsupported(_, mode, _)) => {
+                assert!(!can_reflink);
+                assert_eq!(mode, "reflink");
+            }
+            Err(err) => panic!("unexpected error {:?}", err),
+        }
+        test_view.verify();
+    }
+
+    #[test]
+    fn dedupe_extents_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file2.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file3.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let test_view = test_dir.get_view(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+
+        let options = ApplyOptions {
+            dedupe_extents: true,
+            dry_run: true,
+            ..Default::default()
+        };
+        let report = apply_with_options(&dir_path, &options).unwrap();
+        let mut planned = report
+            .planned
+            .iter()
+            .map(|action| (action.action.as_str(), action.source.clone()))
+            .collect::<Vec<_>>();
+        planned.sort();
+        assert_eq!(
+            planned,
+            vec![
+                ("Dedupe", dir_path.join("file2.txt")),
+                ("Dedupe", dir_path.join("file3.txt")),
+            ]
+        );
+
+        let options = ApplyOptions {
+            dedupe_extents: true,
+            ..Default::default()
+        };
+        match apply_with_options(&dir_path, &options) {
+            Ok(report) => {
+                assert_eq!(report.groups.len(), 1);
+                assert_eq!(report.groups[0].files.len(), 2);
+                revert(&dir_path).unwrap();
+            }
+            // nothing is touched where extents can't be shared
+            Err(MirageError::LinksUnsupported(_, mode, _)) => {
+                assert_eq!(mode, "shared extent");
+            }
+            Err(err) => panic!("unexpected error {:?}", err),
+        }
+        for name in ["file1.txt", "file2.txt", "file3.txt"] {
+            assert!(!dir_path.join(name).is_symlink());
+        }
+        test_view.verify();
+    }
+
+    #[test]
+    fn store_quota_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file2.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file3.txt".to_string(),
+                    contents: "other content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file4.txt".to_string(),
+                    contents: "other content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file5.txt".to_string(),
+                    contents: "other content".to_string(),
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let test_view = test_dir.get_view(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+
+        let options = ApplyOptions {
+            max_store_size: Some(20),
+            ..Default::default()
+        };
+        let report = apply_with_options(&dir_path, &options).unwrap();
+
+        assert_eq!(
+            report.skipped_over_quota,
+            vec![fs::canonicalize(dir_path.join("file3.txt")).unwrap()]
+        );
+        assert!(test_view.get_children()[0].is_symlink());
+        assert!(test_view.get_children()[1].is_symlink());
+        assert!(!test_view.get_children()[2].is_symlink());
+        assert!(!test_view.get_children()[3].is_symlink());
+        assert!(!test_view.get_children()[4].is_symlink());
+        // the whole group is skipped once
+        for name in ["file4.txt", "file5.txt"] {
+            assert_eq!(
+                why(dir_path.join(name)).unwrap(),
+                Why::Decided(Decision::OverQuota)
+            );
+        }
+
+        let state = MirageState::get(&dir_path).unwrap();
+        assert_eq!(state.store_size().unwrap(), 17);
+
+        revert(&dir_path).unwrap();
+
+        test_view.verify();
+    }
+
+    #[test]
+    fn unshare_gc_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file2.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file3.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let test_view = test_dir.get_view(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+        let orig1 = dir_path.join(".mirage/originals/file1.txt");
+
+        apply(&dir_path).unwrap();
+
+        unshare(dir_path.join("file1.txt")).unwrap();
+        test_view.get_children()[0].verify();
+        assert!(orig1.exists());
+        assert_eq!(
+            MirageState::get(&dir_path)
+                .unwrap()
+                .refcount(&fs::canonicalize(&orig1).unwrap()),
+            2
+        );
+
+        remove(dir_path.join("file2.txt")).unwrap();
+        assert!(!dir_path.join("file2.txt").exists());
+        assert!(orig1.exists());
+
+        // last reference goes away, so does the original
+        unshare(dir_path.join("file3.txt")).unwrap();
+        test_view.get_children()[2].verify();
+        assert!(!orig1.exists());
+
+        let state = MirageState::get(&dir_path).unwrap();
+        assert!(state.wal.redirections.is_empty());
+        assert!(state.wal.actions.is_empty());
+        assert_eq!(state.wal.checkpoint, 0);
+
+        revert(&dir_path).unwrap();
+
+        test_view.get_children()[0].verify();
+        test_view.get_children()[2].verify();
+        assert!(!dir_path.join("file2.txt").exists());
+    }
+
+    #[test]
+    fn snapshots_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let snapshot = |name: &str, extra: &str| TestFsObject::Dir {
+            name: name.to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: format!("{}.txt", name),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "extra.txt".to_string(),
+                    contents: extra.to_string(),
+                },
+            ],
+        };
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                snapshot("backup-2024-01-01", "old content"),
+                snapshot("backup-2024-01-02", "new content"),
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let test_view = test_dir.get_view(dir_path);
+
+        let dir_path = fs::canonicalize(test_dir.get_path(dir_path)).unwrap();
+
+        let options = ApplyOptions {
+            snapshots: true,
+            ..Default::default()
+        };
+        let report = apply_with_options(&dir_path, &options).unwrap();
+
+        // the original is named after the newest snapshot's copy
+        let originals_dir = dir_path.join(".mirage/originals");
+        assert!(originals_dir.join("backup-2024-01-02.txt").exists());
+        assert!(!originals_dir.join("backup-2024-01-01.txt").exists());
+
+        assert_eq!(
+            report.snapshot_savings,
+            vec![
+                SnapshotSavings {
+                    snapshot: dir_path.join("backup-2024-01-02"),
+                    files: 1,
+                    bytes: 17,
+                },
+                SnapshotSavings {
+                    snapshot: dir_path.join("backup-2024-01-01"),
+                    files: 1,
+                    bytes: 17,
+                },
+            ]
+        );
+
+        revert(&dir_path).unwrap();
+
+        test_view.verify();
+
+        // the savings are those of the tree, wherever its store is
+        let stores = dir_path.with_file_name("stores");
+        let external = ApplyOptions {
+            store: Some(stores.clone()),
+            ..options
+        };
+        assert_eq!(
+            apply_with_options(&dir_path, &external)
+                .unwrap()
+                .snapshot_savings,
+            report.snapshot_savings
+        );
+        let external = RevertOptions {
+            store: Some(stores),
+            ..Default::default()
+        };
+        revert_with_options(&dir_path, &external).unwrap();
+
+        test_view.verify();
+    }
+
+    #[test]
+    fn plan_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: (1..=3)
+                .map(|i| TestFsObject::File {
+                    name: format!("file{}.txt", i),
+                    contents: "duplicate content".to_string(),
+                })
+                .collect(),
+        };
+
+        test_dir.create(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+        let file = |i: usize| {
+            fs::canonicalize(&dir_path)
+                .unwrap()
+                .join(format!("file{}.txt", i))
+        };
+
+        let options = ApplyOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let planned = apply_with_options(&dir_path, &options).unwrap().planned;
+        let mut text = Vec::new();
+        Plan::from_planned(&fs::canonicalize(&dir_path).unwrap(), &planned)
+            .write(&mut text)
+            .unwrap();
+        let plan = Plan::parse(&String::from_utf8(text).unwrap()).unwrap();
+        assert_eq!(plan.groups.len(), 1);
+        assert_eq!(plan.groups[0].link.len(), 2);
+
+        // reviewed, then applied once one of the files changed
+        fs::write(file(3), "changed content").unwrap();
+        let report = apply_plan(&dir_path, &plan, &ApplyOptions::default()).unwrap();
+        assert_eq!(report.plan_skipped, vec![file(3)]);
+        for i in 1..=2 {
+            assert!(fs::symlink_metadata(file(i)).unwrap().is_symlink());
+            assert_eq!(fs::read_to_string(file(i)).unwrap(), "duplicate content");
+        }
+        assert!(!fs::symlink_metadata(file(3)).unwrap().is_symlink());
+
+        // later runs know the original the plan made
+        fs::write(dir_path.join("file4.txt"), "duplicate content").unwrap();
+        apply(&dir_path).unwrap();
+        assert!(fs::symlink_metadata(dir_path.join("file4.txt"))
+            .unwrap()
+            .is_symlink());
+
+        revert(&dir_path).unwrap();
+        for i in 1..=2 {
+            assert!(!fs::symlink_metadata(file(i)).unwrap().is_symlink());
+            assert_eq!(fs::read_to_string(file(i)).unwrap(), "duplicate content");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn export_script_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "it's a file.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+
+        apply(&dir_path).unwrap();
+        let mut script = Vec::new();
+        assert_eq!(
+            export_script(&dir_path, Shell::Posix, false, &mut script).unwrap(),
+            0
+        );
+        let mut script = Vec::new();
+        assert_eq!(
+            export_script(&dir_path, Shell::Posix, true, &mut script).unwrap(),
+            3
+        );
+
+        // run by hand, the script deduplicates the tree the same way
+        revert(&dir_path).unwrap();
+        fs::create_dir_all(dir_path.join(".mirage").join("originals")).unwrap();
+        let script_path = dir_path.parent().unwrap().join("dedup.sh");
+        fs::write(&script_path, &script).unwrap();
+        let status = std::process::Command::new("sh")
+            .arg(&script_path)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        for name in ["file1.txt", "it's a file.txt"] {
+            let path = dir_path.join(name);
+            assert!(fs::symlink_metadata(&path).unwrap().is_symlink());
+            assert_eq!(fs::read_to_string(&path).unwrap(), "duplicate content");
+        }
+    }
+
+    #[test]
+    fn manifest_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file2.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file3.txt".to_string(),
+                    contents: "unique content".to_string(),
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+
+        apply(&dir_path).unwrap();
+
+        let entries = manifest(&dir_path).unwrap();
+        let original = PathBuf::from(".mirage/originals/file1.txt");
+        assert_eq!(
+            entries
+                .iter()
+                .map(|e| (e.path.to_str().unwrap(), e.content.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("file1.txt", original.clone()),
+                ("file2.txt", original),
+                ("file3.txt", PathBuf::from("file3.txt")),
+            ]
+        );
+        assert_eq!(
+            entries[0].content_id,
+            format!("{:x}", md5::compute("duplicate content"))
+        );
+        assert_eq!(entries[0].content_id, entries[1].content_id);
+        assert_ne!(entries[0].content_id, entries[2].content_id);
+
+        let mut csv = Vec::new();
+        write_manifest_csv(&mut csv, &entries).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(csv.lines().count(), 4);
+        assert!(csv.starts_with("path,content,content_id\nfile1.txt,"));
+    }
+
+    #[test]
+    fn photos_profile_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::Dir {
+                    name: "0_inbox".to_string(),
+                    contents: vec![
+                        TestFsObject::File {
+                            name: "copy.JPG".to_string(),
+                            contents: "image".to_string(),
+                        },
+                        TestFsObject::File {
+                            name: "copy.raw".to_string(),
+                            contents: "image".to_string(),
+                        },
+                        TestFsObject::File {
+                            name: "copy.xmp".to_string(),
+                            contents: "sidecar".to_string(),
+                        },
+                    ],
+                },
+                TestFsObject::Dir {
+                    name: "2024-01-15".to_string(),
+                    contents: vec![
+                        TestFsObject::File {
+                            name: "archived.jpg".to_string(),
+                            contents: "image".to_string(),
+                        },
+                        TestFsObject::File {
+                            name: "archived.xmp".to_string(),
+                            contents: "sidecar".to_string(),
+                        },
+                    ],
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let test_view = test_dir.get_view(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+
+        let mut options = ApplyOptions::default();
+        Profile::Photos.apply_to(&mut options);
+        apply_with_options(&dir_path, &options).unwrap();
+
+        let children = test_view.get_children();
+        let inbox = children[0].get_children();
+        let archive = children[1].get_children();
+        assert!(archive[0].is_symlink());
+        assert!(inbox[0].is_symlink());
+        assert!(!inbox[1].is_symlink());
+        assert!(!archive[1].is_symlink());
+        assert!(!inbox[2].is_symlink());
+
+        let originals = fs::read_dir(dir_path.join(".mirage/originals"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(originals, vec!["archived.jpg"]);
+
+        revert(&dir_path).unwrap();
+
+        test_view.verify();
+    }
+
+    #[test]
+    fn ignore_metadata_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = fs::canonicalize(dir.path()).unwrap();
+
+        let jpeg = |exif: &[u8], scan: &[u8]| {
+            let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE1];
+            jpeg.extend(((exif.len() + 2) as u16).to_be_bytes());
+            jpeg.extend(exif);
+            jpeg.extend([0xFF, 0xDA, 0x00, 0x02]);
+            jpeg.extend(scan);
+            jpeg.extend([0xFF, 0xD9]);
+            jpeg
+        };
+        fs::write(dir_path.join("a.jpg"), jpeg(b"Exif one", b"abc")).unwrap();
+        fs::write(dir_path.join("b.jpg"), jpeg(b"Exif other camera", b"abc")).unwrap();
+        fs::write(dir_path.join("c.jpg"), jpeg(b"Exif one", b"xyz")).unwrap();
+        fs::write(dir_path.join("a.txt"), "some notes").unwrap();
+        fs::write(dir_path.join("b.txt"), "some notes").unwrap();
+
+        let options = ApplyOptions {
+            ignore_metadata: true,
+            ..Default::default()
+        };
+        let report = apply_with_options(&dir_path, &options).unwrap();
+
+        for name in ["a.jpg", "b.jpg", "a.txt", "b.txt"] {
+            assert!(dir_path.join(name).is_symlink(), "{}", name);
+        }
+        assert!(!dir_path.join("c.jpg").is_symlink());
+        // files are grouped by their payload, a.jpg and c.jpg are never
+        // compared although they are of the same size, and a.jpg and b.jpg
+        // are compared without reading their tags
+        assert_eq!(report.bytes_compared, 2 * "some notes".len() as u64);
+
+        revert(&dir_path).unwrap();
+        assert!(!dir_path.join("b.jpg").is_symlink());
+    }
+
+    #[test]
+    fn wal_signature_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+        let key = b"secret key".to_vec();
+
+        let mut state = MirageState::get_keyed(
+            dir_path,
+            Some(key.clone()),
+            false,
+            &StoreLocation::default(),
+        )
+        .unwrap();
+        state
+            .wal
+            .redirections
+            .insert(dir_path.join("a"), dir_path.join("b"));
+        state.commit().unwrap();
+        assert!(dir_path.join(".mirage/wal.json.hmac").exists());
+
+        // untouched state opens fine
+        MirageState::get_keyed(
+            dir_path,
+            Some(key.clone()),
+            false,
+            &StoreLocation::default(),
+        )
+        .unwrap();
+        assert!(matches!(
+            MirageState::get_keyed(dir_path, None, false, &StoreLocation::default()),
+            Err(MirageError::KeyRequired)
+        ));
+        assert!(matches!(
+            MirageState::get_keyed(
+                dir_path,
+                Some(b"wrong key".to_vec()),
+                false,
+                &StoreLocation::default()
+            ),
+            Err(MirageError::WALTampered)
+        ));
+
+        let wal_path = dir_path.join(".mirage/wal.json");
+        let signed = fs::read_to_string(&wal_path).unwrap();
+        fs::write(
+            &wal_path,
+            signed.replace("\"checkpoint\": 0", "\"checkpoint\": 1"),
+        )
+        .unwrap();
+        assert!(matches!(
+            MirageState::get_keyed(
+                dir_path,
+                Some(key.clone()),
+                false,
+                &StoreLocation::default()
+            ),
+            Err(MirageError::WALTampered)
+        ));
+
+        // so is a deleted signature, unless asked to sign the WAL anew
+        fs::write(&wal_path, &signed).unwrap();
+        let signature = dir_path.join(".mirage/wal.json.hmac");
+        fs::remove_file(&signature).unwrap();
+        assert!(matches!(
+            MirageState::get_keyed(
+                dir_path,
+                Some(key.clone()),
+                false,
+                &StoreLocation::default()
+            ),
+            Err(MirageError::WALTampered)
+        ));
+        let mut state =
+            MirageState::get_keyed(dir_path, Some(key.clone()), true, &StoreLocation::default())
+                .unwrap();
+        state.commit().unwrap();
+        assert!(signature.exists());
+        MirageState::get_keyed(
+            dir_path,
+            Some(key.clone()),
+            false,
+            &StoreLocation::default(),
+        )
+        .unwrap();
+
+        // the redirections are covered through their hash
+        let redirections = dir_path.join(".mirage/redirections.0001.json");
+        let edited = fs::read_to_string(&redirections)
+            .unwrap()
+            .replace("/b\"", "/c\"");
+        fs::write(&redirections, edited).unwrap();
+        assert!(matches!(
+            MirageState::get_keyed(dir_path, Some(key), false, &StoreLocation::default()),
+            Err(MirageError::WALTampered)
+        ));
+    }
+
+    #[test]
+    fn interrupted_commit_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+        let key = b"secret key".to_vec();
+        let open = || {
+            MirageState::get_keyed(
+                dir_path,
+                Some(key.clone()),
+                false,
+                &StoreLocation::default(),
+            )
+            .unwrap()
+        };
+
+        let mut state = open();
+        state
+            .wal
+            .redirections
+            .insert(dir_path.join("a"), dir_path.join("b"));
+        state.commit().unwrap();
+        let store = dir_path.join(".mirage");
+        let first = fs::read(store.join("wal.json")).unwrap();
+        state
+            .wal
+            .redirections
+            .insert(dir_path.join("c"), dir_path.join("b"));
+        state.commit().unwrap();
+        drop(state);
+
+        // stopped between renaming the signature and the WAL into place
+        let second = fs::read(store.join("wal.json")).unwrap();
+        fs::write(store.join("wal.json.tmp"), &second).unwrap();
+        fs::write(store.join("wal.json"), &first).unwrap();
+        assert_eq!(open().wal.redirections.len(), 2);
+        assert!(!store.join("wal.json.tmp").exists());
+
+        // stopped before renaming anything, half of the WAL written
+        fs::write(store.join("wal.json.tmp"), &second[..10]).unwrap();
+        fs::write(store.join("wal.json.hmac.tmp"), "0").unwrap();
+        assert_eq!(open().wal.redirections.len(), 2);
+        assert!(!store.join("wal.json.tmp").exists());
+        assert!(!store.join("wal.json.hmac.tmp").exists());
+    }
+
+    #[test]
+    fn lock_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file2.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let test_view = test_dir.get_view(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+
+        apply(&dir_path).unwrap();
+        lock(&dir_path, Some("backup running")).unwrap();
+
+        assert!(matches!(apply(&dir_path), Err(MirageError::Frozen(_))));
+        assert!(matches!(revert(&dir_path), Err(MirageError::Frozen(_))));
+        assert!(matches!(
+            unshare(dir_path.join("file1.txt")),
+            Err(MirageError::Frozen(_))
+        ));
+        assert!(test_view.get_children()[0].is_symlink());
+
+        unlock(&dir_path).unwrap();
+        revert(&dir_path).unwrap();
+
+        test_view.verify();
+    }
+
+    #[test]
+    fn read_only_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file2.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let test_view = test_dir.get_view(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+
+        // an explicit dry run plans without creating a store
+        let options = ApplyOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let report = apply_with_options(&dir_path, &options).unwrap();
+        assert!(report.dry_run);
+        assert_eq!(report.planned.len(), 3);
+        assert!(!dir_path.join(".mirage").exists());
+        test_view.verify();
+
+        apply(&dir_path).unwrap();
+        set_read_only(&dir_path, true).unwrap();
+
+        fs::write(dir_path.join("file3.txt"), "duplicate content").unwrap();
+        let report = apply(&dir_path).unwrap();
+        assert!(report.dry_run);
+        assert_eq!(report.planned.len(), 1);
+        assert!(!dir_path.join("file3.txt").is_symlink());
+        fs::remove_file(dir_path.join("file3.txt")).unwrap();
+
+        let report = revert(&dir_path).unwrap();
+        assert!(report.dry_run);
+        assert_eq!(report.planned.len(), 3);
+        assert!(dir_path.join(".mirage").exists());
+
+        unshare(dir_path.join("file1.txt")).unwrap();
+        assert!(dir_path.join("file1.txt").is_symlink());
+
+        set_read_only(&dir_path, false).unwrap();
+        let report = revert(&dir_path).unwrap();
+        assert!(!report.dry_run);
+
+        test_view.verify();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn ownership_test() {
+        use std::os::unix::fs::{lchown, MetadataExt};
+
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file2.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let test_view = test_dir.get_view(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+
+        let file1 = dir_path.join("file1.txt");
+        let file2 = dir_path.join("file2.txt");
+        if lchown(&file1, Some(4001), Some(4001)).is_err() {
+            // only meaningful with the privilege to give files away
+            return;
+        }
+        lchown(&file2, Some(4002), Some(4002)).unwrap();
+        let owner = |path: &Path| {
+            let meta = fs::symlink_metadata(path).unwrap();
+            (meta.uid(), meta.gid())
+        };
+
+        apply(&dir_path).unwrap();
+
+        assert!(file1.is_symlink());
+        assert_eq!(owner(&file1), (4001, 4001));
+        assert_eq!(owner(&file2), (4002, 4002));
+        let original = fs::read_link(&file1).unwrap();
+        assert_eq!(owner(&original), (4001, 4001));
+
+        revert(&dir_path).unwrap();
+
+        assert_eq!(owner(&file1), (4001, 4001));
+        assert_eq!(owner(&file2), (4002, 4002));
+        test_view.verify();
+    }
+
+    #[test]
+    fn disk_usage_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::Dir {
+                    name: "a".to_string(),
+                    contents: vec![
+                        TestFsObject::File {
+                            name: "file1.txt".to_string(),
+                            contents: "duplicate".to_string(),
+                        },
+                        TestFsObject::File {
+                            name: "file2.txt".to_string(),
+                            contents: "duplicate".to_string(),
+                        },
+                    ],
+                },
+                TestFsObject::Dir {
+                    name: "b".to_string(),
+                    contents: vec![
+                        TestFsObject::File {
+                            name: "file3.txt".to_string(),
+                            contents: "duplicate".to_string(),
+                        },
+                        TestFsObject::File {
+                            name: "file4.txt".to_string(),
+                            contents: "unique".to_string(),
+                        },
+                    ],
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+
+        apply(&dir_path).unwrap();
+
+        let usage = disk_usage(&dir_path)
+            .unwrap()
+            .into_iter()
+            .map(|u| (u.dir.to_str().unwrap().to_string(), u.logical, u.physical))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            usage,
+            vec![
+                ("".to_string(), 33, 15),
+                ("a".to_string(), 18, 9),
+                ("b".to_string(), 15, 15),
+            ]
+        );
+    }
+
+    #[test]
+    fn stats_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file2.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file3.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+
+        apply(&dir_path).unwrap();
+        fs::write(dir_path.join("file4.txt"), "duplicate content").unwrap();
+        apply(&dir_path).unwrap();
+        // nothing left to deduplicate, not recorded
+        apply(&dir_path).unwrap();
+
+        let stats = stats(&dir_path).unwrap();
+        assert_eq!(stats.files_deduped, 3);
+        assert_eq!(stats.bytes_saved, 3 * 17);
+        assert_eq!(
+            stats
+                .sessions
+                .iter()
+                .map(|s| (s.files, s.bytes))
+                .collect::<Vec<_>>(),
+            vec![(2, 34), (1, 17)]
+        );
+
+        let history = stats_history(&dir_path).unwrap();
+        assert_eq!(
+            history
+                .iter()
+                .map(|p| (p.store_size, p.bytes_saved, p.files_managed))
+                .collect::<Vec<_>>(),
+            vec![(Some(17), 34, Some(3)), (Some(17), 51, Some(4))]
+        );
+
+        fs::write(dir_path.join("file5.txt"), "unique content").unwrap();
+        let space = MirageState::open(&dir_path).unwrap().stats().unwrap();
+        assert_eq!(space.files_scanned, 5);
+        assert_eq!(space.duplicate_groups, 1);
+        assert_eq!(space.logical_bytes, 4 * 17 + 14);
+        assert_eq!(space.physical_bytes, 17 + 14);
+        assert_eq!(space.dedup_ratio, (4 * 17 + 14) as f64 / (17 + 14) as f64);
+        assert_eq!(space.bytes_saved, 3 * 17);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn adopt_hardlinks_test() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file3.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let test_view = test_dir.get_view(dir_path);
+
+        let dir_path = fs::canonicalize(test_dir.get_path(dir_path)).unwrap();
+        let file = |name: &str| dir_path.join(name);
+        fs::hard_link(file("file1.txt"), file("file2.txt")).unwrap();
+        let inode = fs::metadata(file("file1.txt")).unwrap().ino();
+
+        let report = adopt_hardlinks(&dir_path, true).unwrap();
+        assert_eq!(
+            report.groups.iter().map(|g| &g.files).collect::<Vec<_>>(),
+            vec![&vec![file("file1.txt"), file("file2.txt")]]
+        );
+        assert!(!dir_path.join(".mirage").exists());
+
+        let report = adopt_hardlinks(&dir_path, false).unwrap();
+        assert_eq!(report.groups.len(), 1);
+        assert_eq!(report.groups[0].saved, 17);
+        // the files are left as they were
+        for name in ["file1.txt", "file2.txt"] {
+            assert_eq!(fs::metadata(file(name)).unwrap().ino(), inode);
+        }
+        let state = MirageState::open(&dir_path).unwrap();
+        assert_eq!(state.refcount(&state.originals_path().join("file1.txt")), 2);
+        drop(state);
+        assert_eq!(diff(&dir_path).unwrap(), vec![]);
+        assert_eq!(stats(&dir_path).unwrap().files_deduped, 1);
+        // adopted once only
+        assert!(adopt_hardlinks(&dir_path, false).unwrap().groups.is_empty());
+
+        // later runs link duplicates to the adopted group
+        apply(&dir_path).unwrap();
+        assert!(file("file3.txt").is_symlink());
+        assert_eq!(fs::metadata(file("file2.txt")).unwrap().ino(), inode);
+
+        revert(&dir_path).unwrap();
+        test_view.verify();
+        assert_eq!(
+            fs::read_to_string(file("file2.txt")).unwrap(),
+            "duplicate content"
+        );
+    }
+
+    #[test]
+    fn known_contents_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file2.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+        let linked = |name: &str| {
+            fs::symlink_metadata(dir_path.join(name))
+                .unwrap()
+                .file_type()
+                .is_symlink()
+        };
+
+        apply(&dir_path).unwrap();
+        let bloom = dir_path.join(".mirage").join("bloom");
+        assert!(bloom.is_file());
+
+        fs::write(dir_path.join("file3.txt"), "duplicate content").unwrap();
+        fs::write(dir_path.join("file4.txt"), "distinct contents").unwrap();
+        apply(&dir_path).unwrap();
+        assert!(linked("file3.txt"));
+        assert!(!linked("file4.txt"));
+        assert!(dir_path.join(".mirage").join("index").is_file());
+
+        // a lost filter is rebuilt from the originals
+        fs::remove_file(&bloom).unwrap();
+        fs::write(dir_path.join("file5.txt"), "duplicate content").unwrap();
+        apply(&dir_path).unwrap();
+        assert!(linked("file5.txt"));
+        assert!(bloom.is_file());
+    }
+
+    #[test]
+    fn incremental_apply_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let file = |name: &str, contents: &str| TestFsObject::File {
+            name: name.to_string(),
+            contents: contents.to_string(),
+        };
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                file("a1.txt", "aaaa"),
+                file("a2.txt", "aaaa"),
+                file("b.txt", "bbbb"),
+                file("c.txt", "cccc"),
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+        let store = dir_path.join(".mirage");
+
+        let first = apply(&dir_path).unwrap();
+        assert_eq!(first.bytes_hashed, 4 * 4);
+        assert!(store.join("index").is_file());
+
+        // unchanged files aren't read again
+        let second = apply(&dir_path).unwrap();
+        assert_eq!(second.bytes_hashed, 0);
+
+        // only the changed file is
+        fs::remove_file(dir_path.join("c.txt")).unwrap();
+        fs::write(dir_path.join("c.txt"), "dddd").unwrap();
+        let third = apply(&dir_path).unwrap();
+        assert_eq!(third.bytes_hashed, 4);
+
+        // a cache kept under its former name is still used, and moved
+        fs::rename(store.join("index"), store.join("cache.json")).unwrap();
+        let fourth = apply(&dir_path).unwrap();
+        assert_eq!(fourth.bytes_hashed, 0);
+        assert!(store.join("index").is_file());
+        assert!(!store.join("cache.json").exists());
+
+        revert(&dir_path).unwrap();
+    }
+
+    #[test]
+    fn originals_by_digest_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let file = |name: &str, contents: &str| TestFsObject::File {
+            name: name.to_string(),
+            contents: contents.to_string(),
+        };
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                file("a1.txt", "aaaa"),
+                file("a2.txt", "aaaa"),
+                file("b1.txt", "bbbb"),
+                file("b2.txt", "bbbb"),
+                file("c1.txt", "cccccc"),
+                file("c2.txt", "cccccc"),
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let test_view = test_dir.get_view(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+        apply(&dir_path).unwrap();
+
+        // new copies are linked to the original of their contents, and
+        // the originals aren't read again to find it
+        fs::write(dir_path.join("b3.txt"), "bbbb").unwrap();
+        fs::write(dir_path.join("d.txt"), "dd").unwrap();
+        let report = apply(&dir_path).unwrap();
+        let target = |name: &str| fs::read_link(dir_path.join(name)).unwrap();
+        assert_eq!(target("b3.txt"), target("b1.txt"));
+        assert_ne!(target("b3.txt"), target("a1.txt"));
+        assert!(!dir_path.join("d.txt").is_symlink());
+        assert_eq!(report.bytes_hashed, 4 + 2);
+
+        fs::remove_file(dir_path.join("b3.txt")).unwrap();
+        fs::remove_file(dir_path.join("d.txt")).unwrap();
+        revert(&dir_path).unwrap();
+        test_view.verify();
+    }
+
+    #[test]
+    fn replay_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file2.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let test_view = test_dir.get_view(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+
+        // an interrupted run, planned but never executed
+        let mut state = MirageState::get(&dir_path).unwrap();
+        let original = state.originals_path().join("file1.txt");
+        let file1 = fs::canonicalize(dir_path.join("file1.txt")).unwrap();
+        let file2 = fs::canonicalize(dir_path.join("file2.txt")).unwrap();
+        state.wal.actions.push(Action::new(
+            ActionType::Copy,
+            file1.clone(),
+            original.clone(),
+        ));
+        for file in [&file1, &file2] {
+            state.wal.actions.push(Action::new(
+                ActionType::Symlink,
+                file.clone(),
+                original.clone(),
+            ));
+            state
+                .wal
+                .redirections
+                .insert(file.clone(), original.clone());
+        }
+        state.commit().unwrap();
+
+        fs::write(&file2, "edited since").unwrap();
+        let hazards = |path: &Path| {
+            replay_plan(path)
+                .unwrap()
+                .into_iter()
+                .map(|step| step.hazard)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            hazards(&dir_path),
+            vec![None, None, Some(Hazard::ContentChanged)]
+        );
+
+        fs::write(&file2, "duplicate content").unwrap();
+        assert_eq!(hazards(&dir_path), vec![None, None, None]);
+
+        replay(&dir_path).unwrap();
+        assert!(replay_plan(&dir_path).unwrap().is_empty());
+        assert!(file1.is_symlink() && file2.is_symlink());
+
+        revert(&dir_path).unwrap();
+        test_view.verify();
+    }
+
+    #[test]
+    fn inspect_wal_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file2.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+
+        apply(&dir_path).unwrap();
+
+        let all = inspect_wal(&dir_path, &WalFilter::default()).unwrap();
+        assert_eq!(all.len(), 3);
+        assert!(all.iter().all(|entry| entry.applied));
+
+        let links = WalFilter {
+            action: Some("symlink".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(inspect_wal(&dir_path, &links).unwrap().len(), 2);
+
+        let file2 = WalFilter {
+            path: Some("file2".to_string()),
+            ..Default::default()
+        };
+        let entries = inspect_wal(&dir_path, &file2).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].index, 2);
+        assert_eq!(entries[0].action.action, "Symlink");
+    }
+
+    #[test]
+    fn forward_compatible_wal_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file2.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let test_view = test_dir.get_view(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+
+        apply(&dir_path).unwrap();
+
+        // what a newer mirage might have written
+        let wal_path = dir_path.join(".mirage").join("wal.json");
+        let mut json: serde_json::Value =
+            serde_json::from_slice(&fs::read(&wal_path).unwrap()).unwrap();
+        let wal = json.as_object_mut().unwrap();
+        wal.insert("generation".to_string(), 7.into());
+        let actions = wal["actions"].as_array_mut().unwrap();
+        actions[0]["checksum"] = "abc".into();
+        actions.push(serde_json::json!({
+            "action": {"Reflink": {"clone_range": true}},
+            "source": "/elsewhere/a",
+            "target": "/elsewhere/b",
+        }));
+        wal["checkpoint"] = 4.into();
+        fs::write(&wal_path, serde_json::to_vec_pretty(&json).unwrap()).unwrap();
+
+        let mut state = MirageState::get(&dir_path).unwrap();
+        state.commit().unwrap();
+        let written = fs::read_to_string(&wal_path).unwrap();
+        assert!(written.contains("generation"));
+        assert!(written.contains("checksum"));
+        assert!(written.contains("clone_range"));
+
+        let entries = inspect_wal(&dir_path, &WalFilter::default()).unwrap();
+        assert_eq!(entries[3].action.action, "Reflink");
+
+        // the known parts are reverted
+        revert(&dir_path).unwrap();
+        test_view.verify();
+    }
+
+    #[test]
+    fn action_inversion_test() {
+        let (a, b) = (PathBuf::from("a"), PathBuf::from("b"));
+        let inverted = |action| {
+            let inverse = Action::new(action, a.clone(), b.clone()).invert();
+            (inverse.action.name(), inverse.source, inverse.target)
+        };
+        let swapped = |name: &str| (name.to_string(), b.clone(), a.clone());
+        let kept = |name: &str| (name.to_string(), a.clone(), b.clone());
+
+        assert_eq!(inverted(ActionType::Copy), swapped("NOP"));
+        assert_eq!(inverted(ActionType::Symlink), swapped("Copy"));
+        assert_eq!(inverted(ActionType::NOP), kept("NOP"));
+        assert_eq!(inverted(ActionType::Hardlink), swapped("Copy"));
+        assert_eq!(inverted(ActionType::Reflink), kept("NOP"));
+        assert_eq!(inverted(ActionType::Delete), swapped("Copy"));
+        assert_eq!(inverted(ActionType::Move), swapped("Move"));
+        assert_eq!(
+            inverted(ActionType::Unknown("Future".into())),
+            kept("Future")
+        );
+    }
+
+    #[test]
+    fn extended_actions_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file2.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file3.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file4.txt".to_string(),
+                    contents: "moved content".to_string(),
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let test_view = test_dir.get_view(dir_path);
+
+        let dir_path = fs::canonicalize(test_dir.get_path(dir_path)).unwrap();
+        let file = |name: &str| dir_path.join(name);
+
+        let mut state = MirageState::get(&dir_path).unwrap();
+        let original = state.originals_path().join("file1.txt");
+        let actions = [
+            (ActionType::Copy, file("file1.txt"), original.clone()),
+            (ActionType::Hardlink, file("file1.txt"), original.clone()),
+            (ActionType::Hardlink, file("file2.txt"), original.clone()),
+            (ActionType::Delete, file("file3.txt"), original.clone()),
+            (ActionType::Move, file("file4.txt"), file("file5.txt")),
+        ];
+        for (action, source, target) in actions {
+            state.wal.actions.push(Action::new(action, source, target));
+        }
+        for name in ["file1.txt", "file2.txt"] {
+            state.wal.redirections.insert(file(name), original.clone());
+        }
+        execute_pending(&mut state).unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            assert_eq!(fs::metadata(file("file2.txt")).unwrap().nlink(), 3);
+        }
+        assert!(!file("file2.txt").is_symlink());
+        assert!(!file("file3.txt").exists());
+        assert!(!file("file4.txt").exists());
+        assert_eq!(
+            fs::read_to_string(file("file5.txt")).unwrap(),
+            "moved content"
+        );
+
+        revert(&dir_path).unwrap();
+        test_view.verify();
+    }
+
+    #[test]
+    fn rollback_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file2.txt".to_string(),
+                    contents: "other duplicate".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file3.txt".to_string(),
+                    contents: "other duplicate".to_string(),
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let test_view = test_dir.get_view(dir_path);
+
+        let dir_path = fs::canonicalize(test_dir.get_path(dir_path)).unwrap();
+        let file = |name: &str| dir_path.join(name);
+
+        // the second link of the first group can't be created
+        let mut state = MirageState::get(&dir_path).unwrap();
+        let first = state.originals_path().join("file1.txt");
+        let second = state.originals_path().join("file2.txt");
+        let vanished = dir_path.join("gone").join("file1.txt");
+        let actions = [
+            (ActionType::Copy, file("file1.txt"), first.clone()),
+            (ActionType::Symlink, file("file1.txt"), first.clone()),
+            (ActionType::Copy, file("file2.txt"), second.clone()),
+            (ActionType::Symlink, vanished.clone(), first.clone()),
+            (ActionType::Symlink, file("file2.txt"), second.clone()),
+            (ActionType::Symlink, file("file3.txt"), second.clone()),
+        ];
+        for (action, source, target) in actions {
+            state
+                .wal
+                .redirections
+                .insert(source.clone(), target.clone());
+            state.wal.actions.push(Action::new(action, source, target));
+        }
+
+        let mut progress = ProgressWriter::new(&state);
+        let rollbacks = execute_transactions(&mut state, &mut progress).unwrap();
+        assert_eq!(rollbacks.len(), 1);
+        assert_eq!(rollbacks[0].original, first);
+        assert_eq!(rollbacks[0].actions.len(), 3);
+
+        // the failed group is undone and forgotten, the other one applied
+        assert!(!file("file1.txt").is_symlink());
+        assert!(!first.exists());
+        assert!(file("file2.txt").is_symlink() && file("file3.txt").is_symlink());
+        assert_eq!(state.wal.actions.len(), 3);
+        assert_eq!(state.wal.checkpoint, 3);
+        assert!(!state.wal.redirections.contains_key(&vanished));
+        assert_eq!(
+            MirageState::get(&dir_path).unwrap().wal.rollbacks,
+            rollbacks
+        );
+
+        revert(&dir_path).unwrap();
+        test_view.verify();
+    }
+
+    #[test]
+    fn interrupted_group_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = fs::canonicalize(dir.path()).unwrap();
+        let file = |name: &str| dir_path.join(name);
+        for (name, contents) in [
+            ("a1", "alpha"),
+            ("a2", "alpha"),
+            ("b1", "beta"),
+            ("b2", "beta"),
+        ] {
+            fs::write(file(name), contents).unwrap();
+        }
+
+        let mut state = MirageState::get(&dir_path).unwrap();
+        let alpha = state.originals_path().join("a1");
+        let beta = state.originals_path().join("b1");
+        let actions = [
+            (ActionType::Copy, file("a1"), alpha.clone()),
+            (ActionType::Symlink, file("a1"), alpha.clone()),
+            (ActionType::Symlink, file("a2"), alpha.clone()),
+            (ActionType::Copy, file("b1"), beta.clone()),
+            (ActionType::Symlink, file("b1"), beta.clone()),
+            (ActionType::Symlink, file("b2"), beta.clone()),
+        ];
+        for (action, source, target) in actions {
+            if action.links() {
+                state
+                    .wal
+                    .redirections
+                    .insert(source.clone(), target.clone());
+            }
+            state.wal.push(Action::new(action, source, target));
+        }
+        // interrupted right after the first action of the first group
+        execute(&state.wal.actions[0], 0, &state.source_path).unwrap();
+        state.wal.checkpoint = 1;
+        state.commit().unwrap();
+
+        crate::interrupt::raise(true);
+        let mut progress = ProgressWriter::new(&state);
+        let interrupted = execute_transactions(&mut state, &mut progress);
+        crate::interrupt::raise(false);
+        assert!(matches!(interrupted, Err(MirageError::Interrupted)));
+        // the group under way is finished, the next one isn't started
+        assert_eq!(state.wal.checkpoint, 3);
+        assert!(file("a1").is_symlink() && file("a2").is_symlink());
+        assert!(!file("b1").is_symlink() && !beta.exists());
+
+        let rollbacks = execute_transactions(&mut state, &mut progress).unwrap();
+        assert!(rollbacks.is_empty());
+        assert!(file("b1").is_symlink() && file("b2").is_symlink());
+        drop(state);
+        revert(&dir_path).unwrap();
+        assert_eq!(fs::read_to_string(file("a2")).unwrap(), "alpha");
+        assert_eq!(fs::read_to_string(file("b2")).unwrap(), "beta");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_loop_test() {
+        use std::os::unix::fs::symlink;
+
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::Dir {
+                    name: "a".to_string(),
+                    contents: vec![TestFsObject::File {
+                        name: "file1.txt".to_string(),
+                        contents: "duplicate content".to_string(),
+                    }],
+                },
+                TestFsObject::Dir {
+                    name: "b".to_string(),
+                    contents: vec![TestFsObject::File {
+                        name: "file2.txt".to_string(),
+                        contents: "duplicate content".to_string(),
+                    }],
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let test_view = test_dir.get_view(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+
+        // a directory loop, a link to a sibling and a cycle of file links
+        symlink(&dir_path, dir_path.join("b").join("loop")).unwrap();
+        symlink(dir_path.join("a"), dir_path.join("c")).unwrap();
+        symlink(dir_path.join("y"), dir_path.join("x")).unwrap();
+        symlink(dir_path.join("x"), dir_path.join("y")).unwrap();
+
+        let options = ApplyOptions {
+            follow_symlinks: true,
+            ..Default::default()
+        };
+        apply_with_options(&dir_path, &options).unwrap();
+
+        let children = test_view.get_children();
+        assert!(children[0].get_children()[0].is_symlink());
+        assert!(children[1].get_children()[0].is_symlink());
+        assert_eq!(
+            MirageState::get(&dir_path).unwrap().wal.redirections.len(),
+            2
+        );
+
+        revert(&dir_path).unwrap();
+        for link in ["b/loop", "c", "x", "y"] {
+            fs::remove_file(dir_path.join(link)).unwrap();
+        }
+        test_view.verify();
+    }
+
+    #[test]
+    fn memory_budget_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file2.txt".to_string(),
+                    contents: "same size content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file3.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file4.txt".to_string(),
+                    contents: "unique".to_string(),
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let test_view = test_dir.get_view(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+
+        // every candidate goes through a spilled run
+        let options = ApplyOptions {
+            memory_budget: Some(1),
+            ..Default::default()
+        };
+        apply_with_options(&dir_path, &options).unwrap();
+
+        let children = test_view.get_children();
+        assert!(children[0].is_symlink());
+        assert!(!children[1].is_symlink());
+        assert!(children[2].is_symlink());
+        assert!(!children[3].is_symlink());
+        assert!(!dir_path.join(".mirage").join("tmp").exists());
+
+        revert(&dir_path).unwrap();
+        test_view.verify();
+    }
+
+    #[test]
+    fn default_ignores_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let sub_dir = |name: &str| TestFsObject::Dir {
+            name: name.to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: ".DS_Store".to_string(),
+                    contents: "finder state".to_string(),
+                },
+                TestFsObject::File {
+                    name: "Thumbs.DB".to_string(),
+                    contents: "thumbnails".to_string(),
+                },
+            ],
+        };
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![sub_dir("a"), sub_dir("b")],
+        };
+
+        test_dir.create(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+        let linked = |path: &str| {
+            fs::symlink_metadata(dir_path.join(path))
+                .unwrap()
+                .file_type()
+                .is_symlink()
+        };
+
+        apply(&dir_path).unwrap();
+        assert!(!linked("b/.DS_Store"));
+        assert!(!linked("b/Thumbs.DB"));
+
+        let options = ApplyOptions {
+            no_default_ignores: true,
+            ..Default::default()
+        };
+        apply_with_options(&dir_path, &options).unwrap();
+        assert!(linked("b/.DS_Store"));
+        assert!(linked("b/Thumbs.DB"));
+    }
+
+    #[test]
+    fn include_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let sub_dir = |name: &str| TestFsObject::Dir {
+            name: name.to_string(),
+            contents: ["disk.iso", "notes.txt", "film.mkv"]
+                .into_iter()
+                .map(|name| TestFsObject::File {
+                    name: name.to_string(),
+                    contents: format!("contents of {}", name),
+                })
+                .collect(),
+        };
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![sub_dir("a"), sub_dir("b")],
+        };
+
+        test_dir.create(dir_path);
+
+        let dir_path = fs::canonicalize(test_dir.get_path(dir_path)).unwrap();
+        let linked = |path: &str| {
+            fs::symlink_metadata(dir_path.join(path))
+                .unwrap()
+                .file_type()
+                .is_symlink()
+        };
+
+        // excluded extensions win over included globs
+        let options = ApplyOptions {
+            include: vec!["*.iso".to_string(), "*.txt".to_string()],
+            exclude_extensions: vec!["txt".to_string()],
+            ..Default::default()
+        };
+        apply_with_options(&dir_path, &options).unwrap();
+        assert!(linked("b/disk.iso"));
+        assert!(!linked("b/notes.txt"));
+        assert!(!linked("b/film.mkv"));
+        assert_eq!(
+            why(dir_path.join("b/film.mkv")).unwrap(),
+            Why::Excluded(Exclusion::NotIncluded)
+        );
+
+        let options = ApplyOptions {
+            include: vec!["b/*.mkv".to_string(), "a/**/*.mkv".to_string()],
+            ..Default::default()
+        };
+        apply_with_options(&dir_path, &options).unwrap();
+        assert!(linked("b/film.mkv"));
+        assert!(!linked("b/notes.txt"));
+    }
+
+    #[test]
+    fn revert_space_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: (1..=3)
+                .map(|i| TestFsObject::File {
+                    name: format!("file{}.txt", i),
+                    contents: "x".repeat(1000),
+                })
+                .collect(),
+        };
+
+        test_dir.create(dir_path);
+
+        let dir_path = fs::canonicalize(test_dir.get_path(dir_path)).unwrap();
+        apply(&dir_path).unwrap();
+
+        let dry_run = RevertOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let report = revert_with_options(&dir_path, &dry_run).unwrap();
+        assert_eq!(report.space.len(), 1);
+        assert_eq!(report.space[0].path, dir_path);
+        assert_eq!(report.space[0].needed, 3000);
+        assert!(report.space[0].fits());
+        let only = RevertOptions {
+            only: vec![dir_path.join("file2.txt")],
+            ..dry_run.clone()
+        };
+        assert_eq!(
+            revert_with_options(&dir_path, &only).unwrap().space[0].needed,
+            1000
+        );
+        // hard links to the originals take no room
+        let hard_links = RevertOptions {
+            as_hardlinks: true,
+            ..dry_run
+        };
+        assert!(revert_with_options(&dir_path, &hard_links)
+            .unwrap()
+            .space
+            .is_empty());
+
+        let short = SpaceNeeded {
+            path: dir_path.clone(),
+            needed: 3000,
+            free: 2999,
+        };
+        assert!(matches!(
+            ensure_space(&[report.space[0].clone(), short]),
+            Err(MirageError::NotEnoughSpace(_, 3000, 2999))
+        ));
+    }
+
+    #[test]
+    fn dry_run_revert_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = fs::canonicalize(dir.path()).unwrap();
+        fs::write(dir_path.join("a.txt"), "same").unwrap();
+        fs::write(dir_path.join("b.txt"), "same").unwrap();
+
+        let dry_run = RevertOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let report = revert_with_options(&dir_path, &dry_run).unwrap();
+        assert!(report.dry_run);
+        assert!(report.planned.is_empty());
+        // planning a revert of a tree that isn't managed leaves it unmanaged
+        assert!(!store_path(&dir_path).exists());
+        assert_eq!(fs::read_dir(&dir_path).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn partial_revert_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let sub_dir = |name: &str| TestFsObject::Dir {
+            name: name.to_string(),
+            contents: vec![TestFsObject::File {
+                name: "file.txt".to_string(),
+                contents: "shared contents".to_string(),
+            }],
+        };
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![sub_dir("a"), sub_dir("b"), sub_dir("c"), sub_dir("d")],
+        };
+
+        test_dir.create(dir_path);
+
+        let dir_path = fs::canonicalize(test_dir.get_path(dir_path)).unwrap();
+        let linked = |path: &str| {
+            fs::symlink_metadata(dir_path.join(path))
+                .unwrap()
+                .file_type()
+                .is_symlink()
+        };
+        apply(&dir_path).unwrap();
+        let original = read_link(dir_path.join("a/file.txt")).unwrap();
+
+        let only_a = RevertOptions {
+            only: vec![dir_path.join("a")],
+            ..Default::default()
+        };
+        let planned = revert_with_options(
+            &dir_path,
+            &RevertOptions {
+                dry_run: true,
+                ..only_a.clone()
+            },
+        )
+        .unwrap();
+        assert_eq!(planned.planned.len(), 1);
+        assert_eq!(planned.planned[0].target, dir_path.join("a/file.txt"));
+        assert!(linked("a/file.txt"));
+
+        let report = revert_with_options(&dir_path, &only_a).unwrap();
+        assert_eq!(report.restored, 1);
+        assert!(!linked("a/file.txt"));
+        assert!(linked("b/file.txt"));
+        assert_eq!(
+            fs::read_to_string(dir_path.join("a/file.txt")).unwrap(),
+            "shared contents"
+        );
+
+        // a path inside the tree reverts just that
+        revert(dir_path.join("b/file.txt")).unwrap();
+        assert!(!linked("b/file.txt"));
+        assert!(linked("c/file.txt"));
+        assert!(matches!(
+            revert(dir_path.join("a")),
+            Err(MirageError::NotManaged(_))
+        ));
+
+        let report = revert_with_options(
+            &dir_path,
+            &RevertOptions {
+                only: vec![dir_path.join("c"), dir_path.join("d")],
+                verify: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!((report.restored, report.verified), (2, 2));
+        assert!(!linked("d/file.txt"));
+        // nothing refers to the original any more, the store stays
+        assert!(!original.exists());
+        assert!(dir_path.join(".mirage").join("wal.json").is_file());
+        assert!(MirageState::open(&dir_path)
+            .unwrap()
+            .wal
+            .redirections
+            .is_empty());
+    }
+
+    #[test]
+    fn interrupted_partial_revert_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = fs::canonicalize(dir.path()).unwrap();
+        let file = |name: &str| dir_path.join("sub").join(name);
+        fs::create_dir(dir_path.join("sub")).unwrap();
+        for name in ["a", "b", "c"] {
+            fs::write(file(name), "shared contents").unwrap();
+        }
+        apply(&dir_path).unwrap();
+
+        let options = RevertOptions {
+            only: vec![dir_path.join("sub")],
+            ..Default::default()
+        };
+        // interrupted once the first path is restored
+        crate::interrupt::raise_after(1);
+        let interrupted = revert_with_options(&dir_path, &options);
+        crate::interrupt::raise(false);
+        assert!(matches!(interrupted, Err(MirageError::Interrupted)));
+        assert!(!file("a").is_symlink());
+        assert!(file("b").is_symlink() && file("c").is_symlink());
+
+        // the restored path is no longer managed, the rest still is
+        let state = MirageState::open(&dir_path).unwrap();
+        assert!(!state.wal.redirections.contains_key(&file("a")));
+        assert!(state.wal.redirections.contains_key(&file("b")));
+        drop(state);
+
+        let report = revert_with_options(&dir_path, &options).unwrap();
+        assert_eq!(report.restored, 2);
+        for name in ["a", "b", "c"] {
+            assert_eq!(fs::read_to_string(file(name)).unwrap(), "shared contents");
+        }
+    }
+
+    #[test]
+    fn external_store_path_test() {
+        let dir = tempdir().unwrap();
+        let photos = dir.path().join("photos");
+        let nested = dir.path().join("backup/photos");
+        fs::create_dir_all(&photos).unwrap();
+        fs::create_dir_all(&nested).unwrap();
+
+        let stores = Path::new("/srv/mirage");
+        let store = external_store_path(stores, &photos);
+        assert_eq!(store.parent(), Some(stores));
+        assert!(store
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with("photos-"));
+        // the same tree however it is named, trees of the same name apart
+        assert_eq!(
+            external_store_path(stores, &nested.join("../../photos")),
+            store
+        );
+        assert_ne!(external_store_path(stores, &nested), store);
+        assert!(external_store_path(Path::new("stores"), &photos).is_absolute());
+    }
+
+    #[test]
+    fn external_store_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = fs::canonicalize(dir.path()).unwrap();
+        let tree = dir_path.join("photos");
+        let stores = dir_path.join("stores");
+        fs::create_dir_all(tree.join("b")).unwrap();
+        fs::write(tree.join("a.jpg"), "same picture").unwrap();
+        fs::write(tree.join("b/a.jpg"), "same picture").unwrap();
+        fs::write(tree.join("c.jpg"), "another picture").unwrap();
+
+        let options = ApplyOptions {
+            store: Some(stores.clone()),
+            ..Default::default()
+        };
+        apply_with_options(&tree, &options).unwrap();
+        let store = external_store_path(&stores, &tree);
+        assert!(store.join("wal.json").is_file());
+        assert!(!tree.join(".mirage").exists());
+        let original = read_link(tree.join("b/a.jpg")).unwrap();
+        assert!(original.starts_with(&store));
+        // a run holding the store is reported by the tree, not the store
+        let mut running = MirageState::get_at(&tree, &options.location()).unwrap();
+        running.lock_run().unwrap();
+        assert!(matches!(
+            apply_with_options(&tree, &options),
+            Err(MirageError::Running(root, _)) if root == tree
+        ));
+        drop(running);
+        // a second run finds the same store and has nothing left to do
+        let report = apply_with_options(&tree, &options).unwrap();
+        assert_eq!(report.actions_executed, 0);
+        assert!(!tree.join(".mirage").exists());
+
+        let options = RevertOptions {
+            store: Some(stores.clone()),
+            ..Default::default()
+        };
+        let planned = RevertOptions {
+            dry_run: true,
+            ..options.clone()
+        };
+        assert!(!revert_with_options(&tree, &planned)
+            .unwrap()
+            .planned
+            .is_empty());
+        revert_with_options(&tree, &options).unwrap();
+        assert!(!store.exists());
+        assert!(!tree.join(".mirage").exists());
+        for file in ["a.jpg", "b/a.jpg"] {
+            assert!(!tree.join(file).is_symlink());
+            assert_eq!(fs::read_to_string(tree.join(file)).unwrap(), "same picture");
+        }
+    }
+
+    #[test]
+    fn other_roots_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let tree = |name: &str, file: &str| TestFsObject::Dir {
+            name: name.to_string(),
+            contents: vec![TestFsObject::File {
+                name: file.to_string(),
+                contents: "same photo".to_string(),
+            }],
+        };
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                tree("photos", "a.jpg"),
+                tree("backup", "b.jpg"),
+                tree("managed", "c.jpg"),
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let dir_path = fs::canonicalize(test_dir.get_path(dir_path)).unwrap();
+        let photos = dir_path.join("photos");
+        let backup = dir_path.join("backup");
+        let linked = |path: &Path| fs::symlink_metadata(path).unwrap().file_type().is_symlink();
+
+        let overlapping = ApplyOptions {
+            other_roots: vec![photos.join(".")],
+            ..Default::default()
+        };
+        assert!(matches!(
+            apply_with_options(&photos, &overlapping),
+            Err(MirageError::OverlappingRoots(_, _))
+        ));
+        apply(dir_path.join("managed")).unwrap();
+        let managed = ApplyOptions {
+            other_roots: vec![dir_path.join("managed")],
+            ..Default::default()
+        };
+        assert!(matches!(
+            apply_with_options(&photos, &managed),
+            Err(MirageError::AncestorStore(_))
+        ));
+        assert!(!store_path(&photos).exists());
+
+        let options = ApplyOptions {
+            other_roots: vec![backup.clone()],
+            ..Default::default()
+        };
+        apply_with_options(&photos, &options).unwrap();
+        assert!(linked(&photos.join("a.jpg")));
+        assert!(linked(&backup.join("b.jpg")));
+        assert!(!store_path(&backup).exists());
+        let original = read_link(backup.join("b.jpg")).unwrap();
+        assert!(original.starts_with(store_path(&photos)));
+
+        revert(&photos).unwrap();
+        assert!(!linked(&backup.join("b.jpg")));
+        assert_eq!(
+            fs::read_to_string(backup.join("b.jpg")).unwrap(),
+            "same photo"
+        );
+    }
+
+    #[test]
+    fn config_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let sub_dir = |name: &str| TestFsObject::Dir {
+            name: name.to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "small.txt".to_string(),
+                    contents: "tiny".to_string(),
+                },
+                TestFsObject::File {
+                    name: "large.txt".to_string(),
+                    contents: "x".repeat(100),
+                },
+                TestFsObject::File {
+                    name: "scratch.tmp".to_string(),
+                    contents: "y".repeat(100),
+                },
+            ],
+        };
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![sub_dir("a"), sub_dir("b")],
+        };
+
+        test_dir.create(dir_path);
+
+        let dir_path = fs::canonicalize(test_dir.get_path(dir_path)).unwrap();
+        let linked = |path: &str| {
+            fs::symlink_metadata(dir_path.join(path))
+                .unwrap()
+                .file_type()
+                .is_symlink()
+        };
+
+        fs::create_dir(dir_path.join(".mirage")).unwrap();
+        fs::write(
+            dir_path.join(".mirage").join(CONFIG_FILE),
+            "min-size = 10\nexclude-extensions = [\"tmp\"]\n",
+        )
+        .unwrap();
+        let config = Config::read(&dir_path.join(".mirage").join(CONFIG_FILE)).unwrap();
+        assert_eq!(config.min_size, Some(10));
+
+        // what the run was given wins
+        let mut options = ApplyOptions {
+            min_size: Some(5),
+            ..Default::default()
+        };
+        config.apply_to(&mut options, None);
+        apply_with_options(&dir_path, &options).unwrap();
+        assert!(linked("b/large.txt"));
+        assert!(!linked("b/small.txt"));
+        assert!(!linked("b/scratch.tmp"));
+        assert_eq!(
+            why(dir_path.join("b/small.txt")).unwrap(),
+            Why::Decided(Decision::TooSmall)
+        );
+    }
+
+    #[test]
+    fn ignore_file_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let sub_dir = |name: &str| TestFsObject::Dir {
+            name: name.to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "disk.iso".to_string(),
+                    contents: "disk image".to_string(),
+                },
+                TestFsObject::File {
+                    name: "scratch.tmp".to_string(),
+                    contents: "scratch".to_string(),
+                },
+                TestFsObject::Dir {
+                    name: "cache".to_string(),
+                    contents: vec![TestFsObject::File {
+                        name: "entry".to_string(),
+                        contents: "cached".to_string(),
+                    }],
+                },
+            ],
+        };
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: IGNORE_FILE.to_string(),
+                    contents: "*.tmp\ncache/\n".to_string(),
+                },
+                sub_dir("a"),
+                sub_dir("b"),
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let dir_path = fs::canonicalize(test_dir.get_path(dir_path)).unwrap();
+        // the rules of deeper files win
+        fs::write(dir_path.join("b").join(IGNORE_FILE), "!*.tmp\n").unwrap();
+        fs::write(dir_path.join("a").join(IGNORE_FILE), "!*.tmp\n").unwrap();
+        let linked = |path: &str| {
+            fs::symlink_metadata(dir_path.join(path))
+                .unwrap()
+                .file_type()
+                .is_symlink()
+        };
+
+        apply(&dir_path).unwrap();
+        assert!(linked("b/disk.iso"));
+        assert!(linked("b/scratch.tmp"));
+        assert!(!linked("b/cache/entry"));
+        assert!(!linked("b/.mirageignore"));
+        assert_eq!(
+            why(dir_path.join("b/cache/entry")).unwrap(),
+            Why::Excluded(Exclusion::IgnoreFile(dir_path.join(IGNORE_FILE)))
+        );
+
+        let options = ApplyOptions {
+            no_ignore_files: true,
+            ..Default::default()
+        };
+        apply_with_options(&dir_path, &options).unwrap();
+        assert!(linked("b/cache/entry"));
+    }
+
+    #[test]
+    fn respect_gitignore_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let file = |name: &str, contents: &str| TestFsObject::File {
+            name: name.to_string(),
+            contents: contents.to_string(),
+        };
+        let dir = |name: &str, contents| TestFsObject::Dir {
+            name: name.to_string(),
+            contents,
+        };
+        let repo = |name: &str| {
+            dir(
+                name,
+                vec![
+                    file(".gitignore", "target/\n"),
+                    dir(".git", vec![file("HEAD", "ref: refs/heads/main")]),
+                    dir("src", vec![file("main.rs", "fn main() {}")]),
+                    dir("target", vec![file("app", "build output")]),
+                ],
+            )
+        };
+        let test_dir = dir("test_dir", vec![repo("a"), repo("b")]);
+
+        test_dir.create(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+        let linked = |path: &str| {
+            fs::symlink_metadata(dir_path.join(path))
+                .unwrap()
+                .file_type()
+                .is_symlink()
+        };
+
+        let options = ApplyOptions {
+            respect_gitignore: true,
+            ..Default::default()
+        };
+        apply_with_options(&dir_path, &options).unwrap();
+        assert!(linked("b/src/main.rs"));
+        assert!(!linked("b/target/app"));
+        assert!(!linked("b/.gitignore"));
+        assert!(!linked("b/.git/HEAD"));
+
+        apply(&dir_path).unwrap();
+        assert!(linked("b/target/app"));
+        assert!(!linked("b/.git/HEAD"));
+    }
+
+    #[test]
+    fn walk_limits_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let file = |name: &str| TestFsObject::File {
+            name: name.to_string(),
+            contents: "duplicate content".to_string(),
+        };
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                file("a.txt"),
+                file("b.txt"),
+                TestFsObject::Dir {
+                    name: "sub".to_string(),
+                    contents: vec![file("c.txt")],
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let dir_path = fs::canonicalize(test_dir.get_path(dir_path)).unwrap();
+        let linked = |path: &str| {
+            fs::symlink_metadata(dir_path.join(path))
+                .unwrap()
+                .file_type()
+                .is_symlink()
+        };
+
+        // the tree is on a single filesystem, nothing is left out for it
+        let options = ApplyOptions {
+            max_depth: Some(1),
+            one_file_system: true,
+            ..Default::default()
+        };
+        apply_with_options(&dir_path, &options).unwrap();
+        assert!(linked("a.txt"));
+        assert!(linked("b.txt"));
+        assert!(!linked("sub/c.txt"));
+        assert_eq!(
+            why(dir_path.join("sub/c.txt")).unwrap(),
+            Why::Excluded(Exclusion::TooDeep)
+        );
+
+        apply(&dir_path).unwrap();
+        assert!(linked("sub/c.txt"));
+    }
+
+    #[test]
+    fn vcs_dirs_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let repo = |name: &str| TestFsObject::Dir {
+            name: name.to_string(),
+            contents: vec![TestFsObject::Dir {
+                name: ".git".to_string(),
+                contents: vec![TestFsObject::File {
+                    name: "HEAD".to_string(),
+                    contents: "ref: refs/heads/main".to_string(),
+                }],
+            }],
+        };
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![repo("a"), repo("b")],
+        };
+
+        test_dir.create(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+        let linked = |path: &str| {
+            fs::symlink_metadata(dir_path.join(path))
+                .unwrap()
+                .file_type()
+                .is_symlink()
+        };
+
+        apply(&dir_path).unwrap();
+        assert!(!linked("b/.git/HEAD"));
+
+        let options = ApplyOptions {
+            include_vcs: true,
+            ..Default::default()
+        };
+        apply_with_options(&dir_path, &options).unwrap();
+        assert!(linked("b/.git/HEAD"));
+    }
+
+    #[test]
+    fn list_groups_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "a1.txt".to_string(),
+                    contents: "short".to_string(),
+                },
+                TestFsObject::File {
+                    name: "a2.txt".to_string(),
+                    contents: "short".to_string(),
+                },
+                TestFsObject::File {
+                    name: "a3.txt".to_string(),
+                    contents: "short".to_string(),
+                },
+                TestFsObject::File {
+                    name: "b1.txt".to_string(),
+                    contents: "much longer content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "b2.txt".to_string(),
+                    contents: "much longer content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "c.txt".to_string(),
+                    contents: "unique".to_string(),
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let dir_path = fs::canonicalize(test_dir.get_path(dir_path)).unwrap();
+        let file = |name: &str| dir_path.join(name);
+        let files = |groups: &[ListedGroup]| {
+            groups
+                .iter()
+                .map(|group| group.files.clone())
+                .collect::<Vec<_>>()
+        };
+        let a = vec![file("a1.txt"), file("a2.txt"), file("a3.txt")];
+        let b = vec![file("b1.txt"), file("b2.txt")];
+
+        let scanned = list_groups(&dir_path, true, GroupOrder::Files).unwrap();
+        assert_eq!(files(&scanned), vec![a.clone(), b.clone()]);
+        assert!(!dir_path.join(".mirage").exists());
+        let scanned = list_groups(&dir_path, true, GroupOrder::Bytes).unwrap();
+        assert_eq!(files(&scanned), vec![b.clone(), a.clone()]);
+        assert_eq!(scanned[0].total_bytes(), 2 * 19);
+
+        apply(&dir_path).unwrap();
+        let state = MirageState::open(&dir_path).unwrap();
+        let listed = list_groups(&dir_path, false, GroupOrder::Files).unwrap();
+        assert_eq!(files(&listed), vec![a, b]);
+        assert!(listed
+            .iter()
+            .all(|group| group.original.starts_with(state.originals_path())));
+        assert!(list_groups(&dir_path, true, GroupOrder::Files)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn store_status_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file2.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file3.txt".to_string(),
+                    contents: "other content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file4.txt".to_string(),
+                    contents: "other content".to_string(),
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+
+        assert!(matches!(
+            store_status(&dir_path),
+            Err(MirageError::MissingStore(_))
+        ));
+        apply(&dir_path).unwrap();
+        let summary = store_status(&dir_path).unwrap();
+        assert_eq!(summary.groups, 2);
+        assert_eq!(summary.pending, 0);
+        assert_eq!(summary.bytes_saved, 17 + 13);
+        assert!(summary.consistent());
+
+        fs::remove_file(dir_path.join("file2.txt")).unwrap();
+        let summary = store_status(&dir_path).unwrap();
+        assert!(!summary.consistent());
+        assert_eq!(summary.divergences.len(), 1);
+    }
+
+    #[test]
+    fn verify_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: (1..=3)
+                .map(|i| TestFsObject::File {
+                    name: format!("file{}.txt", i),
+                    contents: "duplicate content".to_string(),
+                })
+                .collect(),
+        };
+
+        test_dir.create(dir_path);
+
+        let dir_path = fs::canonicalize(test_dir.get_path(dir_path)).unwrap();
+
+        assert!(matches!(
+            verify(&dir_path, false),
+            Err(MirageError::MissingStore(_))
+        ));
+        apply(&dir_path).unwrap();
+        let report = verify(&dir_path, true).unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.checked, 3);
+        assert_eq!(report.hashed, 1);
+        assert_eq!(report.unrecorded, 0);
+
+        let original = dir_path.join(".mirage").join("originals").join("file1.txt");
+        let file = |name: &str| dir_path.join(name);
+        fs::remove_file(file("file2.txt")).unwrap();
+        fs::write(file("file2.txt"), "duplicate content").unwrap();
+        fs::remove_file(file("file3.txt")).unwrap();
+        let report = verify(&dir_path, false).unwrap();
+        assert_eq!(
+            report.mismatches,
+            vec![
+                Mismatch {
+                    path: file("file2.txt"),
+                    original: original.clone(),
+                    discrepancy: Discrepancy::NotLinked,
+                },
+                Mismatch {
+                    path: file("file3.txt"),
+                    original: original.clone(),
+                    discrepancy: Discrepancy::Missing,
+                },
+            ]
+        );
+
+        let mut permissions = fs::metadata(&original).unwrap().permissions();
+        #[allow(clippy::permissions_set_readonly_false)]
+        permissions.set_readonly(false);
+        fs::set_permissions(&original, permissions).unwrap();
+        fs::write(&original, "changed content").unwrap();
+        let report = verify(&dir_path, true).unwrap();
+        assert_eq!(report.mismatches.len(), 3);
+        assert_eq!(report.mismatches[0].path, original);
+        assert_eq!(
+            report.mismatches[0].discrepancy,
+            Discrepancy::ContentChanged
+        );
+        // nothing was modified
+        assert_eq!(
+            fs::read_to_string(file("file2.txt")).unwrap(),
+            "duplicate content"
+        );
+        assert!(!file("file3.txt").exists());
+    }
+
+    #[test]
+    fn watch_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let file = |name: &str, contents: &str| TestFsObject::File {
+            name: name.to_string(),
+            contents: contents.to_string(),
+        };
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                file("file1.txt", "duplicate content"),
+                file("file2.txt", "duplicate content"),
+                file("single.txt", "single"),
+                file("unrelated.txt", "unrelated"),
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let dir_path = fs::canonicalize(test_dir.get_path(dir_path)).unwrap();
+        let file = |name: &str| dir_path.join(name);
+
+        let mut runs = Vec::new();
+        watch(
+            &dir_path,
+            &ApplyOptions::default(),
+            std::time::Duration::from_millis(10),
+            |report| {
+                runs.push(report.actions_executed);
+                if runs.len() == 1 {
+                    // picked up by the next run, linked to the original
+                    // already in the store
+                    fs::write(file("file3.txt"), "duplicate content").unwrap();
+                    // and to the file of the same size it duplicates
+                    fs::write(file("single2.txt"), "single").unwrap();
+                }
+                runs.len() < 2
+            },
+        )
+        .unwrap();
+        assert_eq!(runs, vec![3, 1 + 3]);
+
+        let original = dir_path.join(".mirage").join("originals").join("file1.txt");
+        assert_eq!(read_link(file("file3.txt")).unwrap(), original);
+        assert_eq!(
+            read_link(file("single.txt")).unwrap(),
+            read_link(file("single2.txt")).unwrap()
+        );
+        let state = MirageState::open(&dir_path).unwrap();
+        assert_eq!(state.refcount(&original), 3);
+        // files nothing new could duplicate aren't looked at again
+        assert_eq!(
+            why(file("unrelated.txt")).unwrap(),
+            Why::Decided(Decision::NotListed)
+        );
+    }
+
+    #[test]
+    fn diff_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: (1..=4)
+                .map(|i| TestFsObject::File {
+                    name: format!("file{}.txt", i),
+                    contents: "duplicate content".to_string(),
+                })
+                .collect(),
+        };
+
+        test_dir.create(dir_path);
+
+        let dir_path = fs::canonicalize(test_dir.get_path(dir_path)).unwrap();
+
+        apply(&dir_path).unwrap();
+        assert_eq!(diff(&dir_path).unwrap(), vec![]);
+
+        let file = |name: &str| dir_path.join(name);
+        fs::remove_file(file("file2.txt")).unwrap();
+        fs::write(file("file2.txt"), "edited").unwrap();
+        fs::remove_file(file("file3.txt")).unwrap();
+        fs::remove_file(file("file4.txt")).unwrap();
+        symlink_file(file("file2.txt"), file("file4.txt")).unwrap();
+
+        let original = dir_path.join(".mirage").join("originals").join("file1.txt");
+        let entry = |name: &str, divergence| DiffEntry {
+            path: file(name),
+            original: Some(original.clone()),
+            divergence,
+        };
+        assert_eq!(
+            diff(&dir_path).unwrap(),
+            vec![
+                entry("file2.txt", Divergence::Replaced),
+                entry("file3.txt", Divergence::Missing),
+                entry(
+                    "file4.txt",
+                    Divergence::Retargeted {
+                        actual: file("file2.txt")
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn fsck_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: (1..=3)
+                .map(|i| TestFsObject::File {
+                    name: format!("file{}.txt", i),
+                    contents: "duplicate content".to_string(),
+                })
+                .collect(),
+        };
+
+        test_dir.create(dir_path);
+
+        let dir_path = fs::canonicalize(test_dir.get_path(dir_path)).unwrap();
+
+        apply(&dir_path).unwrap();
+        assert_eq!(fsck(&dir_path, false).unwrap(), vec![]);
+
+        let file = |name: &str| dir_path.join(name);
+        let originals = dir_path.join(".mirage").join("originals");
+        fs::remove_file(file("file2.txt")).unwrap();
+        fs::remove_file(file("file3.txt")).unwrap();
+        fs::write(file("file3.txt"), "edited").unwrap();
+        fs::write(originals.join("stray.txt"), "stray").unwrap();
+        let mut state = MirageState::get(&dir_path).unwrap();
+        state
+            .wal
+            .redirections
+            .insert(file("gone.txt"), originals.join("file1.txt"));
+        state.commit().unwrap();
+
+        let found = |fix| {
+            fsck(&dir_path, fix)
+                .unwrap()
+                .into_iter()
+                .map(|finding| (finding.path, finding.problem, finding.fixed))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            found(true),
+            vec![
+                (file("gone.txt"), Problem::StaleRedirection, true),
+                (originals.join("stray.txt"), Problem::OrphanOriginal, true),
+                (
+                    file("file2.txt"),
+                    Problem::Diverged(Divergence::Missing),
+                    true
+                ),
+                (
+                    file("file3.txt"),
+                    Problem::Diverged(Divergence::Replaced),
+                    false
+                ),
+            ]
+        );
+        assert!(read_link(file("file2.txt")).is_ok());
+        assert!(!originals.join("stray.txt").exists());
+        assert_eq!(
+            found(false),
+            vec![(
+                file("file3.txt"),
+                Problem::Diverged(Divergence::Replaced),
+                false
+            )]
+        );
+    }
+
+    #[test]
+    fn fsck_repair_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: ["a1", "a2", "a3", "b1", "b2"]
+                .into_iter()
+                .map(|name| TestFsObject::File {
+                    name: format!("{}.txt", name),
+                    contents: if name.starts_with('a') {
+                        "duplicate content".to_string()
+                    } else {
+                        "other content".to_string()
+                    },
+                })
+                .collect(),
+        };
+
+        test_dir.create(dir_path);
+
+        let dir_path = fs::canonicalize(test_dir.get_path(dir_path)).unwrap();
+
+        apply(&dir_path).unwrap();
+        let file = |name: &str| dir_path.join(name);
+        let state = MirageState::open(&dir_path).unwrap();
+        let original_a = state.wal.redirections[&file("a1.txt")].clone();
+        let original_b = state.wal.redirections[&file("b1.txt")].clone();
+        drop(state);
+
+        // a2 still holds what the original did, b1 is left dangling
+        fs::remove_file(&original_a).unwrap();
+        fs::remove_file(file("a2.txt")).unwrap();
+        fs::write(file("a2.txt"), "duplicate content").unwrap();
+        fs::remove_file(file("a3.txt")).unwrap();
+        fs::remove_file(&original_b).unwrap();
+        fs::remove_file(file("b2.txt")).unwrap();
+
+        let options = FsckOptions {
+            repair: true,
+            ..Default::default()
+        };
+        let found = fsck_with_options(&dir_path, &options)
+            .unwrap()
+            .into_iter()
+            .map(|finding| (finding.path, finding.problem, finding.fixed))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            vec![
+                (
+                    original_a.clone(),
+                    Problem::Diverged(Divergence::OriginalMissing),
+                    true
+                ),
+                (
+                    original_b.clone(),
+                    Problem::Diverged(Divergence::OriginalMissing),
+                    false
+                ),
+                (
+                    file("a2.txt"),
+                    Problem::Diverged(Divergence::Replaced),
+                    false
+                ),
+                (file("a3.txt"), Problem::Diverged(Divergence::Missing), true),
+                (file("b2.txt"), Problem::Diverged(Divergence::Missing), true),
+            ]
+        );
+        assert_eq!(
+            fs::read_to_string(file("a3.txt")).unwrap(),
+            "duplicate content"
+        );
+        let state = MirageState::open(&dir_path).unwrap();
+        assert!(!state.wal.redirections.contains_key(&file("b2.txt")));
+        assert!(state.wal.redirections.contains_key(&file("b1.txt")));
+        drop(state);
+        assert_eq!(
+            fsck(&dir_path, false).unwrap().len(),
+            2,
+            "only the lost original and the replaced link are left"
+        );
+    }
+
+    #[test]
+    fn group_progress_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+        let file = |name: &str| dir_path.join(name);
+
+        let mut state = MirageState::get(dir_path).unwrap();
+        for (action, source, target) in [
+            (ActionType::Copy, "a", "o1"),
+            (ActionType::Symlink, "a", "o1"),
+            (ActionType::Symlink, "b", "o1"),
+            (ActionType::Copy, "c", "o2"),
+            (ActionType::Symlink, "c", "o2"),
+        ] {
+            state
+                .wal
+                .push(Action::new(action, file(source), file(target)));
+        }
+        state.wal.checkpoint = 2;
+        state.commit().unwrap();
+
+        let progress = || {
+            inspect_groups(dir_path)
+                .unwrap()
+                .into_iter()
+                .map(|status| {
+                    (
+                        status.group,
+                        status.original,
+                        status.applied,
+                        status.progress,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            progress(),
+            vec![
+                (0, file("o1"), 2, GroupProgress::Partial),
+                (1, file("o2"), 0, GroupProgress::NotStarted),
+            ]
+        );
+
+        // reloading keeps adding to the open groups
+        let mut state = MirageState::get(dir_path).unwrap();
+        state
+            .wal
+            .push(Action::new(ActionType::Symlink, file("d"), file("o2")));
+        state.wal.checkpoint = 3;
+        state.commit().unwrap();
+        assert_eq!(
+            progress(),
+            vec![
+                (0, file("o1"), 3, GroupProgress::Applied),
+                (1, file("o2"), 0, GroupProgress::NotStarted),
+            ]
+        );
+        assert_eq!(inspect_groups(dir_path).unwrap()[1].actions, 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stale_lock_test() {
+        use std::time::{Duration, SystemTime};
+
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: (1..=2)
+                .map(|i| TestFsObject::File {
+                    name: format!("file{}.txt", i),
+                    contents: "duplicate content".to_string(),
+                })
+                .collect(),
+        };
+
+        test_dir.create(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+        let mirage_path = dir_path.join(".mirage");
+
+        apply(&dir_path).unwrap();
+        assert!(!mirage_path.join("run.lock").exists());
+
+        // the lock of a run that crashed, by a process that is gone
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        let mut state = MirageState::get(&dir_path).unwrap();
+        state.lock_run().unwrap();
+        let mut owner: LockOwner =
+            serde_json::from_slice(&fs::read(mirage_path.join("run.lock")).unwrap()).unwrap();
+        assert!(matches!(revert(&dir_path), Err(MirageError::Running(_, _))));
+        assert!(matches!(
+            break_stale_lock(&dir_path),
+            Err(MirageError::Running(_, _))
+        ));
+        // keep the file when the state goes
+        std::mem::forget(state.run_lock.take());
+        owner.pid = child.id();
+        fs::write(
+            mirage_path.join("run.lock"),
+            serde_json::to_vec(&owner).unwrap(),
+        )
+        .unwrap();
+
+        assert!(matches!(
+            revert(&dir_path),
+            Err(MirageError::StaleLock(_, _))
+        ));
+        assert_eq!(
+            break_stale_lock(&dir_path).unwrap(),
+            Some(BrokenLock::Dead(owner.clone()))
+        );
+        let audit = fs::read_to_string(mirage_path.join("audit.log")).unwrap();
+        assert!(audit.contains("broke stale run lock"));
+
+        // the pid of the run was taken by a process started after it
+        if cfg!(target_os = "linux") {
+            owner.pid = std::process::id();
+            owner.started = 0;
+            fs::write(
+                mirage_path.join("run.lock"),
+                serde_json::to_vec(&owner).unwrap(),
+            )
+            .unwrap();
+            assert!(matches!(
+                revert(&dir_path),
+                Err(MirageError::StaleLock(_, _))
+            ));
+            break_stale_lock(&dir_path).unwrap();
+        }
+
+        // a run that died before writing its lock
+        let lock = File::create(mirage_path.join("run.lock")).unwrap();
+        assert!(matches!(revert(&dir_path), Err(MirageError::Running(_, _))));
+        lock.set_modified(SystemTime::now() - Duration::from_secs(60))
+            .unwrap();
+        assert!(matches!(
+            revert(&dir_path),
+            Err(MirageError::StaleLock(_, _))
+        ));
+        assert_eq!(
+            break_stale_lock(&dir_path).unwrap(),
+            Some(BrokenLock::Unreadable)
+        );
+        let audit = fs::read_to_string(mirage_path.join("audit.log")).unwrap();
+        assert!(audit.contains("broke unreadable run lock"));
+        revert(&dir_path).unwrap();
+    }
+
+    #[test]
+    fn duplicate_index_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let file = |name: &str, contents: &str| TestFsObject::File {
+            name: name.to_string(),
+            contents: contents.to_string(),
+        };
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                file("a1.txt", "aaaa"),
+                file("a2.txt", "aaaa"),
+                file("b1.txt", "bbbb"),
+                file("b2.txt", "bbbb"),
+                file("c.txt", "cccc"),
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let test_view = test_dir.get_view(dir_path);
+
+        let dir_path = fs::canonicalize(test_dir.get_path(dir_path)).unwrap();
+        let report = apply(&dir_path).unwrap();
+        let target = |name: &str| fs::read_link(dir_path.join(name)).unwrap();
+        assert_eq!(target("a1.txt"), target("a2.txt"));
+        assert_eq!(target("b1.txt"), target("b2.txt"));
+        assert_ne!(target("a1.txt"), target("b1.txt"));
+        assert!(!dir_path.join("c.txt").is_symlink());
+        // the five files of the size are hashed once, and only the ones
+        // whose digests collide compared, once per class
+        assert_eq!(report.bytes_hashed, 5 * 4);
+        assert_eq!(report.bytes_compared, 2 * 2 * 4);
+
+        revert(&dir_path).unwrap();
+        test_view.verify();
+    }
+
+    #[test]
+    fn parallel_hashing_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        // big enough to be hashed on several workers
+        let big = |c: &str| c.repeat(600 * 1024);
+        let file = |name: &str, contents: String| TestFsObject::File {
+            name: name.to_string(),
+            contents,
+        };
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                file("a1.bin", big("a")),
+                file("a2.bin", big("a")),
+                file("b1.bin", big("b")),
+                file("b2.bin", big("b")),
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let test_view = test_dir.get_view(dir_path);
+
+        let dir_path = fs::canonicalize(test_dir.get_path(dir_path)).unwrap();
+        let options = ApplyOptions {
+            jobs: Some(4),
+            ..Default::default()
+        };
+        let report = apply_with_options(&dir_path, &options).unwrap();
+        let target = |name: &str| fs::read_link(dir_path.join(name)).unwrap();
+        assert_eq!(target("a1.bin"), target("a2.bin"));
+        assert_eq!(target("b1.bin"), target("b2.bin"));
+        assert_ne!(target("a1.bin"), target("b1.bin"));
+        assert_eq!(report.bytes_hashed, 4 * 600 * 1024);
+
+        revert(&dir_path).unwrap();
+        test_view.verify();
+    }
+
+    #[test]
+    fn progress_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file2.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file3.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file4.txt".to_string(),
+                    contents: "unique".to_string(),
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+
+        assert_eq!(status(&dir_path).unwrap(), None);
+        let report = apply(&dir_path).unwrap();
+
+        let progress = status(&dir_path).unwrap().unwrap();
+        assert_eq!(progress.phase, Phase::Done);
+        assert_eq!(progress.files_scanned, 4);
+        assert_eq!(progress.bytes_saved, 2 * 17);
+        assert_eq!(progress.current, None);
+        // every duplicate hashed once and compared with the first of them,
+        // whose digest is recorded with the copy
+        assert_eq!(progress.bytes_compared, 2 * 2 * 17);
+        assert_eq!(progress.bytes_copied, 17);
+        assert_eq!(progress.bytes_hashed, 3 * 17);
+        assert_eq!(
+            (
+                report.bytes_hashed,
+                report.bytes_compared,
+                report.bytes_copied
+            ),
+            (3 * 17, 2 * 2 * 17, 17)
+        );
+        // one copy into the store and a link for each duplicate
+        assert_eq!(report.files_scanned, 4);
+        assert_eq!(report.actions_executed, 4);
+        let times = report.times;
+        assert!(times.committing > std::time::Duration::ZERO);
+        assert!(times.total() >= times.committing);
+    }
+
+    #[test]
+    fn metrics_file_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file2.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file3.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let test_path = test_dir.get_path(dir_path);
+        let metrics_file = dir_path.join("mirage.prom");
+        let options = ApplyOptions {
+            metrics_file: Some(metrics_file.clone()),
+            ..Default::default()
+        };
+
+        let report = apply_with_options(&test_path, &options).unwrap();
+        assert_eq!(report.bytes_saved, 2 * 17);
+        assert_eq!(report.store_size, 17);
+
+        let metrics = fs::read_to_string(&metrics_file).unwrap();
+        let labels = format!(
+            "{{path=\"{}\"}}",
+            fs::canonicalize(&test_path).unwrap().display()
+        );
+        assert!(metrics.contains(&format!("mirage_last_run_bytes_saved{} 34\n", labels)));
+        assert!(metrics.contains(&format!("mirage_last_run_success{} 1\n", labels)));
+        assert!(metrics.contains(&format!("mirage_last_run_errors{} 0\n", labels)));
+        assert!(metrics.contains(&format!("mirage_store_size_bytes{} 17\n", labels)));
+
+        // failed runs are reported too
+        let missing = dir_path.join("missing");
+        assert!(apply_with_options(&missing, &options).is_err());
+        let metrics = fs::read_to_string(&metrics_file).unwrap();
+        assert!(metrics.contains("mirage_last_run_success{path=\""));
+        assert!(metrics.contains("} 0\n"));
+        assert!(!metrics.contains("mirage_store_size_bytes"));
+    }
+
+    #[test]
+    fn why_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file2.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "unique.txt".to_string(),
+                    contents: "unique".to_string(),
+                },
+                TestFsObject::File {
+                    name: "same_size1.txt".to_string(),
+                    contents: "aaaa".to_string(),
+                },
+                TestFsObject::File {
+                    name: "same_size2.txt".to_string(),
+                    contents: "bbbb".to_string(),
+                },
+                TestFsObject::File {
+                    name: "Thumbs.db".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::Dir {
+                    name: ".git".to_string(),
+                    contents: vec![TestFsObject::File {
+                        name: "config".to_string(),
+                        contents: "duplicate content".to_string(),
+                    }],
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let dir_path = fs::canonicalize(test_dir.get_path(dir_path)).unwrap();
+        assert_eq!(
+            why(dir_path.join("unique.txt")).unwrap_err().to_string(),
+            MirageError::MissingStore(dir_path.join("unique.txt")).to_string()
+        );
+        apply(&dir_path).unwrap();
+
+        let original = fs::canonicalize(dir_path.join("file1.txt")).unwrap();
+        for file in ["file1.txt", "file2.txt"] {
+            assert_eq!(
+                why(dir_path.join(file)).unwrap(),
+                Why::Decided(Decision::Deduplicated {
+                    original: original.clone()
+                })
+            );
+        }
+        assert_eq!(
+            why(dir_path.join("unique.txt")).unwrap(),
+            Why::Decided(Decision::UniqueSize)
+        );
+        assert_eq!(
+            why(dir_path.join("same_size1.txt")).unwrap(),
+            Why::Decided(Decision::NoMatch { compared: 1 })
+        );
+        assert_eq!(
+            why(dir_path.join("Thumbs.db")).unwrap(),
+            Why::Excluded(Exclusion::Junk)
+        );
+        assert_eq!(
+            why(dir_path.join(".git").join("config")).unwrap(),
+            Why::Excluded(Exclusion::VersionControl)
+        );
+
+        // links aren't scanned again, new files weren't seen
+        File::create(dir_path.join("new.txt")).unwrap();
+        apply(&dir_path).unwrap();
+        assert_eq!(
+            why(dir_path.join("file1.txt")).unwrap(),
+            Why::Managed { original }
+        );
+        fs::write(dir_path.join("newer.txt"), "newer").unwrap();
+        assert_eq!(why(dir_path.join("newer.txt")).unwrap(), Why::NotSeen);
+    }
+
+    #[test]
+    fn journal_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file2.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file3.txt".to_string(),
+                    contents: "different content".to_string(),
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let dir_path = fs::canonicalize(test_dir.get_path(dir_path)).unwrap();
+        apply(&dir_path).unwrap();
+        assert_eq!(journal(&dir_path, false).unwrap(), None);
+        assert_eq!(comparisons(dir_path.join("file3.txt")).unwrap(), None);
+
+        fs::write(dir_path.join("file4.txt"), "different content").unwrap();
+        let options = ApplyOptions {
+            journal: true,
+            ..Default::default()
+        };
+        apply_with_options(&dir_path, &options).unwrap();
+
+        let entries = journal(&dir_path, false).unwrap().unwrap();
+        assert!(entries.contains(&JournalEntry::Compared {
+            candidate: dir_path.join("file3.txt"),
+            with: dir_path.join("file4.txt"),
+            same: true,
+        }));
+        let original = fs::canonicalize(dir_path.join("file3.txt")).unwrap();
+        assert!(entries.contains(&JournalEntry::Decided {
+            path: dir_path.join("file4.txt"),
+            decision: Decision::Deduplicated { original },
+        }));
+        assert_eq!(
+            comparisons(dir_path.join("file4.txt")).unwrap().unwrap(),
+            vec![(dir_path.join("file3.txt"), true)]
+        );
+
+        // the journal of the run before is kept
+        apply_with_options(&dir_path, &options).unwrap();
+        assert_eq!(journal(&dir_path, true).unwrap().unwrap(), entries);
+        assert_eq!(
+            comparisons(dir_path.join("file4.txt")).unwrap().unwrap(),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn identical_subtrees_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let subtree = |name: &str, contents: &str| TestFsObject::Dir {
+            name: name.to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "x.txt".to_string(),
+                    contents: contents.to_string(),
+                },
+                TestFsObject::Dir {
+                    name: "y".to_string(),
+                    contents: vec![TestFsObject::File {
+                        name: "z.txt".to_string(),
+                        contents: "nested content".to_string(),
+                    }],
+                },
+            ],
+        };
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                subtree("a", "duplicate content"),
+                subtree("b", "duplicate content"),
+                subtree("c", "different content"),
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+        apply(&dir_path).unwrap();
+
+        // linked or not, the contents are what counts
+        let groups = identical_subtrees(&dir_path).unwrap();
+        let dirs = |group: &Vec<SubtreeHash>| {
+            group
+                .iter()
+                .map(|subtree| subtree.dir.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(groups.len(), 2);
+        assert_eq!(
+            dirs(&groups[0]),
+            vec![PathBuf::from("a"), PathBuf::from("b")]
+        );
+        assert_eq!(groups[0][0].files, 2);
+        assert_eq!(groups[0][0].bytes, 17 + 14);
+        // matched outside the identical directories too
+        assert_eq!(
+            dirs(&groups[1]),
+            vec![
+                Path::new("a").join("y"),
+                Path::new("b").join("y"),
+                Path::new("c").join("y")
+            ]
+        );
+        assert!(dir_path.join(".mirage").join("merkle.json").is_file());
+
+        // a changed file changes the hashes of every directory above it
+        fs::remove_file(dir_path.join("b").join("x.txt")).unwrap();
+        fs::write(dir_path.join("b").join("x.txt"), "changed content").unwrap();
+        let groups = identical_subtrees(&dir_path).unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(
+            dirs(&groups[0]),
+            vec![
+                Path::new("a").join("y"),
+                Path::new("b").join("y"),
+                Path::new("c").join("y")
+            ]
+        );
+    }
+
+    #[test]
+    fn store_volume_test() {
+        let dir = tempdir().unwrap();
+        let volume = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: "file1.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "file2.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        let test_path = test_dir.get_path(dir_path);
+        let inside = ApplyOptions {
+            store_volume: Some(test_path.clone()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            apply_with_options(&test_path, &inside),
+            Err(MirageError::StoreVolumeInTree(_))
+        ));
+
+        let options = ApplyOptions {
+            store_volume: Some(volume.path().to_path_buf()),
+            ..Default::default()
+        };
+        apply_with_options(&test_path, &options).unwrap();
+        let originals = originals_dir(&test_path).unwrap().unwrap();
+        assert!(originals.starts_with(fs::canonicalize(volume.path()).unwrap()));
+        for file in ["file1.txt", "file2.txt"] {
+            let target = read_link(test_path.join(file)).unwrap();
+            assert!(target.is_absolute());
+            assert!(target.starts_with(&originals));
         }
+        assert_eq!(
+            fs::read_dir(test_path.join(".mirage").join("originals"))
+                .unwrap()
+                .count(),
+            0
+        );
 
-        fn get_path(&self, base_path: &Path) -> PathBuf {
-            match self {
-                TestFsObject::File { name, .. } => base_path.join(name),
-                TestFsObject::Dir { name, .. } => base_path.join(name),
-            }
-        }
+        // later runs find the originals through the WAL
+        fs::write(test_path.join("file3.txt"), "duplicate content").unwrap();
+        apply(&test_path).unwrap();
+        assert!(read_link(test_path.join("file3.txt"))
+            .unwrap()
+            .starts_with(&originals));
+        assert!(matches!(
+            apply_with_options(
+                &test_path,
+                &ApplyOptions {
+                    store_volume: Some(dir_path.to_path_buf()),
+                    ..Default::default()
+                }
+            ),
+            Err(MirageError::StoreVolumeChanged(_))
+        ));
 
-        fn get_contents(&self) -> &str {
-            match self {
-                TestFsObject::File { contents, .. } => contents.as_str(),
-                TestFsObject::Dir { .. } => panic!("Cannot get contents of a directory"),
-            }
-        }
+        revert(&test_path).unwrap();
+        assert!(!originals.exists());
+        assert_eq!(
+            fs::read_to_string(test_path.join("file3.txt")).unwrap(),
+            "duplicate content"
+        );
+    }
 
-        fn get_children(&self) -> &[TestFsObject] {
-            match self {
-                TestFsObject::Dir { contents, .. } => contents.as_slice(),
-                TestFsObject::File { .. } => panic!("Cannot get children of a file"),
-            }
+    #[test]
+    fn store_name_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                TestFsObject::File {
+                    name: ".mirage-notes.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+                TestFsObject::File {
+                    name: "notes.txt".to_string(),
+                    contents: "duplicate content".to_string(),
+                },
+            ],
+        };
+
+        test_dir.create(dir_path);
+
+        // only the store itself is left alone, not names resembling it
+        let test_path = test_dir.get_path(dir_path);
+        apply(&test_path).unwrap();
+        assert!(read_link(test_path.join(".mirage-notes.txt")).is_ok());
+        assert!(read_link(test_path.join("notes.txt")).is_ok());
+        assert!(matches!(
+            why(test_path.join(".mirage-notes.txt")).unwrap(),
+            Why::Decided(Decision::Deduplicated { .. })
+        ));
+    }
+
+    #[test]
+    fn state_dir_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = fs::canonicalize(dir.path()).unwrap();
+        let nested = dir_path.join("nested");
+        fs::create_dir(&nested).unwrap();
+        for file in ["a.txt", "b.txt", "nested/a.txt", "nested/b.txt"] {
+            fs::write(dir_path.join(file), "duplicate content").unwrap();
         }
 
-        fn is_symlink(&self, base_path: &Path) -> bool {
-            match self {
-                TestFsObject::File { name, .. } => {
-                    debug!("Checking if file symlink for {:?} {:?}", base_path, name);
-                    let file_path = base_path.join(name);
-                    fs::symlink_metadata(file_path)
-                        .map(|meta| meta.file_type().is_symlink())
-                        .expect("Error checking symlink metadata")
-                }
-                TestFsObject::Dir { name, .. } => {
-                    debug!("Checking if dir symlink for {:?} {:?}", base_path, name);
-                    let dir_path = base_path.join(name);
-                    fs::symlink_metadata(dir_path)
-                        .map(|meta| meta.file_type().is_symlink())
-                        .expect("Error checking symlink metadata")
-                }
-            }
+        let options = ApplyOptions {
+            state_dir: Some(".dedup".into()),
+            ..Default::default()
+        };
+        // stores of the same name are nested ones
+        apply_with_options(&nested, &options).unwrap();
+        assert!(nested.join(".dedup/wal.json").is_file());
+        assert!(matches!(
+            apply_with_options(&dir_path, &options),
+            Err(MirageError::NestedStore(root)) if root == nested
+        ));
+        let adopting = ApplyOptions {
+            adopt_nested: true,
+            ..options.clone()
+        };
+        apply_with_options(&dir_path, &adopting).unwrap();
+        assert!(dir_path.join(".dedup/wal.json").is_file());
+        assert!(!nested.join(".dedup").exists());
+        assert!(!dir_path.join(".mirage").exists());
+        for file in ["a.txt", "b.txt", "nested/a.txt", "nested/b.txt"] {
+            assert!(dir_path.join(file).is_symlink());
         }
+        // the store isn't taken for files of the tree
+        let report = apply_with_options(&dir_path, &options).unwrap();
+        assert_eq!(report.actions_executed, 0);
 
-        fn get_name(&self) -> &str {
-            match self {
-                TestFsObject::File { name, .. } => name,
-                TestFsObject::Dir { name, .. } => name,
-            }
+        let options = RevertOptions {
+            state_dir: Some(".dedup".into()),
+            ..Default::default()
+        };
+        revert_with_options(&dir_path, &options).unwrap();
+        assert!(!dir_path.join(".dedup").exists());
+        for file in ["a.txt", "b.txt", "nested/a.txt", "nested/b.txt"] {
+            assert!(!dir_path.join(file).is_symlink());
+            assert_eq!(
+                fs::read_to_string(dir_path.join(file)).unwrap(),
+                "duplicate content"
+            );
         }
     }
 
     #[test]
-    fn simple_test() {
-        // pretty_env_logger::init();
+    fn ancestor_store_test() {
         let dir = tempdir().unwrap();
         let dir_path = dir.path();
 
@@ -594,63 +8137,182 @@ mod tests {
                     name: "file2.txt".to_string(),
                     contents: "duplicate content".to_string(),
                 },
-                TestFsObject::File {
-                    name: "file3.txt".to_string(),
-                    contents: "unique content".to_string(),
+                TestFsObject::Dir {
+                    name: "subdir".to_string(),
+                    contents: vec![],
                 },
             ],
         };
 
         test_dir.create(dir_path);
 
-        let test_view = test_dir.get_view(dir_path);
+        let test_path = test_dir.get_path(dir_path);
+        let subdir_path = test_path.join("subdir");
+        apply(&test_path).unwrap();
+        let original = read_link(test_path.join("file1.txt")).unwrap();
 
-        let dir_path = test_dir.get_path(dir_path);
+        fs::write(subdir_path.join("file3.txt"), "duplicate content").unwrap();
+        fs::write(subdir_path.join("file4.txt"), "other content").unwrap();
+        fs::write(subdir_path.join("file5.txt"), "other content").unwrap();
+        apply(&subdir_path).unwrap();
 
-        apply(&dir_path).unwrap();
+        // the subdirectory shares the store of the tree
+        assert!(!subdir_path.join(".mirage").exists());
+        assert_eq!(read_link(subdir_path.join("file3.txt")).unwrap(), original);
+        let other = read_link(subdir_path.join("file4.txt")).unwrap();
+        assert!(other.starts_with(fs::canonicalize(&test_path).unwrap().join(".mirage")));
+        assert_eq!(read_link(subdir_path.join("file5.txt")).unwrap(), other);
+        assert!(matches!(
+            apply_with_options(
+                &subdir_path,
+                &ApplyOptions {
+                    per_subdirectory: true,
+                    ..Default::default()
+                }
+            ),
+            Err(MirageError::AncestorStore(root)) if root == test_path
+        ));
 
-        let originals_dir = dir_path.join(".mirage/originals");
+        revert(&test_path).unwrap();
+        assert_eq!(
+            fs::read_to_string(subdir_path.join("file3.txt")).unwrap(),
+            "duplicate content"
+        );
+        assert_eq!(
+            fs::read_to_string(subdir_path.join("file5.txt")).unwrap(),
+            "other content"
+        );
+    }
 
-        // file1 should now be in .mirage/originals
-        let orig1 = originals_dir.join("file1.txt");
-        assert!(orig1.exists());
+    #[test]
+    fn wal_segments_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+        let mirage_path = dir_path.join(".mirage");
 
-        // file1 should now be a symlink to file1 in .mirage/originals
-        assert!(test_view.get_children()[0].is_symlink());
+        let mut state = MirageState::get(dir_path).unwrap();
+        for i in 0..5000 {
+            state.wal.actions.push(Action::new(
+                ActionType::NOP,
+                PathBuf::from(format!("/tree/{}", i)),
+                PathBuf::from("/original"),
+            ));
+        }
+        state.commit().unwrap();
+        // pending actions are sealed as well
+        assert!(mirage_path.join("wal.0001.json").is_file());
 
-        assert!(test_view.get_children()[1].is_symlink());
+        state.wal.checkpoint = 4500;
+        state.commit().unwrap();
+        let index = fs::read_to_string(mirage_path.join("wal.json")).unwrap();
+        assert!(!index.contains("\"/tree/0\""));
+        assert!(index.contains("\"/tree/4999\""));
 
-        assert_eq!(
-            fs::canonicalize(read_link(&test_view.get_children()[0].get_full_path()).unwrap())
-                .unwrap(),
-            fs::canonicalize(&orig1).unwrap()
-        );
+        // untouched segments aren't written again
+        let modified = || {
+            fs::metadata(mirage_path.join("wal.0001.json"))
+                .unwrap()
+                .modified()
+                .unwrap()
+        };
+        let before = modified();
+        state.wal.checkpoint = 5000;
+        state.commit().unwrap();
+        assert_eq!(modified(), before);
 
-        assert_eq!(
-            fs::canonicalize(read_link(&test_view.get_children()[1].get_full_path()).unwrap())
-                .unwrap(),
-            fs::canonicalize(&orig1).unwrap()
-        );
+        let state = MirageState::get(dir_path).unwrap();
+        assert_eq!(state.wal.actions.len(), 5000);
+        assert_eq!(state.wal.actions[4096].source, Path::new("/tree/4096"));
+        let entries = inspect_wal(dir_path, &WalFilter::default()).unwrap();
+        assert_eq!(entries.len(), 5000);
+        assert_eq!(entries[4999].action.source, Path::new("/tree/4999"));
+        assert!(entries.iter().all(|entry| entry.applied));
 
-        assert!(&test_view.get_children()[2]
-            .get_fs_metadata()
-            .file_type()
-            .is_file());
+        // a changed segment moves to a new file
+        let mut state = state;
+        state.forget_actions(|action| action.source == Path::new("/tree/7"));
+        state.commit().unwrap();
+        assert!(!mirage_path.join("wal.0001.json").exists());
+        assert!(mirage_path.join("wal.0002.json").is_file());
+        let state = MirageState::get(dir_path).unwrap();
+        assert_eq!(state.wal.actions.len(), 4999);
+        assert_eq!(state.wal.checkpoint, 4999);
 
-        revert(&dir_path).unwrap();
+        let segment = mirage_path.join("wal.0002.json");
+        let edited = fs::read_to_string(&segment)
+            .unwrap()
+            .replace("/tree/8\"", "/tree/9\"");
+        fs::write(&segment, edited).unwrap();
+        assert!(matches!(
+            MirageState::get(dir_path),
+            Err(MirageError::WALTampered)
+        ));
+        assert!(matches!(
+            inspect_wal(dir_path, &WalFilter::default()),
+            Err(MirageError::WALTampered)
+        ));
+    }
 
-        test_view.verify();
+    #[test]
+    fn wal_segment_compaction_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
+        let mirage_path = dir_path.join(".mirage");
+        let segment = |n: u64| mirage_path.join(format!("wal.{:04}.json", n));
 
-        // Check if mirage directory is removed
+        let mut state = MirageState::get(dir_path).unwrap();
+        for i in 0..10000 {
+            state.wal.actions.push(Action::new(
+                ActionType::NOP,
+                PathBuf::from(format!("/tree/{}", i)),
+                PathBuf::from("/original"),
+            ));
+        }
+        state.wal.checkpoint = 10000;
+        state.commit().unwrap();
+        assert!(segment(1).is_file());
+        assert!(segment(2).is_file());
 
-        assert!(!dir_path.join(".mirage").exists());
-        assert!(!dir_path.join(".mirage/originals").exists());
-        assert!(!dir_path.join(".mirage/wal.json").exists());
+        // only the segment holding a changed action is written again
+        state.wal.actions[5].source = PathBuf::from("/moved");
+        state.wal.touch(5);
+        state.commit().unwrap();
+        assert!(!segment(1).exists());
+        assert!(segment(2).is_file());
+        assert!(segment(3).is_file());
+
+        // the moved action stays first
+        let number = |path: &Path| -> usize {
+            path.strip_prefix("/tree")
+                .map_or(usize::MAX, |name| name.to_str().unwrap().parse().unwrap())
+        };
+        state.forget_actions(|action| number(&action.source) < 100);
+        state.commit().unwrap();
+        assert!(!segment(3).exists());
+        assert!(segment(2).is_file());
+        assert!(segment(4).is_file());
+
+        // shrunk segments are folded together once they fit in one
+        state.forget_actions(|action| (4096..8150).contains(&number(&action.source)));
+        state.commit().unwrap();
+        assert_eq!(state.wal.segments.len(), 1);
+        assert!(!segment(2).exists());
+        assert!(!segment(4).exists());
+        assert!(segment(5).is_file());
+
+        let state = MirageState::get(dir_path).unwrap();
+        assert_eq!(state.wal.sealed(), 3997 + 42);
+        assert_eq!(state.wal.actions.len(), 10000 - 99 - 4054);
+        assert_eq!(state.wal.actions[0].source, Path::new("/moved"));
+        assert_eq!(state.wal.actions[3997].source, Path::new("/tree/8150"));
+        assert_eq!(
+            state.wal.actions.last().unwrap().source,
+            Path::new("/tree/9999")
+        );
     }
 
     #[test]
-    fn complex_test() {
-        // pretty_env_logger::init();
+    fn state_backups_test() {
         let dir = tempdir().unwrap();
         let dir_path = dir.path();
 
@@ -665,96 +8327,279 @@ mod tests {
                     name: "file2.txt".to_string(),
                     contents: "duplicate content".to_string(),
                 },
-                TestFsObject::File {
-                    name: "file3.txt".to_string(),
-                    contents: "unique content".to_string(),
-                },
-                TestFsObject::Dir {
-                    name: "subdir".to_string(),
-                    contents: vec![
-                        TestFsObject::File {
-                            name: "file4.txt".to_string(),
-                            contents: "duplicate content".to_string(),
-                        },
-                        TestFsObject::File {
-                            name: "file5.txt".to_string(),
-                            contents: "unique content".to_string(),
-                        },
-                    ],
-                },
             ],
         };
 
         test_dir.create(dir_path);
 
-        let test_view = test_dir.get_view(dir_path);
+        let test_path = test_dir.get_path(dir_path);
+        // nothing to back up before the first run
+        apply(&test_path).unwrap();
+        assert!(state_backups(&test_path).unwrap().is_empty());
 
-        let dir_path = test_dir.get_path(dir_path);
+        fs::write(test_path.join("file3.txt"), "duplicate content").unwrap();
+        apply(&test_path).unwrap();
+        let backups = state_backups(&test_path).unwrap();
+        assert_eq!(backups.len(), 1);
+        assert_eq!(backups[0].number, 1);
+        assert_eq!(backups[0].command, "apply");
+        assert_eq!(backups[0].actions, 3);
 
-        apply(&dir_path).unwrap();
+        // only as many as asked for are kept
+        let options = ApplyOptions {
+            backups: Some(2),
+            ..Default::default()
+        };
+        for _ in 0..3 {
+            apply_with_options(&test_path, &options).unwrap();
+        }
+        let backups = state_backups(&test_path).unwrap();
+        assert_eq!(
+            backups
+                .iter()
+                .map(|backup| backup.number)
+                .collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert!(backups.iter().all(|backup| backup.actions == 4));
 
-        let originals_dir = dir_path.join(".mirage/originals");
+        assert!(matches!(
+            restore_state(&test_path, 3),
+            Err(MirageError::MissingBackup(3))
+        ));
+        fs::remove_dir_all(test_path.join(".mirage").join("backups").join("2")).unwrap();
+        apply_with_options(
+            &test_path,
+            &ApplyOptions {
+                backups: Some(0),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let file3 = fs::canonicalize(&test_path).unwrap().join("file3.txt");
+        let mut state = MirageState::get(&test_path).unwrap();
+        assert!(state.wal.redirections.remove(&file3).is_some());
+        state.commit().unwrap();
+        drop(state);
+        restore_state(&test_path, 1).unwrap();
+        let state = MirageState::get(&test_path).unwrap();
+        assert!(state.wal.redirections.contains_key(&file3));
+    }
 
-        // file1 should now be in .mirage/originals
-        let orig1 = originals_dir.join("file1.txt");
-        assert!(orig1.exists());
+    #[test]
+    fn bounded_run_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
 
-        let orig3 = originals_dir.join("file3.txt");
-        assert!(orig3.exists());
+        let file = |name: &str, contents: &str| TestFsObject::File {
+            name: name.to_string(),
+            contents: contents.to_string(),
+        };
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![
+                file("a1.txt", "a"),
+                file("a2.txt", "a"),
+                file("b1.txt", "bb"),
+                file("b2.txt", "bb"),
+                file("c1.txt", "ccc"),
+                file("c2.txt", "ccc"),
+            ],
+        };
 
-        // file1 should now be a symlink to file1 in .mirage/originals
-        assert!(test_view.get_children()[0].is_symlink());
+        test_dir.create(dir_path);
 
-        assert!(test_view.get_children()[1].is_symlink());
+        let test_path = fs::canonicalize(test_dir.get_path(dir_path)).unwrap();
+        let options = ApplyOptions {
+            max_files: Some(2),
+            ..Default::default()
+        };
+        let report = apply_with_options(&test_path, &options).unwrap();
+        assert_eq!(report.stopped_at, Some(test_path.join("b1.txt")));
+        assert!(read_link(test_path.join("b2.txt")).is_ok());
+        assert!(read_link(test_path.join("c1.txt")).is_err());
 
-        assert!(test_view.get_children()[2].is_symlink());
+        // the next run picks up where the last one stopped
+        let report = apply_with_options(&test_path, &options).unwrap();
+        assert_eq!(report.stopped_at, None);
+        assert!(read_link(test_path.join("c1.txt")).is_ok());
+        assert!(read_link(test_path.join("c2.txt")).is_ok());
+        assert!(!test_path.join(".mirage").join("resume.json").exists());
 
-        assert!(test_view.get_children()[3].get_children()[0].is_symlink());
+        fs::write(test_path.join("d1.txt"), "dddd").unwrap();
+        fs::write(test_path.join("d2.txt"), "dddd").unwrap();
+        let report = apply_with_options(
+            &test_path,
+            &ApplyOptions {
+                max_duration: Some(std::time::Duration::ZERO),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(report.stopped_at, Some(test_path.join("d1.txt")));
+        assert!(read_link(test_path.join("d1.txt")).is_err());
+    }
 
-        assert!(test_view.get_children()[3].get_children()[1].is_symlink());
+    #[test]
+    fn checkpoint_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
 
-        assert_eq!(
-            fs::canonicalize(read_link(&test_view.get_children()[0].get_full_path()).unwrap())
-                .unwrap(),
-            fs::canonicalize(&orig1).unwrap()
-        );
+        let file = |name: &str, contents: &str| TestFsObject::File {
+            name: name.to_string(),
+            contents: contents.to_string(),
+        };
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![file("a.txt", "early"), file("b.txt", "early")],
+        };
 
-        assert_eq!(
-            fs::canonicalize(read_link(&test_view.get_children()[1].get_full_path()).unwrap())
-                .unwrap(),
-            fs::canonicalize(&orig1).unwrap()
-        );
+        test_dir.create(dir_path);
 
-        assert_eq!(
-            fs::canonicalize(
-                read_link(&test_view.get_children()[3].get_children()[0].get_full_path()).unwrap()
-            )
-            .unwrap(),
-            fs::canonicalize(&orig1).unwrap()
-        );
+        let dir_path = fs::canonicalize(test_dir.get_path(dir_path)).unwrap();
+        let linked = |name: &str| {
+            fs::symlink_metadata(dir_path.join(name))
+                .unwrap()
+                .file_type()
+                .is_symlink()
+        };
+        apply(&dir_path).unwrap();
+        let before = create_checkpoint(&dir_path, "before-cleanup").unwrap();
+        assert!(matches!(
+            create_checkpoint(&dir_path, "before-cleanup"),
+            Err(MirageError::CheckpointExists(_))
+        ));
 
-        assert_eq!(
-            fs::canonicalize(read_link(&test_view.get_children()[2].get_full_path()).unwrap())
-                .unwrap(),
-            fs::canonicalize(&orig3).unwrap()
-        );
+        for (name, contents) in [("c.txt", "late"), ("d.txt", "late"), ("e.txt", "early")] {
+            fs::write(dir_path.join(name), contents).unwrap();
+        }
+        apply(&dir_path).unwrap();
+        create_checkpoint(&dir_path, "after").unwrap();
+        let late = read_link(dir_path.join("c.txt")).unwrap();
+        assert!(linked("e.txt"));
 
-        assert_eq!(
-            fs::canonicalize(
-                read_link(&test_view.get_children()[3].get_children()[1].get_full_path()).unwrap()
-            )
-            .unwrap(),
-            fs::canonicalize(&orig3).unwrap()
-        );
+        let to = |name: &str| RevertOptions {
+            to: Some(name.to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            revert_with_options(&dir_path, &to("missing")),
+            Err(MirageError::UnknownCheckpoint(_))
+        ));
+        let planned = revert_with_options(
+            &dir_path,
+            &RevertOptions {
+                dry_run: true,
+                ..to("before-cleanup")
+            },
+        )
+        .unwrap();
+        assert!(planned.dry_run);
+        assert!(linked("c.txt"));
+
+        let report = revert_with_options(&dir_path, &to("before-cleanup")).unwrap();
+        assert!(report.failed.is_empty());
+        for (name, contents) in [("c.txt", "late"), ("d.txt", "late"), ("e.txt", "early")] {
+            assert!(!linked(name));
+            assert_eq!(fs::read_to_string(dir_path.join(name)).unwrap(), contents);
+        }
+        assert!(linked("a.txt") && linked("b.txt"));
+        // the original copied since is gone, the earlier one kept
+        assert!(!late.exists());
+        assert!(read_link(dir_path.join("a.txt")).unwrap().exists());
 
+        let state = MirageState::open(&dir_path).unwrap();
+        assert_eq!(state.wal.actions.len(), before.actions);
+        assert_eq!(state.wal.redirections.len(), 2);
+        assert_eq!(checkpoints(&dir_path).unwrap(), vec![before]);
+        drop(state);
+
+        delete_checkpoint(&dir_path, "before-cleanup").unwrap();
+        assert!(checkpoints(&dir_path).unwrap().is_empty());
+        assert!(matches!(
+            delete_checkpoint(&dir_path, "before-cleanup"),
+            Err(MirageError::UnknownCheckpoint(_))
+        ));
         revert(&dir_path).unwrap();
+        assert!(!linked("a.txt"));
+    }
 
-        test_view.verify();
+    #[test]
+    fn wal_version_test() {
+        let dir = tempdir().unwrap();
+        let dir_path = dir.path();
 
-        // Check if mirage directory is removed
+        let file = |name: &str| TestFsObject::File {
+            name: name.to_string(),
+            contents: "duplicate content".to_string(),
+        };
+        let test_dir = TestFsObject::Dir {
+            name: "test_dir".to_string(),
+            contents: vec![file("file1.txt"), file("file2.txt")],
+        };
 
-        assert!(!dir_path.join(".mirage").exists());
-        assert!(!dir_path.join(".mirage/originals").exists());
-        assert!(!dir_path.join(".mirage/wal.json").exists());
+        test_dir.create(dir_path);
+
+        let dir_path = test_dir.get_path(dir_path);
+        apply(&dir_path).unwrap();
+
+        let wal_path = dir_path.join(".mirage").join("wal.json");
+        let read = || -> serde_json::Value {
+            serde_json::from_slice(&fs::read(&wal_path).unwrap()).unwrap()
+        };
+        assert_eq!(read()["version"], WAL_VERSION);
+
+        // as written before versions were recorded
+        let mut json = read();
+        json.as_object_mut().unwrap().remove("version");
+        fs::write(&wal_path, serde_json::to_vec_pretty(&json).unwrap()).unwrap();
+        let entries = inspect_wal(&dir_path, &WalFilter::default()).unwrap();
+        assert_eq!(entries.len(), 3);
+        let mut state = MirageState::get(&dir_path).unwrap();
+        assert_eq!(state.wal.version, 0);
+        assert_eq!(state.wal.actions.len(), 3);
+        state.commit().unwrap();
+        assert_eq!(read()["version"], WAL_VERSION);
+
+        // as written before the redirections got files of their own
+        let redirected = state
+            .wal
+            .redirections
+            .iter()
+            .map(|(path, original)| {
+                (
+                    path.to_string_lossy().into_owned(),
+                    original.to_string_lossy().into(),
+                )
+            })
+            .collect::<serde_json::Map<_, _>>();
+        drop(state);
+        let mut json = read();
+        json["version"] = 1.into();
+        json["redirections"] = redirected.into();
+        json.as_object_mut().unwrap().remove("redirection_files");
+        fs::write(&wal_path, serde_json::to_vec_pretty(&json).unwrap()).unwrap();
+        assert_eq!(
+            list_groups(&dir_path, false, GroupOrder::Files)
+                .unwrap()
+                .len(),
+            1
+        );
+        let mut state = MirageState::get(&dir_path).unwrap();
+        assert_eq!(state.wal.redirections.len(), 2);
+        state.commit().unwrap();
+        assert!(read().get("redirections").is_none());
+        assert_eq!(read()["redirection_files"].as_array().unwrap().len(), 1);
+        drop(state);
+
+        // a newer version is kept
+        let mut json = read();
+        json["version"] = (WAL_VERSION + 1).into();
+        fs::write(&wal_path, serde_json::to_vec_pretty(&json).unwrap()).unwrap();
+        MirageState::get(&dir_path).unwrap().commit().unwrap();
+        assert_eq!(read()["version"], WAL_VERSION + 1);
+
+        revert(&dir_path).unwrap();
+        assert!(!wal_path.exists());
     }
 }
@@ -0,0 +1,106 @@
+//! Listing the duplicate groups of a tree: the files linked to each
+//! original of its store, or those a run would link together now.
+
+use std::{
+    cmp::Reverse,
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use serde::Serialize;
+
+use crate::{apply_with_options, walstream::WalStream, ApplyOptions, MirageError};
+
+/// How [`list_groups`] orders the groups.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GroupOrder {
+    /// By original, in path order.
+    #[default]
+    Original,
+    /// Groups of the most files first.
+    Files,
+    /// Groups taking the most bytes first.
+    Bytes,
+}
+
+impl FromStr for GroupOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "original" => Ok(GroupOrder::Original),
+            "files" | "size" => Ok(GroupOrder::Files),
+            "bytes" => Ok(GroupOrder::Bytes),
+            _ => Err(format!("unknown order {:?}", s)),
+        }
+    }
+}
+
+/// The files sharing one original.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct ListedGroup {
+    /// The original in the store, or where a run would put it.
+    pub original: PathBuf,
+    /// Size of one of the files.
+    pub size: u64,
+    /// The files linked to the original, in path order.
+    pub files: Vec<PathBuf>,
+}
+
+impl ListedGroup {
+    /// Bytes the files take as seen through their links.
+    pub fn total_bytes(&self) -> u64 {
+        self.size * self.files.len() as u64
+    }
+}
+
+/// The duplicate groups of the tree at `target_dir`, from the redirections
+/// of its store or, with `scan`, from a dry run finding what an apply would
+/// deduplicate now. Nothing is modified either way.
+pub fn list_groups<T: AsRef<Path>>(
+    target_dir: T,
+    scan: bool,
+    order: GroupOrder,
+) -> Result<Vec<ListedGroup>, MirageError> {
+    let mut groups: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+    if scan {
+        let options = ApplyOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let report = apply_with_options(&target_dir, &options)?;
+        for action in report.planned {
+            if action.action != "Copy" && action.action != "NOP" {
+                groups.entry(action.target).or_default().push(action.source);
+            }
+        }
+    } else {
+        for (path, original) in WalStream::open(&target_dir)?.redirections()? {
+            groups.entry(original).or_default().push(path);
+        }
+    }
+
+    let mut listed = Vec::new();
+    for (original, mut files) in groups {
+        files.sort();
+        files.dedup();
+        // an original a dry run only planned isn't in the store yet
+        let size = match fs::metadata(&original) {
+            Ok(meta) => meta.len(),
+            Err(_) => fs::metadata(&files[0])?.len(),
+        };
+        listed.push(ListedGroup {
+            original,
+            size,
+            files,
+        });
+    }
+    match order {
+        GroupOrder::Original => {}
+        GroupOrder::Files => listed.sort_by_key(|group| Reverse(group.files.len())),
+        GroupOrder::Bytes => listed.sort_by_key(|group| Reverse(group.total_bytes())),
+    }
+    Ok(listed)
+}
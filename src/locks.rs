@@ -0,0 +1,106 @@
+//! Advisory locks on single originals of a store.
+//!
+//! The run lock keeps two runs from rewriting the same WAL, but originals may
+//! be touched by more than one WAL at a time, e.g. by every root sharing a
+//! store. Whatever creates, links to or deletes an original holds its lock
+//! meanwhile, so operations on different originals still run in parallel.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    path::Path,
+};
+
+use log::trace;
+
+use crate::{MirageError, MirageState};
+
+/// Directory of the store holding one lock file per original ever locked.
+const LOCKS_DIR: &str = "locks";
+
+/// Held while an original is worked on, released on drop.
+pub(crate) struct OriginalLock {
+    _file: Option<File>,
+}
+
+#[cfg(unix)]
+fn lock_exclusive(file: &File) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } == 0 {
+            return Ok(());
+        }
+        let err = std::io::Error::last_os_error();
+        if err.kind() != std::io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn lock_exclusive(_file: &File) -> std::io::Result<()> {
+    Ok(())
+}
+
+impl MirageState {
+    /// Waits for and takes the lock of `original`. Dry runs touch nothing and
+    /// take no lock.
+    pub(crate) fn lock_original(&self, original: &Path) -> Result<OriginalLock, MirageError> {
+        if self.dry_run {
+            return Ok(OriginalLock { _file: None });
+        }
+        let dir = self.source_path.join(LOCKS_DIR);
+        fs::create_dir_all(&dir)?;
+        // lock files are never removed, someone may be waiting on them
+        let name = format!("{:x}", md5::compute(original.to_string_lossy().as_bytes()));
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(dir.join(name))?;
+        trace!("Locking original {:?}", original);
+        lock_exclusive(&file)?;
+        Ok(OriginalLock { _file: Some(file) })
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    use tempfile::tempdir;
+
+    use crate::MirageState;
+
+    #[test]
+    fn serializes_per_original() {
+        let dir = tempdir().unwrap();
+        let state = Arc::new(MirageState::get(dir.path()).unwrap());
+        let original = dir.path().join("original");
+
+        let held = state.lock_original(&original).unwrap();
+        // other originals aren't held up
+        drop(state.lock_original(&dir.path().join("other")).unwrap());
+
+        let locked = Arc::new(AtomicBool::new(false));
+        let waiter = {
+            let (state, locked, original) = (state.clone(), locked.clone(), original.clone());
+            thread::spawn(move || {
+                let _lock = state.lock_original(&original).unwrap();
+                locked.store(true, Ordering::SeqCst);
+            })
+        };
+        thread::sleep(Duration::from_millis(100));
+        assert!(!locked.load(Ordering::SeqCst));
+        drop(held);
+        waiter.join().unwrap();
+        assert!(locked.load(Ordering::SeqCst));
+    }
+}
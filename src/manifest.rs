@@ -0,0 +1,97 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use log::trace;
+use serde::Serialize;
+
+use crate::{
+    cache::Cache, canonicalize_link, walk, warn_walk_error, ApplyOptions, HashAlgorithm,
+    MirageError, MirageState,
+};
+
+/// One logical file of a managed tree and the file actually holding its
+/// contents. Paths are relative to the root of the tree.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub path: PathBuf,
+    /// The original in the store for managed files, the file itself otherwise.
+    pub content: PathBuf,
+    /// Digest of the contents, shared by every path with the same contents.
+    pub content_id: String,
+}
+
+/// Maps every file of the tree at `target_dir` to its canonical content file
+/// and content id, so consumers can load each unique file exactly once.
+pub fn manifest<T: AsRef<Path>>(target_dir: T) -> Result<Vec<ManifestEntry>, MirageError> {
+    let state = MirageState::open(&target_dir)?;
+    // content ids have always been MD5s
+    let mut cache = Cache::load(&state, HashAlgorithm::Md5)?;
+    let root = fs::canonicalize(&target_dir)?;
+    let relative = |path: &Path| path.strip_prefix(&root).unwrap_or(path).to_path_buf();
+
+    let mut ids: HashMap<PathBuf, String> = HashMap::new();
+    let mut entries = Vec::new();
+    for entry in walk(&root, &ApplyOptions::everything()) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(x) => {
+                warn_walk_error(&x);
+                continue;
+            }
+        };
+        if entry.file_type().is_dir() {
+            continue;
+        }
+        let path = canonicalize_link(entry.path())?;
+        let content = match state.wal.redirections.get(&path) {
+            Some(original) => original.clone(),
+            None if entry.path_is_symlink() => {
+                trace!("Skipping unmanaged symlink {:?}", path);
+                continue;
+            }
+            None => path.clone(),
+        };
+        let content_id = match ids.get(&content) {
+            Some(id) => id.clone(),
+            None => {
+                let id = cache.hash(&content)?;
+                ids.insert(content.clone(), id.clone());
+                id
+            }
+        };
+        entries.push(ManifestEntry {
+            path: relative(&path),
+            content: relative(&content),
+            content_id,
+        });
+    }
+    cache.save()?;
+    Ok(entries)
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Writes manifest entries as CSV with a `path,content,content_id` header.
+pub fn write_manifest_csv<W: Write>(mut out: W, entries: &[ManifestEntry]) -> io::Result<()> {
+    writeln!(out, "path,content,content_id")?;
+    for entry in entries {
+        writeln!(
+            out,
+            "{},{},{}",
+            csv_field(&entry.path.to_string_lossy()),
+            csv_field(&entry.content.to_string_lossy()),
+            csv_field(&entry.content_id)
+        )?;
+    }
+    Ok(())
+}
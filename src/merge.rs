@@ -0,0 +1,155 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use log::debug;
+
+use crate::{
+    check_if_files_are_same, execute_pending, Action, ActionType, HashAlgorithm, MirageError,
+    MirageState, StoreLocation, DEFAULT_HASH_ALGORITHM,
+};
+
+/// Folds the store of the tree at `other_root` into the store of `target_dir`.
+///
+/// Originals of the other store that duplicate one of ours are dropped, the
+/// rest are copied into our store, and every link of the other tree is
+/// rewritten to point at our copy, keeping the kind of link it was. The
+/// other `.mirage` is removed once all of its links have been rewritten.
+/// Fails with [`MirageError::ReadOnly`] if either store is read-only.
+pub fn merge<T: AsRef<Path>, U: AsRef<Path>>(
+    target_dir: T,
+    other_root: U,
+) -> Result<(), MirageError> {
+    let location = StoreLocation::from_env();
+    merge_into(
+        target_dir.as_ref(),
+        &location,
+        other_root.as_ref(),
+        &location,
+        DEFAULT_HASH_ALGORITHM,
+    )
+}
+
+/// Like [`merge`], with the store of `target_dir` kept at `location` and the
+/// one of `other_root` at `other_location`. Originals copied without a
+/// recorded digest are hashed with `algorithm`.
+pub(crate) fn merge_into(
+    target_dir: &Path,
+    location: &StoreLocation,
+    other_root: &Path,
+    other_location: &StoreLocation,
+    algorithm: HashAlgorithm,
+) -> Result<(), MirageError> {
+    let mut other = MirageState::open_at(other_root, other_location)?;
+    other.ensure_unfrozen()?;
+    if other.wal.checkpoint < other.wal.actions.len() {
+        return Err(MirageError::PendingActions(other_root.to_path_buf()));
+    }
+    let mut state = MirageState::get_at(target_dir, location)?;
+    state.ensure_unfrozen()?;
+    for store in [&state, &other] {
+        if store.dry_run {
+            return Err(MirageError::ReadOnly(store.source_path.clone()));
+        }
+    }
+    state.lock_run()?;
+    other.lock_run()?;
+    if state.source_path == other.source_path {
+        debug!("Refusing to merge {:?} into itself", other.source_path);
+        return Ok(());
+    }
+
+    // (our original, file holding its contents right now)
+    let mut pool = state
+        .wal
+        .redirections
+        .values()
+        .map(|original| (original.clone(), original.clone()))
+        .collect::<Vec<_>>();
+    pool.sort();
+    pool.dedup();
+
+    // how each of their paths was linked last, and what their originals hold
+    let applied = &other.wal.actions[..other.wal.checkpoint];
+    let mut modes: HashMap<&Path, &ActionType> = HashMap::new();
+    let mut recorded = HashMap::new();
+    for action in applied {
+        match action.action {
+            ActionType::Copy => {
+                if let Some(digest) = action.recorded_digest() {
+                    recorded.insert(action.target.as_path(), digest);
+                }
+            }
+            ActionType::Symlink
+            | ActionType::Hardlink
+            | ActionType::Reflink
+            | ActionType::Delete
+            | ActionType::Dedupe => {
+                modes.insert(action.source.as_path(), &action.action);
+            }
+            _ => {}
+        }
+    }
+
+    let mut remapped: HashMap<PathBuf, PathBuf> = HashMap::new();
+    let mut theirs = other.wal.redirections.iter().collect::<Vec<_>>();
+    theirs.sort();
+
+    for (path, their_original) in theirs {
+        let original = match remapped.get(their_original) {
+            Some(original) => original.clone(),
+            None => {
+                let mut found = None;
+                for (ours, contents) in pool.iter() {
+                    if contents.exists() && check_if_files_are_same(their_original, contents)? {
+                        found = Some(ours.clone());
+                        break;
+                    }
+                }
+                let ours = match found {
+                    Some(ours) => {
+                        debug!("{:?} duplicates {:?}, dropping it", their_original, ours);
+                        ours
+                    }
+                    None => {
+                        let name = their_original
+                            .file_name()
+                            .ok_or(MirageError::DotMirageInInconsistentState)?;
+                        let (digest, algorithm) = match recorded.get(their_original.as_path()) {
+                            Some(&(digest, algorithm)) => (digest.to_string(), algorithm),
+                            None => (algorithm.hash_file(their_original)?, algorithm),
+                        };
+                        let ours = state.new_original_path(name, &digest);
+                        let mut copy =
+                            Action::new(ActionType::Copy, their_original.clone(), ours.clone());
+                        copy.digest = Some(digest);
+                        copy.algorithm = Some(algorithm);
+                        state.wal.push(copy);
+                        pool.push((ours.clone(), their_original.clone()));
+                        ours
+                    }
+                };
+                remapped.insert(their_original.clone(), ours.clone());
+                ours
+            }
+        };
+
+        // links keep their kind, a hard link stays a hard link
+        let mode = modes
+            .get(path.as_path())
+            .map_or(ActionType::Symlink, |&mode| mode.clone());
+        state
+            .wal
+            .push(Action::new(mode, path.clone(), original.clone()));
+        state.wal.redirections.insert(path.clone(), original);
+    }
+    state.commit()?;
+
+    execute_pending(&mut state)?;
+
+    debug!("Removing merged store {:?}", other.source_path);
+    other.remove_store()?;
+
+    Ok(())
+}
@@ -0,0 +1,221 @@
+//! Content hashes of whole directories, Merkle style: a directory hashes the
+//! names, kinds and hashes of its entries, so two subtrees with the same
+//! contents are found by comparing one digest.
+//!
+//! Every directory is also stamped with the identities of its entries, which
+//! a stat is enough to check. A directory whose stamp is the one recorded by
+//! the last run keeps its hash without a single file of it being read.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::OsString,
+    fs::{self, File},
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+};
+
+use log::{debug, trace};
+use serde::{Deserialize, Serialize};
+
+use crate::{cache::Cache, state_dir_name, HashAlgorithm, MirageError, MirageState};
+
+/// File in the store holding the directory hashes of the last run.
+const MERKLE_FILE: &str = "merkle.json";
+
+/// The content hash of one directory of a managed tree.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SubtreeHash {
+    /// Relative to the root of the tree, empty for the root itself.
+    pub dir: PathBuf,
+    pub hash: String,
+    /// Number of files in the subtree.
+    pub files: u64,
+    /// Total size of those files.
+    pub bytes: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Stamped {
+    stamp: String,
+    #[serde(flatten)]
+    subtree: SubtreeHash,
+}
+
+#[cfg(unix)]
+fn identity(meta: &fs::Metadata) -> String {
+    use std::os::unix::fs::MetadataExt;
+    format!(
+        "{}:{}:{}.{}:{}",
+        meta.dev(),
+        meta.ino(),
+        meta.mtime(),
+        meta.mtime_nsec(),
+        meta.len()
+    )
+}
+
+#[cfg(not(unix))]
+fn identity(meta: &fs::Metadata) -> String {
+    format!("{:?}:{}", meta.modified().ok(), meta.len())
+}
+
+struct Hasher<'a> {
+    root: PathBuf,
+    /// Name of the state directory, skipped wherever it is.
+    store: OsString,
+    cache: &'a mut Cache,
+    previous: HashMap<PathBuf, Stamped>,
+    hashed: BTreeMap<PathBuf, Stamped>,
+}
+
+impl Hasher<'_> {
+    /// Hashes the directory at `dir` and everything below it.
+    fn hash_dir(&mut self, dir: &Path) -> Result<(String, String, u64, u64), MirageError> {
+        let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        // stamps need no contents, hashes are taken once a stamp differs
+        let mut stamp = md5::Context::new();
+        let mut children = Vec::new();
+        let (mut files, mut bytes) = (0, 0);
+        for entry in entries {
+            let name = entry.file_name();
+            if name == self.store {
+                continue;
+            }
+            let path = entry.path();
+            let kind = entry.file_type()?;
+            let managed_or_file = !kind.is_symlink() || path.is_file();
+            let (child_stamp, child) = if kind.is_dir() {
+                let (child_stamp, hash, child_files, child_bytes) = self.hash_dir(&path)?;
+                files += child_files;
+                bytes += child_bytes;
+                (child_stamp, Some(("d", hash)))
+            } else if managed_or_file {
+                // links count as what they link to
+                let meta = fs::metadata(&path)?;
+                files += 1;
+                bytes += meta.len();
+                (identity(&meta), None)
+            } else {
+                let target = fs::read_link(&path)?;
+                let target = target.to_string_lossy().into_owned();
+                (target.clone(), Some(("l", target)))
+            };
+            stamp.consume(name.as_encoded_bytes());
+            stamp.consume([0]);
+            stamp.consume(child_stamp.as_bytes());
+            stamp.consume([b'\n']);
+            children.push((name, path, child));
+        }
+        let stamp = format!("{:x}", stamp.compute());
+
+        let relative = dir.strip_prefix(&self.root).unwrap_or(dir).to_path_buf();
+        let hash = match self.previous.remove(&relative) {
+            Some(previous) if previous.stamp == stamp => {
+                trace!("Directory {:?} is unchanged", dir);
+                previous.subtree.hash
+            }
+            _ => {
+                let mut hash = md5::Context::new();
+                for (name, path, child) in children {
+                    let (kind, child) = match child {
+                        Some(child) => child,
+                        None => ("f", self.cache.hash(&path)?),
+                    };
+                    hash.consume(name.as_encoded_bytes());
+                    hash.consume([0]);
+                    hash.consume(kind.as_bytes());
+                    hash.consume([0]);
+                    hash.consume(child.as_bytes());
+                    hash.consume([b'\n']);
+                }
+                format!("{:x}", hash.compute())
+            }
+        };
+        self.hashed.insert(
+            relative.clone(),
+            Stamped {
+                stamp: stamp.clone(),
+                subtree: SubtreeHash {
+                    dir: relative,
+                    hash: hash.clone(),
+                    files,
+                    bytes,
+                },
+            },
+        );
+        Ok((stamp, hash, files, bytes))
+    }
+}
+
+/// The content hash of every directory of the tree at `target_dir`, in
+/// path order, reusing those of the last call for unchanged directories.
+pub fn subtree_hashes<T: AsRef<Path>>(target_dir: T) -> Result<Vec<SubtreeHash>, MirageError> {
+    let state = MirageState::open(&target_dir)?;
+    let path = state.source_path.join(MERKLE_FILE);
+    let previous: Vec<Stamped> = if path.is_file() {
+        serde_json::from_reader(BufReader::new(File::open(&path)?)).unwrap_or_else(|err| {
+            debug!("Discarding unreadable directory hashes: {:?}", err);
+            Vec::new()
+        })
+    } else {
+        Vec::new()
+    };
+
+    // recorded hashes stay comparable across runs
+    let mut cache = Cache::load(&state, HashAlgorithm::Md5)?;
+    let mut hasher = Hasher {
+        root: fs::canonicalize(&target_dir)?,
+        store: state_dir_name(),
+        cache: &mut cache,
+        previous: previous
+            .into_iter()
+            .map(|stamped| (stamped.subtree.dir.clone(), stamped))
+            .collect(),
+        hashed: BTreeMap::new(),
+    };
+    let root = hasher.root.clone();
+    hasher.hash_dir(&root)?;
+    let hashed = hasher.hashed.into_values().collect::<Vec<_>>();
+    cache.save()?;
+    if !state.dry_run {
+        serde_json::to_writer(BufWriter::new(File::create(&path)?), &hashed)?;
+    }
+    Ok(hashed.into_iter().map(|stamped| stamped.subtree).collect())
+}
+
+/// Groups of directories of the tree at `target_dir` with identical
+/// contents, largest first. Groups of directories that are all inside
+/// identical directories are left out, the outer match says it all.
+pub fn identical_subtrees<T: AsRef<Path>>(
+    target_dir: T,
+) -> Result<Vec<Vec<SubtreeHash>>, MirageError> {
+    let hashes = subtree_hashes(target_dir)?;
+    let mut by_hash: BTreeMap<&str, Vec<&SubtreeHash>> = BTreeMap::new();
+    for subtree in hashes.iter().filter(|subtree| subtree.files > 0) {
+        by_hash.entry(&subtree.hash).or_default().push(subtree);
+    }
+    let duplicated = by_hash
+        .values()
+        .filter(|group| group.len() > 1)
+        .flatten()
+        .map(|subtree| subtree.dir.as_path())
+        .collect::<HashSet<_>>();
+
+    let mut groups = by_hash
+        .into_values()
+        .filter(|group| group.len() > 1)
+        .filter(|group| {
+            group.iter().any(|subtree| {
+                !subtree
+                    .dir
+                    .parent()
+                    .is_some_and(|parent| duplicated.contains(parent))
+            })
+        })
+        .map(|group| group.into_iter().cloned().collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    groups.sort_by(|a, b| b[0].bytes.cmp(&a[0].bytes).then(a[0].dir.cmp(&b[0].dir)));
+    Ok(groups)
+}
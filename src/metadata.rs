@@ -0,0 +1,216 @@
+//! Comparison of media files that ignores their embedded tags.
+//!
+//! For known formats only the byte ranges holding the actual media are
+//! compared, so two photos differing only in EXIF, or two songs differing only
+//! in ID3 tags, are considered equal. Anything else is compared exactly.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    ops::Range,
+    path::Path,
+};
+
+use log::trace;
+
+use crate::{check_if_files_are_same, MirageError};
+
+/// Byte ranges of a JPEG without its EXIF/XMP (APP1), IPTC (APP13) and
+/// comment segments.
+fn jpeg_payload(file: &mut File, len: u64) -> io::Result<Option<Vec<Range<u64>>>> {
+    // start of image marker
+    let mut ranges = Vec::new();
+    ranges.push(0..2);
+    let mut at = 2;
+    loop {
+        let mut marker = [0u8; 4];
+        file.seek(SeekFrom::Start(at))?;
+        if file.read_exact(&mut marker).is_err() || marker[0] != 0xFF {
+            return Ok(None);
+        }
+        if marker[1] == 0xDA {
+            // start of scan, the compressed image runs to the end
+            ranges.push(at..len);
+            return Ok(Some(ranges));
+        }
+        let segment_len = u16::from_be_bytes([marker[2], marker[3]]) as u64;
+        let end = at + 2 + segment_len;
+        if end > len {
+            return Ok(None);
+        }
+        match marker[1] {
+            0xE1 | 0xED | 0xFE => trace!("Ignoring jpeg metadata segment {:x}", marker[1]),
+            _ => ranges.push(at..end),
+        }
+        at = end;
+    }
+}
+
+/// Byte range of an MP3 without its ID3v2 header and ID3v1 trailer.
+fn mp3_payload(file: &mut File, len: u64) -> io::Result<Option<Vec<Range<u64>>>> {
+    let mut start = 0;
+    let mut header = [0u8; 10];
+    file.read_exact(&mut header)?;
+    if &header[..3] == b"ID3" {
+        let size = header[6..10]
+            .iter()
+            .fold(0u64, |size, byte| (size << 7) | (*byte & 0x7F) as u64);
+        let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+        start = 10 + size + footer;
+    }
+    let mut end = len;
+    if len >= start + 128 {
+        let mut trailer = [0u8; 3];
+        file.seek(SeekFrom::Start(len - 128))?;
+        file.read_exact(&mut trailer)?;
+        if &trailer == b"TAG" {
+            end = len - 128;
+        }
+    }
+    if start > end {
+        return Ok(None);
+    }
+    let audio = start..end;
+    Ok(Some(vec![audio]))
+}
+
+/// Byte ranges holding the media of a known format, or `None` when the file
+/// is not one or can't be parsed.
+fn payload_ranges(path: &Path) -> io::Result<Option<Vec<Range<u64>>>> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    if len < 10 {
+        return Ok(None);
+    }
+    let mut magic = [0u8; 3];
+    file.read_exact(&mut magic)?;
+    file.seek(SeekFrom::Start(0))?;
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    if magic[..2] == [0xFF, 0xD8] {
+        jpeg_payload(&mut file, len)
+    } else if &magic == b"ID3" || extension == "mp3" {
+        mp3_payload(&mut file, len)
+    } else {
+        Ok(None)
+    }
+}
+
+fn payload_digest(path: &Path, ranges: &[Range<u64>]) -> io::Result<md5::Digest> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut context = md5::Context::new();
+    let mut buf = [0; 10000];
+    for range in ranges {
+        reader.seek(SeekFrom::Start(range.start))?;
+        let mut part = (&mut reader).take(range.end - range.start);
+        loop {
+            let n = part.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            context.consume(&buf[..n]);
+        }
+    }
+    Ok(context.compute())
+}
+
+fn total(ranges: &[Range<u64>]) -> u64 {
+    ranges.iter().map(|r| r.end - r.start).sum()
+}
+
+/// Bytes of `path` [`same_ignoring_metadata`] compares, its media for a known
+/// format and the whole file otherwise. Files it finds the same have the
+/// same payload length, so files can be grouped by it rather than by size.
+pub(crate) fn payload_len(path: &Path) -> Result<u64, MirageError> {
+    Ok(match payload_ranges(path)? {
+        Some(ranges) => total(&ranges),
+        None => fs::metadata(path)?.len(),
+    })
+}
+
+/// Digest of the bytes of `path` [`same_ignoring_metadata`] compares, equal
+/// for files it finds the same.
+pub(crate) fn payload_hash(path: &Path) -> Result<String, MirageError> {
+    let ranges = match payload_ranges(path)? {
+        Some(ranges) => ranges,
+        None => vec![Range {
+            start: 0,
+            end: fs::metadata(path)?.len(),
+        }],
+    };
+    Ok(format!("{:x}", payload_digest(path, &ranges)?))
+}
+
+/// Compares two files ignoring embedded metadata when both are of a known
+/// media format, and exactly otherwise.
+pub fn same_ignoring_metadata(here: &Path, there: &Path) -> Result<bool, MirageError> {
+    match (payload_ranges(here)?, payload_ranges(there)?) {
+        (Some(here_ranges), Some(there_ranges)) => {
+            if total(&here_ranges) != total(&there_ranges) {
+                return Ok(false);
+            }
+            Ok(payload_digest(here, &here_ranges)? == payload_digest(there, &there_ranges)?)
+        }
+        _ => check_if_files_are_same(here, there),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::tempdir;
+
+    use super::{payload_hash, payload_len, same_ignoring_metadata};
+
+    fn jpeg(exif: &[u8]) -> Vec<u8> {
+        let mut jpeg = vec![0xFF, 0xD8];
+        jpeg.extend([0xFF, 0xE1]);
+        jpeg.extend(((exif.len() + 2) as u16).to_be_bytes());
+        jpeg.extend(exif);
+        jpeg.extend([0xFF, 0xDB, 0x00, 0x04, 0x01, 0x02]);
+        jpeg.extend([0xFF, 0xDA, 0x00, 0x02, 0x10, 0x20, 0x30, 0xFF, 0xD9]);
+        jpeg
+    }
+
+    fn mp3(title: &[u8], audio: &[u8]) -> Vec<u8> {
+        let mut mp3 = b"ID3\x03\x00\x00\x00\x00\x00".to_vec();
+        mp3.push(title.len() as u8);
+        mp3.extend(title);
+        mp3.extend(audio);
+        mp3
+    }
+
+    #[test]
+    fn ignores_tags() {
+        let dir = tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name);
+
+        fs::write(path("a.jpg"), jpeg(b"Exif camera one")).unwrap();
+        fs::write(path("b.jpg"), jpeg(b"Exif other camera and date")).unwrap();
+        assert!(same_ignoring_metadata(&path("a.jpg"), &path("b.jpg")).unwrap());
+
+        fs::write(path("a.mp3"), mp3(b"title", b"audio frames")).unwrap();
+        fs::write(path("b.mp3"), mp3(b"another title", b"audio frames")).unwrap();
+        fs::write(path("c.mp3"), mp3(b"title", b"other frames")).unwrap();
+        assert!(same_ignoring_metadata(&path("a.mp3"), &path("b.mp3")).unwrap());
+        assert!(!same_ignoring_metadata(&path("a.mp3"), &path("c.mp3")).unwrap());
+        assert_eq!(payload_len(&path("a.mp3")).unwrap(), 12);
+        assert_eq!(
+            payload_hash(&path("a.mp3")).unwrap(),
+            payload_hash(&path("b.mp3")).unwrap()
+        );
+        assert_ne!(
+            payload_hash(&path("a.mp3")).unwrap(),
+            payload_hash(&path("c.mp3")).unwrap()
+        );
+
+        // unknown formats are compared exactly
+        fs::write(path("a.txt"), "some text").unwrap();
+        fs::write(path("b.txt"), "other text").unwrap();
+        assert!(!same_ignoring_metadata(&path("a.txt"), &path("b.txt")).unwrap());
+        assert_eq!(payload_len(&path("a.txt")).unwrap(), 9);
+    }
+}
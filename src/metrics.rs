@@ -0,0 +1,171 @@
+//! Metrics of apply runs in the text format of the node_exporter textfile
+//! collector, so scheduled runs show up next to the rest of a host's metrics.
+
+use std::{
+    fmt::Write as _,
+    fs,
+    path::Path,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::debug;
+
+use crate::{ApplyReport, MirageError, MirageState, Redaction, StoreLocation};
+
+/// When a run started, taken before it does anything.
+pub(crate) struct RunMetrics {
+    started: Instant,
+}
+
+/// Escapes a label value the way the text format wants it.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, labels: &str, value: impl std::fmt::Display) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+}
+
+/// Renders the metrics of a run of `root` that took `duration` seconds and
+/// ended at `at` with `result`. The store size is left out when unknown.
+fn render(
+    root: &Path,
+    at: f64,
+    duration: f64,
+    result: &Result<ApplyReport, MirageError>,
+    store_size: Option<u64>,
+) -> String {
+    let labels = format!("path=\"{}\"", escape(&root.to_string_lossy()));
+    let (bytes_saved, errors) = match result {
+        Ok(report) => (report.bytes_saved, report.rolled_back.len()),
+        Err(_) => (0, 1),
+    };
+    let mut out = String::new();
+    gauge(
+        &mut out,
+        "mirage_last_run_timestamp_seconds",
+        "When the last apply run ended.",
+        &labels,
+        at,
+    );
+    gauge(
+        &mut out,
+        "mirage_last_run_duration_seconds",
+        "How long the last apply run took.",
+        &labels,
+        duration,
+    );
+    gauge(
+        &mut out,
+        "mirage_last_run_success",
+        "Whether the last apply run finished without failing.",
+        &labels,
+        u8::from(result.is_ok()),
+    );
+    gauge(
+        &mut out,
+        "mirage_last_run_bytes_saved",
+        "Bytes the last apply run saved.",
+        &labels,
+        bytes_saved,
+    );
+    gauge(
+        &mut out,
+        "mirage_last_run_errors",
+        "Duplicate groups the last apply run rolled back, 1 if it failed.",
+        &labels,
+        errors,
+    );
+    if let Some(store_size) = store_size {
+        gauge(
+            &mut out,
+            "mirage_store_size_bytes",
+            "Size of the originals store.",
+            &labels,
+            store_size,
+        );
+    }
+    out
+}
+
+impl RunMetrics {
+    pub fn start() -> RunMetrics {
+        RunMetrics {
+            started: Instant::now(),
+        }
+    }
+
+    /// Replaces `file` with the metrics of the run of `target_dir`, its state
+    /// kept at `location`, that ended with `result`, labelled with the path
+    /// of the tree as `redaction` redacts it.
+    pub fn write(
+        &self,
+        file: &Path,
+        target_dir: &Path,
+        location: &StoreLocation,
+        redaction: Option<Redaction>,
+        result: &Result<ApplyReport, MirageError>,
+    ) -> Result<(), MirageError> {
+        let duration = self.started.elapsed().as_secs_f64();
+        let at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default();
+        let root = fs::canonicalize(target_dir).unwrap_or_else(|_| target_dir.to_path_buf());
+        let store_size = match result {
+            Ok(report) => Some(report.store_size),
+            // a failed run may not even have a store
+            Err(_) => MirageState::open_at(&root, location)
+                .and_then(|state| state.store_size())
+                .ok(),
+        };
+        debug!("Writing metrics of {:?} to {:?}", root, file);
+        // the collector reads every *.prom file, so it must never see half of
+        // one
+        let tmp = file.with_extension("prom.tmp");
+        let label = match redaction {
+            Some(redaction) => redaction.path(&root),
+            None => root,
+        };
+        fs::write(&tmp, render(&label, at, duration, result, store_size))?;
+        fs::rename(&tmp, file)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::render;
+    use crate::{ApplyReport, MirageError};
+
+    #[test]
+    fn renders_text_format() {
+        let report = ApplyReport {
+            bytes_saved: 34,
+            ..Default::default()
+        };
+        let out = render(Path::new("/data/\"x\""), 5.0, 1.5, &Ok(report), Some(17));
+        assert!(out.contains("# TYPE mirage_last_run_bytes_saved gauge\n"));
+        assert!(out.contains("mirage_last_run_bytes_saved{path=\"/data/\\\"x\\\"\"} 34\n"));
+        assert!(out.contains("mirage_last_run_duration_seconds{path=\"/data/\\\"x\\\"\"} 1.5\n"));
+        assert!(out.contains("mirage_store_size_bytes{path=\"/data/\\\"x\\\"\"} 17\n"));
+
+        let out = render(
+            Path::new("/data"),
+            5.0,
+            1.5,
+            &Err(MirageError::DotMirageInInconsistentState),
+            None,
+        );
+        assert!(out.contains("mirage_last_run_success{path=\"/data\"} 0\n"));
+        assert!(out.contains("mirage_last_run_errors{path=\"/data\"} 1\n"));
+        assert!(!out.contains("mirage_store_size_bytes"));
+    }
+}
@@ -0,0 +1,141 @@
+//! Looking up the originals of a store by their contents. A run sorts the
+//! originals by length the first time it looks one up, digests those of a
+//! length once a file of that length is looked up, and from then on finds
+//! the ones a file may match by its digest alone rather than by comparing it
+//! with each of them.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use log::debug;
+
+use crate::{
+    cache::Cache,
+    metadata::{payload_hash, payload_len},
+    ApplyOptions, HashAlgorithm, MirageError, MirageState,
+};
+
+/// The originals of a store by the length and digest of what
+/// [`files_match`] compares of them.
+///
+/// [`files_match`]: crate::files_match
+#[derive(Debug)]
+pub(crate) struct OriginalIndex {
+    algorithm: HashAlgorithm,
+    ignore_metadata: bool,
+    lengths: HashMap<u64, Originals>,
+}
+
+/// The originals of one length.
+#[derive(Debug, Default)]
+struct Originals {
+    /// Not digested yet.
+    pending: Vec<PathBuf>,
+    by_digest: HashMap<String, Vec<PathBuf>>,
+}
+
+/// Length of what files are compared by with `options`, their whole
+/// contents or only their media with metadata ignored.
+pub(crate) fn content_len(path: &Path, options: &ApplyOptions) -> Result<u64, MirageError> {
+    if options.ignore_metadata {
+        payload_len(path)
+    } else {
+        Ok(path.metadata()?.len())
+    }
+}
+
+/// Digest of what files are compared by with `options`.
+pub(crate) fn content_digest(
+    path: &Path,
+    options: &ApplyOptions,
+    cache: &mut Cache,
+) -> Result<String, MirageError> {
+    if options.ignore_metadata {
+        payload_hash(path)
+    } else {
+        cache.hash(path)
+    }
+}
+
+impl MirageState {
+    /// The originals `path` may be a copy of, holding contents of its length
+    /// and digest, in path order. Originals no path is redirected to any
+    /// more are left out.
+    pub(crate) fn originals_like(
+        &mut self,
+        path: &Path,
+        options: &ApplyOptions,
+        cache: &mut Cache,
+    ) -> Result<Vec<PathBuf>, MirageError> {
+        let built = self.original_index.as_ref().is_some_and(|index| {
+            index.algorithm == cache.algorithm() && index.ignore_metadata == options.ignore_metadata
+        });
+        if !built {
+            let mut originals = self.wal.redirections.originals().collect::<Vec<_>>();
+            originals.sort();
+            debug!("Indexing {} originals by length", originals.len());
+            let mut index = OriginalIndex {
+                algorithm: cache.algorithm(),
+                ignore_metadata: options.ignore_metadata,
+                lengths: HashMap::new(),
+            };
+            for original in originals.into_iter().filter(|original| original.is_file()) {
+                index
+                    .lengths
+                    .entry(content_len(original, options)?)
+                    .or_default()
+                    .pending
+                    .push(original.clone());
+            }
+            self.original_index = Some(index);
+        }
+        let len = content_len(path, options)?;
+        let Some(originals) = self
+            .original_index
+            .as_mut()
+            .and_then(|index| index.lengths.get_mut(&len))
+        else {
+            return Ok(Vec::new());
+        };
+        for original in std::mem::take(&mut originals.pending) {
+            if original.is_file() {
+                let digest = content_digest(&original, options, cache)?;
+                originals
+                    .by_digest
+                    .entry(digest)
+                    .or_default()
+                    .push(original);
+            }
+        }
+        let digest = content_digest(path, options, cache)?;
+        let redirections = &self.wal.redirections;
+        Ok(originals
+            .by_digest
+            .get(&digest)
+            .into_iter()
+            .flatten()
+            .filter(|original| redirections.links(original) > 0)
+            .cloned()
+            .collect())
+    }
+
+    /// Adds `original`, holding contents of `len` and `digest` as compared
+    /// with `options`, to the index once something is redirected to it.
+    pub(crate) fn index_original(&mut self, len: u64, digest: String, original: PathBuf) {
+        if let Some(index) = &mut self.original_index {
+            let originals = index
+                .lengths
+                .entry(len)
+                .or_default()
+                .by_digest
+                .entry(digest)
+                .or_default();
+            if !originals.contains(&original) {
+                originals.push(original);
+                originals.sort();
+            }
+        }
+    }
+}
@@ -0,0 +1,55 @@
+//! Ownership of the files mirage replaces.
+//!
+//! Links and restored copies are created by whoever runs mirage, so on a
+//! shared tree deduplicated as root everything would end up owned by root.
+//! Owners are read before a file is replaced and put back on whatever takes
+//! its place. Without the privilege to do so this quietly does nothing, which
+//! is fine since then every file mirage touches is ours anyway.
+
+use std::{io, path::Path};
+
+use log::trace;
+
+use crate::MirageError;
+
+/// Uid and gid of a file.
+pub(crate) type Owner = (u32, u32);
+
+/// Owner of `path` itself, not following symlinks.
+#[cfg(unix)]
+pub(crate) fn owner_of(path: &Path) -> Option<Owner> {
+    use std::os::unix::fs::MetadataExt;
+
+    std::fs::symlink_metadata(path)
+        .ok()
+        .map(|meta| (meta.uid(), meta.gid()))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn owner_of(_path: &Path) -> Option<Owner> {
+    None
+}
+
+/// Gives `path` itself, not what it links to, back to `owner`.
+#[cfg(unix)]
+pub(crate) fn restore_owner(path: &Path, owner: Option<Owner>) -> Result<(), MirageError> {
+    let Some((uid, gid)) = owner else {
+        return Ok(());
+    };
+    if owner_of(path) == Some((uid, gid)) {
+        return Ok(());
+    }
+    trace!("Restoring owner {}:{} of {:?}", uid, gid, path);
+    match std::os::unix::fs::lchown(path, Some(uid), Some(gid)) {
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+            trace!("Not allowed to change owner of {:?}", path);
+            Ok(())
+        }
+        result => Ok(result?),
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn restore_owner(_path: &Path, _owner: Option<Owner>) -> Result<(), MirageError> {
+    Ok(())
+}
@@ -0,0 +1,233 @@
+//! Undoing the actions of a tree concurrently, one duplicate group per
+//! worker. The files of a group are restored in order by the worker that
+//! took it, while different groups write different files and only read
+//! their originals, so they never get in each other's way.
+//!
+//! Hashing the candidates of an apply run concurrently too, which only
+//! reads them.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+use log::{debug, warn};
+
+use crate::{
+    check_interrupted, undo, Action, ActionType, HashAlgorithm, MirageError, RevertFailure,
+    RevertReport,
+};
+
+/// Number of workers when not told otherwise, one per CPU.
+pub(crate) fn default_jobs() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// Splits inverted actions, in the order they are to be undone, into stages
+/// run one after the other. Each stage is a list of groups of actions that
+/// can be undone concurrently. Moves, and actions nothing is known about,
+/// are stages of their own, since they may shift files under other groups.
+pub(crate) fn stages(inverted: impl Iterator<Item = Action>) -> Vec<Vec<Vec<Action>>> {
+    let mut stages = Vec::new();
+    let mut stage: Vec<Vec<Action>> = Vec::new();
+    // the group of the stage restoring from each original
+    let mut groups: HashMap<PathBuf, usize> = HashMap::new();
+    // the group of the stage writing each file
+    let mut written: HashMap<PathBuf, usize> = HashMap::new();
+    for action in inverted {
+        match action.action {
+            ActionType::NOP => continue,
+            ActionType::Copy => {
+                let mut group = groups.get(&action.source).copied();
+                // a file restored twice is restored in order, by one worker
+                if written
+                    .get(&action.target)
+                    .is_some_and(|&writer| Some(writer) != group)
+                {
+                    stages.push(std::mem::take(&mut stage));
+                    groups.clear();
+                    written.clear();
+                    group = None;
+                }
+                let group = group.unwrap_or_else(|| {
+                    stage.push(Vec::new());
+                    groups.insert(action.source.clone(), stage.len() - 1);
+                    stage.len() - 1
+                });
+                written.insert(action.target.clone(), group);
+                stage[group].push(action);
+            }
+            _ => {
+                stages.push(std::mem::take(&mut stage));
+                stages.push(vec![vec![action]]);
+                groups.clear();
+                written.clear();
+            }
+        }
+    }
+    stages.push(stage);
+    stages.retain(|stage| !stage.is_empty());
+    stages
+}
+
+/// Undoes every stage in turn, the groups of a stage on up to `jobs`
+/// workers, restoring files as hard links with `hard_link`. A file that
+/// fails to be restored is reported and the others carry on, only a signal
+/// stops the workers before they are through.
+pub(crate) fn undo_stages(
+    stages: Vec<Vec<Vec<Action>>>,
+    jobs: usize,
+    hard_link: bool,
+) -> Result<RevertReport, MirageError> {
+    let report = Mutex::new(RevertReport::default());
+    for stage in stages {
+        let workers = jobs.clamp(1, stage.len());
+        debug!("Undoing {} groups on {} workers", stage.len(), workers);
+        let queue = Mutex::new(stage.into_iter());
+        let stop = AtomicBool::new(false);
+        let work = || {
+            while !stop.load(Ordering::SeqCst) {
+                let Some(group) = queue.lock().unwrap().next() else {
+                    return;
+                };
+                for action in group {
+                    if check_interrupted().is_err() {
+                        stop.store(true, Ordering::SeqCst);
+                        return;
+                    }
+                    let done = undo(&action, hard_link);
+                    let mut report = report.lock().unwrap();
+                    match done {
+                        Ok(true) => report.restored += 1,
+                        Ok(false) => report.skipped.push(action.target),
+                        Err(err) => {
+                            warn!("Failed to restore {:?}: {:?}", action.target, err);
+                            report.failed.push(RevertFailure {
+                                path: action.target,
+                                error: format!("{:?}", err),
+                            });
+                        }
+                    }
+                }
+            }
+        };
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(work);
+            }
+        });
+        check_interrupted()?;
+    }
+    let mut report = report.into_inner().unwrap();
+    report.skipped.sort();
+    report.failed.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(report)
+}
+
+/// Hashes the files at `paths` with `algorithm` on up to `jobs` workers,
+/// returning their digests in the same order.
+pub(crate) fn hash_files(
+    paths: &[&Path],
+    jobs: usize,
+    algorithm: HashAlgorithm,
+) -> Vec<Result<String, MirageError>> {
+    let workers = jobs.clamp(1, paths.len().max(1));
+    debug!("Hashing {} files on {} workers", paths.len(), workers);
+    let next = AtomicUsize::new(0);
+    let digests = Mutex::new((0..paths.len()).map(|_| None).collect::<Vec<_>>());
+    let work = || loop {
+        let i = next.fetch_add(1, Ordering::SeqCst);
+        let Some(path) = paths.get(i) else {
+            return;
+        };
+        let digest = algorithm.hash_file(path);
+        digests.lock().unwrap()[i] = Some(digest);
+    };
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(work);
+        }
+    });
+    digests
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|digest| digest.expect("every file is hashed"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use tempfile::tempdir;
+
+    use super::{hash_files, stages};
+    use crate::{Action, ActionType, HashAlgorithm};
+
+    fn action(action: ActionType, source: &str, target: &str) -> Action {
+        Action::new(action, PathBuf::from(source), PathBuf::from(target))
+    }
+
+    #[test]
+    fn splits_groups_and_barriers() {
+        let shape = |inverted: Vec<Action>| {
+            stages(inverted.into_iter())
+                .iter()
+                .map(|stage| stage.iter().map(Vec::len).collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        };
+
+        // restores from two originals go to two groups, copies into the
+        // store need no undoing
+        assert_eq!(
+            shape(vec![
+                action(ActionType::Copy, "/o/a", "/t/a2"),
+                action(ActionType::Copy, "/o/b", "/t/b2"),
+                action(ActionType::Copy, "/o/a", "/t/a1"),
+                action(ActionType::NOP, "/o/a", "/t/a1"),
+            ]),
+            vec![vec![2, 1]]
+        );
+
+        // moves and files restored from two originals wait for the rest
+        assert_eq!(
+            shape(vec![
+                action(ActionType::Copy, "/o/a", "/t/x"),
+                action(ActionType::Move, "/t/y", "/t/z"),
+                action(ActionType::Copy, "/o/b", "/t/x"),
+                action(ActionType::Copy, "/o/c", "/t/x"),
+            ]),
+            vec![vec![1], vec![1], vec![1], vec![1]]
+        );
+    }
+
+    #[test]
+    fn hashes_in_order() {
+        let dir = tempdir().unwrap();
+        let paths = (0..10)
+            .map(|i| {
+                let path = dir.path().join(i.to_string());
+                fs::write(&path, "x".repeat(i)).unwrap();
+                path
+            })
+            .collect::<Vec<_>>();
+        let paths = paths.iter().map(PathBuf::as_path).collect::<Vec<_>>();
+        let digests = hash_files(&paths, 3, HashAlgorithm::Blake3)
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let expected = paths
+            .iter()
+            .map(|path| HashAlgorithm::Blake3.hash_file(path).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(digests, expected);
+        let missing = dir.path().join("missing");
+        assert!(hash_files(&[missing.as_path()], 2, HashAlgorithm::Blake3)[0].is_err());
+    }
+}
@@ -0,0 +1,630 @@
+//! Deduplication plans as TOML files, for reviewing what a run will do
+//! before it does it and for applying the reviewed decisions elsewhere,
+//! e.g. by CI onto a directory of build artifacts.
+//!
+//! A plan lists duplicate groups, every one with the file whose contents
+//! the group keeps, how the others refer to it and which files they are.
+//! Paths are relative to the tree the plan is applied to:
+//!
+//! ```toml
+//! version = 1
+//!
+//! [[group]]
+//! original = "photos/a.jpg"
+//! mode = "symlink"  # or "hardlink", "reflink"
+//! link = [
+//!     "photos/copy of a.jpg",
+//!     "backup/a.jpg",
+//! ]
+//! ```
+//!
+//! The original of a group is copied into the store and linked like the
+//! rest, unless it is an original in the store already. Only the subset of
+//! TOML the schema needs is read: basic and literal strings, integers and
+//! arrays, comments, and `[[group]]` tables.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use log::{debug, warn};
+
+use crate::{
+    back_up, canonicalize_link, check_interrupted, drop_rolled_back, duplicate_groups,
+    execute_transactions, full_match, known_contents, managing_root, record_originals,
+    record_session, Action, ActionType, ApplyOptions, ApplyReport, Cache, LinkMode, MirageError,
+    MirageState, Phase, PhaseTimes, PlannedAction, ProgressWriter, DEFAULT_BACKUPS,
+};
+
+/// Version of the schema written in plans.
+const VERSION: i64 = 1;
+
+/// Duplicate groups to deduplicate, as read from or written to a plan file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Plan {
+    pub groups: Vec<PlanGroup>,
+}
+
+/// Files with the same contents and the one of them the group keeps.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlanGroup {
+    /// The file whose contents are kept, or an original in the store.
+    pub original: PathBuf,
+    /// How the other files refer to the original.
+    pub mode: LinkMode,
+    /// The files replaced with links to the original.
+    pub link: Vec<PathBuf>,
+}
+
+/// A value of the TOML subset plans are written in.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Array(Vec<Value>),
+}
+
+/// Keys of a table and their values.
+type Table = HashMap<String, Value>;
+
+/// Reads a plan file one value at a time.
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn new(text: &str) -> Self {
+        Parser {
+            chars: text.chars().collect(),
+            pos: 0,
+        }
+    }
+
+    fn error(&self, message: impl Into<String>) -> MirageError {
+        let line = self.chars[..self.pos.min(self.chars.len())]
+            .iter()
+            .filter(|&&c| c == '\n')
+            .count();
+        MirageError::InvalidPlan(line + 1, message.into())
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), MirageError> {
+        if self.peek() != Some(c) {
+            return Err(self.error(format!("expected {:?}", c)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    /// Skips spaces and tabs, and newlines and comments with `lines`.
+    fn skip(&mut self, lines: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' | '\r' => self.pos += 1,
+                '\n' if lines => self.pos += 1,
+                '#' => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.pos += 1;
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    /// Checks that nothing but a comment follows on the line.
+    fn end_of_line(&mut self) -> Result<(), MirageError> {
+        self.skip(false);
+        match self.peek() {
+            None => Ok(()),
+            Some('\n') => {
+                self.pos += 1;
+                Ok(())
+            }
+            Some(_) => Err(self.error("expected the end of the line")),
+        }
+    }
+
+    fn key(&mut self) -> Result<String, MirageError> {
+        if matches!(self.peek(), Some('"' | '\'')) {
+            return self.string();
+        }
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Err(self.error("expected a key"));
+        }
+        Ok(self.chars[start..self.pos].iter().collect())
+    }
+
+    fn string(&mut self) -> Result<String, MirageError> {
+        let quote = self.peek().ok_or_else(|| self.error("expected a string"))?;
+        self.pos += 1;
+        let mut s = String::new();
+        loop {
+            let c = self
+                .peek()
+                .filter(|&c| c != '\n')
+                .ok_or_else(|| self.error("unterminated string"))?;
+            self.pos += 1;
+            match c {
+                _ if c == quote => return Ok(s),
+                '\\' if quote == '"' => {
+                    let escaped = self
+                        .peek()
+                        .ok_or_else(|| self.error("unterminated string"))?;
+                    self.pos += 1;
+                    match escaped {
+                        '"' => s.push('"'),
+                        '\\' => s.push('\\'),
+                        'n' => s.push('\n'),
+                        't' => s.push('\t'),
+                        'r' => s.push('\r'),
+                        'u' | 'U' => {
+                            let len = if escaped == 'u' { 4 } else { 8 };
+                            let digits = self
+                                .chars
+                                .get(self.pos..self.pos + len)
+                                .map(|d| d.iter().collect::<String>())
+                                .ok_or_else(|| self.error("short unicode escape"))?;
+                            let c = u32::from_str_radix(&digits, 16)
+                                .ok()
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| self.error("invalid unicode escape"))?;
+                            self.pos += len;
+                            s.push(c);
+                        }
+                        _ => return Err(self.error(format!("unknown escape \\{}", escaped))),
+                    }
+                }
+                _ => s.push(c),
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, MirageError> {
+        match self.peek() {
+            Some('"' | '\'') => Ok(Value::String(self.string()?)),
+            Some('[') => {
+                self.pos += 1;
+                let mut values = Vec::new();
+                loop {
+                    self.skip(true);
+                    if self.peek() == Some(']') {
+                        self.pos += 1;
+                        return Ok(Value::Array(values));
+                    }
+                    values.push(self.value()?);
+                    self.skip(true);
+                    match self.peek() {
+                        Some(',') => self.pos += 1,
+                        Some(']') => {}
+                        _ => return Err(self.error("expected ',' or ']'")),
+                    }
+                }
+            }
+            Some(c) if c.is_ascii_digit() || c == '-' || c == '+' => {
+                let start = self.pos;
+                self.pos += 1;
+                while self.peek().is_some_and(|c| c.is_ascii_digit() || c == '_') {
+                    self.pos += 1;
+                }
+                let digits = self.chars[start..self.pos]
+                    .iter()
+                    .filter(|&&c| c != '_')
+                    .collect::<String>();
+                digits
+                    .parse()
+                    .map(Value::Integer)
+                    .map_err(|_| self.error(format!("invalid integer {:?}", digits)))
+            }
+            _ => Err(self.error("expected a string, an integer or an array")),
+        }
+    }
+
+    /// Reads the document into its top-level table and its groups, keeping
+    /// where every group starts.
+    fn document(mut self) -> Result<(Table, Vec<(usize, Table)>), MirageError> {
+        let mut top = HashMap::new();
+        let mut groups: Vec<(usize, Table)> = Vec::new();
+        loop {
+            self.skip(true);
+            let Some(c) = self.peek() else {
+                return Ok((top, groups));
+            };
+            if c == '[' {
+                let line = self.pos;
+                for c in "[[group]]".chars() {
+                    if self.peek() != Some(c) {
+                        return Err(self.error("only [[group]] tables are known"));
+                    }
+                    self.pos += 1;
+                }
+                self.end_of_line()?;
+                groups.push((line, HashMap::new()));
+                continue;
+            }
+            let key = self.key()?;
+            self.skip(false);
+            self.expect('=')?;
+            self.skip(false);
+            let value = self.value()?;
+            self.end_of_line()?;
+            let table = match groups.last_mut() {
+                Some((_, group)) => group,
+                None => &mut top,
+            };
+            if table.insert(key.clone(), value).is_some() {
+                return Err(self.error(format!("{} is set twice", key)));
+            }
+        }
+    }
+}
+
+impl Plan {
+    /// Reads a plan from the text of a plan file.
+    pub fn parse(text: &str) -> Result<Plan, MirageError> {
+        let parser = Parser::new(text);
+        let line_of = |pos: usize| text.chars().take(pos).filter(|&c| c == '\n').count() + 1;
+        let (top, tables) = parser.document()?;
+        for (key, value) in &top {
+            match (key.as_str(), value) {
+                ("version", Value::Integer(VERSION)) => {}
+                ("version", _) => {
+                    return Err(MirageError::InvalidPlan(
+                        1,
+                        format!("unknown version {:?}", value),
+                    ))
+                }
+                _ => return Err(MirageError::InvalidPlan(1, format!("unknown key {}", key))),
+            }
+        }
+
+        let mut groups = Vec::new();
+        for (pos, mut table) in tables {
+            let invalid = |message: String| MirageError::InvalidPlan(line_of(pos), message);
+            let original = match table.remove("original") {
+                Some(Value::String(original)) => PathBuf::from(original),
+                Some(_) => return Err(invalid("original must be a string".into())),
+                None => return Err(invalid("group has no original".into())),
+            };
+            let mode = match table.remove("mode") {
+                Some(Value::String(mode)) => mode.parse().map_err(invalid)?,
+                Some(_) => return Err(invalid("mode must be a string".into())),
+                None => LinkMode::Symlink,
+            };
+            let link = match table.remove("link") {
+                Some(Value::Array(values)) => values
+                    .into_iter()
+                    .map(|value| match value {
+                        Value::String(path) => Ok(PathBuf::from(path)),
+                        _ => Err(invalid("link must list strings".into())),
+                    })
+                    .collect::<Result<_, _>>()?,
+                Some(_) => return Err(invalid("link must be an array".into())),
+                None => Vec::new(),
+            };
+            if let Some(key) = table.keys().next() {
+                return Err(invalid(format!("unknown key {}", key)));
+            }
+            groups.push(PlanGroup {
+                original,
+                mode,
+                link,
+            });
+        }
+        Ok(Plan { groups })
+    }
+
+    /// The plan carrying out the actions a dry run of apply planned on the
+    /// tree at `root`.
+    pub fn from_planned(root: &Path, planned: &[PlannedAction]) -> Plan {
+        let relative = |path: &Path| path.strip_prefix(root).unwrap_or(path).to_path_buf();
+        let mut groups: Vec<PlanGroup> = Vec::new();
+        // the group linking to each original of the store
+        let mut of: HashMap<&Path, usize> = HashMap::new();
+        for action in planned {
+            let Ok(mode) = action.action.to_lowercase().parse::<LinkMode>() else {
+                if action.action == "Copy" {
+                    of.insert(&action.target, groups.len());
+                    groups.push(PlanGroup {
+                        original: relative(&action.source),
+                        mode: LinkMode::Symlink,
+                        link: Vec::new(),
+                    });
+                }
+                continue;
+            };
+            let index = *of.entry(&action.target).or_insert_with(|| {
+                groups.push(PlanGroup {
+                    original: relative(&action.target),
+                    mode,
+                    link: Vec::new(),
+                });
+                groups.len() - 1
+            });
+            let group = &mut groups[index];
+            group.mode = mode;
+            let path = relative(&action.source);
+            if path != group.original {
+                group.link.push(path);
+            }
+        }
+        groups.retain(|group| !group.link.is_empty());
+        Plan { groups }
+    }
+
+    /// Writes the plan as a plan file.
+    pub fn write<W: Write>(&self, mut out: W) -> io::Result<()> {
+        writeln!(
+            out,
+            "# Deduplication plan written by mirage, see `mirage apply --plan`"
+        )?;
+        writeln!(out, "version = {}", VERSION)?;
+        for group in &self.groups {
+            writeln!(out)?;
+            writeln!(out, "[[group]]")?;
+            writeln!(out, "original = {}", quote(&group.original))?;
+            writeln!(out, "mode = {}", quote(Path::new(group.mode.name())))?;
+            writeln!(out, "link = [")?;
+            for path in &group.link {
+                writeln!(out, "    {},", quote(path))?;
+            }
+            writeln!(out, "]")?;
+        }
+        out.flush()
+    }
+}
+
+/// `path` as a basic TOML string.
+fn quote(path: &Path) -> String {
+    let mut s = String::from('"');
+    for c in path.to_string_lossy().chars() {
+        match c {
+            '"' => s.push_str("\\\""),
+            '\\' => s.push_str("\\\\"),
+            c if c.is_control() => s.push_str(&format!("\\u{:04x}", c as u32)),
+            c => s.push(c),
+        }
+    }
+    s.push('"');
+    s
+}
+
+/// Deduplicates the tree at `target_dir` as `plan` says, rather than as
+/// apply would decide. A file is only linked once it is checked to hold
+/// what its group's original holds, the files that don't, are gone or are
+/// managed already are left alone and reported. Only the dry run, backup,
+/// store and hash algorithm settings of `options` apply.
+pub fn apply_plan<T: AsRef<Path>>(
+    target_dir: T,
+    plan: &Plan,
+    options: &ApplyOptions,
+) -> Result<ApplyReport, MirageError> {
+    let root = fs::canonicalize(&target_dir)?;
+    let location = options.location();
+    let store_root = managing_root(&root, &location);
+    let mut state = if options.dry_run {
+        MirageState::peek_at(&store_root, &location)?
+    } else {
+        MirageState::get_at(&store_root, &location)?
+    };
+    state.ensure_unfrozen()?;
+    state.lock_run()?;
+    let mut modes = plan
+        .groups
+        .iter()
+        .map(|group| group.mode)
+        .collect::<Vec<_>>();
+    modes.dedup();
+    for mode in modes {
+        state.probe_links(mode)?;
+    }
+    back_up(&state, "apply", options.backups.unwrap_or(DEFAULT_BACKUPS))?;
+    let mut report = ApplyReport {
+        dry_run: state.dry_run,
+        ..Default::default()
+    };
+    let mut cache = Cache::load(&state, options.algorithm())?;
+    let known = known_contents(&state, &mut cache)?;
+
+    for group in &plan.groups {
+        check_interrupted()?;
+        let in_tree =
+            |path: &Path| path.starts_with(&root) && !path.starts_with(&state.source_path);
+        let is_file = |path: &Path| fs::symlink_metadata(path).is_ok_and(|meta| meta.is_file());
+        let original = canonicalize_link(&root.join(&group.original))?;
+        let stored = state.wal.redirections.values().any(|o| *o == original);
+        if !(stored && original.is_file() || in_tree(&original) && is_file(&original)) {
+            warn!(
+                "Original {:?} of the plan is gone, leaving its group",
+                original
+            );
+            report.plan_skipped.push(original);
+            continue;
+        }
+
+        let mut linked = Vec::new();
+        for path in &group.link {
+            let path = match canonicalize_link(&root.join(path)) {
+                Ok(path) => path,
+                Err(_) => root.join(path),
+            };
+            let matches = in_tree(&path)
+                && is_file(&path)
+                && path != original
+                && !state.wal.redirections.contains_key(&path)
+                && full_match(&path, &original)?;
+            if matches {
+                linked.push(path);
+            } else {
+                warn!(
+                    "{:?} doesn't hold what {:?} does, leaving it",
+                    path, original
+                );
+                report.plan_skipped.push(path);
+            }
+        }
+        if linked.is_empty() {
+            continue;
+        }
+
+        let target = if stored {
+            original
+        } else {
+            let content = cache.hash(&original)?;
+            let target = state.new_original_path(
+                original.file_name().unwrap_or("original".as_ref()),
+                &content,
+            );
+            let mut copy = Action::new(ActionType::Copy, original.clone(), target.clone());
+            copy.digest = Some(content);
+            copy.algorithm = Some(cache.algorithm());
+            state.wal.push(copy);
+            linked.insert(0, original);
+            target
+        };
+        debug!("Linking {} files to {:?}", linked.len(), target);
+        for path in linked {
+            state.wal.push(Action::new(
+                group.mode.action(),
+                path.clone(),
+                target.clone(),
+            ));
+            state.wal.redirections.insert(path, target.clone());
+        }
+        state.commit()?;
+    }
+
+    if state.dry_run {
+        report.planned = state.wal.actions[state.wal.checkpoint..]
+            .iter()
+            .map(PlannedAction::from)
+            .collect();
+    }
+    let executed = state.wal.checkpoint;
+    report.groups = duplicate_groups(&state.wal.actions[executed..], &mut cache)?;
+    let mut progress = ProgressWriter::new(&state);
+    progress.enter(&state, Phase::Executing)?;
+    report.rolled_back = execute_transactions(&mut state, &mut progress)?;
+    drop_rolled_back(&mut report.groups, &report.rolled_back);
+    progress.enter(&state, Phase::Done)?;
+    report.bytes_copied = progress.progress().bytes_copied;
+    report.actions_executed = progress.executed_actions();
+    report.times = PhaseTimes {
+        committing: state.committing,
+        ..progress.times()
+    };
+    report.store_size = state.store_size()?;
+    if !state.dry_run {
+        report.bytes_saved =
+            record_session(&state, &state.wal.actions[executed..], report.store_size)?;
+        record_originals(&state, known, &state.wal.actions[executed..], &mut cache)?;
+    }
+    cache.save()?;
+    report.plan_skipped.sort();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::{Plan, PlanGroup};
+    use crate::{LinkMode, MirageError, PlannedAction};
+
+    #[test]
+    fn writes_and_reads_plans() {
+        let plan = Plan {
+            groups: vec![
+                PlanGroup {
+                    original: PathBuf::from("a"),
+                    mode: LinkMode::Hardlink,
+                    link: vec![PathBuf::from("b \"quoted\""), PathBuf::from("dir/c")],
+                },
+                PlanGroup {
+                    original: PathBuf::from(".mirage/originals/d"),
+                    mode: LinkMode::Symlink,
+                    link: vec![PathBuf::from("e\\f")],
+                },
+            ],
+        };
+        let mut text = Vec::new();
+        plan.write(&mut text).unwrap();
+        assert_eq!(
+            Plan::parse(&String::from_utf8(text).unwrap()).unwrap(),
+            plan
+        );
+
+        // written by hand, comments, literal strings and defaults included
+        let text = "version = 1 # the schema\n\n[[group]]\noriginal = 'x'\nlink = [\n  \"y\", # one\n  '\\z'\n]\n";
+        assert_eq!(
+            Plan::parse(text).unwrap().groups,
+            vec![PlanGroup {
+                original: PathBuf::from("x"),
+                mode: LinkMode::Symlink,
+                link: vec![PathBuf::from("y"), PathBuf::from("\\z")],
+            }]
+        );
+
+        for (text, line) in [
+            ("version = 2\n", 1),
+            ("[[group]]\nlink = []\n", 1),
+            ("[[group]]\noriginal = \"a\"\nmode = \"copy\"\n", 1),
+            ("[[group]]\noriginal = \"a\"\nlinks = []\n", 1),
+            ("[[group]]\noriginal = \"a\n", 2),
+            ("\n[group]\n", 2),
+        ] {
+            match Plan::parse(text) {
+                Err(MirageError::InvalidPlan(at, _)) => assert_eq!(at, line, "{:?}", text),
+                other => panic!("{:?} parsed to {:?}", text, other),
+            }
+        }
+    }
+
+    #[test]
+    fn plans_from_planned_actions() {
+        let action = |action: &str, source: &str, target: &str| PlannedAction {
+            action: action.to_string(),
+            source: PathBuf::from(source),
+            target: PathBuf::from(target),
+        };
+        let plan = Plan::from_planned(
+            Path::new("/t"),
+            &[
+                action("Copy", "/t/a", "/t/.mirage/originals/a"),
+                action("Symlink", "/t/a", "/t/.mirage/originals/a"),
+                action("Symlink", "/t/b", "/t/.mirage/originals/a"),
+                action("Symlink", "/t/c", "/t/.mirage/originals/old"),
+            ],
+        );
+        assert_eq!(
+            plan.groups,
+            vec![
+                PlanGroup {
+                    original: PathBuf::from("a"),
+                    mode: LinkMode::Symlink,
+                    link: vec![PathBuf::from("b")],
+                },
+                PlanGroup {
+                    original: PathBuf::from(".mirage/originals/old"),
+                    mode: LinkMode::Symlink,
+                    link: vec![PathBuf::from("c")],
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,109 @@
+//! Picking how each deduplicated file refers to its original: by size, by
+//! the device the file and its original are on, and by what the filesystem
+//! of the store can hold. Large files are best cloned where the filesystem
+//! can, small ones hard linked, leaving symlinks for whatever can't be
+//! either. The action of every file records what it got.
+
+use std::{fs, path::Path};
+
+use log::debug;
+
+use crate::{ActionType, LinkMode, MirageState};
+
+/// Size thresholds picking the link of each file, symlinks being used for
+/// files falling under neither.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LinkPolicy {
+    /// Files at least this large are cloned from their original.
+    pub reflink_min_size: Option<u64>,
+    /// Files at most this large are hard linked to their original.
+    pub hardlink_max_size: Option<u64>,
+}
+
+/// Whether `path` and `original` are on the same device, as hard links and
+/// clones need. An original not copied into the store yet counts as being
+/// on the device of the directory it goes in.
+#[cfg(unix)]
+pub(crate) fn same_device(path: &Path, original: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    let Ok(meta) = fs::metadata(path) else {
+        return false;
+    };
+    original
+        .ancestors()
+        .find_map(|dir| fs::metadata(dir).ok())
+        .is_some_and(|dir| dir.dev() == meta.dev())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn same_device(_path: &Path, _original: &Path) -> bool {
+    true
+}
+
+/// A [`LinkPolicy`] and the mode files falling under neither of its
+/// thresholds are linked with, along with what the filesystem of the store
+/// can hold, probed once per run.
+pub(crate) struct Linker {
+    policy: LinkPolicy,
+    mode: LinkMode,
+    can_hardlink: bool,
+    can_reflink: bool,
+}
+
+impl Linker {
+    pub(crate) fn new(
+        state: &MirageState,
+        policy: Option<&LinkPolicy>,
+        mode: Option<LinkMode>,
+    ) -> Self {
+        let policy = policy.cloned().unwrap_or_default();
+        let mode = mode.unwrap_or(LinkMode::Symlink);
+        let can_hardlink = (policy.hardlink_max_size.is_some() || mode == LinkMode::Hardlink)
+            && state.can_link(LinkMode::Hardlink);
+        let can_reflink = (policy.reflink_min_size.is_some() || mode == LinkMode::Reflink)
+            && state.can_link(LinkMode::Reflink);
+        debug!(
+            "Linking with {:?} and {}s otherwise, hard links {}, clones {}",
+            policy,
+            mode.name(),
+            if can_hardlink { "work" } else { "unused" },
+            if can_reflink { "work" } else { "unused" }
+        );
+        Linker {
+            policy,
+            mode,
+            can_hardlink,
+            can_reflink,
+        }
+    }
+
+    /// The action linking `path` to `original`. Files a hard link or clone
+    /// can't reach are symlinked.
+    pub(crate) fn action(&self, path: &Path, original: &Path) -> ActionType {
+        if !self.can_hardlink && !self.can_reflink {
+            return ActionType::Symlink;
+        }
+        let Ok(size) = fs::metadata(path).map(|meta| meta.len()) else {
+            return ActionType::Symlink;
+        };
+        if !same_device(path, original) {
+            return ActionType::Symlink;
+        }
+        let usable = |mode: LinkMode| match mode {
+            LinkMode::Symlink => true,
+            LinkMode::Hardlink => self.can_hardlink,
+            LinkMode::Reflink => self.can_reflink,
+        };
+        if self.can_reflink && self.policy.reflink_min_size.is_some_and(|min| size >= min) {
+            ActionType::Reflink
+        } else if self.can_hardlink && self.policy.hardlink_max_size.is_some_and(|max| size <= max)
+        {
+            ActionType::Hardlink
+        } else if usable(self.mode) {
+            self.mode.action()
+        } else {
+            ActionType::Symlink
+        }
+    }
+}
@@ -0,0 +1,116 @@
+//! Checking that the filesystem of a store can hold the links a run is
+//! about to make, before anything is modified. FAT and exFAT can't hold
+//! symlinks or hard links, and neither can some SMB mounts, where the first
+//! link of a run would otherwise fail with the tree half deduplicated.
+
+use std::{fs, path::Path, process};
+
+use log::debug;
+use symlink::symlink_file;
+
+use crate::{
+    diff::same_inode, extents::share_extents, reflink, LinkMode, MirageError, MirageState,
+};
+
+/// What the probe file holds, to tell the link reaches it.
+const PROBE_CONTENTS: &[u8] = b"mirage probe";
+
+/// Makes a link to `file` at `link` as `mode` and checks it reaches `file`.
+fn try_link(file: &Path, link: &Path, mode: LinkMode) -> std::io::Result<()> {
+    match mode {
+        LinkMode::Symlink => {
+            symlink_file(file, link)?;
+            if fs::read_link(link)? != file {
+                return Err(std::io::Error::other("symlink reads back wrong"));
+            }
+        }
+        LinkMode::Hardlink => {
+            fs::hard_link(file, link)?;
+            if !same_inode(&fs::metadata(link)?, file) {
+                return Err(std::io::Error::other("hard link is a copy"));
+            }
+        }
+        LinkMode::Reflink => reflink(file, link)?,
+    }
+    if fs::read(link)? != PROBE_CONTENTS {
+        return Err(std::io::Error::other("link doesn't reach its target"));
+    }
+    Ok(())
+}
+
+impl MirageState {
+    /// Makes and removes a link of `mode` in the store, failing with
+    /// [`MirageError::LinksUnsupported`] if the filesystem can't hold one.
+    /// Nothing is probed for a read-only store, which links nothing, nor for
+    /// clones, which a filesystem may make of some files only and are
+    /// reported per file.
+    pub(crate) fn probe_links(&self, mode: LinkMode) -> Result<(), MirageError> {
+        if self.dry_run || mode == LinkMode::Reflink {
+            return Ok(());
+        }
+        self.try_probe(mode)
+    }
+
+    /// Like [`MirageState::probe_links`], probing clones too, for runs told
+    /// to make them, which would otherwise fail on every file of a
+    /// filesystem that can't clone.
+    pub(crate) fn require_links(&self, mode: LinkMode) -> Result<(), MirageError> {
+        if self.dry_run {
+            return Ok(());
+        }
+        self.try_probe(mode)
+    }
+
+    /// Whether the filesystem of the store can hold links of `mode`. A
+    /// read-only store is taken to, as probing it would modify it.
+    pub(crate) fn can_link(&self, mode: LinkMode) -> bool {
+        self.dry_run || self.try_probe(mode).is_ok()
+    }
+
+    /// Shares the extents of two probe files in the store, failing with
+    /// [`MirageError::LinksUnsupported`] if the filesystem can't, for runs
+    /// deduplicating extents in place. The probe files span a few blocks,
+    /// as filesystems only share whole ones.
+    pub(crate) fn require_extent_sharing(&self) -> Result<(), MirageError> {
+        if self.dry_run {
+            return Ok(());
+        }
+        let file = self.source_path.join(format!(".probe-{}", process::id()));
+        let other = self
+            .source_path
+            .join(format!(".probe-{}-extents", process::id()));
+        debug!("Probing extent sharing in {:?}", self.source_path);
+        let contents = PROBE_CONTENTS.repeat((64 << 10) / PROBE_CONTENTS.len());
+        let probed = fs::write(&file, &contents)
+            .and_then(|()| fs::write(&other, &contents))
+            .and_then(|()| share_extents(&file, &other))
+            .and_then(|shared| match shared {
+                0 => Err(std::io::Error::other("no block was shared")),
+                _ => Ok(()),
+            });
+        let _ = fs::remove_file(&other);
+        let _ = fs::remove_file(&file);
+        probed.map_err(|err| {
+            MirageError::LinksUnsupported(
+                self.source_path.clone(),
+                "shared extent",
+                err.to_string(),
+            )
+        })
+    }
+
+    fn try_probe(&self, mode: LinkMode) -> Result<(), MirageError> {
+        let file = self.source_path.join(format!(".probe-{}", process::id()));
+        let link = self
+            .source_path
+            .join(format!(".probe-{}-{}", process::id(), mode.name()));
+        debug!("Probing {}s in {:?}", mode.name(), self.source_path);
+        let _ = fs::remove_file(&link);
+        let probed = fs::write(&file, PROBE_CONTENTS).and_then(|()| try_link(&file, &link, mode));
+        let _ = fs::remove_file(&link);
+        let _ = fs::remove_file(&file);
+        probed.map_err(|err| {
+            MirageError::LinksUnsupported(self.source_path.clone(), mode.name(), err.to_string())
+        })
+    }
+}
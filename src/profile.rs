@@ -0,0 +1,154 @@
+use std::{path::Path, str::FromStr};
+
+use crate::{ApplyOptions, LinkMode};
+
+/// Named bundles of apply options suited to a particular kind of tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Profile {
+    /// Photo libraries: sidecar files are left alone, a RAW is never grouped
+    /// with its JPEG, and originals are named after copies in date-organized
+    /// archive directories.
+    Photos,
+    /// Source trees: files are only grouped with files of the same type and
+    /// editor leftovers are skipped. Version control internals stay excluded.
+    /// Small files are left alone and duplicates are cloned where the
+    /// filesystem can, so editing one never changes the others.
+    Code,
+    /// Trees of dated backup snapshots: the newest snapshot holds the
+    /// originals and savings are reported per snapshot. Duplicates are hard
+    /// linked, the way snapshot tools link unchanged files.
+    Backups,
+    /// Music and video collections: files differing only in their tags are
+    /// duplicates, as long as they are of the same format. Files too small
+    /// to be a track or a clip are left alone.
+    Media,
+}
+
+/// Editing metadata stored next to photos, which tools expect to be real
+/// files beside the image they describe.
+const PHOTO_SIDECARS: &[&str] = &["xmp", "aae", "thm"];
+
+/// Swap and backup files of editors, rewritten under their feet.
+const EDITOR_LEFTOVERS: &[&str] = &["swp", "swo", "bak", "orig"];
+
+/// Source files below this size are too cheap to be worth a link.
+const CODE_MIN_SIZE: u64 = 4 << 10;
+
+/// Media files below this size are thumbnails and snippets, not tracks.
+const MEDIA_MIN_SIZE: u64 = 64 << 10;
+
+impl Profile {
+    /// Turns on the behaviour of this profile in `options`. The minimum size
+    /// and link mode it suggests are only used where `options` sets none.
+    pub fn apply_to(self, options: &mut ApplyOptions) {
+        let (min_size, link_mode) = self.defaults();
+        options.min_size = options.min_size.or(min_size);
+        // sharing extents links nothing
+        if !options.dedupe_extents {
+            options.link_mode = options.link_mode.or(link_mode);
+        }
+        match self {
+            Profile::Photos => {
+                options
+                    .exclude_extensions
+                    .extend(PHOTO_SIDECARS.iter().map(|e| e.to_string()));
+                options.same_extension_only = true;
+                options.prefer_dated_dirs = true;
+            }
+            Profile::Code => {
+                options
+                    .exclude_extensions
+                    .extend(EDITOR_LEFTOVERS.iter().map(|e| e.to_string()));
+                options.same_extension_only = true;
+            }
+            Profile::Backups => {
+                options.snapshots = true;
+            }
+            Profile::Media => {
+                options.ignore_metadata = true;
+                options.same_extension_only = true;
+            }
+        }
+    }
+}
+
+impl Profile {
+    /// The minimum size and link mode this profile suggests.
+    fn defaults(self) -> (Option<u64>, Option<LinkMode>) {
+        match self {
+            Profile::Photos => (None, None),
+            Profile::Code => (Some(CODE_MIN_SIZE), Some(LinkMode::Reflink)),
+            Profile::Backups => (None, Some(LinkMode::Hardlink)),
+            Profile::Media => (Some(MEDIA_MIN_SIZE), None),
+        }
+    }
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "photos" => Ok(Profile::Photos),
+            "code" => Ok(Profile::Code),
+            "backups" => Ok(Profile::Backups),
+            "media" => Ok(Profile::Media),
+            _ => Err(format!("unknown profile {:?}", s)),
+        }
+    }
+}
+
+/// Whether a directory name starts like a year or a date (`2024`,
+/// `2024-01-15`, `20240115_trip`).
+fn looks_dated(name: &str) -> bool {
+    let digits = name.chars().take_while(|c| c.is_ascii_digit()).count();
+    digits >= 4 && (name.starts_with("19") || name.starts_with("20"))
+}
+
+/// Number of date-named directories a path lives under.
+pub(crate) fn date_score(path: &Path) -> usize {
+    path.parent()
+        .map(|parent| {
+            parent
+                .components()
+                .filter(|c| looks_dated(&c.as_os_str().to_string_lossy()))
+                .count()
+        })
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::Profile;
+    use crate::{ApplyOptions, LinkMode};
+
+    #[test]
+    fn parses_every_profile() {
+        for (name, profile) in [
+            ("photos", Profile::Photos),
+            ("code", Profile::Code),
+            ("backups", Profile::Backups),
+            ("media", Profile::Media),
+        ] {
+            assert_eq!(Profile::from_str(name), Ok(profile));
+        }
+        assert!(Profile::from_str("music").is_err());
+
+        let mut options = ApplyOptions::default();
+        Profile::Media.apply_to(&mut options);
+        assert!(options.ignore_metadata && options.same_extension_only);
+        assert_eq!(options.min_size, Some(64 << 10));
+
+        // what a run sets already stays
+        let mut options = ApplyOptions {
+            link_mode: Some(LinkMode::Symlink),
+            ..Default::default()
+        };
+        Profile::Backups.apply_to(&mut options);
+        assert_eq!(options.link_mode, Some(LinkMode::Symlink));
+        Profile::Code.apply_to(&mut options);
+        assert_eq!(options.min_size, Some(4 << 10));
+    }
+}
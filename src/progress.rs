@@ -0,0 +1,255 @@
+//! Progress of a running apply, for checking on it from elsewhere.
+
+use std::{
+    fs::{self, File},
+    io::BufWriter,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{cache::Cache, store_path, ActionType, MirageError, MirageState};
+
+/// File in the store the running apply keeps its progress in.
+const PROGRESS_FILE: &str = "progress.json";
+
+/// How often progress is written out at most, phase changes aside.
+const INTERVAL: Duration = Duration::from_secs(1);
+
+/// What an apply run is busy with.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Phase {
+    /// Walking the tree for candidates.
+    Scanning,
+    /// Comparing candidates of the same size and planning links.
+    Comparing,
+    /// Executing the planned actions.
+    Executing,
+    /// Finished, the numbers are final.
+    Done,
+}
+
+/// Where an apply run is at, as last written to the store.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Progress {
+    pub phase: Phase,
+    pub files_scanned: u64,
+    /// Saved by the links planned so far, once they are executed.
+    pub bytes_saved: u64,
+    /// Read to hash files so far, the ones hashed by earlier runs aside.
+    #[serde(default)]
+    pub bytes_hashed: u64,
+    /// Of the files whose contents were compared so far.
+    #[serde(default)]
+    pub bytes_compared: u64,
+    /// Copied into the store so far.
+    #[serde(default)]
+    pub bytes_copied: u64,
+    /// The file being scanned or compared.
+    pub current: Option<PathBuf>,
+    pub pid: u32,
+    /// Seconds since the Unix epoch at which the run started.
+    pub started: u64,
+    /// Seconds since the Unix epoch at which this was written.
+    pub updated: u64,
+}
+
+/// Wall-clock time an apply run spent in each phase.
+#[derive(Debug, Clone, Copy, Default, Serialize, PartialEq, Eq)]
+pub struct PhaseTimes {
+    pub scanning: Duration,
+    pub comparing: Duration,
+    pub executing: Duration,
+    /// Spent writing the WAL, in whichever phase.
+    pub committing: Duration,
+}
+
+impl PhaseTimes {
+    /// Total time of the run.
+    pub fn total(&self) -> Duration {
+        self.scanning + self.comparing + self.executing
+    }
+
+    pub(crate) fn add(&mut self, other: &PhaseTimes) {
+        self.scanning += other.scanning;
+        self.comparing += other.comparing;
+        self.executing += other.executing;
+        self.committing += other.committing;
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Keeps the progress file of a run up to date, without writing it more
+/// than once per [`INTERVAL`].
+pub(crate) struct ProgressWriter {
+    /// `None` for dry runs, which write nothing.
+    path: Option<PathBuf>,
+    progress: Progress,
+    /// Bytes saved so far, copies into the store counting against it.
+    net: i64,
+    /// Number of WAL actions whose savings are counted.
+    counted: usize,
+    /// Number of WAL actions before the run.
+    start: usize,
+    written: Option<Instant>,
+    /// When the current phase started.
+    entered: Instant,
+    times: PhaseTimes,
+    executed: usize,
+}
+
+impl ProgressWriter {
+    pub fn new(state: &MirageState) -> ProgressWriter {
+        let started = now();
+        ProgressWriter {
+            path: (!state.dry_run).then(|| state.source_path.join(PROGRESS_FILE)),
+            progress: Progress {
+                phase: Phase::Scanning,
+                files_scanned: 0,
+                bytes_saved: 0,
+                bytes_hashed: 0,
+                bytes_compared: 0,
+                bytes_copied: 0,
+                current: None,
+                pid: std::process::id(),
+                started,
+                updated: started,
+            },
+            net: 0,
+            counted: state.wal.actions.len(),
+            start: state.wal.actions.len(),
+            written: None,
+            entered: Instant::now(),
+            times: PhaseTimes::default(),
+            executed: 0,
+        }
+    }
+
+    pub fn scanned(&mut self, path: &Path) -> Result<(), MirageError> {
+        self.progress.files_scanned += 1;
+        self.tick(path)
+    }
+
+    pub fn comparing(
+        &mut self,
+        state: &MirageState,
+        cache: &Cache,
+        path: &Path,
+    ) -> Result<(), MirageError> {
+        self.tally(cache);
+        if self.progress.phase != Phase::Comparing {
+            return self.enter(state, Phase::Comparing);
+        }
+        self.count(state);
+        self.tick(path)
+    }
+
+    /// Catches up with the bytes `cache` hashed and compared.
+    pub fn tally(&mut self, cache: &Cache) {
+        self.progress.bytes_hashed = cache.bytes_hashed();
+        self.progress.bytes_compared = cache.bytes_compared();
+    }
+
+    /// Counts the action on `path` as executed, having copied `copied` bytes.
+    pub fn executed(&mut self, path: &Path, copied: u64) -> Result<(), MirageError> {
+        self.progress.bytes_copied += copied;
+        self.executed += 1;
+        self.tick(path)
+    }
+
+    /// Number of actions counted by [`ProgressWriter::executed`].
+    pub fn executed_actions(&self) -> usize {
+        self.executed
+    }
+
+    /// Time spent in each phase, up to the current one.
+    pub fn times(&self) -> PhaseTimes {
+        self.times
+    }
+
+    /// Where the run is at, whether or not it was written out.
+    pub fn progress(&self) -> &Progress {
+        &self.progress
+    }
+
+    /// Moves on to `phase`, writing the progress out right away.
+    pub fn enter(&mut self, state: &MirageState, phase: Phase) -> Result<(), MirageError> {
+        if phase == Phase::Done {
+            // counted again without the groups that were rolled back
+            self.net = 0;
+            self.counted = self.start;
+        }
+        self.count(state);
+        let spent = match self.progress.phase {
+            Phase::Scanning => &mut self.times.scanning,
+            Phase::Comparing => &mut self.times.comparing,
+            // nothing is entered once done
+            Phase::Executing | Phase::Done => &mut self.times.executing,
+        };
+        *spent += self.entered.elapsed();
+        self.entered = Instant::now();
+        self.progress.phase = phase;
+        self.progress.current = None;
+        self.write()
+    }
+
+    fn count(&mut self, state: &MirageState) {
+        let new = state.wal.actions.iter().skip(self.counted);
+        for action in new {
+            let size = || {
+                fs::metadata(&action.source)
+                    .map(|meta| meta.len() as i64)
+                    .unwrap_or_default()
+            };
+            match action.action {
+                ActionType::Symlink
+                | ActionType::Hardlink
+                | ActionType::Reflink
+                | ActionType::Delete
+                | ActionType::Dedupe => self.net += size(),
+                ActionType::Copy => self.net -= size(),
+                _ => {}
+            }
+        }
+        self.counted = state.wal.actions.len();
+        self.progress.bytes_saved = self.net.max(0) as u64;
+    }
+
+    fn tick(&mut self, path: &Path) -> Result<(), MirageError> {
+        if self.written.is_some_and(|at| at.elapsed() < INTERVAL) {
+            return Ok(());
+        }
+        self.progress.current = Some(path.to_path_buf());
+        self.write()
+    }
+
+    fn write(&mut self) -> Result<(), MirageError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        self.progress.updated = now();
+        // written aside and renamed so readers never see half of it
+        let tmp = path.with_extension("json.tmp");
+        serde_json::to_writer(BufWriter::new(File::create(&tmp)?), &self.progress)?;
+        fs::rename(&tmp, path)?;
+        self.written = Some(Instant::now());
+        Ok(())
+    }
+}
+
+/// The progress of the running or last apply of the tree at `target_dir`,
+/// `None` if it was never applied since progress was recorded.
+pub fn status<T: AsRef<Path>>(target_dir: T) -> Result<Option<Progress>, MirageError> {
+    let path = store_path(target_dir.as_ref()).join(PROGRESS_FILE);
+    if !path.is_file() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_slice(&fs::read(path)?)?))
+}
@@ -0,0 +1,223 @@
+//! Deduplicating a tree again after it was restored from a backup, from a
+//! copy of its WAL kept elsewhere. Backup tools tend to store the links of a
+//! managed tree as the files they point at, or not at all, and may leave the
+//! store behind.
+//!
+//! The recorded WAL is installed as the tree's own, then every managed path
+//! is linked to its original again as the WAL says it was. Nothing is
+//! linked on trust: a file only gives way to a link once its contents match
+//! the ones recorded for its original, and an original missing from the
+//! store is put back from such a file.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use log::{debug, warn};
+use serde::Serialize;
+
+use crate::{
+    back_up, check_interrupted, full_match,
+    schema::read_wal,
+    signing_existing, signing_key,
+    upgrade::{intact, relink},
+    verify_signature, ActionType, HashAlgorithm, LinkMode, MirageError, MirageState,
+    DEFAULT_BACKUPS, WAL,
+};
+
+/// What [`reapply`] did to the tree.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ReapplyReport {
+    /// Number of paths linked to their original again.
+    pub relinked: usize,
+    /// Number of paths still linked as the WAL says.
+    pub intact: usize,
+    /// Originals missing from the store, or not holding what they should,
+    /// copied back from a file of the tree.
+    pub recovered: Vec<PathBuf>,
+    /// Paths left as they are since their contents differ from the ones
+    /// recorded for their original.
+    pub mismatched: Vec<PathBuf>,
+    /// Paths whose original is gone with no file of the tree to bring it
+    /// back from, left as they are.
+    pub lost: Vec<PathBuf>,
+}
+
+/// Reads the WAL at `from`, a `wal.json` or a directory holding one along
+/// with its segments, such as a state backup or an archived state.
+fn load(from: &Path, key: Option<&[u8]>) -> Result<WAL, MirageError> {
+    let (dir, wal_path) = if from.is_dir() {
+        (from.to_path_buf(), from.join("wal.json"))
+    } else {
+        let dir = from.parent().unwrap_or(Path::new(".")).to_path_buf();
+        (dir, from.to_path_buf())
+    };
+    debug!("Reading recorded wal {:?}", wal_path);
+    let bytes = fs::read(&wal_path)?;
+    verify_signature(&dir, &bytes[..], key, signing_existing())?;
+    let mut wal = read_wal(&bytes)?;
+    wal.load_segments(&dir)?;
+    wal.load_redirections(&dir)?;
+    Ok(wal)
+}
+
+/// Whether the file at `path` holds what `original` should, as told by the
+/// recorded `digest` or, when there is none, by `original` itself.
+fn holds(
+    path: &Path,
+    original: &Path,
+    digest: Option<(&str, HashAlgorithm)>,
+) -> Result<bool, MirageError> {
+    match digest {
+        Some((digest, algorithm)) => Ok(algorithm.hash_file(path)? == digest),
+        None => Ok(original.is_file() && full_match(path, original)?),
+    }
+}
+
+/// Installs the WAL recorded at `from` as the one of the tree at
+/// `target_dir` and links every managed path to its original again. Paths
+/// whose contents don't match the recorded ones are reported and left as
+/// they are. Pending actions of the recorded WAL stay pending.
+pub fn reapply<T: AsRef<Path>, F: AsRef<Path>>(
+    target_dir: T,
+    from: F,
+) -> Result<ReapplyReport, MirageError> {
+    // checked before the tree gets a store of its own
+    let root = fs::canonicalize(&target_dir)?;
+    let mut wal = load(from.as_ref(), signing_key()?.as_deref())?;
+    if wal.redirections.keys().any(|path| !path.starts_with(&root)) {
+        return Err(MirageError::ForeignWal(root));
+    }
+    let mut state = MirageState::get(&root)?;
+    state.ensure_unfrozen()?;
+    let mut report = ReapplyReport::default();
+    if state.dry_run {
+        warn!("Store is read-only, not reapplying {:?}", root);
+        return Ok(report);
+    }
+    state.lock_run()?;
+    let mut modes = wal
+        .actions
+        .iter()
+        .filter_map(|a| LinkMode::of(&a.action))
+        .collect::<Vec<_>>();
+    modes.dedup();
+    for mode in modes {
+        state.probe_links(mode)?;
+    }
+    back_up(&state, "reapply", DEFAULT_BACKUPS)?;
+
+    // the segments and redirections are written again in the store, under
+    // names of its own
+    wal.obsolete = std::mem::take(&mut state.wal.obsolete);
+    wal.obsolete.extend(
+        state
+            .wal
+            .segments
+            .iter()
+            .map(|segment| segment.file.clone()),
+    );
+    wal.segments.clear();
+    wal.next_segment = wal.next_segment.max(state.wal.next_segment);
+    wal.obsolete.extend(
+        state
+            .wal
+            .redirection_files
+            .iter()
+            .map(|file| file.file.clone()),
+    );
+    wal.redirection_files.clear();
+    wal.next_redirection_file = wal
+        .next_redirection_file
+        .max(state.wal.next_redirection_file);
+    state.wal = wal;
+    state.commit()?;
+
+    let applied = &state.wal.actions[..state.wal.checkpoint.min(state.wal.actions.len())];
+    let mut groups: BTreeMap<&Path, Vec<(&Path, LinkMode)>> = BTreeMap::new();
+    for (path, original) in &state.wal.redirections {
+        // the action that linked the path last says how
+        let mode = applied
+            .iter()
+            .rev()
+            .find(|a| a.action.links() && a.source == *path)
+            .and_then(|a| LinkMode::of(&a.action));
+        if let Some(mode) = mode {
+            groups.entry(original).or_default().push((path, mode));
+        }
+    }
+
+    for (original, mut paths) in groups {
+        check_interrupted()?;
+        paths.sort_by_key(|&(path, _)| path);
+        let _lock = state.lock_original(original)?;
+        let digest = applied
+            .iter()
+            .rev()
+            .find(|a| matches!(a.action, ActionType::Copy) && a.target == original)
+            .and_then(|a| a.recorded_digest());
+
+        let whole = match digest {
+            Some(_) => original.is_file() && holds(original, original, digest)?,
+            None => original.is_file(),
+        };
+        if !whole {
+            // only a file that is no link can stand in for its original
+            let copy = match digest {
+                Some(_) => paths.iter().find(|(path, _)| {
+                    fs::symlink_metadata(path).is_ok_and(|meta| meta.is_file())
+                        && holds(path, original, digest).unwrap_or(false)
+                }),
+                None => None,
+            };
+            let Some((copy, _)) = copy else {
+                warn!(
+                    "Original {:?} is gone, nothing to bring it back from",
+                    original
+                );
+                report
+                    .lost
+                    .extend(paths.iter().map(|(path, _)| path.to_path_buf()));
+                continue;
+            };
+            debug!("Recovering original {:?} from {:?}", original, copy);
+            if let Some(parent) = original.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(copy, original)?;
+            report.recovered.push(original.to_path_buf());
+        }
+
+        for (path, mode) in paths {
+            if intact(path, original, mode)? {
+                report.intact += 1;
+                continue;
+            }
+            // links carry no contents, any file does that isn't the original's
+            let replaceable = match fs::symlink_metadata(path) {
+                Err(_) => true,
+                Ok(meta) if meta.file_type().is_symlink() => true,
+                Ok(meta) => meta.is_file() && holds(path, original, digest)?,
+            };
+            if !replaceable {
+                warn!(
+                    "{:?} differs from what {:?} holds, leaving it",
+                    path, original
+                );
+                report.mismatched.push(path.to_path_buf());
+                continue;
+            }
+            debug!("Linking {:?} to {:?} again", path, original);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            relink(path, original, mode)?;
+            report.relinked += 1;
+        }
+    }
+    report.lost.sort();
+    report.mismatched.sort();
+    Ok(report)
+}
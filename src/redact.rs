@@ -0,0 +1,197 @@
+//! Redacting the names in paths, so the logs and reports of runs on trees
+//! with sensitive file names can be handed to support or monitoring
+//! systems. Only the names are touched: how deep a path is, and the
+//! extensions of hashed names, stay readable.
+
+use std::{
+    ffi::OsStr,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::digest::{sha256, to_hex};
+
+/// Characters of a name kept by [`Redaction::Truncate`].
+const KEPT_CHARS: usize = 3;
+
+/// Hex digits of the hash of a name kept by [`Redaction::Hash`].
+const HASH_DIGITS: usize = 12;
+
+/// How the names in paths are redacted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Redaction {
+    /// Names are replaced with a short hash, the same name always giving the
+    /// same one, their extensions kept.
+    Hash,
+    /// Names are cut to their first few characters.
+    Truncate,
+}
+
+impl FromStr for Redaction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hash" => Ok(Redaction::Hash),
+            "truncate" => Ok(Redaction::Truncate),
+            _ => Err(format!("unknown redaction {:?}", s)),
+        }
+    }
+}
+
+impl Redaction {
+    fn name(&self, name: &OsStr) -> String {
+        let name = name.to_string_lossy();
+        match self {
+            Redaction::Hash => {
+                let (stem, extension) = match name.rsplit_once('.') {
+                    Some((stem, extension)) if !stem.is_empty() => (stem, Some(extension)),
+                    _ => (name.as_ref(), None),
+                };
+                let hash = &to_hex(&sha256(stem.as_bytes()))[..HASH_DIGITS];
+                match extension {
+                    Some(extension) => format!("{}.{}", hash, extension),
+                    None => hash.to_string(),
+                }
+            }
+            Redaction::Truncate if name.chars().count() <= KEPT_CHARS => name.into_owned(),
+            Redaction::Truncate => {
+                let kept = name.chars().take(KEPT_CHARS).collect::<String>();
+                format!("{}…", kept)
+            }
+        }
+    }
+
+    /// `path` with every name in it redacted.
+    pub fn path(&self, path: &Path) -> PathBuf {
+        path.components()
+            .map(|component| match component {
+                Component::Normal(name) => PathBuf::from(self.name(name)),
+                other => PathBuf::from(other.as_os_str()),
+            })
+            .collect()
+    }
+
+    /// `text` with the paths in it redacted, as far as they can be told
+    /// apart: logs and errors quote the paths they mention, so every quoted
+    /// string holding a path separator is taken for one.
+    pub fn text(&self, text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(start) = rest.find('"') {
+            out.push_str(&rest[..start]);
+            let quoted = &rest[start..];
+            match unquote(quoted) {
+                Some((value, len)) if value.contains(std::path::is_separator) => {
+                    out.push_str(&format!("{:?}", self.path(Path::new(&value))));
+                    rest = &quoted[len..];
+                }
+                Some((_, len)) => {
+                    out.push_str(&quoted[..len]);
+                    rest = &quoted[len..];
+                }
+                None => {
+                    out.push_str(quoted);
+                    rest = "";
+                }
+            }
+        }
+        out.push_str(rest);
+        out
+    }
+
+    /// Redacts every string of `value` holding a path separator, as reports
+    /// written as JSON hold paths.
+    pub fn json(&self, value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::String(s) if s.contains(std::path::is_separator) => {
+                *s = self
+                    .path(Path::new(s.as_str()))
+                    .to_string_lossy()
+                    .into_owned();
+            }
+            serde_json::Value::Array(values) => values.iter_mut().for_each(|v| self.json(v)),
+            serde_json::Value::Object(map) => map.values_mut().for_each(|v| self.json(v)),
+            _ => {}
+        }
+    }
+}
+
+/// The string quoted at the start of `text` the way `Debug` quotes it, and
+/// the length of the quoted form. `None` if the quote isn't closed.
+fn unquote(text: &str) -> Option<(String, usize)> {
+    let mut value = String::new();
+    let mut chars = text.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((value, i + 1)),
+            '\\' => match chars.next()?.1 {
+                'n' => value.push('\n'),
+                't' => value.push('\t'),
+                'r' => value.push('\r'),
+                '0' => value.push('\0'),
+                'u' => {
+                    // \u{...}
+                    let mut hex = String::new();
+                    for (_, c) in chars.by_ref() {
+                        match c {
+                            '{' => {}
+                            '}' => break,
+                            c => hex.push(c),
+                        }
+                    }
+                    value.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                }
+                c => value.push(c),
+            },
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::Redaction;
+
+    #[test]
+    fn redacts_names() {
+        let path = Path::new("/home/alice/taxes 2024.pdf");
+        let hashed = Redaction::Hash.path(path);
+        assert_eq!(hashed.components().count(), 4);
+        assert_eq!(hashed.extension().unwrap(), "pdf");
+        assert!(!hashed.to_string_lossy().contains("alice"));
+        // the same name hashes the same wherever it is
+        assert_eq!(
+            Redaction::Hash.path(Path::new("/backup/alice")).file_name(),
+            hashed.parent().unwrap().file_name()
+        );
+        assert_eq!(
+            Redaction::Truncate.path(path),
+            PathBuf::from("/hom…/ali…/tax…")
+        );
+        assert_eq!(
+            Redaction::Truncate.path(Path::new("../a/.mirage")),
+            PathBuf::from("../a/.mi…")
+        );
+    }
+
+    #[test]
+    fn redacts_quoted_paths() {
+        let text = r#"Linking "/t/secret \"x\"" to "/t/.mirage/originals/y", run "apply""#;
+        assert_eq!(
+            Redaction::Truncate.text(text),
+            r#"Linking "/t/sec…" to "/t/.mi…/ori…/y", run "apply""#
+        );
+        assert_eq!(Redaction::Truncate.text("open \"/t/ab"), "open \"/t/ab");
+
+        let mut value = serde_json::json!({"path": "/t/secret", "content": "abc", "n": 1});
+        Redaction::Truncate.json(&mut value);
+        assert_eq!(
+            value,
+            serde_json::json!({"path": "/t/sec…", "content": "abc", "n": 1})
+        );
+    }
+}
@@ -0,0 +1,388 @@
+//! Redirections of the WAL, from every managed path to its original. They
+//! are spread over buckets by a hash of the path, each kept in a file of its
+//! own, `redirections.0001.json` and on, listed by `wal.json` along with
+//! their hashes like the segments of actions, so that a commit only writes
+//! the buckets whose paths changed rather than every redirection.
+//!
+//! A bucket file is never written twice, a changed bucket goes to a new file
+//! and the old one is deleted once `wal.json` no longer lists it. The number
+//! of buckets doubles as the tree grows and halves as it shrinks, only then
+//! are all of them written again.
+
+use std::{
+    borrow::Borrow,
+    collections::{BTreeMap, HashMap},
+    fs,
+    hash::Hash,
+    ops::Index,
+    path::{Path, PathBuf},
+};
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    digest::{sha256, to_hex},
+    write_synced, MirageError, WAL,
+};
+
+/// Paths a bucket holds on average before the buckets are doubled.
+const BUCKET_PATHS: usize = 4096;
+
+/// A file of redirections, as listed in `wal.json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct RedirectionFile {
+    /// Name of the file in the store.
+    pub file: String,
+    /// Number of redirections it holds.
+    pub paths: usize,
+    /// SHA-256 of the file, so that the signature of `wal.json` covers it.
+    pub sha256: String,
+}
+
+/// The redirections of a WAL, by bucket.
+#[derive(Debug)]
+pub(crate) struct Redirections {
+    buckets: Vec<HashMap<PathBuf, PathBuf>>,
+    /// Whether the paths of each bucket changed since its file was read or
+    /// written.
+    dirty: Vec<bool>,
+    /// Number of paths redirected to each original.
+    links: HashMap<PathBuf, usize>,
+    len: usize,
+}
+
+impl Default for Redirections {
+    fn default() -> Self {
+        Redirections {
+            buckets: vec![HashMap::new()],
+            dirty: vec![false],
+            links: HashMap::new(),
+            len: 0,
+        }
+    }
+}
+
+/// FNV-1a of `path`, stable across runs and platforms unlike the hasher of
+/// the standard library.
+fn path_hash(path: &Path) -> u64 {
+    path.as_os_str()
+        .as_encoded_bytes()
+        .iter()
+        .fold(0xcbf29ce484222325, |hash, &byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        })
+}
+
+impl Redirections {
+    fn bucket(&self, path: &Path) -> usize {
+        (path_hash(path) % self.buckets.len() as u64) as usize
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get<Q>(&self, path: &Q) -> Option<&PathBuf>
+    where
+        PathBuf: Borrow<Q>,
+        Q: AsRef<Path> + Hash + Eq + ?Sized,
+    {
+        self.buckets[self.bucket(path.as_ref())].get(path)
+    }
+
+    pub fn contains_key<Q>(&self, path: &Q) -> bool
+    where
+        PathBuf: Borrow<Q>,
+        Q: AsRef<Path> + Hash + Eq + ?Sized,
+    {
+        self.get(path).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&PathBuf, &PathBuf)> {
+        self.buckets.iter().flatten()
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &PathBuf> {
+        self.iter().map(|(path, _)| path)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &PathBuf> {
+        self.iter().map(|(_, original)| original)
+    }
+
+    /// Every original some path is redirected to, once.
+    pub fn originals(&self) -> impl Iterator<Item = &PathBuf> {
+        self.links.keys()
+    }
+
+    /// Number of paths redirected to `original`.
+    pub fn links(&self, original: &Path) -> usize {
+        self.links.get(original).copied().unwrap_or(0)
+    }
+
+    fn unlink(&mut self, original: &Path) {
+        if let Some(links) = self.links.get_mut(original) {
+            *links -= 1;
+            if *links == 0 {
+                self.links.remove(original);
+            }
+        }
+    }
+
+    /// Redirects `path` to `original`, returning what it was redirected to.
+    pub fn insert(&mut self, path: PathBuf, original: PathBuf) -> Option<PathBuf> {
+        *self.links.entry(original.clone()).or_default() += 1;
+        let bucket = self.bucket(&path);
+        self.dirty[bucket] = true;
+        let old = self.buckets[bucket].insert(path, original);
+        match &old {
+            Some(old) => self.unlink(old),
+            None => {
+                self.len += 1;
+                if self.len > self.buckets.len() * BUCKET_PATHS {
+                    self.rebucket(self.buckets.len() * 2);
+                }
+            }
+        }
+        old
+    }
+
+    /// Drops the redirection of `path`, returning its original.
+    pub fn remove(&mut self, path: &Path) -> Option<PathBuf> {
+        let bucket = self.bucket(path);
+        let old = self.buckets[bucket].remove(path)?;
+        self.dirty[bucket] = true;
+        self.unlink(&old);
+        self.len -= 1;
+        if self.buckets.len() > 1 && self.len * 4 < self.buckets.len() * BUCKET_PATHS {
+            self.rebucket(self.buckets.len() / 2);
+        }
+        Some(old)
+    }
+
+    /// Redirects the paths of `from` to `to` instead.
+    pub fn retarget(&mut self, from: &Path, to: &Path) {
+        let Some(links) = self.links.remove(from) else {
+            return;
+        };
+        *self.links.entry(to.to_path_buf()).or_default() += links;
+        for (bucket, paths) in self.buckets.iter_mut().enumerate() {
+            for original in paths.values_mut().filter(|original| *original == from) {
+                *original = to.to_path_buf();
+                self.dirty[bucket] = true;
+            }
+        }
+    }
+
+    /// Spreads the paths over `buckets` buckets, all of them to be written.
+    fn rebucket(&mut self, buckets: usize) {
+        debug!(
+            "Spreading {} redirections over {} buckets",
+            self.len, buckets
+        );
+        let paths = std::mem::replace(&mut self.buckets, vec![HashMap::new(); buckets]);
+        self.dirty = vec![true; buckets];
+        for (path, original) in paths.into_iter().flatten() {
+            let bucket = self.bucket(&path);
+            self.buckets[bucket].insert(path, original);
+        }
+    }
+
+    /// Replaces every redirection with the ones of `paths`.
+    pub fn replace(&mut self, paths: impl IntoIterator<Item = (PathBuf, PathBuf)>) {
+        *self = paths.into_iter().collect();
+        self.touch_all();
+    }
+
+    /// Marks every bucket as changed, for a WAL moving to another store.
+    pub fn touch_all(&mut self) {
+        self.dirty.iter_mut().for_each(|dirty| *dirty = true);
+    }
+}
+
+impl<'a> IntoIterator for &'a Redirections {
+    type Item = (&'a PathBuf, &'a PathBuf);
+    type IntoIter = std::iter::Flatten<std::slice::Iter<'a, HashMap<PathBuf, PathBuf>>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.buckets.iter().flatten()
+    }
+}
+
+impl FromIterator<(PathBuf, PathBuf)> for Redirections {
+    fn from_iter<I: IntoIterator<Item = (PathBuf, PathBuf)>>(iter: I) -> Self {
+        let mut redirections = Redirections::default();
+        for (path, original) in iter {
+            redirections.insert(path, original);
+        }
+        redirections
+    }
+}
+
+impl<P: AsRef<Path>> Index<P> for Redirections {
+    type Output = PathBuf;
+
+    fn index(&self, path: P) -> &PathBuf {
+        self.get(path.as_ref()).expect("path is redirected")
+    }
+}
+
+impl WAL {
+    /// Reads the redirections of every bucket file, checking each against
+    /// its hash, along with the ones of a `wal.json` from before they got
+    /// files of their own, written to bucket files with the next commit.
+    pub(crate) fn load_redirections(&mut self, mirage_path: &Path) -> Result<(), MirageError> {
+        let mut redirections = Redirections::default();
+        if !self.redirection_files.is_empty() {
+            redirections.rebucket(self.redirection_files.len());
+        }
+        for (bucket, file) in self.redirection_files.iter().enumerate() {
+            debug!("Reading redirections {:?}", file.file);
+            let bytes = fs::read(mirage_path.join(&file.file))?;
+            if to_hex(&sha256(&bytes)) != file.sha256 {
+                return Err(MirageError::WALTampered);
+            }
+            let paths: HashMap<PathBuf, PathBuf> = serde_json::from_slice(&bytes)?;
+            if paths.len() != file.paths || paths.keys().any(|p| redirections.bucket(p) != bucket) {
+                return Err(MirageError::WALTampered);
+            }
+            for original in paths.values() {
+                *redirections.links.entry(original.clone()).or_default() += 1;
+            }
+            redirections.len += paths.len();
+            redirections.buckets[bucket] = paths;
+            redirections.dirty[bucket] = false;
+        }
+        for (path, original) in self.inline_redirections.drain() {
+            redirections.insert(path, original);
+        }
+        self.redirections = redirections;
+        Ok(())
+    }
+
+    /// Writes the buckets whose paths changed to new files, the files they
+    /// replace deleted once `wal.json` no longer lists them.
+    pub(crate) fn write_redirections(&mut self, mirage_path: &Path) -> Result<(), MirageError> {
+        let buckets = self.redirections.buckets.len();
+        if self.redirections.is_empty() && self.redirection_files.len() <= 1 {
+            // nothing to keep, a store without redirections has no files
+            self.obsolete
+                .extend(self.redirection_files.drain(..).map(|file| file.file));
+            return Ok(());
+        }
+        if self.redirection_files.len() != buckets {
+            self.redirections.touch_all();
+            let files = std::mem::take(&mut self.redirection_files);
+            self.obsolete
+                .extend(files.into_iter().map(|file| file.file));
+        }
+        for bucket in 0..buckets {
+            if self.redirection_files.len() > bucket && !self.redirections.dirty[bucket] {
+                continue;
+            }
+            let paths = self.redirections.buckets[bucket]
+                .iter()
+                .collect::<BTreeMap<_, _>>();
+            let bytes = serde_json::to_vec_pretty(&paths)?;
+            self.next_redirection_file += 1;
+            let file = RedirectionFile {
+                file: format!("redirections.{:04}.json", self.next_redirection_file),
+                paths: paths.len(),
+                sha256: to_hex(&sha256(&bytes)),
+            };
+            debug!("Writing redirections {:?}", file.file);
+            write_synced(&mirage_path.join(&file.file), &bytes)?;
+            if bucket < self.redirection_files.len() {
+                let old = std::mem::replace(&mut self.redirection_files[bucket], file);
+                self.obsolete.push(old.file);
+            } else {
+                self.redirection_files.push(file);
+            }
+            self.redirections.dirty[bucket] = false;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use tempfile::tempdir;
+
+    use super::{Redirections, BUCKET_PATHS};
+    use crate::WAL;
+
+    #[test]
+    fn writes_changed_buckets_only() {
+        let dir = tempdir().unwrap();
+        let path = |i: usize| PathBuf::from(format!("/tree/{}", i));
+        let original = |i: usize| PathBuf::from(format!("/store/{}", i % 7));
+        let mut wal = WAL {
+            redirections: (0..3 * BUCKET_PATHS)
+                .map(|i| (path(i), original(i)))
+                .collect(),
+            ..Default::default()
+        };
+        assert_eq!(wal.redirections.buckets.len(), 4);
+        assert_eq!(
+            wal.redirections.links(&original(0)),
+            3 * BUCKET_PATHS / 7 + 1
+        );
+
+        wal.write_redirections(dir.path()).unwrap();
+        assert_eq!(wal.redirection_files.len(), 4);
+        let files = |wal: &WAL| {
+            wal.redirection_files
+                .iter()
+                .map(|file| file.file.clone())
+                .collect::<Vec<_>>()
+        };
+        let before = files(&wal);
+
+        // one changed path rewrites its bucket alone
+        wal.redirections.remove(&path(5));
+        wal.write_redirections(dir.path()).unwrap();
+        let after = files(&wal);
+        assert_eq!(before.iter().zip(&after).filter(|(a, b)| a != b).count(), 1);
+        assert_eq!(wal.obsolete.len(), 1);
+
+        // and reads back whole
+        let mut read = WAL {
+            redirection_files: wal.redirection_files.clone(),
+            ..Default::default()
+        };
+        read.load_redirections(dir.path()).unwrap();
+        assert_eq!(read.redirections.len(), 3 * BUCKET_PATHS - 1);
+        assert_eq!(read.redirections[path(6)], original(6));
+        assert!(!read.redirections.contains_key(&path(5)));
+        assert_eq!(
+            read.redirections.links(&original(5)),
+            wal.redirections.links(&original(5))
+        );
+
+        // a bucket file that was changed is refused
+        let file = dir.path().join(&wal.redirection_files[0].file);
+        fs::write(&file, "{}").unwrap();
+        assert!(read.load_redirections(dir.path()).is_err());
+
+        // the buckets shrink along with the tree
+        let mut shrunk = Redirections::default();
+        for i in 0..3 * BUCKET_PATHS {
+            shrunk.insert(path(i), original(i));
+        }
+        for i in BUCKET_PATHS / 2..3 * BUCKET_PATHS {
+            shrunk.remove(&path(i));
+        }
+        assert_eq!(shrunk.buckets.len(), 2);
+        assert_eq!(shrunk.len(), BUCKET_PATHS / 2);
+        assert!(shrunk.iter().all(|(path, _)| shrunk.get(path).is_some()));
+        shrunk.retarget(&original(1), &original(2));
+        assert_eq!(shrunk.links(&original(1)), 0);
+    }
+}
@@ -0,0 +1,52 @@
+//! Copy-on-write clones of files, on filesystems that support them.
+
+use std::{io, path::Path};
+
+/// Makes `dst` a copy-on-write clone of `src`, sharing its extents until
+/// either is written to. Fails with [`io::ErrorKind::Unsupported`] where the
+/// filesystem or platform can't clone, `dst` is not left behind then.
+#[cfg(target_os = "linux")]
+pub(crate) fn reflink(src: &Path, dst: &Path) -> io::Result<()> {
+    use std::{fs::File, os::fd::AsRawFd};
+
+    let src_file = File::open(src)?;
+    let dst_file = File::options().write(true).create_new(true).open(dst)?;
+    let ret = unsafe { libc::ioctl(dst_file.as_raw_fd(), libc::FICLONE, src_file.as_raw_fd()) };
+    if ret < 0 {
+        let err = io::Error::last_os_error();
+        drop(dst_file);
+        std::fs::remove_file(dst)?;
+        return Err(match err.raw_os_error() {
+            Some(libc::EOPNOTSUPP) | Some(libc::EXDEV) | Some(libc::EINVAL) => {
+                io::Error::new(io::ErrorKind::Unsupported, err)
+            }
+            _ => err,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+pub(crate) fn reflink(src: &Path, dst: &Path) -> io::Result<()> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let src = CString::new(src.as_os_str().as_bytes())?;
+    let dst = CString::new(dst.as_os_str().as_bytes())?;
+    // clonefile never leaves a partial dst behind
+    let ret = unsafe { libc::clonefile(src.as_ptr(), dst.as_ptr(), 0) };
+    if ret < 0 {
+        let err = io::Error::last_os_error();
+        return Err(match err.raw_os_error() {
+            Some(libc::ENOTSUP) | Some(libc::EXDEV) => {
+                io::Error::new(io::ErrorKind::Unsupported, err)
+            }
+            _ => err,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub(crate) fn reflink(_src: &Path, _dst: &Path) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
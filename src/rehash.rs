@@ -0,0 +1,88 @@
+//! Moving the digests recorded for originals over to another algorithm, so
+//! a store started with a weaker one can be brought up to date without
+//! reverting. Every digest is checked under the algorithm it was taken with
+//! before being replaced, so an original that changed since keeps the digest
+//! that tells.
+
+use std::path::{Path, PathBuf};
+
+use log::{debug, warn};
+use serde::Serialize;
+
+use crate::{
+    back_up, check_interrupted, ActionType, HashAlgorithm, MirageError, MirageState,
+    DEFAULT_BACKUPS,
+};
+
+/// What [`rehash`] did to the recorded digests.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RehashReport {
+    /// Number of digests taken again with the new algorithm.
+    pub rehashed: usize,
+    /// Number of digests already taken with it.
+    pub already: usize,
+    /// Originals no longer holding what their digest says, left with the
+    /// digest they had.
+    pub mismatched: Vec<PathBuf>,
+    /// Originals gone from the store.
+    pub missing: Vec<PathBuf>,
+}
+
+/// Takes the digests recorded for the originals of the tree at `target_dir`
+/// again with `to`, recording the algorithm along with them. Digests that
+/// don't match their original are left as they are and reported.
+pub fn rehash<T: AsRef<Path>>(
+    target_dir: T,
+    to: HashAlgorithm,
+) -> Result<RehashReport, MirageError> {
+    let target_dir = target_dir.as_ref();
+    let mut state = MirageState::open(target_dir)?;
+    state.ensure_unfrozen()?;
+    let mut report = RehashReport::default();
+    if state.dry_run {
+        warn!("Store is read-only, not rehashing {:?}", target_dir);
+        return Ok(report);
+    }
+    state.lock_run()?;
+    if state.wal.checkpoint < state.wal.actions.len() {
+        return Err(MirageError::PendingActions(target_dir.to_path_buf()));
+    }
+    back_up(&state, "rehash", DEFAULT_BACKUPS)?;
+
+    for index in 0..state.wal.actions.len() {
+        check_interrupted()?;
+        let action = &state.wal.actions[index];
+        if !matches!(action.action, ActionType::Copy) {
+            continue;
+        }
+        let Some((digest, from)) = action.recorded_digest() else {
+            continue;
+        };
+        if from == to {
+            report.already += 1;
+            continue;
+        }
+        let original = action.target.clone();
+        if !original.is_file() {
+            report.missing.push(original);
+            continue;
+        }
+        let _lock = state.lock_original(&original)?;
+        if from.hash_file(&original)? != digest {
+            warn!("{:?} no longer matches its digest, leaving it", original);
+            report.mismatched.push(original);
+            continue;
+        }
+        debug!("Rehashing {:?} with {}", original, to.name());
+        let digest = to.hash_file(&original)?;
+        let action = &mut state.wal.actions[index];
+        action.digest = Some(digest);
+        action.algorithm = Some(to);
+        state.wal.touch(index);
+        report.rehashed += 1;
+    }
+    state.commit()?;
+    report.mismatched.sort();
+    report.missing.sort();
+    Ok(report)
+}
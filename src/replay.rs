@@ -0,0 +1,135 @@
+use std::{collections::HashMap, fmt, fs, path::Path};
+
+use serde::Serialize;
+
+use crate::{
+    check_if_files_are_same, execute_pending, walstream::WalStream, ActionType, MirageError,
+    MirageState, PlannedAction,
+};
+
+/// Something about the tree that no longer matches what a pending action
+/// expects.
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+pub enum Hazard {
+    /// The file the action reads from is gone.
+    SourceMissing,
+    /// The original a link would point at neither exists nor is about to be
+    /// copied into the store.
+    OriginalMissing,
+    /// The file a link would replace no longer has the original's contents,
+    /// replaying would lose the changes.
+    ContentChanged,
+    /// The copy already exists, most likely half written when interrupted,
+    /// and will be overwritten.
+    TargetExists,
+    /// Written by a newer mirage, replaying stops here.
+    UnknownAction,
+}
+
+impl fmt::Display for Hazard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Hazard::SourceMissing => "source is missing",
+            Hazard::OriginalMissing => "original is missing",
+            Hazard::ContentChanged => "file changed since it was planned",
+            Hazard::TargetExists => "target exists and will be overwritten",
+            Hazard::UnknownAction => "written by a newer mirage, can't be replayed",
+        })
+    }
+}
+
+/// A pending action and what stands in its way, if anything.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct ReplayStep {
+    /// Position of the action in the WAL.
+    pub index: usize,
+    pub action: PlannedAction,
+    pub hazard: Option<Hazard>,
+}
+
+/// Lists the actions past the checkpoint of the tree at `target_dir`, in the
+/// order a replay would execute them, checking each against the tree as it
+/// is now. Nothing is modified.
+pub fn replay_plan<T: AsRef<Path>>(target_dir: T) -> Result<Vec<ReplayStep>, MirageError> {
+    let wal = WalStream::open(&target_dir)?;
+    // originals that earlier pending copies will have created by then, and
+    // the files they will be copied from
+    let mut created = HashMap::new();
+    let mut steps = Vec::new();
+    wal.for_each_action(|index, action| {
+        if index < wal.checkpoint() {
+            return Ok(());
+        }
+        let hazard = match action.action {
+            ActionType::Copy => {
+                created.insert(action.target.clone(), action.source.clone());
+                if !action.source.is_file() {
+                    Some(Hazard::SourceMissing)
+                } else if fs::symlink_metadata(&action.target).is_ok() {
+                    Some(Hazard::TargetExists)
+                } else {
+                    None
+                }
+            }
+            ActionType::Symlink
+            | ActionType::Hardlink
+            | ActionType::Reflink
+            | ActionType::Delete => {
+                let contents = match created.get(&action.target) {
+                    Some(source) => source,
+                    None => &action.target,
+                };
+                let linked = fs::read_link(&action.source).ok();
+                if !contents.is_file() {
+                    Some(Hazard::OriginalMissing)
+                } else if linked.as_ref() == Some(&action.target) {
+                    None
+                } else if fs::symlink_metadata(&action.source).is_err() {
+                    Some(Hazard::SourceMissing)
+                } else if linked.is_none() && !check_if_files_are_same(&action.source, contents)? {
+                    Some(Hazard::ContentChanged)
+                } else {
+                    None
+                }
+            }
+            ActionType::Move => {
+                if fs::symlink_metadata(&action.source).is_err() {
+                    Some(Hazard::SourceMissing)
+                } else if fs::symlink_metadata(&action.target).is_ok() {
+                    Some(Hazard::TargetExists)
+                } else {
+                    None
+                }
+            }
+            ActionType::Dedupe => {
+                if !action.target.is_file() {
+                    Some(Hazard::OriginalMissing)
+                } else if !action.source.is_file() {
+                    Some(Hazard::SourceMissing)
+                } else if !check_if_files_are_same(&action.source, &action.target)? {
+                    Some(Hazard::ContentChanged)
+                } else {
+                    None
+                }
+            }
+            ActionType::NOP => None,
+            ActionType::Unknown(_) => Some(Hazard::UnknownAction),
+        };
+        steps.push(ReplayStep {
+            index,
+            action: PlannedAction::from(&action),
+            hazard,
+        });
+        Ok(())
+    })?;
+    Ok(steps)
+}
+
+/// Executes the actions left pending by an interrupted run on the tree at
+/// `target_dir`.
+pub fn replay<T: AsRef<Path>>(target_dir: T) -> Result<(), MirageError> {
+    let mut state = MirageState::open(&target_dir)?;
+    state.ensure_unfrozen()?;
+    state.lock_run()?;
+    execute_pending(&mut state)
+}
@@ -0,0 +1,225 @@
+//! Run reports saved after apply runs, and comparing two of them to tell
+//! whether a tree keeps growing new duplicates between runs.
+//!
+//! A report lists the duplicate groups a run deduplicated, or would have on
+//! a dry run, by the content id their files share, so the same contents are
+//! recognised across runs wherever they sit in the store.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::{self, File},
+    io::{BufReader, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{cache::Cache, Action, ActionType, ApplyReport, MirageError, Rollback};
+
+/// Duplicate files a run linked to one original.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DuplicateGroup {
+    /// Content id the files share.
+    pub content: String,
+    /// Size of one of the files.
+    pub size: u64,
+    /// The files linked, in path order.
+    pub files: Vec<PathBuf>,
+    /// Bytes linking them saves.
+    pub saved: u64,
+}
+
+/// What an apply run found, as saved with `apply --save-report`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RunReport {
+    /// Seconds since the Unix epoch at which the run finished.
+    pub at: u64,
+    pub root: PathBuf,
+    pub dry_run: bool,
+    pub store_size: u64,
+    /// Duplicate groups of the run, by content id.
+    pub groups: Vec<DuplicateGroup>,
+}
+
+impl RunReport {
+    pub fn new(root: &Path, report: &ApplyReport) -> Self {
+        let mut groups = report.groups.clone();
+        groups.sort_by(|a, b| a.content.cmp(&b.content));
+        RunReport {
+            at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            root: root.to_path_buf(),
+            dry_run: report.dry_run,
+            store_size: report.store_size,
+            groups,
+        }
+    }
+
+    /// Bytes the groups of the run save.
+    pub fn saved(&self) -> u64 {
+        self.groups.iter().map(|group| group.saved).sum()
+    }
+
+    pub fn read(path: &Path) -> Result<Self, MirageError> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    }
+
+    pub fn write<W: Write>(&self, out: W) -> Result<(), MirageError> {
+        serde_json::to_writer_pretty(out, self)?;
+        Ok(())
+    }
+}
+
+/// How a later run report differs from an earlier one.
+#[derive(Debug, Clone, Default, Serialize, PartialEq, Eq)]
+pub struct ReportDiff {
+    /// Groups of the later run whose contents the earlier one didn't find.
+    pub new_groups: Vec<DuplicateGroup>,
+    /// Groups of the earlier run whose contents the later one didn't find.
+    pub resolved_groups: Vec<DuplicateGroup>,
+    /// Groups found by both runs whose files differ, as they are in the
+    /// later one.
+    pub changed_groups: Vec<DuplicateGroup>,
+    /// Bytes saved by the later run less the ones saved by the earlier.
+    pub saved_change: i64,
+    /// Size of the store after the later run less the one after the earlier.
+    pub store_size_change: i64,
+}
+
+/// Compares the run report `before` with the later `after`.
+pub fn diff_reports(before: &RunReport, after: &RunReport) -> ReportDiff {
+    let earlier = before
+        .groups
+        .iter()
+        .map(|group| (group.content.as_str(), group))
+        .collect::<BTreeMap<_, _>>();
+    let later = after
+        .groups
+        .iter()
+        .map(|group| (group.content.as_str(), group))
+        .collect::<BTreeMap<_, _>>();
+    let mut diff = ReportDiff {
+        saved_change: after.saved() as i64 - before.saved() as i64,
+        store_size_change: after.store_size as i64 - before.store_size as i64,
+        ..Default::default()
+    };
+    for (content, &group) in &later {
+        match earlier.get(content) {
+            None => diff.new_groups.push(group.clone()),
+            Some(&old) if old.files != group.files => diff.changed_groups.push(group.clone()),
+            Some(_) => {}
+        }
+    }
+    diff.resolved_groups = earlier
+        .iter()
+        .filter(|(content, _)| !later.contains_key(*content))
+        .map(|(_, &group)| group.clone())
+        .collect();
+    diff
+}
+
+/// The duplicate groups `actions` deduplicate. Taken before the actions are
+/// executed, while the files they link still hold their contents.
+pub(crate) fn duplicate_groups(
+    actions: &[Action],
+    cache: &mut Cache,
+) -> Result<Vec<DuplicateGroup>, MirageError> {
+    let mut groups: BTreeMap<&Path, (Option<&str>, Vec<&Path>, u64)> = BTreeMap::new();
+    for action in actions {
+        let (content, files, copies) = groups.entry(&action.target).or_default();
+        match action.action {
+            ActionType::Copy => {
+                *content = action.digest.as_deref();
+                *copies += 1;
+            }
+            ActionType::Symlink
+            | ActionType::Hardlink
+            | ActionType::Reflink
+            | ActionType::Delete
+            | ActionType::Dedupe => files.push(&action.source),
+            ActionType::NOP | ActionType::Move | ActionType::Unknown(_) => {}
+        }
+    }
+
+    let mut found = Vec::new();
+    for (original, (content, mut files, copies)) in groups {
+        let Some(&first) = files.first() else {
+            continue;
+        };
+        // an original of an earlier run is already in the store
+        let content = match content {
+            Some(content) => content.to_string(),
+            None if original.is_file() => cache.hash(original)?,
+            None => cache.hash(first)?,
+        };
+        let size = fs::metadata(first)?.len();
+        files.sort();
+        files.dedup();
+        found.push(DuplicateGroup {
+            content,
+            size,
+            saved: size * (files.len() as u64).saturating_sub(copies),
+            files: files.into_iter().map(Path::to_path_buf).collect(),
+        });
+    }
+    Ok(found)
+}
+
+/// Drops the groups `rolled_back` from `groups`.
+pub(crate) fn drop_rolled_back(groups: &mut Vec<DuplicateGroup>, rolled_back: &[Rollback]) {
+    let undone = rolled_back
+        .iter()
+        .flat_map(|rollback| &rollback.actions)
+        .map(|action| action.source.as_path())
+        .collect::<HashSet<_>>();
+    groups.retain(|group| {
+        !group
+            .files
+            .iter()
+            .any(|file| undone.contains(file.as_path()))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{diff_reports, DuplicateGroup, RunReport};
+
+    fn group(content: &str, files: &[&str]) -> DuplicateGroup {
+        DuplicateGroup {
+            content: content.to_string(),
+            size: 10,
+            files: files.iter().map(PathBuf::from).collect(),
+            saved: 10 * (files.len() as u64 - 1),
+        }
+    }
+
+    #[test]
+    fn diffs_reports_by_content() {
+        let before = RunReport {
+            store_size: 100,
+            groups: vec![group("a", &["/t/1", "/t/2"]), group("b", &["/t/3", "/t/4"])],
+            ..Default::default()
+        };
+        let after = RunReport {
+            store_size: 120,
+            groups: vec![
+                group("b", &["/t/3", "/t/4", "/t/5"]),
+                group("c", &["/t/6", "/t/7"]),
+            ],
+            ..Default::default()
+        };
+        let diff = diff_reports(&before, &after);
+        assert_eq!(diff.new_groups, vec![group("c", &["/t/6", "/t/7"])]);
+        assert_eq!(diff.resolved_groups, vec![group("a", &["/t/1", "/t/2"])]);
+        assert_eq!(
+            diff.changed_groups,
+            vec![group("b", &["/t/3", "/t/4", "/t/5"])]
+        );
+        assert_eq!((diff.saved_change, diff.store_size_change), (10, 20));
+    }
+}
@@ -0,0 +1,269 @@
+//! The lock a mutating run holds on its store while it runs.
+//!
+//! The lock file records who holds it, so that a lock left behind by a run
+//! that crashed can be told apart from one held by a run still going, and
+//! broken with an entry in the store's audit log. A lock that can't be read
+//! was left by a run that crashed before writing it, once it is older than
+//! a run takes to write it.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{store_path, MirageError, MirageState};
+
+/// File in the store held by the running mutating command.
+const RUN_LOCK_FILE: &str = "run.lock";
+
+/// Append-only log of interventions on the store, one JSON object per line.
+const AUDIT_FILE: &str = "audit.log";
+
+/// How long a run may take to write its lock after creating it.
+const UNREADABLE_GRACE: Duration = Duration::from_secs(10);
+
+/// Who holds a run lock.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LockOwner {
+    pub pid: u32,
+    pub host: String,
+    /// Seconds since the Unix epoch at which the run started.
+    pub started: u64,
+}
+
+impl LockOwner {
+    fn current() -> LockOwner {
+        LockOwner {
+            pid: std::process::id(),
+            host: hostname(),
+            started: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Whether the owner is known to have exited, its pid gone or taken by
+    /// a process started after it. Processes of other hosts can't be checked
+    /// and are assumed to be running.
+    fn is_dead(&self) -> bool {
+        if self.host != hostname() {
+            return false;
+        }
+        // the start time is only known to the second
+        !process_alive(self.pid)
+            || process_started(self.pid).is_some_and(|started| started > self.started + 1)
+    }
+}
+
+/// A lock left behind by a run that died, see [`break_stale_lock`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BrokenLock {
+    Dead(LockOwner),
+    /// A lock its run crashed before writing.
+    Unreadable,
+}
+
+impl std::fmt::Display for BrokenLock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BrokenLock::Dead(owner) => owner.fmt(f),
+            BrokenLock::Unreadable => write!(f, "a run that died before writing it"),
+        }
+    }
+}
+
+impl std::fmt::Display for LockOwner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "pid {} on {} started at {}",
+            self.pid, self.host, self.started
+        )
+    }
+}
+
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) };
+    if ret != 0 {
+        return String::new();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_default()
+}
+
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // signal 0 only checks that the process exists
+    let ret = unsafe { libc::kill(pid, 0) };
+    ret == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn process_alive(_pid: u32) -> bool {
+    true
+}
+
+/// Seconds since the Unix epoch at which process `pid` started.
+#[cfg(target_os = "linux")]
+fn process_started(pid: u32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // the command in parentheses may hold spaces, starttime is the 20th
+    // field after it, in clock ticks since boot
+    let after = stat.get(stat.rfind(')')? + 2..)?;
+    let ticks: u64 = after.split(' ').nth(19)?.parse().ok()?;
+    let boot: u64 = fs::read_to_string("/proc/stat")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))?
+        .trim()
+        .parse()
+        .ok()?;
+    let per_second = u64::try_from(unsafe { libc::sysconf(libc::_SC_CLK_TCK) }).ok()?;
+    Some(boot + ticks / per_second.max(1))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn process_started(_pid: u32) -> Option<u64> {
+    None
+}
+
+/// Held by a state for as long as it may modify its store, removed on drop.
+#[derive(Debug)]
+pub(crate) struct RunLock {
+    path: PathBuf,
+}
+
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        match fs::remove_file(&self.path) {
+            // gone with the whole store, e.g. when merged into another
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => warn!("Can't release run lock {:?}: {:?}", self.path, err),
+            Ok(()) => {}
+        }
+    }
+}
+
+fn read_owner(path: &Path) -> Result<LockOwner, MirageError> {
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+/// Who holds an existing run lock.
+enum Holder {
+    Running(LockOwner),
+    /// A run that just started and is writing the lock.
+    Starting,
+    Dead(BrokenLock),
+}
+
+impl Holder {
+    fn of(path: &Path) -> Result<Holder, MirageError> {
+        Ok(match read_owner(path) {
+            Ok(owner) if owner.is_dead() => Holder::Dead(BrokenLock::Dead(owner)),
+            Ok(owner) => Holder::Running(owner),
+            Err(_) => {
+                let age = fs::metadata(path)?
+                    .modified()?
+                    .elapsed()
+                    .unwrap_or_default();
+                if age < UNREADABLE_GRACE {
+                    Holder::Starting
+                } else {
+                    Holder::Dead(BrokenLock::Unreadable)
+                }
+            }
+        })
+    }
+
+    /// The error of a run finding the lock held.
+    fn error(self, root: PathBuf) -> MirageError {
+        let owner = match self {
+            Holder::Running(owner) => owner.to_string(),
+            Holder::Starting => "another run".to_string(),
+            Holder::Dead(broken) => return MirageError::StaleLock(root, broken.to_string()),
+        };
+        MirageError::Running(root, owner)
+    }
+}
+
+impl MirageState {
+    /// Takes the run lock of the store, held until the state is dropped.
+    /// Fails if another run holds it, or held it and died.
+    pub(crate) fn lock_run(&mut self) -> Result<(), MirageError> {
+        if self.dry_run || self.run_lock.is_some() {
+            return Ok(());
+        }
+        let path = self.source_path.join(RUN_LOCK_FILE);
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                file.write_all(&serde_json::to_vec(&LockOwner::current())?)?;
+                debug!("Took run lock {:?}", path);
+                self.run_lock = Some(RunLock { path });
+                Ok(())
+            }
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                Err(Holder::of(&path)?.error(self.root.clone()))
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AuditEntry<'a> {
+    at: u64,
+    event: &'a str,
+    /// `None` for a lock that couldn't be read.
+    owner: Option<&'a LockOwner>,
+    by: LockOwner,
+}
+
+/// Removes the run lock of the tree at `target_dir` if the run holding it is
+/// dead, recording it in the store's audit log. Returns the lock broken,
+/// `None` if there was no lock. Fails if the owner is running.
+pub fn break_stale_lock<T: AsRef<Path>>(target_dir: T) -> Result<Option<BrokenLock>, MirageError> {
+    let mirage_path = store_path(target_dir.as_ref());
+    let path = mirage_path.join(RUN_LOCK_FILE);
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let broken = match Holder::of(&path)? {
+        Holder::Dead(broken) => broken,
+        holder => return Err(holder.error(target_dir.as_ref().to_path_buf())),
+    };
+    warn!("Breaking stale run lock of {}", broken);
+    let by = LockOwner::current();
+    let (event, owner) = match &broken {
+        BrokenLock::Dead(owner) => ("broke stale run lock", Some(owner)),
+        BrokenLock::Unreadable => ("broke unreadable run lock", None),
+    };
+    let entry = AuditEntry {
+        at: by.started,
+        event,
+        owner,
+        by,
+    };
+    let mut audit = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(mirage_path.join(AUDIT_FILE))?;
+    writeln!(audit, "{}", serde_json::to_string(&entry)?)?;
+    fs::remove_file(&path)?;
+    Ok(Some(broken))
+}
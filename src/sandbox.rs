@@ -0,0 +1,172 @@
+//! Landlock sandbox restricting where mirage may write.
+//!
+//! Reading stays allowed everywhere, but creating, writing, renaming and
+//! removing anything is only allowed beneath the given directories, which
+//! should be the managed trees along with their `.mirage` stores. Like any
+//! Landlock ruleset it applies to the calling thread and whatever it spawns
+//! afterwards, so it should be entered before any other thread is started.
+
+use std::path::Path;
+
+use crate::MirageError;
+
+#[cfg(target_os = "linux")]
+mod landlock {
+    use std::{
+        fs::File,
+        io,
+        os::fd::{AsRawFd, FromRawFd, OwnedFd},
+        path::Path,
+    };
+
+    const CREATE_RULESET_VERSION: u32 = 1;
+    const RULE_PATH_BENEATH: u32 = 1;
+
+    const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+    const ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+    const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+    const ACCESS_FS_MAKE_CHAR: u64 = 1 << 6;
+    const ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+    const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+    const ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+    const ACCESS_FS_MAKE_FIFO: u64 = 1 << 10;
+    const ACCESS_FS_MAKE_BLOCK: u64 = 1 << 11;
+    const ACCESS_FS_MAKE_SYM: u64 = 1 << 12;
+    /// Linking or renaming across directories, from ABI 2.
+    const ACCESS_FS_REFER: u64 = 1 << 13;
+    /// From ABI 3.
+    const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    fn check(ret: libc::c_long) -> io::Result<libc::c_long> {
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ret)
+        }
+    }
+
+    /// Write accesses known to the running kernel.
+    fn write_access() -> io::Result<u64> {
+        let abi = check(unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<RulesetAttr>(),
+                0usize,
+                CREATE_RULESET_VERSION,
+            )
+        })?;
+        let mut access = ACCESS_FS_WRITE_FILE
+            | ACCESS_FS_REMOVE_DIR
+            | ACCESS_FS_REMOVE_FILE
+            | ACCESS_FS_MAKE_CHAR
+            | ACCESS_FS_MAKE_DIR
+            | ACCESS_FS_MAKE_REG
+            | ACCESS_FS_MAKE_SOCK
+            | ACCESS_FS_MAKE_FIFO
+            | ACCESS_FS_MAKE_BLOCK
+            | ACCESS_FS_MAKE_SYM;
+        if abi >= 2 {
+            access |= ACCESS_FS_REFER;
+        }
+        if abi >= 3 {
+            access |= ACCESS_FS_TRUNCATE;
+        }
+        Ok(access)
+    }
+
+    pub fn restrict_writes(writable: &[&Path]) -> io::Result<()> {
+        let access = write_access()?;
+        let attr = RulesetAttr {
+            handled_access_fs: access,
+        };
+        let ruleset = check(unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const RulesetAttr,
+                std::mem::size_of::<RulesetAttr>(),
+                0u32,
+            )
+        })?;
+        let ruleset = unsafe { OwnedFd::from_raw_fd(ruleset as i32) };
+
+        for path in writable {
+            let dir = File::open(path)?;
+            let rule = PathBeneathAttr {
+                allowed_access: access,
+                parent_fd: dir.as_raw_fd(),
+            };
+            check(unsafe {
+                libc::syscall(
+                    libc::SYS_landlock_add_rule,
+                    ruleset.as_raw_fd(),
+                    RULE_PATH_BENEATH,
+                    &rule as *const PathBeneathAttr,
+                    0u32,
+                )
+            })?;
+        }
+
+        check(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } as libc::c_long)?;
+        check(unsafe {
+            libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0u32)
+        })?;
+        Ok(())
+    }
+}
+
+/// Restricts the calling thread, and any thread it spawns afterwards, to
+/// writing beneath `writable`. Fails with [`MirageError::SandboxUnavailable`]
+/// when the kernel can't enforce it, rather than carrying on unprotected.
+pub fn sandbox<T: AsRef<Path>>(writable: &[T]) -> Result<(), MirageError> {
+    let writable = writable.iter().map(AsRef::as_ref).collect::<Vec<_>>();
+    #[cfg(target_os = "linux")]
+    let restricted = landlock::restrict_writes(&writable);
+    #[cfg(not(target_os = "linux"))]
+    let restricted = Err(std::io::Error::from(std::io::ErrorKind::Unsupported));
+    restricted.map_err(MirageError::SandboxUnavailable)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io, thread};
+
+    use tempfile::tempdir;
+
+    use super::sandbox;
+    use crate::MirageError;
+
+    #[test]
+    fn confines_writes() {
+        let allowed = tempdir().unwrap();
+        let denied = tempdir().unwrap();
+        let (inside, outside) = (allowed.path().join("a"), denied.path().join("b"));
+        let allowed_path = allowed.path().to_path_buf();
+
+        // landlock confines only the current thread, keep the others free
+        let writes = thread::spawn(move || {
+            match sandbox(&[allowed_path]) {
+                Err(MirageError::SandboxUnavailable(_)) => return None,
+                result => result.unwrap(),
+            }
+            Some((fs::write(inside, "in"), fs::write(outside, "out")))
+        })
+        .join()
+        .unwrap();
+
+        if let Some((inside, outside)) = writes {
+            inside.unwrap();
+            assert_eq!(outside.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        }
+    }
+}
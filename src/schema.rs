@@ -0,0 +1,136 @@
+//! Versions of the format of the WAL. `wal.json` records the version it was
+//! written in, and a WAL of an older one is read through the migrations of
+//! every version since, rewriting its JSON into the current format before
+//! it is deserialized. The next commit writes it back in that format.
+//!
+//! A change to the format bumps [`WAL_VERSION`] and adds a migration from
+//! the version before. A WAL of a newer version is read as it is, what only
+//! that version knows kept verbatim, see [`ActionType::Unknown`], and keeps
+//! its version when written back.
+//!
+//! [`ActionType::Unknown`]: crate::ActionType::Unknown
+
+use log::{debug, warn};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::{Action, MirageError, WAL};
+
+/// Version of the format of the WAL written by this mirage. `wal.json`
+/// files without one are of version 0.
+pub const WAL_VERSION: u64 = 2;
+
+/// Rewrites the JSON of a WAL of one version into the next: the fields of
+/// `wal.json` with `wal`, and every action, whether of `wal.json` or of a
+/// segment, with `action`.
+struct Migration {
+    wal: fn(&mut Map<String, Value>),
+    action: fn(&mut Value),
+}
+
+/// The migration from every version to the next, from version 0 on.
+const MIGRATIONS: [Migration; WAL_VERSION as usize] = [
+    // only the version was added
+    Migration {
+        wal: |_| {},
+        action: |_| {},
+    },
+    // the redirections moved to files of their own, the ones still in
+    // `wal.json` are read from there and moved with the next commit
+    Migration {
+        wal: |_| {},
+        action: |_| {},
+    },
+];
+
+/// The version recorded by a WAL, 0 if it records none.
+pub(crate) fn check_version(version: Option<&Value>) -> Result<u64, MirageError> {
+    let version = match version {
+        Some(version) => u64::deserialize(version)?,
+        None => 0,
+    };
+    if version > WAL_VERSION {
+        warn!(
+            "wal.json is of version {}, this mirage only knows up to {}",
+            version, WAL_VERSION
+        );
+    }
+    Ok(version)
+}
+
+/// Reads the `wal.json` held by `bytes`, of any version. The version it was
+/// read from is kept as [`WAL::version`] for the actions of its segments,
+/// read by [`WAL::load_segments`] with [`read_actions`].
+pub(crate) fn read_wal(bytes: &[u8]) -> Result<WAL, MirageError> {
+    let mut fields: Map<String, Value> = serde_json::from_slice(bytes)?;
+    let version = check_version(fields.get("version"))?;
+    if version < WAL_VERSION {
+        debug!(
+            "Upgrading wal.json from version {} to {}",
+            version, WAL_VERSION
+        );
+    }
+    for migration in MIGRATIONS.iter().skip(version as usize) {
+        (migration.wal)(&mut fields);
+        if let Some(Value::Array(actions)) = fields.get_mut("actions") {
+            actions.iter_mut().for_each(migration.action);
+        }
+    }
+    Ok(serde_json::from_value(Value::Object(fields))?)
+}
+
+/// Reads the actions held by `bytes`, a segment of a WAL of `version`.
+pub(crate) fn read_actions(bytes: &[u8], version: u64) -> Result<Vec<Action>, MirageError> {
+    if version >= WAL_VERSION {
+        return Ok(serde_json::from_slice(bytes)?);
+    }
+    let mut actions: Vec<Value> = serde_json::from_slice(bytes)?;
+    for action in &mut actions {
+        migrate_action(action, version);
+    }
+    Ok(actions
+        .into_iter()
+        .map(serde_json::from_value)
+        .collect::<Result<_, _>>()?)
+}
+
+/// Rewrites `action`, part of a WAL of `version`, into the current format.
+pub(crate) fn migrate_action(action: &mut Value, version: u64) {
+    for migration in MIGRATIONS.iter().skip(version as usize) {
+        (migration.action)(action);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{read_actions, read_wal, WAL_VERSION};
+
+    #[test]
+    fn reads_any_version() {
+        let action = json!({"action": "Symlink", "source": "/t/b", "target": "/t/.mirage/a"});
+        let unversioned = json!({
+            "actions": [action],
+            "redirections": {"/t/b": "/t/.mirage/a"},
+            "checkpoint": 1,
+        });
+        let wal = read_wal(&serde_json::to_vec(&unversioned).unwrap()).unwrap();
+        assert_eq!(wal.version, 0);
+        assert_eq!(wal.actions.len(), 1);
+        assert!(wal.extra.is_empty());
+        let segment = serde_json::to_vec(&json!([action, action])).unwrap();
+        assert_eq!(read_actions(&segment, 0).unwrap().len(), 2);
+
+        let newer = json!({
+            "version": WAL_VERSION + 1,
+            "actions": [],
+            "redirections": {},
+            "checkpoint": 0,
+        });
+        let wal = read_wal(&serde_json::to_vec(&newer).unwrap()).unwrap();
+        assert_eq!(wal.version, WAL_VERSION + 1);
+        let invalid = json!({"version": "one", "actions": [], "redirections": {}, "checkpoint": 0});
+        assert!(read_wal(&serde_json::to_vec(&invalid).unwrap()).is_err());
+    }
+}
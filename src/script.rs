@@ -0,0 +1,190 @@
+//! Rendering the actions of the WAL as a shell script of plain file
+//! commands, for reading what a run does or will do, or for carrying it out
+//! by hand where mirage itself can't run.
+//!
+//! The script only touches the tree: running it leaves the WAL as it is, so
+//! mirage still considers the actions it holds pending.
+
+use std::{
+    io::{self, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use crate::{walstream::WalStream, Action, ActionType, MirageError};
+
+/// The shell a script is written for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shell {
+    /// POSIX `sh`, with the `cp` and `ln` of coreutils.
+    Posix,
+    PowerShell,
+}
+
+impl FromStr for Shell {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sh" | "posix" => Ok(Shell::Posix),
+            "powershell" | "pwsh" => Ok(Shell::PowerShell),
+            _ => Err(format!("unknown shell {:?}", s)),
+        }
+    }
+}
+
+/// `path` quoted as a single word of `shell`.
+fn quote(path: &Path, shell: Shell) -> Result<String, MirageError> {
+    let path = path
+        .to_str()
+        .ok_or_else(|| io::Error::other(format!("{:?} isn't valid UTF-8", path)))?;
+    Ok(match shell {
+        Shell::Posix => format!("'{}'", path.replace('\'', r"'\''")),
+        Shell::PowerShell => format!("'{}'", path.replace('\'', "''")),
+    })
+}
+
+/// The commands carrying out `action`.
+fn render(action: &Action, shell: Shell) -> Result<Vec<String>, MirageError> {
+    let (source, target) = (quote(&action.source, shell)?, quote(&action.target, shell)?);
+    let lines = match (shell, &action.action) {
+        (_, ActionType::NOP) => vec!["# nothing to do".to_string()],
+        (_, ActionType::Unknown(_)) => vec![format!(
+            "# {} of {} is unknown to this mirage, left out",
+            action.action.name(),
+            source
+        )],
+        (Shell::Posix, ActionType::Copy) => vec![format!("cp -p -- {} {}", source, target)],
+        (Shell::Posix, ActionType::Symlink) => vec![
+            format!("rm -f -- {}", source),
+            format!("ln -s -- {} {}", target, source),
+        ],
+        (Shell::Posix, ActionType::Hardlink) => vec![format!("ln -f -- {} {}", target, source)],
+        (Shell::Posix, ActionType::Reflink) => {
+            vec![format!("cp --reflink=always -- {} {}", target, source)]
+        }
+        (Shell::Posix, ActionType::Delete) => vec![format!("rm -f -- {}", source)],
+        (Shell::Posix, ActionType::Move) => vec![format!("mv -- {} {}", source, target)],
+        (Shell::Posix, ActionType::Dedupe) => {
+            vec![format!("duperemove -dq -- {} {}", target, source)]
+        }
+        (Shell::PowerShell, ActionType::Copy) => vec![format!(
+            "Copy-Item -LiteralPath {} -Destination {}",
+            source, target
+        )],
+        (Shell::PowerShell, ActionType::Symlink) => vec![
+            format!("Remove-Item -LiteralPath {} -Force", source),
+            format!(
+                "New-Item -ItemType SymbolicLink -Path {} -Target {} | Out-Null",
+                source, target
+            ),
+        ],
+        (Shell::PowerShell, ActionType::Hardlink) => vec![
+            format!("Remove-Item -LiteralPath {} -Force", source),
+            format!(
+                "New-Item -ItemType HardLink -Path {} -Target {} | Out-Null",
+                source, target
+            ),
+        ],
+        (Shell::PowerShell, ActionType::Reflink) => vec![
+            "# PowerShell can't clone files, copying instead".to_string(),
+            format!(
+                "Copy-Item -LiteralPath {} -Destination {} -Force",
+                target, source
+            ),
+        ],
+        (Shell::PowerShell, ActionType::Delete) => {
+            vec![format!("Remove-Item -LiteralPath {} -Force", source)]
+        }
+        (Shell::PowerShell, ActionType::Move) => vec![format!(
+            "Move-Item -LiteralPath {} -Destination {}",
+            source, target
+        )],
+        (Shell::PowerShell, ActionType::Dedupe) => vec![format!(
+            "# PowerShell can't share extents, leaving {} as it is",
+            source
+        )],
+    };
+    Ok(lines)
+}
+
+/// Writes the actions of the WAL of the tree at `target_dir` to `out` as a
+/// script for `shell`, every one of them with `all` and only the pending
+/// ones otherwise. Returns the number of actions written. Nothing is
+/// modified.
+pub fn export_script<T: AsRef<Path>, W: Write>(
+    target_dir: T,
+    shell: Shell,
+    all: bool,
+    mut out: W,
+) -> Result<usize, MirageError> {
+    let wal = WalStream::open(&target_dir)?;
+    let checkpoint = wal.checkpoint();
+    match shell {
+        Shell::Posix => {
+            writeln!(out, "#!/bin/sh")?;
+            writeln!(out, "set -eu")?;
+        }
+        Shell::PowerShell => writeln!(out, "$ErrorActionPreference = 'Stop'")?,
+    }
+    writeln!(
+        out,
+        "# {} actions of {}, written by mirage",
+        if all { "All" } else { "Pending" },
+        target_dir.as_ref().display()
+    )?;
+    writeln!(out, "# running this leaves the WAL as it is")?;
+    let mut written = 0;
+    wal.for_each_action(|index, action| {
+        if !all && index < checkpoint {
+            return Ok(());
+        }
+        writeln!(out)?;
+        let done = if index < checkpoint { ", done" } else { "" };
+        writeln!(out, "# {}: {}{}", index, action.action.name(), done)?;
+        for line in render(&action, shell)? {
+            writeln!(out, "{}", line)?;
+        }
+        written += 1;
+        Ok(())
+    })?;
+    out.flush()?;
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::{quote, render, Shell};
+    use crate::{Action, ActionType};
+
+    #[test]
+    fn quotes_and_renders_actions() {
+        let path = Path::new("/t/it's here");
+        assert_eq!(quote(path, Shell::Posix).unwrap(), r"'/t/it'\''s here'");
+        assert_eq!(quote(path, Shell::PowerShell).unwrap(), "'/t/it''s here'");
+
+        let symlink = Action::new(
+            ActionType::Symlink,
+            PathBuf::from("/t/a"),
+            PathBuf::from("/t/.mirage/originals/a"),
+        );
+        assert_eq!(
+            render(&symlink, Shell::Posix).unwrap(),
+            vec![
+                "rm -f -- '/t/a'".to_string(),
+                "ln -s -- '/t/.mirage/originals/a' '/t/a'".to_string(),
+            ]
+        );
+        let copy = Action::new(
+            ActionType::Copy,
+            PathBuf::from("/t/a"),
+            PathBuf::from("/t/.mirage/originals/a"),
+        );
+        assert_eq!(
+            render(&copy, Shell::PowerShell).unwrap(),
+            vec!["Copy-Item -LiteralPath '/t/a' -Destination '/t/.mirage/originals/a'"]
+        );
+    }
+}
@@ -0,0 +1,184 @@
+//! Segments of the WAL. Actions are sealed into files of their own,
+//! `wal.0001.json` and on, listed in order by `wal.json` along with their
+//! hashes, so that `wal.json` only holds the recent actions and a commit
+//! only writes the segments that changed. Pending actions are sealed as well
+//! as applied ones, a run queueing many of them doesn't write them all again
+//! with every commit.
+//!
+//! A segment file is never written twice: a segment whose actions change
+//! goes to a new file and the old one is deleted once `wal.json` no longer
+//! lists it, so a crash midway leaves a WAL that still reads back whole and
+//! the files of old segments can be archived as they are.
+//!
+//! Whatever changes a sealed action marks its segment dirty with
+//! [`WAL::touch`], and a commit only writes the dirty segments. Segments
+//! shrink as actions are forgotten, and a shrunk segment is folded into its
+//! neighbour once both fit in one, leaving the others as they are.
+
+use std::{fs, path::Path};
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    digest::{sha256, to_hex},
+    schema::read_actions,
+    write_synced, MirageError, WAL, WAL_VERSION,
+};
+
+/// Actions are sealed into segments of this many.
+const SEGMENT_ACTIONS: usize = 4096;
+
+/// A sealed run of actions, as listed in `wal.json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Segment {
+    /// Name of the file in the store.
+    pub file: String,
+    /// Number of actions it holds.
+    pub actions: usize,
+    /// SHA-256 of the file, so that the signature of `wal.json` covers it.
+    pub sha256: String,
+    /// Whether its actions changed since the file was read or written.
+    #[serde(skip)]
+    dirty: bool,
+}
+
+impl WAL {
+    /// Number of actions held by segments rather than `wal.json` itself.
+    pub(crate) fn sealed(&self) -> usize {
+        self.segments.iter().map(|segment| segment.actions).sum()
+    }
+
+    /// Reads the actions of every segment back in front of the ones of
+    /// `wal.json`, checking each segment against its hash. Segments of an
+    /// older format are written again in the current one with the next
+    /// commit.
+    pub(crate) fn load_segments(&mut self, mirage_path: &Path) -> Result<(), MirageError> {
+        if self.segments.is_empty() {
+            return Ok(());
+        }
+        let mut actions = Vec::with_capacity(self.sealed() + self.actions.len());
+        for segment in &mut self.segments {
+            debug!("Reading wal segment {:?}", segment.file);
+            let bytes = fs::read(mirage_path.join(&segment.file))?;
+            if to_hex(&sha256(&bytes)) != segment.sha256 {
+                return Err(MirageError::WALTampered);
+            }
+            let mut sealed = read_actions(&bytes, self.version)?;
+            if sealed.len() != segment.actions {
+                return Err(MirageError::WALTampered);
+            }
+            segment.dirty = self.version < WAL_VERSION;
+            actions.append(&mut sealed);
+        }
+        actions.append(&mut self.actions);
+        self.actions = actions;
+        Ok(())
+    }
+
+    /// Marks the segment holding the action at `index` as changed, nothing
+    /// to do for an action still in `wal.json`.
+    pub(crate) fn touch(&mut self, index: usize) {
+        let mut end = 0;
+        for segment in &mut self.segments {
+            end += segment.actions;
+            if index < end {
+                segment.dirty = true;
+                return;
+            }
+        }
+    }
+
+    /// Seals full runs of actions into new segments, folds shrunk
+    /// segments into their neighbours, then writes the dirty segments.
+    pub(crate) fn write_segments(&mut self, mirage_path: &Path) -> Result<(), MirageError> {
+        while self.actions.len().saturating_sub(self.sealed()) >= SEGMENT_ACTIONS {
+            self.segments.push(Segment {
+                file: String::new(),
+                actions: SEGMENT_ACTIONS,
+                sha256: String::new(),
+                dirty: true,
+            });
+        }
+        self.compact_segments();
+
+        let mut start = 0;
+        for segment in &mut self.segments {
+            let range = start..start + segment.actions;
+            start = range.end;
+            if !segment.dirty {
+                continue;
+            }
+            let bytes = serde_json::to_vec_pretty(&self.actions[range])?;
+            if !segment.file.is_empty() {
+                self.obsolete.push(std::mem::take(&mut segment.file));
+            }
+            self.next_segment += 1;
+            segment.file = format!("wal.{:04}.json", self.next_segment);
+            debug!("Writing wal segment {:?}", segment.file);
+            write_synced(&mirage_path.join(&segment.file), &bytes)?;
+            segment.sha256 = to_hex(&sha256(&bytes));
+            segment.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Merges every dirty segment with the next or previous one as long as
+    /// both fit in a single segment. Only segments that shrank can fit, so
+    /// untouched runs of full segments stay as they are.
+    fn compact_segments(&mut self) {
+        let mut i = 0;
+        while i + 1 < self.segments.len() {
+            let (first, second) = (&self.segments[i], &self.segments[i + 1]);
+            if !(first.dirty || second.dirty) || first.actions + second.actions > SEGMENT_ACTIONS {
+                i += 1;
+                continue;
+            }
+            let second = self.segments.remove(i + 1);
+            debug!(
+                "Folding wal segment {:?} into the previous one",
+                second.file
+            );
+            if !second.file.is_empty() {
+                self.obsolete.push(second.file);
+            }
+            let first = &mut self.segments[i];
+            first.actions += second.actions;
+            first.dirty = true;
+        }
+    }
+
+    /// Accounts for the actions at `forgotten`, positions before
+    /// they were removed, dropping segments left empty.
+    pub(crate) fn shrink_segments(&mut self, forgotten: &[usize]) {
+        let mut end = 0;
+        let mut forgotten = forgotten.iter().peekable();
+        for segment in &mut self.segments {
+            end += segment.actions;
+            while forgotten.next_if(|&&index| index < end).is_some() {
+                segment.actions -= 1;
+                segment.dirty = true;
+            }
+        }
+        let obsolete = &mut self.obsolete;
+        self.segments.retain(|segment| {
+            if segment.actions == 0 {
+                obsolete.push(segment.file.clone());
+            }
+            segment.actions > 0
+        });
+    }
+
+    /// Deletes the files of segments `wal.json` no longer lists, once it has
+    /// been written.
+    pub(crate) fn remove_obsolete(&mut self, mirage_path: &Path) -> Result<(), MirageError> {
+        for file in self.obsolete.drain(..) {
+            let path = mirage_path.join(file);
+            if path.exists() {
+                debug!("Removing obsolete wal segment {:?}", path);
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,175 @@
+//! Fanning the originals store out over subdirectories named after the
+//! contents of the originals, as a single directory holding hundreds of
+//! thousands of them gets slow to look up on many filesystems, and moving
+//! the originals of a flat store over.
+
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    back_up, check_interrupted, hash_file, upgrade::relink, ActionType, LinkMode, MirageError,
+    MirageState, DEFAULT_BACKUPS,
+};
+
+/// Number of originals a flat store holds past which runs suggest sharding
+/// it.
+const SHARD_HINT_ORIGINALS: usize = 10_000;
+
+/// How the originals store is laid out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreLayout {
+    /// Every original directly in the store.
+    #[default]
+    Flat,
+    /// Originals two levels down, in `ab/cd/` for contents whose id starts
+    /// with `abcd`.
+    Sharded,
+}
+
+impl StoreLayout {
+    pub(crate) fn is_flat(&self) -> bool {
+        *self == StoreLayout::Flat
+    }
+
+    /// The directory of the store at `originals` holding an original whose
+    /// content id is `content`.
+    pub(crate) fn dir(&self, originals: &Path, content: &str) -> PathBuf {
+        match (self, content.get(..2), content.get(2..4)) {
+            (StoreLayout::Sharded, Some(outer), Some(inner)) => originals.join(outer).join(inner),
+            _ => originals.to_path_buf(),
+        }
+    }
+}
+
+impl MirageState {
+    /// Suggests sharding a flat store holding more originals than most
+    /// filesystems look up quickly in one directory.
+    pub(crate) fn hint_sharding(&self) {
+        if !self.wal.layout.is_flat() {
+            return;
+        }
+        let originals = self.originals_path();
+        let flat = self
+            .wal
+            .actions
+            .iter()
+            .filter(|action| matches!(action.action, ActionType::Copy))
+            .filter(|action| action.target.parent() == Some(originals.as_path()))
+            .map(|action| &action.target)
+            .collect::<HashSet<_>>()
+            .len();
+        if flat > SHARD_HINT_ORIGINALS {
+            warn!(
+                "{:?} holds {} originals in one directory, `mirage shard` spreads them out",
+                originals, flat
+            );
+        }
+    }
+}
+
+/// What [`shard`] moved.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ShardReport {
+    /// Number of originals moved into subdirectories.
+    pub moved: usize,
+    /// Number of originals already in one.
+    pub already: usize,
+    /// Originals gone from the store, left as they are.
+    pub missing: Vec<PathBuf>,
+}
+
+/// Lays the originals store of the tree at `target_dir` out as
+/// [`StoreLayout::Sharded`], moving every original of a flat store into its
+/// subdirectory and pointing the actions, redirections and symlinks that
+/// refer to it there. Hard links and clones don't refer to the path of
+/// their original and are left alone. A store that hasn't been created yet
+/// is created sharded.
+pub fn shard<T: AsRef<Path>>(target_dir: T) -> Result<ShardReport, MirageError> {
+    let target_dir = target_dir.as_ref();
+    let mut state = MirageState::get(target_dir)?;
+    state.ensure_unfrozen()?;
+    let mut report = ShardReport::default();
+    if state.dry_run {
+        warn!("Store is read-only, not sharding {:?}", target_dir);
+        return Ok(report);
+    }
+    state.lock_run()?;
+    if state.wal.checkpoint < state.wal.actions.len() {
+        return Err(MirageError::PendingActions(target_dir.to_path_buf()));
+    }
+    back_up(&state, "shard", DEFAULT_BACKUPS)?;
+    state.wal.layout = StoreLayout::Sharded;
+    state.commit()?;
+
+    let originals = state.originals_path();
+    let mut flat = state
+        .wal
+        .actions
+        .iter()
+        .filter(|action| matches!(action.action, ActionType::Copy))
+        .filter(|action| action.target.parent() == Some(originals.as_path()))
+        .map(|action| (action.target.clone(), action.digest.clone()))
+        .collect::<Vec<_>>();
+    report.already = state
+        .wal
+        .actions
+        .iter()
+        .filter(|action| matches!(action.action, ActionType::Copy))
+        .filter(|action| action.target.parent() != Some(originals.as_path()))
+        .count();
+    flat.sort();
+    flat.dedup_by(|a, b| a.0 == b.0);
+
+    for (original, digest) in flat {
+        check_interrupted()?;
+        if !original.is_file() {
+            report.missing.push(original);
+            continue;
+        }
+        let _lock = state.lock_original(&original)?;
+        let content = match digest {
+            Some(digest) => digest,
+            None => hash_file(&original)?,
+        };
+        let name = original
+            .file_name()
+            .ok_or(MirageError::DotMirageInInconsistentState)?;
+        let sharded = state.new_original_path(name, &content);
+        debug!("Moving {:?} to {:?}", original, sharded);
+        if let Some(dir) = sharded.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::rename(&original, &sharded)?;
+
+        let mut symlinks = Vec::new();
+        for index in 0..state.wal.actions.len() {
+            let action = &mut state.wal.actions[index];
+            if action.target != original {
+                continue;
+            }
+            action.target = sharded.clone();
+            if matches!(action.action, ActionType::Symlink) {
+                symlinks.push(action.source.clone());
+            }
+            state.wal.touch(index);
+        }
+        state.wal.redirections.retarget(&original, &sharded);
+        // a crash from here on leaves dangling symlinks fsck --fix mends
+        state.commit()?;
+        for path in symlinks {
+            if fs::read_link(&path).is_ok_and(|pointee| pointee == original) {
+                relink(&path, &sharded, LinkMode::Symlink)?;
+            }
+        }
+        report.moved += 1;
+    }
+    report.missing.sort();
+    Ok(report)
+}
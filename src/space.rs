@@ -0,0 +1,102 @@
+//! The room restoring files takes. Every file a revert restores is a copy
+//! of its original until the store is deleted at the end, so the
+//! filesystems of the tree have to hold all of them at once.
+
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use log::debug;
+use serde::Serialize;
+
+use crate::{policy::same_device, Action, ActionType};
+
+/// Bytes free to unprivileged users on the filesystem holding `path`.
+#[cfg(unix)]
+fn free_space(path: &Path) -> io::Result<u64> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is NUL-terminated and `stat` is written on success
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let stat = unsafe { stat.assume_init() };
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn free_space(_path: &Path) -> io::Result<u64> {
+    Ok(u64::MAX)
+}
+
+/// The device of the filesystem holding `dir`.
+#[cfg(unix)]
+fn device(dir: &Path) -> io::Result<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    Ok(fs::metadata(dir)?.dev())
+}
+
+#[cfg(not(unix))]
+fn device(_dir: &Path) -> io::Result<u64> {
+    Ok(0)
+}
+
+/// The room restoring takes on one filesystem.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct SpaceNeeded {
+    /// The directory of the first file restored to the filesystem.
+    pub path: PathBuf,
+    /// Bytes the restored files take.
+    pub needed: u64,
+    /// Bytes free on the filesystem.
+    pub free: u64,
+}
+
+impl SpaceNeeded {
+    /// Whether the filesystem can hold the restored files.
+    pub fn fits(&self) -> bool {
+        self.needed <= self.free
+    }
+}
+
+/// The room taken on every filesystem by `restores`, copies of their source
+/// over their target, none where `hard_link` can link a file to its
+/// original instead. Originals missing from the store restore nothing.
+pub(crate) fn space_needed<'a, I>(restores: I, hard_link: bool) -> io::Result<Vec<SpaceNeeded>>
+where
+    I: IntoIterator<Item = &'a Action>,
+{
+    let mut needed: BTreeMap<u64, SpaceNeeded> = BTreeMap::new();
+    for action in restores {
+        if !matches!(action.action, ActionType::Copy) {
+            continue;
+        }
+        let (Ok(meta), Some(dir)) = (fs::metadata(&action.source), action.target.parent()) else {
+            continue;
+        };
+        if hard_link && same_device(&action.source, dir) {
+            continue;
+        }
+        let device = device(dir)?;
+        let space = match needed.entry(device) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let free = free_space(dir)?;
+                debug!("{} bytes are free on the filesystem of {:?}", free, dir);
+                entry.insert(SpaceNeeded {
+                    path: dir.to_path_buf(),
+                    needed: 0,
+                    free,
+                })
+            }
+        };
+        space.needed += meta.len();
+    }
+    Ok(needed.into_values().collect())
+}
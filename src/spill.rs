@@ -0,0 +1,273 @@
+//! Grouping of candidate files by size, spilling to disk past a budget.
+//!
+//! Candidates are buffered in memory up to a number of entries. Past it the
+//! buffer is sorted and written out as a run under the store's `tmp`
+//! directory, and the runs are merged back when the groups are read, so the
+//! memory used stays bounded however big the tree is. Groups are sorted a
+//! second time the same way to hand them back in walk order.
+
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::PathBuf,
+};
+
+use log::{debug, warn};
+
+use crate::MirageError;
+
+/// Candidates kept in memory before spilling, unless configured otherwise.
+pub const DEFAULT_MEMORY_BUDGET: usize = 4_000_000;
+
+/// (size, position in walk order, path)
+type Candidate = (u64, u64, PathBuf);
+
+#[cfg(unix)]
+fn path_bytes(path: &std::path::Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+fn path_bytes(path: &std::path::Path) -> Vec<u8> {
+    path.to_string_lossy().into_owned().into_bytes()
+}
+
+#[cfg(unix)]
+fn bytes_path(bytes: Vec<u8>) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+    PathBuf::from(std::ffi::OsString::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+fn bytes_path(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn write_candidate<W: Write>(out: &mut W, (size, index, path): &Candidate) -> io::Result<()> {
+    let bytes = path_bytes(path);
+    out.write_all(&size.to_le_bytes())?;
+    out.write_all(&index.to_le_bytes())?;
+    out.write_all(&(bytes.len() as u64).to_le_bytes())?;
+    out.write_all(&bytes)
+}
+
+fn read_candidate<R: Read>(input: &mut R) -> io::Result<Option<Candidate>> {
+    let mut word = [0u8; 8];
+    match input.read_exact(&mut word) {
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        result => result?,
+    }
+    let size = u64::from_le_bytes(word);
+    input.read_exact(&mut word)?;
+    let index = u64::from_le_bytes(word);
+    input.read_exact(&mut word)?;
+    let mut bytes = vec![0; u64::from_le_bytes(word) as usize];
+    input.read_exact(&mut bytes)?;
+    Ok(Some((size, index, bytes_path(bytes))))
+}
+
+/// Collects candidates and hands them back grouped by size.
+pub(crate) struct Grouper {
+    budget: usize,
+    /// Distinguishes the runs of groupers sharing `spill_dir`.
+    prefix: &'static str,
+    /// Where runs are spilled, `None` to keep everything in memory.
+    spill_dir: Option<PathBuf>,
+    buffer: Vec<Candidate>,
+    runs: Vec<PathBuf>,
+    next_index: u64,
+}
+
+impl Grouper {
+    pub fn new(budget: usize, spill_dir: Option<PathBuf>) -> Self {
+        Grouper::with_prefix(budget, spill_dir, "size")
+    }
+
+    fn with_prefix(budget: usize, spill_dir: Option<PathBuf>, prefix: &'static str) -> Self {
+        Grouper {
+            budget: budget.max(1),
+            prefix,
+            spill_dir,
+            buffer: Vec::new(),
+            runs: Vec::new(),
+            next_index: 0,
+        }
+    }
+
+    pub fn push(&mut self, size: u64, path: PathBuf) -> Result<(), MirageError> {
+        self.buffer.push((size, self.next_index, path));
+        self.next_index += 1;
+        if self.buffer.len() >= self.budget {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn spill(&mut self) -> Result<(), MirageError> {
+        let Some(dir) = &self.spill_dir else {
+            return Ok(());
+        };
+        fs::create_dir_all(dir)?;
+        let run = dir.join(format!("{}-{}", self.prefix, self.runs.len()));
+        debug!("Spilling {} candidates to {:?}", self.buffer.len(), run);
+        self.buffer.sort_unstable();
+        let mut out = BufWriter::new(File::create(&run)?);
+        for candidate in self.buffer.drain(..) {
+            write_candidate(&mut out, &candidate)?;
+        }
+        out.flush()?;
+        self.runs.push(run);
+        Ok(())
+    }
+
+    /// Groups of paths sharing a size, in the order their first path was
+    /// pushed, each in the order its paths were pushed.
+    pub fn finish(self) -> Result<Groups, MirageError> {
+        let mut ordered = Grouper::with_prefix(self.budget, self.spill_dir.clone(), "position");
+        let mut by_size = self.finish_by_size()?;
+        while let Some((first, group)) = by_size.next_group() {
+            for path in group {
+                ordered.push(first, path)?;
+            }
+        }
+        drop(by_size);
+        ordered.finish_by_size()
+    }
+
+    /// Groups of paths sharing a size, smallest size first, each in the order
+    /// its paths were pushed.
+    fn finish_by_size(mut self) -> Result<Groups, MirageError> {
+        self.buffer.sort_unstable();
+        let mut readers = Vec::new();
+        for run in &self.runs {
+            readers.push(BufReader::new(File::open(run)?));
+        }
+        let mut groups = Groups {
+            buffer: self.buffer.into_iter(),
+            readers,
+            runs: self.runs,
+            spill_dir: self.spill_dir,
+            heap: BinaryHeap::new(),
+            next: None,
+        };
+        for source in 0..=groups.readers.len() {
+            groups.refill(source)?;
+        }
+        groups.next = groups.pop();
+        Ok(groups)
+    }
+}
+
+/// Merges the in-memory buffer with the spilled runs.
+pub(crate) struct Groups {
+    buffer: std::vec::IntoIter<Candidate>,
+    readers: Vec<BufReader<File>>,
+    runs: Vec<PathBuf>,
+    spill_dir: Option<PathBuf>,
+    /// Smallest unread candidate of every source, the buffer being the last.
+    heap: BinaryHeap<Reverse<(Candidate, usize)>>,
+    next: Option<Candidate>,
+}
+
+impl Groups {
+    fn refill(&mut self, source: usize) -> Result<(), MirageError> {
+        let candidate = match self.readers.get_mut(source) {
+            Some(reader) => read_candidate(reader)?,
+            None => self.buffer.next(),
+        };
+        if let Some(candidate) = candidate {
+            self.heap.push(Reverse((candidate, source)));
+        }
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Option<Candidate> {
+        self.heap.pop().map(|Reverse((candidate, source))| {
+            // a failed read shows up as the run ending early
+            if let Err(err) = self.refill(source) {
+                warn!("Can't read spilled candidates: {:?}", err);
+            }
+            candidate
+        })
+    }
+}
+
+impl Groups {
+    /// The next group along with the position its first path was pushed at.
+    fn next_group(&mut self) -> Option<(u64, Vec<PathBuf>)> {
+        let (size, first, path) = self.next.take()?;
+        let mut group = vec![path];
+        loop {
+            match self.pop() {
+                Some((next_size, _, path)) if next_size == size => group.push(path),
+                next => {
+                    self.next = next;
+                    return Some((first, group));
+                }
+            }
+        }
+    }
+}
+
+impl Iterator for Groups {
+    type Item = Vec<PathBuf>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_group().map(|(_, group)| group)
+    }
+}
+
+impl Drop for Groups {
+    fn drop(&mut self) {
+        for run in &self.runs {
+            let _ = fs::remove_file(run);
+        }
+        if let Some(dir) = &self.spill_dir {
+            let _ = fs::remove_dir(dir);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use tempfile::tempdir;
+
+    use super::Grouper;
+
+    #[test]
+    fn spills_and_merges() {
+        let dir = tempdir().unwrap();
+        let spill_dir = dir.path().join("tmp");
+        let mut grouper = Grouper::new(3, Some(spill_dir.clone()));
+        for (size, name) in [
+            (5, "a"),
+            (1, "b"),
+            (5, "c"),
+            (2, "d"),
+            (1, "e"),
+            (5, "f"),
+            (9, "g"),
+        ] {
+            grouper.push(size, PathBuf::from(name)).unwrap();
+        }
+        assert!(spill_dir.join("size-1").exists());
+
+        let groups = grouper.finish().unwrap().collect::<Vec<_>>();
+        let names = |names: &[&str]| names.iter().map(PathBuf::from).collect::<Vec<_>>();
+        assert_eq!(
+            groups,
+            vec![
+                names(&["a", "c", "f"]),
+                names(&["b", "e"]),
+                names(&["d"]),
+                names(&["g"]),
+            ]
+        );
+        assert!(!spill_dir.exists());
+    }
+}
@@ -0,0 +1,239 @@
+//! Keeping the history of a reverted tree. Rather than going with the rest of
+//! the store, the WAL, its backups and the logs of every run are archived
+//! first, either into a directory of their own or into a tarball.
+//!
+//! Only the originals, scratch files and locks are left out: they are
+//! either restored into the tree by then or meaningless without it.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::debug;
+
+use crate::{gzip::GzipWriter, MirageError, MirageState};
+
+/// Entries of the store that aren't worth keeping.
+const LEFT_OUT: &[&str] = &["originals", "tmp", "locks", "run.lock"];
+
+/// Tar files are written in blocks of this many bytes.
+const BLOCK: usize = 512;
+
+/// Where an archive of the state goes, made out of what the user asked for.
+enum Destination {
+    Dir(PathBuf),
+    Tar { path: PathBuf, gzip: bool },
+}
+
+impl Destination {
+    fn of(to: &Path) -> Destination {
+        let name = to.file_name().unwrap_or_default().to_string_lossy();
+        if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Destination::Tar {
+                path: to.to_path_buf(),
+                gzip: true,
+            }
+        } else if name.ends_with(".tar") {
+            Destination::Tar {
+                path: to.to_path_buf(),
+                gzip: false,
+            }
+        } else {
+            Destination::Dir(to.to_path_buf())
+        }
+    }
+}
+
+/// Where the state of `root`, one of several trees reverted at once, goes
+/// when they are all archived to `to`. Tarballs get a name of their own,
+/// directories keep every tree below a name of its own already.
+pub(crate) fn archive_path_for(to: &Path, root: &Path) -> PathBuf {
+    match Destination::of(to) {
+        Destination::Dir(dir) => dir,
+        Destination::Tar { path, .. } => {
+            let mut name = root.file_name().unwrap_or_default().to_os_string();
+            name.push("-");
+            name.push(path.file_name().unwrap_or_default());
+            path.with_file_name(name)
+        }
+    }
+}
+
+/// The files and directories of the store worth archiving, relative to it,
+/// parents before their children.
+fn entries(mirage_path: &Path) -> Result<Vec<(PathBuf, bool)>, MirageError> {
+    let mut entries = Vec::new();
+    let walk = walkdir::WalkDir::new(mirage_path)
+        .min_depth(1)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| {
+            entry.depth() > 1 || !LEFT_OUT.iter().any(|name| entry.file_name() == *name)
+        });
+    for entry in walk {
+        let entry = entry?;
+        let relative = entry
+            .path()
+            .strip_prefix(mirage_path)
+            .map_err(|_| MirageError::DotMirageInInconsistentState)?
+            .to_path_buf();
+        entries.push((relative, entry.file_type().is_dir()));
+    }
+    Ok(entries)
+}
+
+/// A ustar header for `name`, split over the prefix and name fields when it
+/// doesn't fit in the latter.
+fn tar_header(name: &str, size: u64, mtime: u64, dir: bool) -> io::Result<[u8; BLOCK]> {
+    let mut header = [0u8; BLOCK];
+    let (prefix, name) = if name.len() <= 100 {
+        ("", name)
+    } else {
+        name.char_indices()
+            .filter(|&(i, c)| c == '/' && i <= 155 && name.len() - i - 1 <= 100)
+            .map(|(i, _)| (&name[..i], &name[i + 1..]))
+            .next()
+            .ok_or_else(|| io::Error::other(format!("{} is too long for tar", name)))?
+    };
+    let octal = |field: &mut [u8], value: u64| {
+        let digits = format!("{:0width$o}", value, width = field.len() - 1);
+        field[..digits.len()].copy_from_slice(digits.as_bytes());
+    };
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut header[100..108], if dir { 0o755 } else { 0o644 });
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    octal(&mut header[124..136], size);
+    octal(&mut header[136..148], mtime);
+    header[156] = if dir { b'5' } else { b'0' };
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+    // the checksum is taken with its own field filled with spaces
+    header[148..156].fill(b' ');
+    let sum = header.iter().map(|&b| b as u64).sum::<u64>();
+    let digits = format!("{:06o}\0 ", sum);
+    header[148..156].copy_from_slice(digits.as_bytes());
+    Ok(header)
+}
+
+/// Writes `entries` of the store as a tar file below `base`.
+fn write_tar<W: Write>(
+    out: &mut W,
+    mirage_path: &Path,
+    base: &str,
+    entries: &[(PathBuf, bool)],
+) -> Result<(), MirageError> {
+    for (relative, dir) in entries {
+        let path = mirage_path.join(relative);
+        let meta = fs::metadata(&path)?;
+        let mtime = meta
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let mut name = format!("{}/{}", base, relative.to_string_lossy());
+        if *dir {
+            name.push('/');
+        }
+        let size = if *dir { 0 } else { meta.len() };
+        out.write_all(&tar_header(&name, size, mtime, *dir)?)?;
+        if *dir {
+            continue;
+        }
+        let copied = io::copy(&mut File::open(&path)?.take(size), out)?;
+        if copied != size {
+            return Err(io::Error::other(format!("{:?} shrank while archived", path)).into());
+        }
+        let padding = (BLOCK - (size as usize % BLOCK)) % BLOCK;
+        out.write_all(&[0; BLOCK][..padding])?;
+    }
+    out.write_all(&[0; 2 * BLOCK])?;
+    Ok(())
+}
+
+/// Archives the state of `state` to `to`: a `.tar`, `.tar.gz` or `.tgz`
+/// file, or a directory to keep it in below a name of its own. Returns
+/// where it went.
+pub(crate) fn archive_state(state: &MirageState, to: &Path) -> Result<PathBuf, MirageError> {
+    let tree = state
+        .root
+        .file_name()
+        .map_or_else(|| "tree".into(), |name| name.to_string_lossy());
+    let taken = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let base = format!("{}-{}", tree, taken);
+    let entries = entries(&state.source_path)?;
+
+    match Destination::of(to) {
+        Destination::Dir(dir) => {
+            let archive = dir.join(&base);
+            debug!(
+                "Archiving the state of {:?} to {:?}",
+                state.source_path, archive
+            );
+            fs::create_dir_all(&archive)?;
+            for (relative, is_dir) in &entries {
+                if *is_dir {
+                    fs::create_dir_all(archive.join(relative))?;
+                } else {
+                    fs::copy(state.source_path.join(relative), archive.join(relative))?;
+                }
+            }
+            Ok(archive)
+        }
+        Destination::Tar { path, gzip } => {
+            debug!(
+                "Archiving the state of {:?} to {:?}",
+                state.source_path, path
+            );
+            if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+                fs::create_dir_all(parent)?;
+            }
+            let file = BufWriter::new(File::create(&path)?);
+            if gzip {
+                let mut out = GzipWriter::new(file)?;
+                write_tar(&mut out, &state.source_path, &base, &entries)?;
+                out.finish()?;
+            } else {
+                let mut out = file;
+                write_tar(&mut out, &state.source_path, &base, &entries)?;
+                out.flush()?;
+            }
+            Ok(path)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::tar_header;
+
+    #[test]
+    fn writes_ustar_headers() {
+        let header = tar_header("tree-1/wal.json", 1234, 0, false).unwrap();
+        assert_eq!(&header[..15], b"tree-1/wal.json");
+        assert_eq!(&header[124..136], b"00000002322\0");
+        assert_eq!(header[156], b'0');
+        assert_eq!(&header[257..262], b"ustar");
+        let sum = header
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| if (148..156).contains(&i) { b' ' } else { b } as u64)
+            .sum::<u64>();
+        let recorded = std::str::from_utf8(&header[148..154]).unwrap();
+        assert_eq!(u64::from_str_radix(recorded, 8).unwrap(), sum);
+
+        // long names go partly in the prefix
+        let long = format!("{}/{}", "d".repeat(120), "f".repeat(90));
+        let header = tar_header(&long, 0, 0, false).unwrap();
+        assert_eq!(&header[..90], "f".repeat(90).as_bytes());
+        assert_eq!(&header[345..465], "d".repeat(120).as_bytes());
+        assert!(tar_header(&"x".repeat(300), 0, 0, false).is_err());
+    }
+}
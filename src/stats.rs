@@ -0,0 +1,267 @@
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    fs::{self, File},
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, trace};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    canonicalize_link, diff::diverging, walk, warn_walk_error, Action, ActionType, ApplyOptions,
+    DiffEntry, MirageError, MirageState, StoreLayout,
+};
+
+/// File in the store holding the statistics accumulated across runs.
+const STATS_FILE: &str = "stats.json";
+
+/// What one apply run deduplicated.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Session {
+    /// Seconds since the Unix epoch at which the run finished.
+    pub at: u64,
+    pub files: u64,
+    pub bytes: u64,
+    /// Size of the originals store after the run, not recorded by older
+    /// versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub store_size: Option<u64>,
+    /// Number of files managed after the run, not recorded by older
+    /// versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub managed: Option<u64>,
+}
+
+/// Savings accumulated by every apply run since the tree came under
+/// management. They are kept in the store and go away with it on revert.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Stats {
+    pub bytes_saved: u64,
+    pub files_deduped: u64,
+    pub sessions: Vec<Session>,
+}
+
+fn read_stats(state: &MirageState) -> Result<Stats, MirageError> {
+    let path = state.source_path.join(STATS_FILE);
+    if !path.exists() {
+        return Ok(Stats::default());
+    }
+    Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+}
+
+/// Adds the savings of the just executed `actions` to the statistics of the
+/// store, along with the `store_size` they left it at, and returns the bytes
+/// they saved. Runs that deduplicated nothing aren't recorded.
+pub(crate) fn record_session(
+    state: &MirageState,
+    actions: &[Action],
+    store_size: u64,
+) -> Result<u64, MirageError> {
+    // every link saves its original once, except the one replacing the file
+    // the original was copied from
+    let (mut links, mut linked, mut copies, mut copied) = (0u64, 0u64, 0u64, 0u64);
+    for action in actions {
+        let size = || fs::metadata(&action.target).map(|meta| meta.len());
+        match action.action {
+            ActionType::Symlink
+            | ActionType::Hardlink
+            | ActionType::Reflink
+            | ActionType::Delete
+            | ActionType::Dedupe => {
+                links += 1;
+                linked += size()?;
+            }
+            ActionType::Copy => {
+                copies += 1;
+                copied += size()?;
+            }
+            ActionType::NOP | ActionType::Move | ActionType::Unknown(_) => {}
+        }
+    }
+    let mut session = Session {
+        files: links.saturating_sub(copies),
+        bytes: linked.saturating_sub(copied),
+        store_size: Some(store_size),
+        managed: Some(state.wal.redirections.len() as u64),
+        ..Default::default()
+    };
+    if session.files == 0 {
+        return Ok(0);
+    }
+    session.at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    debug!(
+        "Recording session saving {} bytes over {} files",
+        session.bytes, session.files
+    );
+
+    let bytes = session.bytes;
+    let mut stats = read_stats(state)?;
+    stats.bytes_saved += session.bytes;
+    stats.files_deduped += session.files;
+    stats.sessions.push(session);
+    let writer = BufWriter::new(File::create(state.source_path.join(STATS_FILE))?);
+    serde_json::to_writer_pretty(writer, &stats)?;
+    Ok(bytes)
+}
+
+/// Savings accumulated across every apply run on the tree at `target_dir`.
+pub fn stats<T: AsRef<Path>>(target_dir: T) -> Result<Stats, MirageError> {
+    read_stats(&MirageState::open(&target_dir)?)
+}
+
+/// The state of a tree after one apply run.
+#[derive(Debug, Clone, Default, Serialize, PartialEq, Eq)]
+pub struct HistoryPoint {
+    /// Seconds since the Unix epoch at which the run finished.
+    pub at: u64,
+    /// Size of the originals store, `None` for runs of older versions.
+    pub store_size: Option<u64>,
+    /// Bytes saved by this run and every one before it.
+    pub bytes_saved: u64,
+    /// Number of files managed, `None` for runs of older versions.
+    pub files_managed: Option<u64>,
+}
+
+/// How the store of the tree at `target_dir` grew run after run, oldest
+/// first.
+pub fn stats_history<T: AsRef<Path>>(target_dir: T) -> Result<Vec<HistoryPoint>, MirageError> {
+    let mut bytes_saved = 0;
+    Ok(stats(target_dir)?
+        .sessions
+        .iter()
+        .map(|session| {
+            bytes_saved += session.bytes;
+            HistoryPoint {
+                at: session.at,
+                store_size: session.store_size,
+                bytes_saved,
+                files_managed: session.managed,
+            }
+        })
+        .collect())
+}
+
+/// Where the store of a managed tree stands, see [`store_status`].
+#[derive(Debug, Clone, Default, Serialize, PartialEq, Eq)]
+pub struct StoreStatus {
+    /// Number of duplicate groups executed actions deduplicated.
+    pub groups: usize,
+    /// Number of actions past the checkpoint, planned but not executed.
+    pub pending: usize,
+    /// Bytes saved by every run, as in [`Stats::bytes_saved`].
+    pub bytes_saved: u64,
+    /// The paths that aren't what the WAL says, as [`diff`](crate::diff)
+    /// lists them.
+    pub divergences: Vec<DiffEntry>,
+    /// How the originals are laid out, see [`shard`](crate::shard).
+    pub layout: StoreLayout,
+}
+
+impl StoreStatus {
+    /// Whether the tree is what its WAL says.
+    pub fn consistent(&self) -> bool {
+        self.divergences.is_empty()
+    }
+}
+
+/// Where the store of the tree at `target_dir` stands. Nothing is modified.
+pub fn store_status<T: AsRef<Path>>(target_dir: T) -> Result<StoreStatus, MirageError> {
+    let state = MirageState::open(&target_dir)?;
+    let executed = &state.wal.actions[..state.wal.checkpoint.min(state.wal.actions.len())];
+    let groups = executed
+        .iter()
+        .filter(|action| {
+            action.action.links()
+                || matches!(action.action, ActionType::Delete | ActionType::Dedupe)
+        })
+        .map(|action| &action.target)
+        .collect::<HashSet<_>>()
+        .len();
+    Ok(StoreStatus {
+        groups,
+        pending: state.wal.actions.len() - executed.len(),
+        bytes_saved: read_stats(&state)?.bytes_saved,
+        divergences: diverging(&state),
+        layout: state.wal.layout,
+    })
+}
+
+/// The space a managed tree takes, see [`MirageState::stats`].
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct SpaceStats {
+    /// Regular files and managed links of the tree.
+    pub files_scanned: u64,
+    /// Number of originals more than one file of the tree is linked to.
+    pub duplicate_groups: u64,
+    /// Size of every file as seen through its link.
+    pub logical_bytes: u64,
+    /// Size of the unique contents, each original counted once.
+    pub physical_bytes: u64,
+    /// How many times over the unique contents are used, 1 without
+    /// duplicates.
+    pub dedup_ratio: f64,
+    /// Savings recorded by every run, as in [`Stats`].
+    pub bytes_saved: u64,
+    pub files_deduped: u64,
+}
+
+impl MirageState {
+    /// Walks the tree of the store and accounts for the space it takes,
+    /// following the redirections of the store. Nothing is modified.
+    pub fn stats(&self) -> Result<SpaceStats, MirageError> {
+        let root = &self.root;
+        let mut space = SpaceStats::default();
+        let mut contents: HashMap<PathBuf, (u64, u64)> = HashMap::new();
+        for entry in walk(root, &ApplyOptions::everything()) {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    warn_walk_error(&err);
+                    continue;
+                }
+            };
+            if entry.file_type().is_dir() {
+                continue;
+            }
+            let path = canonicalize_link(entry.path())?;
+            let content = match self.wal.redirections.get(&path) {
+                Some(original) => original.clone(),
+                None if entry.path_is_symlink() => {
+                    trace!("Skipping unmanaged symlink {:?}", path);
+                    continue;
+                }
+                None => path,
+            };
+            space.files_scanned += 1;
+            let (size, users) = match contents.entry(content) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let size = fs::metadata(entry.key())?.len();
+                    entry.insert((size, 0))
+                }
+            };
+            *users += 1;
+            space.logical_bytes += *size;
+        }
+        for (size, users) in contents.values() {
+            space.physical_bytes += size;
+            if *users > 1 {
+                space.duplicate_groups += 1;
+            }
+        }
+        space.dedup_ratio = if space.physical_bytes == 0 {
+            1.0
+        } else {
+            space.logical_bytes as f64 / space.physical_bytes as f64
+        };
+        let stats = read_stats(self)?;
+        space.bytes_saved = stats.bytes_saved;
+        space.files_deduped = stats.files_deduped;
+        Ok(space)
+    }
+}
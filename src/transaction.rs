@@ -0,0 +1,188 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::PathBuf,
+};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    check_interrupted, execute, locks::OriginalLock, progress::ProgressWriter, undo, Action,
+    ActionType, MirageError, MirageState, PlannedAction,
+};
+
+/// A duplicate group undone because one of its actions failed. Kept in the
+/// WAL so the tree's history explains why the group isn't deduplicated.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Rollback {
+    /// The original the group shared.
+    pub original: PathBuf,
+    /// Why the failing action failed.
+    pub error: String,
+    /// Every action of the group, executed or not, in WAL order.
+    pub actions: Vec<PlannedAction>,
+}
+
+/// What ties the actions of a duplicate group together. Actions planned
+/// before groups were recorded are grouped by original.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum GroupKey {
+    Id(u64),
+    Original(PathBuf),
+}
+
+impl GroupKey {
+    fn of(action: &Action) -> GroupKey {
+        match action.group {
+            Some(id) => GroupKey::Id(id),
+            None => GroupKey::Original(action.target.clone()),
+        }
+    }
+}
+
+/// The groups partly executed, as far as the checkpoint tells, with the
+/// number of actions each has left to execute.
+#[derive(Debug, Default)]
+struct OpenGroups {
+    remaining: HashMap<GroupKey, usize>,
+    started: HashSet<GroupKey>,
+    open: usize,
+}
+
+impl OpenGroups {
+    fn new(state: &MirageState) -> OpenGroups {
+        let (done, pending) = state.wal.actions.split_at(state.wal.checkpoint);
+        let mut groups = OpenGroups::default();
+        for action in pending {
+            *groups.remaining.entry(GroupKey::of(action)).or_default() += 1;
+        }
+        groups.started = done
+            .iter()
+            .map(GroupKey::of)
+            .filter(|key| groups.remaining.contains_key(key))
+            .collect();
+        groups.open = groups.started.len();
+        groups
+    }
+
+    /// Records that `action`, the one at the checkpoint, was executed.
+    fn executed(&mut self, action: &Action) {
+        let key = GroupKey::of(action);
+        let Some(remaining) = self.remaining.get_mut(&key) else {
+            return;
+        };
+        *remaining -= 1;
+        let first = self.started.insert(key);
+        match (first, *remaining) {
+            (true, 1..) => self.open += 1,
+            (false, 0) => self.open -= 1,
+            _ => {}
+        }
+    }
+
+    /// Whether every group is either untouched or done, so that stopping
+    /// leaves none half deduplicated.
+    fn at_boundary(&self) -> bool {
+        self.open == 0
+    }
+}
+
+/// Executes the actions past the checkpoint like `execute_pending`, but
+/// treats the actions sharing an original as one transaction: when one of
+/// them fails, the ones already executed are undone, the whole group is
+/// dropped from the WAL and the rollback recorded, and the other groups carry
+/// on. Only failing to undo is an error. Interrupts are only heeded between
+/// groups, a group being executed is finished first.
+pub(crate) fn execute_transactions(
+    state: &mut MirageState,
+    progress: &mut ProgressWriter,
+) -> Result<Vec<Rollback>, MirageError> {
+    let mut rollbacks = Vec::new();
+    if state.dry_run {
+        return Ok(rollbacks);
+    }
+    let start = state.wal.checkpoint;
+    let mut held: Option<(PathBuf, OriginalLock)> = None;
+    let mut groups = OpenGroups::new(state);
+    while state.wal.checkpoint < state.wal.actions.len() {
+        if groups.at_boundary() {
+            check_interrupted()?;
+        }
+        let index = state.wal.checkpoint;
+        let original = &state.wal.actions[index].target;
+        if held.as_ref().is_none_or(|(locked, _)| locked != original) {
+            // one original at a time, waiting on it never holds up another
+            drop(held.take());
+            held = Some((original.clone(), state.lock_original(original)?));
+        }
+        match execute(&state.wal.actions[index], index, &state.source_path) {
+            Ok(copied) => {
+                progress.executed(&state.wal.actions[index].source, copied)?;
+                groups.executed(&state.wal.actions[index]);
+                state.wal.checkpoint += 1;
+            }
+            Err(err) => {
+                let rollback = roll_back(state, start, err)?;
+                warn!(
+                    "Rolled back the group of {:?}: {}",
+                    rollback.original, rollback.error
+                );
+                state.wal.rollbacks.push(rollback.clone());
+                rollbacks.push(rollback);
+                // the group is gone, the others are where they were
+                groups = OpenGroups::new(state);
+            }
+        }
+        state.commit()?;
+    }
+    Ok(rollbacks)
+}
+
+/// Undoes and drops the group of the action at the checkpoint, among the
+/// actions queued since `start`.
+fn roll_back(
+    state: &mut MirageState,
+    start: usize,
+    err: MirageError,
+) -> Result<Rollback, MirageError> {
+    let failed = state.wal.checkpoint;
+    let original = state.wal.actions[failed].target.clone();
+    // actions planned before groups were recorded are grouped by original
+    let group_id = state.wal.actions[failed].group;
+    let group = (start..state.wal.actions.len())
+        .filter(|&i| match group_id {
+            Some(id) => state.wal.actions[i].group == Some(id),
+            None => state.wal.actions[i].target == original,
+        })
+        .collect::<Vec<_>>();
+
+    for &i in group.iter().rev().filter(|&&i| i < failed) {
+        let action = &state.wal.actions[i];
+        undo(&action.invert(), false)?;
+        if matches!(action.action, ActionType::Copy) {
+            // the original was made for this group only
+            fs::remove_file(&action.target)?;
+        }
+    }
+
+    let mut actions = Vec::new();
+    for &i in group.iter().rev() {
+        let action = state.wal.actions.remove(i);
+        if action.action.links() {
+            state.wal.redirections.remove(&action.source);
+        }
+        if i < failed {
+            state.wal.checkpoint -= 1;
+        }
+        actions.push(PlannedAction::from(&action));
+    }
+    actions.reverse();
+    state.wal.shrink_segments(&group);
+
+    Ok(Rollback {
+        original,
+        error: format!("{:?}", err),
+        actions,
+    })
+}
@@ -0,0 +1,232 @@
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use log::{debug, warn};
+
+use crate::{
+    back_up, canonicalize_link, check_interrupted, ensure_space, mark_lost,
+    ownership::{owner_of, restore_owner},
+    space_needed, verify_restored, Action, ActionType, MirageError, MirageState, PlannedAction,
+    RevertFailure, RevertOptions, RevertReport, StoreLocation, DEFAULT_BACKUPS,
+};
+
+/// Walks up from `path` to the root of the tree whose store manages it.
+pub fn find_store_root<T: AsRef<Path>>(path: T) -> Result<PathBuf, MirageError> {
+    find_store_root_at(path.as_ref(), &StoreLocation::from_env())
+}
+
+/// Like [`find_store_root`], with the state of trees kept at `location`.
+pub(crate) fn find_store_root_at(
+    path: &Path,
+    location: &StoreLocation,
+) -> Result<PathBuf, MirageError> {
+    let path = canonicalize_link(path)?;
+    path.ancestors()
+        .skip(1)
+        .find(|dir| location.store(dir).join("wal.json").is_file())
+        .map(Path::to_path_buf)
+        .ok_or(MirageError::MissingStore(path))
+}
+
+/// Opens the store of the tree at `root`, kept at `location`, and checks that
+/// it is safe to edit.
+pub(crate) fn open_store(
+    root: &Path,
+    location: &StoreLocation,
+) -> Result<MirageState, MirageError> {
+    let mut state = MirageState::open_at(root, location)?;
+    state.ensure_unfrozen()?;
+    state.lock_run()?;
+    if state.wal.checkpoint < state.wal.actions.len() {
+        return Err(MirageError::PendingActions(root.to_path_buf()));
+    }
+    Ok(state)
+}
+
+/// Opens the store managing `path` and checks that it is safe to edit.
+fn open_for<T: AsRef<Path>>(path: T) -> Result<(PathBuf, MirageState), MirageError> {
+    let location = StoreLocation::from_env();
+    let state = open_store(&find_store_root_at(path.as_ref(), &location)?, &location)?;
+    let path = canonicalize_link(path.as_ref())?;
+    if !state.wal.redirections.contains_key(&path) {
+        return Err(MirageError::NotManaged(path));
+    }
+    Ok((path, state))
+}
+
+/// Replaces the link at `path` with an independent copy of its original and
+/// stops managing it. The original is deleted from the store once no other
+/// path refers to it.
+pub fn unshare<T: AsRef<Path>>(path: T) -> Result<(), MirageError> {
+    let (path, mut state) = open_for(path)?;
+    let original = state.wal.redirections[&path].clone();
+    if state.dry_run {
+        warn!("Store is read-only, not unsharing {:?}", path);
+        return Ok(());
+    }
+
+    debug!("Unsharing {:?} from {:?}", path, original);
+    restore(&path, &original, false)?;
+
+    state.release(&path)?;
+    state.commit()
+}
+
+/// Replaces the link at `path` with a file of its own holding what
+/// `original` holds, a hard link to it with `hard_link` where the
+/// filesystem allows.
+fn restore(path: &Path, original: &Path, hard_link: bool) -> Result<(), MirageError> {
+    // made next to the link and renamed over it so the path is never missing
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(
+        path.file_name()
+            .ok_or(MirageError::NotManaged(path.to_path_buf()))?,
+    );
+    tmp_name.push(".mirage-tmp");
+    let tmp = path.with_file_name(tmp_name);
+    let linked = hard_link
+        && match fs::hard_link(original, &tmp) {
+            Ok(()) => true,
+            Err(err) => {
+                debug!("Can't link {:?}, copying it: {}", path, err);
+                false
+            }
+        };
+    // a hard link shares the inode, and so the owner, of the original
+    if !linked {
+        let owner = owner_of(path);
+        fs::copy(original, &tmp)?;
+        restore_owner(&tmp, owner)?;
+    }
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Restores the managed files of the tree at `root` below any of `only`, a
+/// file or directory each, to files of their own, and stops managing them.
+/// The rest of the tree stays deduplicated and the store is kept, originals
+/// only being deleted once nothing refers to them any more. Files that
+/// differ from their recorded contents with [`RevertOptions::verify`] stay
+/// managed, keeping their original for a look.
+pub(crate) fn revert_only(
+    root: &Path,
+    only: &[PathBuf],
+    options: &RevertOptions,
+) -> Result<RevertReport, MirageError> {
+    let mut state = open_store(root, &options.location())?;
+    state.dry_run |= options.dry_run;
+    let mut selected = Vec::new();
+    for part in only {
+        let part = canonicalize_link(part)?;
+        let before = selected.len();
+        selected.extend(
+            state
+                .wal
+                .redirections
+                .iter()
+                .filter(|(path, _)| path.starts_with(&part))
+                .map(|(path, original)| (path.clone(), original.clone())),
+        );
+        if selected.len() == before {
+            return Err(MirageError::NotManaged(part));
+        }
+    }
+    selected.sort();
+    selected.dedup();
+    // restoring is copying the original over the link
+    let restores = selected
+        .iter()
+        .map(|(path, original)| Action::new(ActionType::Copy, original.clone(), path.clone()))
+        .collect::<Vec<_>>();
+
+    let space = space_needed(&restores, options.as_hardlinks)?;
+
+    if state.dry_run {
+        return Ok(RevertReport {
+            dry_run: true,
+            planned: restores.iter().map(PlannedAction::from).collect(),
+            space,
+            ..Default::default()
+        });
+    }
+    if let Some(action) = restores
+        .iter()
+        .find(|action| !action.source.exists())
+        .filter(|_| !options.force)
+    {
+        return Err(MirageError::MissingOriginal(action.source.clone()));
+    }
+
+    ensure_space(&space)?;
+
+    back_up(&state, "revert", options.backups.unwrap_or(DEFAULT_BACKUPS))?;
+    let mut report = RevertReport {
+        space,
+        ..Default::default()
+    };
+    let mut restored = HashMap::new();
+    let mut released = Vec::new();
+    let mut interrupted = Ok(());
+    for action in &restores {
+        // what was restored so far is released below before stopping
+        interrupted = check_interrupted();
+        if interrupted.is_err() {
+            break;
+        }
+        let (original, path) = (&action.source, &action.target);
+        if !original.exists() {
+            if mark_lost(action)? {
+                report.lost.push(path.clone());
+            }
+            released.push(path.clone());
+            continue;
+        }
+        debug!("Restoring {:?} from {:?}", path, original);
+        match restore(path, original, options.as_hardlinks) {
+            Ok(()) => {
+                report.restored += 1;
+                restored.insert(path.clone(), original.clone());
+            }
+            Err(err) => report.failed.push(RevertFailure {
+                path: path.clone(),
+                error: err.to_string(),
+            }),
+        }
+    }
+    if options.verify && interrupted.is_ok() {
+        verify_restored(&state, restored.clone(), &mut report)?;
+    }
+    released.extend(
+        restored
+            .into_keys()
+            .filter(|path| !report.mismatched.contains(path)),
+    );
+    released.sort();
+    for path in released {
+        state.release(&path)?;
+    }
+    state.commit()?;
+    interrupted?;
+    Ok(report)
+}
+
+/// Deletes the managed link at `path` and stops managing it. The original is
+/// deleted from the store once no other path refers to it.
+pub fn remove<T: AsRef<Path>>(path: T) -> Result<(), MirageError> {
+    let (path, mut state) = open_for(path)?;
+    if state.dry_run {
+        warn!("Store is read-only, not removing {:?}", path);
+        return Ok(());
+    }
+    debug!("Removing {:?}", path);
+    if fs::symlink_metadata(&path).is_ok() {
+        fs::remove_file(&path)?;
+    }
+
+    state.release(&path)?;
+    state.commit()
+}
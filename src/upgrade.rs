@@ -0,0 +1,274 @@
+//! Converting the links of an already deduplicated tree to another kind in
+//! place. Every path to convert is replaced with a symlink to, a hard link
+//! to or a clone of its original made next to it and renamed over, and the
+//! action that linked it is rewritten to match, so a revert still knows how
+//! to undo it.
+
+use std::{
+    ffi::OsString,
+    fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use log::{debug, warn};
+use serde::Serialize;
+use symlink::symlink_file;
+
+use crate::{
+    back_up, check_interrupted, diff::same_inode, full_match, owner_of, reflink, restore_owner,
+    ActionType, MirageError, MirageState, DEFAULT_BACKUPS,
+};
+
+/// How a deduplicated path refers to its original.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkMode {
+    Symlink,
+    Hardlink,
+    Reflink,
+}
+
+impl LinkMode {
+    /// The mode a linking action links with.
+    pub(crate) fn of(action: &ActionType) -> Option<LinkMode> {
+        match action {
+            ActionType::Symlink => Some(LinkMode::Symlink),
+            ActionType::Hardlink => Some(LinkMode::Hardlink),
+            ActionType::Reflink => Some(LinkMode::Reflink),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LinkMode::Symlink => "symlink",
+            LinkMode::Hardlink => "hardlink",
+            LinkMode::Reflink => "reflink",
+        }
+    }
+
+    pub(crate) fn action(&self) -> ActionType {
+        match self {
+            LinkMode::Symlink => ActionType::Symlink,
+            LinkMode::Hardlink => ActionType::Hardlink,
+            LinkMode::Reflink => ActionType::Reflink,
+        }
+    }
+}
+
+impl FromStr for LinkMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "symlink" => Ok(LinkMode::Symlink),
+            "hardlink" => Ok(LinkMode::Hardlink),
+            "reflink" => Ok(LinkMode::Reflink),
+            _ => Err(format!("unknown link mode {:?}", s)),
+        }
+    }
+}
+
+/// What [`upgrade`] converted.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct UpgradeReport {
+    /// Number of links converted.
+    pub upgraded: usize,
+    /// Symlinked paths left as they were, because they no longer linked to
+    /// their originals or converting them failed.
+    pub unchanged: Vec<PathBuf>,
+}
+
+/// Why [`migrate`] left a path as it was.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum Unmigrated {
+    /// The original is on another device, which hard links and clones
+    /// can't reach.
+    OtherDevice,
+    /// The filesystem can't clone files.
+    Unsupported,
+    /// The path no longer is what its action made it: the link was replaced
+    /// or retargeted, or the clone written to.
+    Diverged,
+    /// Converting it failed for another reason.
+    Failed(String),
+}
+
+/// What [`migrate`] converted.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct MigrateReport {
+    /// Number of paths converted.
+    pub migrated: usize,
+    /// Number of paths that already referred to their original that way.
+    pub already: usize,
+    /// Paths left as they were and why, in path order.
+    pub remaining: Vec<(PathBuf, Unmigrated)>,
+}
+
+/// Whether `path` still is what linking it to `original` as `mode` made it.
+pub(crate) fn intact(path: &Path, original: &Path, mode: LinkMode) -> Result<bool, MirageError> {
+    let Ok(meta) = fs::symlink_metadata(path) else {
+        return Ok(false);
+    };
+    Ok(match mode {
+        LinkMode::Symlink => fs::read_link(path).is_ok_and(|target| target == original),
+        LinkMode::Hardlink => meta.is_file() && same_inode(&meta, original),
+        // a clone is a file of its own, it may have been written to since
+        LinkMode::Reflink => meta.is_file() && full_match(path, original)?,
+    })
+}
+
+/// Why `path` can't refer to `original` as `mode`, as far as can be told
+/// without trying.
+#[cfg(unix)]
+fn infeasible(path: &Path, original: &Path, mode: LinkMode) -> Option<Unmigrated> {
+    use std::os::unix::fs::MetadataExt;
+
+    if mode == LinkMode::Symlink {
+        return None;
+    }
+    let dir = path.parent()?;
+    let (dir, original) = (fs::metadata(dir).ok()?, fs::metadata(original).ok()?);
+    (dir.dev() != original.dev()).then_some(Unmigrated::OtherDevice)
+}
+
+#[cfg(not(unix))]
+fn infeasible(_path: &Path, _original: &Path, _mode: LinkMode) -> Option<Unmigrated> {
+    None
+}
+
+/// Makes `path` refer to `original` as `to`, through a file next to it
+/// renamed over it so the path is never missing.
+pub(crate) fn relink(path: &Path, original: &Path, to: LinkMode) -> Result<(), MirageError> {
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(path.file_name().unwrap_or_default());
+    tmp_name.push(".mirage-tmp");
+    let tmp = path.with_file_name(tmp_name);
+    let owner = owner_of(path);
+    let made = match to {
+        LinkMode::Symlink => symlink_file(original, &tmp)
+            .map_err(MirageError::from)
+            .and_then(|()| restore_owner(&tmp, owner)),
+        // shares the inode, and so the owner, of the original
+        LinkMode::Hardlink => fs::hard_link(original, &tmp).map_err(MirageError::from),
+        LinkMode::Reflink => reflink(original, &tmp)
+            .map_err(MirageError::from)
+            .and_then(|()| restore_owner(&tmp, owner)),
+    };
+    if let Err(err) = made.and_then(|()| Ok(fs::rename(&tmp, path)?)) {
+        if fs::symlink_metadata(&tmp).is_ok() {
+            fs::remove_file(&tmp)?;
+        }
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Converts the managed paths of the tree at `target_dir` linked as one of
+/// `from` so they refer to their originals as `to`, along with the actions
+/// that linked them. Paths that can't be converted are left as they are and
+/// reported, the others carry on.
+fn convert(
+    target_dir: &Path,
+    from: &[LinkMode],
+    to: LinkMode,
+) -> Result<MigrateReport, MirageError> {
+    let mut state = MirageState::open(target_dir)?;
+    state.ensure_unfrozen()?;
+    let mut report = MigrateReport::default();
+    if state.dry_run {
+        warn!("Store is read-only, not converting {:?}", target_dir);
+        return Ok(report);
+    }
+    state.lock_run()?;
+    if state.wal.checkpoint < state.wal.actions.len() {
+        return Err(MirageError::PendingActions(target_dir.to_path_buf()));
+    }
+    state.probe_links(to)?;
+    back_up(&state, "migrate", DEFAULT_BACKUPS)?;
+
+    let mut redirected = state
+        .wal
+        .redirections
+        .iter()
+        .map(|(path, original)| (path.clone(), original.clone()))
+        .collect::<Vec<_>>();
+    redirected.sort();
+    for (path, original) in redirected {
+        check_interrupted()?;
+        // the action that linked the path last is the one a revert undoes
+        let Some(index) = state
+            .wal
+            .actions
+            .iter()
+            .rposition(|a| a.action.links() && a.source == path)
+        else {
+            continue;
+        };
+        let Some(mode) = LinkMode::of(&state.wal.actions[index].action) else {
+            continue;
+        };
+        if !from.contains(&mode) {
+            continue;
+        }
+        if mode == to {
+            report.already += 1;
+            continue;
+        }
+        let _lock = state.lock_original(&original)?;
+        if !intact(&path, &original, mode)? {
+            report.remaining.push((path, Unmigrated::Diverged));
+            continue;
+        }
+        if let Some(reason) = infeasible(&path, &original, to) {
+            report.remaining.push((path, reason));
+            continue;
+        }
+        debug!("Converting {:?} to a {:?} of {:?}", path, to, original);
+        match relink(&path, &original, to) {
+            Ok(()) => {
+                state.wal.actions[index].action = to.action();
+                state.wal.touch(index);
+                state.commit()?;
+                report.migrated += 1;
+            }
+            Err(MirageError::ErrorDuringIO(err)) if err.kind() == io::ErrorKind::Unsupported => {
+                report.remaining.push((path, Unmigrated::Unsupported));
+            }
+            Err(err) => {
+                warn!("Failed to convert {:?}: {:?}", path, err);
+                report
+                    .remaining
+                    .push((path, Unmigrated::Failed(format!("{:?}", err))));
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Converts every managed path of the tree at `target_dir`, however it is
+/// linked, so it refers to its original as `to`. Each path is checked first
+/// to still be what its action made it and to be able to refer to its
+/// original that way, paths that aren't or can't are reported.
+pub fn migrate<T: AsRef<Path>>(target_dir: T, to: LinkMode) -> Result<MigrateReport, MirageError> {
+    convert(
+        target_dir.as_ref(),
+        &[LinkMode::Symlink, LinkMode::Hardlink, LinkMode::Reflink],
+        to,
+    )
+}
+
+/// Converts every symlinked path of the tree at `target_dir` to a hard link
+/// to, or a clone of, its original. A path that fails to convert stays a
+/// symlink and is reported, the others carry on.
+pub fn upgrade<T: AsRef<Path>>(target_dir: T, to: LinkMode) -> Result<UpgradeReport, MirageError> {
+    if to == LinkMode::Symlink {
+        MirageState::open(&target_dir)?;
+        return Ok(UpgradeReport::default());
+    }
+    let report = convert(target_dir.as_ref(), &[LinkMode::Symlink], to)?;
+    Ok(UpgradeReport {
+        upgraded: report.migrated,
+        unchanged: report.remaining.into_iter().map(|(path, _)| path).collect(),
+    })
+}
@@ -0,0 +1,166 @@
+//! Checking that every redirection of a store still holds: each managed
+//! path is the link its action made to its recorded original, and each
+//! original is in the store, holding what its digest says if asked to hash
+//! them.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use log::{debug, trace};
+use serde::Serialize;
+
+use crate::{check_interrupted, diff::same_inode, ActionType, MirageError, MirageState};
+
+/// How a managed path or original differs from what the store recorded.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub enum Discrepancy {
+    /// The managed path is gone.
+    Missing,
+    /// The managed path is no longer the link its action made.
+    NotLinked,
+    /// A symlink points somewhere other than its original.
+    WrongTarget { actual: PathBuf },
+    /// The original is gone from the store.
+    OriginalMissing,
+    /// The original no longer holds what its recorded digest says.
+    ContentChanged,
+}
+
+impl fmt::Display for Discrepancy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Discrepancy::Missing => f.write_str("missing"),
+            Discrepancy::NotLinked => f.write_str("no longer a link to its original"),
+            Discrepancy::WrongTarget { actual } => {
+                write!(f, "points at {}", actual.display())
+            }
+            Discrepancy::OriginalMissing => f.write_str("original is missing"),
+            Discrepancy::ContentChanged => f.write_str("original differs from its digest"),
+        }
+    }
+}
+
+/// A path that isn't what the store recorded.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct Mismatch {
+    /// The managed path, or the original itself.
+    pub path: PathBuf,
+    pub original: PathBuf,
+    pub discrepancy: Discrepancy,
+}
+
+/// What [`verify`] checked and found.
+#[derive(Debug, Clone, Default, Serialize, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Number of redirections checked.
+    pub checked: usize,
+    /// Number of originals hashed, on a deep check.
+    pub hashed: usize,
+    /// Number of originals without a recorded digest, which a deep check
+    /// can't tell anything about.
+    pub unrecorded: usize,
+    /// Originals first, then managed paths, each in path order.
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl VerifyReport {
+    /// Whether everything checked is as the store recorded.
+    pub fn is_clean(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+/// What linking `path` to `original` with `link` should have left there.
+fn check_link(path: &Path, original: &Path, link: &ActionType) -> Option<Discrepancy> {
+    let Ok(meta) = fs::symlink_metadata(path) else {
+        return Some(Discrepancy::Missing);
+    };
+    match link {
+        ActionType::Hardlink => {
+            (!meta.is_file() || !same_inode(&meta, original)).then_some(Discrepancy::NotLinked)
+        }
+        // a clone is a file of its own, whatever it holds now
+        ActionType::Reflink => (!meta.is_file()).then_some(Discrepancy::NotLinked),
+        _ => {
+            if !meta.file_type().is_symlink() {
+                return Some(Discrepancy::NotLinked);
+            }
+            let actual = fs::read_link(path).ok()?;
+            let resolved = fs::canonicalize(path).ok();
+            (actual != original && resolved.as_deref() != Some(original))
+                .then_some(Discrepancy::WrongTarget { actual })
+        }
+    }
+}
+
+/// Checks every redirection of the tree at `target_dir`: that the managed
+/// path is the link its action made, pointing at the recorded original, and
+/// that the original exists. With `deep`, every original is hashed and
+/// compared with the digest recorded when it was copied. Nothing is
+/// modified.
+pub fn verify<T: AsRef<Path>>(target_dir: T, deep: bool) -> Result<VerifyReport, MirageError> {
+    let state = MirageState::open(&target_dir)?;
+    let executed = &state.wal.actions[..state.wal.checkpoint.min(state.wal.actions.len())];
+    // the last link each path got, and the digest each original was taken
+    // with
+    let mut links = HashMap::new();
+    let mut digests = HashMap::new();
+    for action in executed {
+        if action.action.links() {
+            links.insert(&action.source, &action.action);
+        } else if matches!(action.action, ActionType::Copy) {
+            if let Some(recorded) = action.recorded_digest() {
+                digests.insert(&action.target, recorded);
+            }
+        }
+    }
+
+    let mut originals: BTreeMap<&PathBuf, Vec<&PathBuf>> = BTreeMap::new();
+    for (path, original) in &state.wal.redirections {
+        originals.entry(original).or_default().push(path);
+    }
+
+    let mut report = VerifyReport::default();
+    let mut managed = Vec::new();
+    for (original, mut paths) in originals {
+        check_interrupted()?;
+        paths.sort();
+        let mismatch = |path: &Path, discrepancy| Mismatch {
+            path: path.to_path_buf(),
+            original: original.clone(),
+            discrepancy,
+        };
+        if !original.is_file() {
+            report
+                .mismatches
+                .push(mismatch(original, Discrepancy::OriginalMissing));
+        } else if deep {
+            match digests.get(original) {
+                Some(&(digest, algorithm)) => {
+                    debug!("Hashing {:?}", original);
+                    report.hashed += 1;
+                    if algorithm.hash_file(original)? != digest {
+                        report
+                            .mismatches
+                            .push(mismatch(original, Discrepancy::ContentChanged));
+                    }
+                }
+                None => report.unrecorded += 1,
+            }
+        }
+        for path in paths {
+            report.checked += 1;
+            let link = links.get(path).copied().unwrap_or(&ActionType::Symlink);
+            match check_link(path, original, link) {
+                Some(discrepancy) => managed.push(mismatch(path, discrepancy)),
+                None => trace!("{:?} still links to {:?}", path, original),
+            }
+        }
+    }
+    managed.sort_by(|a, b| a.path.cmp(&b.path));
+    report.mismatches.extend(managed);
+    Ok(report)
+}
@@ -0,0 +1,305 @@
+//! Reading the WAL one action at a time. Commands that only look at the
+//! history, like `inspect` and `replay --plan`, never hold more than one
+//! action in memory, and the redirections are only read when asked for.
+//! Segments are read in order, then the recent actions of `wal.json`.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{BufReader, Read},
+    path::{Path, PathBuf},
+};
+
+use log::debug;
+use serde::{
+    de::{self, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor},
+    Deserialize, Deserializer,
+};
+
+use crate::{
+    digest::{to_hex, Sha256},
+    redirections::RedirectionFile,
+    schema::{check_version, migrate_action},
+    segment::Segment,
+    signing_existing, signing_key, store_path, verify_signature, Action, MirageError, WAL,
+    WAL_VERSION,
+};
+
+/// The fields of the WAL small enough to be read upfront. Everything else is
+/// skipped without being kept.
+#[derive(Deserialize)]
+struct Header {
+    #[serde(default)]
+    version: Option<serde_json::Value>,
+    checkpoint: usize,
+    #[serde(default)]
+    segments: Vec<Segment>,
+}
+
+/// The WAL of a managed tree, opened for streaming. Nothing is ever written.
+pub(crate) struct WalStream {
+    path: PathBuf,
+    /// Version of the format of the WAL, its actions read through the
+    /// migrations since, see [`schema`](crate::schema).
+    version: u64,
+    checkpoint: usize,
+    segments: Vec<Segment>,
+}
+
+/// Hands every action of the `actions` field to a callback, skipping the
+/// other fields. An error of the callback ends the walk and is kept aside,
+/// so that it comes back out unchanged rather than as a parse error.
+struct Actions<'a, F> {
+    /// Position in the WAL of the first action.
+    start: usize,
+    version: u64,
+    each: &'a mut F,
+    failed: &'a RefCell<Option<MirageError>>,
+}
+
+impl<'de, F> Visitor<'de> for Actions<'_, F>
+where
+    F: FnMut(usize, Action) -> Result<(), MirageError>,
+{
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a WAL")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let (start, version, each, failed) = (self.start, self.version, self.each, self.failed);
+        while let Some(key) = map.next_key::<String>()? {
+            if key == "actions" {
+                map.next_value_seed(Actions {
+                    start,
+                    version,
+                    each,
+                    failed,
+                })?;
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let mut index = self.start;
+        while let Some(action) = self.next_action(&mut seq)? {
+            if let Err(err) = (self.each)(index, action) {
+                *self.failed.borrow_mut() = Some(err);
+                return Err(de::Error::custom("stopped"));
+            }
+            index += 1;
+        }
+        Ok(())
+    }
+}
+
+impl<F> Actions<'_, F> {
+    /// The next action of `seq`, migrated from an older format if need be.
+    fn next_action<'de, A: SeqAccess<'de>>(&self, seq: &mut A) -> Result<Option<Action>, A::Error> {
+        if self.version >= WAL_VERSION {
+            return seq.next_element();
+        }
+        let Some(mut action) = seq.next_element::<serde_json::Value>()? else {
+            return Ok(None);
+        };
+        migrate_action(&mut action, self.version);
+        serde_json::from_value(action)
+            .map(Some)
+            .map_err(de::Error::custom)
+    }
+}
+
+impl<'de, F> DeserializeSeed<'de> for Actions<'_, F>
+where
+    F: FnMut(usize, Action) -> Result<(), MirageError>,
+{
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+/// Only the fields of the WAL telling where its redirections are.
+#[derive(Deserialize)]
+struct Redirected {
+    #[serde(default)]
+    redirections: HashMap<PathBuf, PathBuf>,
+    #[serde(default)]
+    redirection_files: Vec<RedirectionFile>,
+}
+
+impl WalStream {
+    /// Opens the WAL of the managed tree at `target_dir`, checking it against
+    /// its signature first.
+    pub fn open<T: AsRef<Path>>(target_dir: T) -> Result<WalStream, MirageError> {
+        let mirage_path = store_path(target_dir.as_ref());
+        let path = mirage_path.join("wal.json");
+        if !path.is_file() {
+            return Err(MirageError::MissingStore(target_dir.as_ref().to_path_buf()));
+        }
+        let key = signing_key()?;
+        verify_signature(
+            &mirage_path,
+            BufReader::new(File::open(&path)?),
+            key.as_deref(),
+            signing_existing(),
+        )?;
+        debug!("Streaming wal file {:?}", path);
+        let header: Header = serde_json::from_reader(BufReader::new(File::open(&path)?))?;
+        let version = check_version(header.version.as_ref())?;
+        for segment in &header.segments {
+            let mut file = File::open(mirage_path.join(&segment.file))?;
+            let mut hasher = Sha256::default();
+            let mut buf = vec![0; 64 * 1024];
+            loop {
+                let read = file.read(&mut buf)?;
+                if read == 0 {
+                    break;
+                }
+                hasher.update(&buf[..read]);
+            }
+            if to_hex(&hasher.finish()) != segment.sha256 {
+                return Err(MirageError::WALTampered);
+            }
+        }
+        Ok(WalStream {
+            path,
+            version,
+            checkpoint: header.checkpoint,
+            segments: header.segments,
+        })
+    }
+
+    /// Number of actions already executed.
+    pub fn checkpoint(&self) -> usize {
+        self.checkpoint
+    }
+
+    /// Calls `each` with every action of the WAL and its position, in order,
+    /// stopping at the first error.
+    pub fn for_each_action<F>(&self, mut each: F) -> Result<(), MirageError>
+    where
+        F: FnMut(usize, Action) -> Result<(), MirageError>,
+    {
+        let failed = RefCell::new(None);
+        let mut start = 0;
+        let dir = self.path.parent().unwrap_or(Path::new(""));
+        for segment in &self.segments {
+            let file = File::open(dir.join(&segment.file))?;
+            let mut deserializer = serde_json::Deserializer::from_reader(BufReader::new(file));
+            let result = deserializer.deserialize_seq(Actions {
+                start,
+                version: self.version,
+                each: &mut each,
+                failed: &failed,
+            });
+            if let Some(err) = failed.take() {
+                return Err(err);
+            }
+            result?;
+            deserializer.end()?;
+            start += segment.actions;
+        }
+
+        let mut deserializer =
+            serde_json::Deserializer::from_reader(BufReader::new(File::open(&self.path)?));
+        let result = deserializer.deserialize_map(Actions {
+            start,
+            version: self.version,
+            each: &mut each,
+            failed: &failed,
+        });
+        if let Some(err) = failed.into_inner() {
+            return Err(err);
+        }
+        result?;
+        deserializer.end()?;
+        Ok(())
+    }
+
+    /// Reads the redirections, skipping the actions.
+    pub fn redirections(&self) -> Result<HashMap<PathBuf, PathBuf>, MirageError> {
+        let redirected: Redirected =
+            serde_json::from_reader(BufReader::new(File::open(&self.path)?))?;
+        let mut wal = WAL {
+            inline_redirections: redirected.redirections,
+            redirection_files: redirected.redirection_files,
+            ..Default::default()
+        };
+        wal.load_redirections(self.path.parent().unwrap_or(Path::new("")))?;
+        Ok(wal
+            .redirections
+            .iter()
+            .map(|(path, original)| (path.clone(), original.clone()))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use tempfile::tempdir;
+
+    use super::WalStream;
+    use crate::{ActionType, MirageError};
+
+    #[test]
+    fn streams_actions() {
+        let dir = tempdir().unwrap();
+        fs::create_dir(dir.path().join(".mirage")).unwrap();
+        fs::write(
+            dir.path().join(".mirage").join("wal.json"),
+            r#"{"actions":[
+                {"action":"Copy","source":"/t/a","target":"/o/a","group":0},
+                {"action":"Symlink","source":"/t/a","target":"/o/a","group":0},
+                {"action":"Symlink","source":"/t/b","target":"/o/a","group":0}
+            ],"redirections":{"/t/a":"/o/a","/t/b":"/o/a"},"checkpoint":2,"next_group":1}"#,
+        )
+        .unwrap();
+
+        let wal = WalStream::open(dir.path()).unwrap();
+        assert_eq!(wal.checkpoint(), 2);
+        let mut seen = Vec::new();
+        wal.for_each_action(|index, action| {
+            seen.push((
+                index,
+                matches!(action.action, ActionType::Copy),
+                action.source,
+            ));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            seen,
+            vec![
+                (0, true, PathBuf::from("/t/a")),
+                (1, false, PathBuf::from("/t/a")),
+                (2, false, PathBuf::from("/t/b")),
+            ]
+        );
+        assert_eq!(
+            wal.redirections().unwrap()[&PathBuf::from("/t/b")],
+            PathBuf::from("/o/a")
+        );
+
+        // the first error ends the walk as it was raised
+        let mut calls = 0;
+        let result = wal.for_each_action(|index, _| {
+            calls += 1;
+            if index == 1 {
+                return Err(MirageError::UnknownAction(index));
+            }
+            Ok(())
+        });
+        assert!(matches!(result, Err(MirageError::UnknownAction(1))));
+        assert_eq!(calls, 2);
+    }
+}
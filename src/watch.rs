@@ -0,0 +1,142 @@
+//! Deduplicating a tree continuously. The tree is polled: every so often it
+//! is walked and the size and modification time of its files compared with
+//! the last walk. Files that appeared or changed are only picked up once
+//! left alone for a whole interval, so that nothing is linked while still
+//! being written. Runs after the first only look at those files and the
+//! files they may duplicate, the ones of the same size.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use log::debug;
+
+use crate::{apply_with_options, check_interrupted, walk, ApplyOptions, ApplyReport, MirageError};
+
+/// Longest the watch sleeps between two checks for an interrupt.
+const TICK: Duration = Duration::from_millis(100);
+
+/// Size and modification time of every regular file of a tree.
+type Snapshot = BTreeMap<PathBuf, (u64, Option<SystemTime>)>;
+
+/// The files an apply with `options` would look at, links left out.
+fn snapshot(target_dir: &Path, options: &ApplyOptions) -> Snapshot {
+    let mut files = Snapshot::new();
+    for entry in walk(target_dir, options) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                // warned about once by every apply, not on every poll
+                debug!("Can't access {:?} due to {:?}", err.path(), err.io_error());
+                continue;
+            }
+        };
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        if let Ok(meta) = entry.metadata() {
+            files.insert(entry.into_path(), (meta.len(), meta.modified().ok()));
+        }
+    }
+    files
+}
+
+/// The options of a run after files of `now` that `applied` doesn't have
+/// as they are appeared or changed: only those and the files of the same
+/// size are looked at. With metadata ignored files of other sizes may match
+/// too, and every file is.
+fn rerun_options(options: &ApplyOptions, applied: &Snapshot, now: &Snapshot) -> ApplyOptions {
+    if options.ignore_metadata {
+        return options.clone();
+    }
+    let sizes = now
+        .iter()
+        .filter(|(path, file)| applied.get(*path) != Some(file))
+        .map(|(_, (size, _))| *size)
+        .collect::<HashSet<_>>();
+    // apply lists files by their canonical paths
+    let changed = now
+        .iter()
+        .filter(|(_, (size, _))| sizes.contains(size))
+        .filter_map(|(path, _)| fs::canonicalize(path).ok())
+        .filter(|path| {
+            options
+                .files_from
+                .as_ref()
+                .is_none_or(|listed| listed.contains(path))
+        })
+        .collect();
+    ApplyOptions {
+        files_from: Some(changed),
+        ..options.clone()
+    }
+}
+
+/// Sleeps for `duration`, failing with [`MirageError::Interrupted`] as soon
+/// as a handled signal comes in.
+fn pause(duration: Duration) -> Result<(), MirageError> {
+    let mut left = duration;
+    while !left.is_zero() {
+        check_interrupted()?;
+        let tick = left.min(TICK);
+        thread::sleep(tick);
+        left -= tick;
+    }
+    check_interrupted()
+}
+
+/// Applies deduplication to the tree at `target_dir` with `options`, then
+/// polls it every `interval` and applies again to the files that appeared
+/// or changed, linking the new duplicates to the originals already in the
+/// store and committing the WAL as every run does. `on_run` gets the report
+/// of every run, the first included, and stops watching by returning
+/// `false`. A signal handled between two runs stops it too, one coming in
+/// during a run fails it with [`MirageError::Interrupted`] like any apply.
+pub fn watch<T, F>(
+    target_dir: T,
+    options: &ApplyOptions,
+    interval: Duration,
+    mut on_run: F,
+) -> Result<(), MirageError>
+where
+    T: AsRef<Path>,
+    F: FnMut(&ApplyReport) -> bool,
+{
+    let target_dir = target_dir.as_ref();
+    // the tree as the last run found it, taken before the run so that files
+    // written meanwhile are picked up by the next, and as the last poll did
+    let mut applied = snapshot(target_dir, options);
+    if !on_run(&apply_with_options(target_dir, options)?) {
+        return Ok(());
+    }
+    let mut polled = applied.clone();
+    loop {
+        match pause(interval) {
+            Err(MirageError::Interrupted) => return Ok(()),
+            paused => paused?,
+        }
+        let now = snapshot(target_dir, options);
+        let changed = now
+            .iter()
+            .filter(|(path, file)| applied.get(*path) != Some(file))
+            .count();
+        if changed == 0 {
+            // nothing new, files may have been removed or linked
+            applied = now.clone();
+        } else if now != polled {
+            debug!("{} files changed, waiting for them to settle", changed);
+        } else {
+            debug!("{} files appeared or changed, applying again", changed);
+            let rerun = rerun_options(options, &applied, &now);
+            applied = now.clone();
+            if !on_run(&apply_with_options(target_dir, &rerun)?) {
+                return Ok(());
+            }
+        }
+        polled = now;
+    }
+}
@@ -0,0 +1,445 @@
+//! Why a file was or wasn't deduplicated by the last apply run.
+//!
+//! Every run records what it decided for each file it scanned, along with
+//! the filters it walked the tree with, so that files it never saw can be
+//! explained too.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    fs::{self, File},
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    canonicalize_link, extension_of, find_store_root,
+    glob::Glob,
+    ignore::{ignore_files, Ignores},
+    is_included,
+    journal::Journal,
+    state_dir_name, ApplyOptions, JournalEntry, MirageError, MirageState, Rollback,
+    DEFAULT_IGNORES, VCS_DIRS,
+};
+
+/// File in the store holding the decisions of the last run.
+const DECISIONS_FILE: &str = "decisions.json";
+
+/// What an apply run made of a file it scanned.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Decision {
+    /// The file resolves outside the tree, through a followed symlink.
+    OutsideTree,
+    /// No other file has its size.
+    UniqueSize,
+    /// Compared with other files of its size, none of them matched.
+    NoMatch { compared: usize },
+    /// Its group was left alone, its original would exceed the store quota.
+    OverQuota,
+    /// Already linked to an original by an earlier run.
+    AlreadyManaged { original: PathBuf },
+    /// Linked to an original already in the store.
+    LinkedToOriginal { original: PathBuf },
+    /// Matched another file, both linked to a new original.
+    Deduplicated { original: PathBuf },
+    /// Its group was undone because one of its actions failed.
+    RolledBack { error: String },
+    /// Not in the list of files the run was given.
+    NotListed,
+    /// Matched another file, whose extents it now shares in place.
+    ExtentsShared { with: PathBuf },
+    /// Smaller than the minimum size files need to be deduplicated.
+    TooSmall,
+}
+
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Decision::OutsideTree => f.write_str("resolves outside the tree"),
+            Decision::UniqueSize => f.write_str("no other file has its size"),
+            Decision::NoMatch { compared } => write!(
+                f,
+                "compared with {} files of its size, none matched",
+                compared
+            ),
+            Decision::OverQuota => f.write_str("its original would exceed the store quota"),
+            Decision::AlreadyManaged { original } => {
+                write!(f, "already linked to {}", original.display())
+            }
+            Decision::LinkedToOriginal { original } => {
+                write!(f, "linked to existing original {}", original.display())
+            }
+            Decision::Deduplicated { original } => {
+                write!(f, "deduplicated into {}", original.display())
+            }
+            Decision::RolledBack { error } => write!(f, "its group was rolled back: {}", error),
+            Decision::NotListed => f.write_str("not in the list of files given to the run"),
+            Decision::ExtentsShared { with } => {
+                write!(f, "shares its extents with {}", with.display())
+            }
+            Decision::TooSmall => f.write_str("smaller than the minimum size"),
+        }
+    }
+}
+
+/// A filter that kept a file from being scanned at all.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub enum Exclusion {
+    /// One of the [`DEFAULT_IGNORES`].
+    Junk,
+    /// Inside one of the [`VCS_DIRS`].
+    VersionControl,
+    /// Its extension is excluded.
+    Extension(String),
+    /// Part of the store itself.
+    Store,
+    /// Matches none of the globs files had to match.
+    NotIncluded,
+    /// Ignored by the rules of this [`IGNORE_FILE`](crate::IGNORE_FILE) or
+    /// `.gitignore`, or inside a directory they ignore.
+    IgnoreFile(PathBuf),
+    /// Deeper below the root than the run walked.
+    TooDeep,
+    /// On another filesystem than the root, which the run didn't cross into.
+    OtherFileSystem,
+}
+
+/// Why a file is the way the last run left it.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub enum Why {
+    /// The tree was never applied since decisions were recorded.
+    NoRun,
+    /// What the last run decided for the file.
+    Decided(Decision),
+    /// A managed link, which runs don't scan again.
+    Managed { original: PathBuf },
+    /// Filtered out before the last run looked at it.
+    Excluded(Exclusion),
+    /// An unmanaged symlink, which runs leave alone.
+    Symlink,
+    /// The last run didn't see the file, it was created since.
+    NotSeen,
+}
+
+impl fmt::Display for Why {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Why::NoRun => f.write_str("no apply run was recorded"),
+            Why::Decided(decision) => decision.fmt(f),
+            Why::Managed { original } => write!(f, "managed link to {}", original.display()),
+            Why::Excluded(Exclusion::Junk) => f.write_str("excluded as a junk file"),
+            Why::Excluded(Exclusion::VersionControl) => {
+                f.write_str("excluded as version control internals")
+            }
+            Why::Excluded(Exclusion::Extension(extension)) => {
+                write!(f, "excluded by its extension {}", extension)
+            }
+            Why::Excluded(Exclusion::Store) => f.write_str("part of the store"),
+            Why::Excluded(Exclusion::NotIncluded) => {
+                f.write_str("excluded by matching none of the included globs")
+            }
+            Why::Excluded(Exclusion::IgnoreFile(file)) => {
+                write!(f, "ignored by {}", file.display())
+            }
+            Why::Excluded(Exclusion::TooDeep) => f.write_str("deeper than the run walked"),
+            Why::Excluded(Exclusion::OtherFileSystem) => {
+                f.write_str("on another filesystem than the tree")
+            }
+            Why::Symlink => f.write_str("unmanaged symlink"),
+            Why::NotSeen => f.write_str("not seen by the last run, created since"),
+        }
+    }
+}
+
+/// The filters of [`ApplyOptions`] a run walked the tree with.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Filters {
+    no_default_ignores: bool,
+    include_vcs: bool,
+    exclude_extensions: Vec<String>,
+    #[serde(default)]
+    include: Vec<String>,
+    #[serde(default)]
+    no_ignore_files: bool,
+    #[serde(default)]
+    respect_gitignore: bool,
+    #[serde(default)]
+    max_depth: Option<usize>,
+    #[serde(default)]
+    one_file_system: bool,
+}
+
+/// Decisions of the running apply, saved to the store when it ends.
+#[derive(Default, Serialize, Deserialize)]
+pub(crate) struct Decisions {
+    /// `None` for dry runs, which save nothing.
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    #[serde(skip)]
+    journal: Option<Journal>,
+    filters: Filters,
+    files: BTreeMap<PathBuf, Decision>,
+}
+
+impl Decisions {
+    pub fn new(state: &MirageState, options: &ApplyOptions) -> Result<Decisions, MirageError> {
+        let journal = if options.journal && !state.dry_run {
+            Some(Journal::create(state)?)
+        } else {
+            None
+        };
+        Ok(Decisions {
+            path: (!state.dry_run).then(|| state.source_path.join(DECISIONS_FILE)),
+            journal,
+            filters: Filters {
+                no_default_ignores: options.no_default_ignores,
+                include_vcs: options.include_vcs,
+                exclude_extensions: options
+                    .exclude_extensions
+                    .iter()
+                    .map(|extension| extension.to_lowercase())
+                    .collect(),
+                include: options.include.clone(),
+                no_ignore_files: options.no_ignore_files,
+                respect_gitignore: options.respect_gitignore,
+                max_depth: options.max_depth,
+                one_file_system: options.one_file_system,
+            },
+            files: BTreeMap::new(),
+        })
+    }
+
+    /// Journals the comparison of `candidate` with `with`.
+    pub fn compared(
+        &mut self,
+        candidate: &Path,
+        with: &Path,
+        same: bool,
+    ) -> Result<(), MirageError> {
+        if let Some(journal) = &mut self.journal {
+            journal.write(&JournalEntry::Compared {
+                candidate: candidate.to_path_buf(),
+                with: with.to_path_buf(),
+                same,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Records `decision` for `path`, unless something was decided already.
+    pub fn record(&mut self, path: &Path, decision: impl FnOnce() -> Decision) {
+        self.files
+            .entry(path.to_path_buf())
+            .or_insert_with(decision);
+    }
+
+    /// Turns the decisions to link to a rolled back original into the error
+    /// that undid them.
+    pub fn rolled_back(&mut self, rollbacks: &[Rollback]) {
+        let errors = rollbacks
+            .iter()
+            .map(|rollback| (&rollback.original, &rollback.error))
+            .collect::<HashMap<_, _>>();
+        for decision in self.files.values_mut() {
+            if let Decision::LinkedToOriginal { original }
+            | Decision::Deduplicated { original }
+            | Decision::ExtentsShared { with: original } = decision
+            {
+                if let Some(error) = errors.get(original) {
+                    *decision = Decision::RolledBack {
+                        error: error.to_string(),
+                    };
+                }
+            }
+        }
+    }
+
+    pub fn save(&mut self) -> Result<(), MirageError> {
+        if let Some(path) = &self.path {
+            serde_json::to_writer(BufWriter::new(File::create(path)?), self)?;
+        }
+        if let Some(mut journal) = self.journal.take() {
+            for (path, decision) in &self.files {
+                journal.write(&JournalEntry::Decided {
+                    path: path.clone(),
+                    decision: decision.clone(),
+                })?;
+            }
+            journal.finish()?;
+        }
+        Ok(())
+    }
+
+    /// The filter of the run that would have kept `path` of the tree at
+    /// `root` from being scanned.
+    fn exclusion(&self, root: &Path, path: &Path) -> Option<Exclusion> {
+        let relative = path.strip_prefix(root).ok()?;
+        if self
+            .filters
+            .max_depth
+            .is_some_and(|depth| relative.components().count() > depth)
+        {
+            return Some(Exclusion::TooDeep);
+        }
+        if self.filters.one_file_system && other_device(root, path) {
+            return Some(Exclusion::OtherFileSystem);
+        }
+        let store = state_dir_name();
+        let mut names = relative.iter().filter_map(|name| name.to_str()).peekable();
+        while let Some(name) = names.next() {
+            if name == store {
+                return Some(Exclusion::Store);
+            }
+            if !self.filters.include_vcs && VCS_DIRS.contains(&name) {
+                return Some(Exclusion::VersionControl);
+            }
+            let last = names.peek().is_none();
+            if last
+                && !self.filters.no_default_ignores
+                && DEFAULT_IGNORES
+                    .iter()
+                    .any(|junk| junk.eq_ignore_ascii_case(name))
+            {
+                return Some(Exclusion::Junk);
+            }
+        }
+        if let Some(extension) = extension_of(path)
+            .filter(|extension| self.filters.exclude_extensions.contains(extension))
+        {
+            return Some(Exclusion::Extension(extension));
+        }
+        let included = self
+            .filters
+            .include
+            .iter()
+            .map(|pattern| Glob::new(pattern))
+            .collect::<Vec<_>>();
+        if !is_included(path, root, &included) {
+            return Some(Exclusion::NotIncluded);
+        }
+        // from the top, a directory ignoring all of what is below it
+        let mut ignores = Ignores::new(
+            root,
+            ignore_files(
+                self.filters.respect_gitignore,
+                !self.filters.no_ignore_files,
+            ),
+        )?;
+        let mut ancestors = path
+            .ancestors()
+            .take_while(|ancestor| *ancestor != root)
+            .collect::<Vec<_>>();
+        ancestors.reverse();
+        ancestors
+            .into_iter()
+            .find_map(|ancestor| ignores.ignoring(ancestor, ancestor != path || path.is_dir()))
+            .map(Exclusion::IgnoreFile)
+    }
+}
+
+/// Whether `path` is on another device than `root`.
+#[cfg(unix)]
+fn other_device(root: &Path, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (fs::metadata(root), fs::symlink_metadata(path)) {
+        (Ok(root), Ok(meta)) => root.dev() != meta.dev(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn other_device(_root: &Path, _path: &Path) -> bool {
+    false
+}
+
+/// Explains why the last apply run of the tree managing `path` did or didn't
+/// deduplicate it.
+pub fn why<T: AsRef<Path>>(path: T) -> Result<Why, MirageError> {
+    let root = find_store_root(&path)?;
+    let state = MirageState::open(&root)?;
+    let path = canonicalize_link(path.as_ref())?;
+    let recorded = state.source_path.join(DECISIONS_FILE);
+    let decisions: Decisions = if recorded.is_file() {
+        serde_json::from_reader(BufReader::new(File::open(recorded)?))?
+    } else {
+        return Ok(Why::NoRun);
+    };
+
+    if let Some(decision) = decisions.files.get(&path) {
+        return Ok(Why::Decided(decision.clone()));
+    }
+    if let Some(original) = state.wal.redirections.get(&path) {
+        return Ok(Why::Managed {
+            original: original.clone(),
+        });
+    }
+    let root = fs::canonicalize(&root)?;
+    if let Some(exclusion) = decisions.exclusion(&root, &path) {
+        return Ok(Why::Excluded(exclusion));
+    }
+    if fs::symlink_metadata(&path).is_ok_and(|meta| meta.file_type().is_symlink()) {
+        return Ok(Why::Symlink);
+    }
+    Ok(Why::NotSeen)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{Decisions, Exclusion, Filters};
+
+    #[test]
+    fn explains_exclusions() {
+        let decisions = Decisions {
+            filters: Filters {
+                exclude_extensions: vec!["iso".to_string()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let root = Path::new("/tree");
+        let exclusion = |path: &str| decisions.exclusion(root, Path::new(path));
+        assert_eq!(exclusion("/tree/a/Thumbs.db"), Some(Exclusion::Junk));
+        assert_eq!(
+            exclusion("/tree/.git/objects/ab"),
+            Some(Exclusion::VersionControl)
+        );
+        assert_eq!(
+            exclusion("/tree/disk.ISO"),
+            Some(Exclusion::Extension("iso".to_string()))
+        );
+        assert_eq!(exclusion("/tree/.mirage/wal.json"), Some(Exclusion::Store));
+        // junk names only count for files
+        assert_eq!(exclusion("/tree/Thumbs.db/file"), None);
+        assert_eq!(exclusion("/tree/a/file.txt"), None);
+
+        let decisions = Decisions {
+            filters: Filters {
+                include: vec!["*.mkv".to_string()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let exclusion = |path: &str| decisions.exclusion(root, Path::new(path));
+        assert_eq!(exclusion("/tree/films/a.mkv"), None);
+        assert_eq!(exclusion("/tree/films/a.srt"), Some(Exclusion::NotIncluded));
+
+        let decisions = Decisions {
+            filters: Filters {
+                max_depth: Some(2),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let exclusion = |path: &str| decisions.exclusion(root, Path::new(path));
+        assert_eq!(exclusion("/tree/films/a.mkv"), None);
+        assert_eq!(
+            exclusion("/tree/films/2024/a.mkv"),
+            Some(Exclusion::TooDeep)
+        );
+    }
+}